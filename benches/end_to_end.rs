@@ -0,0 +1,89 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kvs::thread_pool::{
+    NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool, WorkStealingThreadPool,
+};
+use kvs::{Codec, KvServer, KvStore, KvsClient, KvsEngine, SledKvsEngine};
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+use tempfile::TempDir;
+
+/// Concurrent clients issuing requests during each iteration.
+const CLIENTS: usize = 8;
+/// Set and get pairs of every client in iteration.
+const REQUESTS: usize = 50;
+
+/// Serves the engine with the pool on an ephemeral port.
+fn start_embedded<E: KvsEngine, P: ThreadPool + Send + Sync + 'static>(
+    engine: E,
+    pool: P,
+) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = KvServer::new(engine, pool);
+    thread::spawn(move || server.run_on(listener));
+    addr
+}
+
+/// Clients connect, write and read back their keys, then disconnect, so
+/// that pools with fewer threads than clients serve them in turns.
+fn clients_round(addr: SocketAddr) {
+    thread::scope(|scope| {
+        for idx in 0..CLIENTS {
+            scope.spawn(move || {
+                let mut client = KvsClient::connect(addr, Codec::Bincode).unwrap();
+                for i in 0..REQUESTS {
+                    let key = format!("key{}-{}", idx, i);
+                    client.set(key.clone(), "value".to_owned()).unwrap();
+                    assert!(client.get(key).unwrap().is_some());
+                }
+            });
+        }
+    })
+}
+
+fn bench_end_to_end<P: ThreadPool + Send + Sync + 'static>(
+    c: &mut Criterion,
+    pool_name: &str,
+    threads: &[usize],
+) {
+    let mut group = c.benchmark_group("end_to_end");
+    group.sample_size(20);
+    group.throughput(Throughput::Elements((CLIENTS * REQUESTS * 2) as u64));
+
+    for &threads in threads {
+        let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+        let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+        let addrs = [
+            (
+                "kvs",
+                start_embedded(
+                    KvStore::open(kvs_dir.path()).unwrap(),
+                    P::new(threads).unwrap(),
+                ),
+            ),
+            (
+                "sled",
+                start_embedded(
+                    SledKvsEngine::new(sled_dir.path()).unwrap(),
+                    P::new(threads).unwrap(),
+                ),
+            ),
+        ];
+        for (engine, addr) in addrs {
+            let id = BenchmarkId::new(format!("{}/{}", engine, pool_name), threads);
+            group.bench_function(id, |b| b.iter(|| clients_round(addr)));
+        }
+    }
+    group.finish();
+}
+
+fn end_to_end_benchmark(c: &mut Criterion) {
+    // naive pool runs every connection on its own thread.
+    bench_end_to_end::<NaiveThreadPool>(c, "naive", &[0]);
+    bench_end_to_end::<SharedQueueThreadPool>(c, "shared_queue", &[1, 2, 4, 8]);
+    bench_end_to_end::<RayonThreadPool>(c, "rayon", &[1, 2, 4, 8]);
+    bench_end_to_end::<WorkStealingThreadPool>(c, "work_stealing", &[1, 2, 4, 8]);
+}
+
+criterion_group!(benches, end_to_end_benchmark);
+criterion_main!(benches);
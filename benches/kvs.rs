@@ -1,7 +1,15 @@
-use criterion::{criterion_group, criterion_main, Criterion};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use kvs::{KvStore, KvsEngine, SledKvsEngine, StoreOptions};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::path::Path;
 use tempfile::TempDir;
 
+/// Keys written before reads are measured.
+const KEYS: usize = 1 << 12;
+/// Random reads in each iteration.
+const READS: usize = 1000;
+
 fn criterion_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("set_bench");
 
@@ -42,5 +50,108 @@ fn criterion_benchmark(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, criterion_benchmark);
+/// Sets keys `key0..KEYS` to values of 100 bytes.
+fn fill(engine: &impl KvsEngine) {
+    for i in 0..KEYS {
+        engine.set(format!("key{}", i), "v".repeat(100)).unwrap();
+    }
+}
+
+/// Reads random existing keys.
+fn random_reads(engine: &impl KvsEngine, rng: &mut StdRng) {
+    for _ in 0..READS {
+        let key = format!("key{}", rng.gen_range(0, KEYS));
+        assert!(engine.get(key).unwrap().is_some());
+    }
+}
+
+/// Random reads from an engine kept open (warm) and from one reopened
+/// before every iteration (cold), which has to rebuild its index or caches.
+fn get_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get_bench");
+    group.throughput(Throughput::Elements(READS as u64));
+
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(&KvStore::open(kvs_dir.path()).unwrap());
+    fill(&SledKvsEngine::new(sled_dir.path()).unwrap());
+
+    bench_reads(&mut group, "kvs", || KvStore::open(kvs_dir.path()).unwrap());
+    bench_reads(&mut group, "sled", || {
+        SledKvsEngine::new(sled_dir.path()).unwrap()
+    });
+    group.finish();
+}
+
+fn bench_reads<E: KvsEngine>(
+    group: &mut criterion::BenchmarkGroup<criterion::measurement::WallTime>,
+    name: &str,
+    open: impl Fn() -> E,
+) {
+    let mut rng = StdRng::seed_from_u64(42);
+    {
+        let engine = open();
+        group.bench_function(BenchmarkId::new("warm", name), |b| {
+            b.iter(|| random_reads(&engine, &mut rng))
+        });
+    }
+    group.bench_function(BenchmarkId::new("cold", name), |b| {
+        b.iter_batched(
+            &open,
+            |engine| {
+                random_reads(&engine, &mut rng);
+                // dropped outside of measurement.
+                engine
+            },
+            BatchSize::PerIteration,
+        )
+    });
+}
+
+/// Set that starts compaction of a generation full of overwritten keys.
+fn compaction_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compaction");
+    group.sample_size(20);
+
+    for keys in [100, 1000] {
+        group.bench_function(BenchmarkId::new("kvs", keys), |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir =
+                        TempDir::new().expect("unable to create temporary working directory");
+                    let store = open_compacting(temp_dir.path(), KEYS as u64);
+                    // every key is overwritten many times, only last value survives.
+                    for i in 0..KEYS {
+                        store
+                            .set(format!("key{}", i % keys), "v".repeat(100))
+                            .unwrap();
+                    }
+                    (store, temp_dir)
+                },
+                |(store, temp_dir)| {
+                    // generation is full, this set compacts it first.
+                    store.set("key0".to_owned(), "v".repeat(100)).unwrap();
+                    (store, temp_dir)
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn open_compacting(path: &Path, threshold: u64) -> KvStore {
+    let options = StoreOptions {
+        compaction_threshold: threshold,
+        ..StoreOptions::default()
+    };
+    KvStore::open_with(path, options).unwrap()
+}
+
+criterion_group!(
+    benches,
+    criterion_benchmark,
+    get_benchmark,
+    compaction_benchmark
+);
 criterion_main!(benches);
@@ -0,0 +1,60 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::thread_pool::{NaiveThreadPool, ThreadPool};
+use kvs::{Codec, KvServer, KvStore, KvsClient, Runtime};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use tempfile::TempDir;
+
+/// Concurrent clients issuing requests during each iteration.
+const CLIENTS: usize = 8;
+/// Connections kept open and idle while clients run.
+const IDLE: usize = 500;
+
+fn start_server(runtime: Runtime, temp_dir: &TempDir) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = KvServer::new(
+        KvStore::open(temp_dir.path()).unwrap(),
+        NaiveThreadPool::new(0).unwrap(),
+    );
+    thread::spawn(move || match runtime {
+        Runtime::Threads => server.run_on(listener),
+        Runtime::Async => server.run_async_on(listener),
+    });
+    addr
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("server");
+
+    for runtime in [Runtime::Threads, Runtime::Async] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let addr = start_server(runtime, &temp_dir);
+        let _idle: Vec<TcpStream> = (0..IDLE)
+            .map(|_| TcpStream::connect(addr).unwrap())
+            .collect();
+        let mut clients: Vec<KvsClient> = (0..CLIENTS)
+            .map(|_| KvsClient::connect(addr, Codec::Bincode).unwrap())
+            .collect();
+
+        group.bench_function(BenchmarkId::new("set_get", runtime), |b| {
+            b.iter(|| {
+                thread::scope(|scope| {
+                    for (idx, client) in clients.iter_mut().enumerate() {
+                        scope.spawn(move || {
+                            for i in 0..100 {
+                                let key = format!("key{}-{}", idx, i);
+                                client.set(key.clone(), "value".to_owned()).unwrap();
+                                assert!(client.get(key).unwrap().is_some());
+                            }
+                        });
+                    }
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,80 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use crossbeam::sync::WaitGroup;
+use kvs::thread_pool::{
+    NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool, WorkStealingThreadPool,
+};
+use std::sync::Arc;
+
+/// Threads of pools that keep a fixed number of them.
+const THREADS: usize = 4;
+
+/// Spawns `jobs` small jobs and waits for all of them.
+fn spawn_jobs(pool: &impl ThreadPool, jobs: usize) {
+    let wg = WaitGroup::new();
+    for i in 0..jobs {
+        let wg = wg.clone();
+        pool.spawn(move || {
+            criterion::black_box(i * i);
+            drop(wg);
+        });
+    }
+    wg.wait();
+}
+
+fn bench_pool<P: ThreadPool>(c: &mut Criterion, name: &str) {
+    let pool = P::new(THREADS).unwrap();
+    let mut group = c.benchmark_group("spawn");
+    for jobs in [100, 1000] {
+        group.throughput(Throughput::Elements(jobs as u64));
+        group.bench_with_input(BenchmarkId::new(name, jobs), &jobs, |b, &jobs| {
+            b.iter(|| spawn_jobs(&pool, jobs))
+        });
+    }
+    group.finish();
+}
+
+/// Spawns `jobs` tiny jobs from inside a job of the pool, so that pools
+/// with local queues keep them on the spawning worker.
+fn spawn_nested_jobs<P: ThreadPool + Send + Sync + 'static>(pool: &Arc<P>, jobs: usize) {
+    let wg = WaitGroup::new();
+    let inner_pool = pool.clone();
+    let outer_wg = wg.clone();
+    pool.spawn(move || {
+        for i in 0..jobs {
+            let wg = outer_wg.clone();
+            inner_pool.spawn(move || {
+                criterion::black_box(i * i);
+                drop(wg);
+            });
+        }
+    });
+    wg.wait();
+}
+
+/// Many tiny jobs, spawned from outside and from inside the pool.
+fn bench_tiny_jobs<P: ThreadPool + Send + Sync + 'static>(c: &mut Criterion, name: &str) {
+    let pool = Arc::new(P::new(THREADS).unwrap());
+    let jobs = 10_000;
+    let mut group = c.benchmark_group("tiny_jobs");
+    group.throughput(Throughput::Elements(jobs as u64));
+    group.bench_function(BenchmarkId::new("outside", name), |b| {
+        b.iter(|| spawn_jobs(&*pool, jobs))
+    });
+    group.bench_function(BenchmarkId::new("nested", name), |b| {
+        b.iter(|| spawn_nested_jobs(&pool, jobs))
+    });
+    group.finish();
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    bench_pool::<NaiveThreadPool>(c, "naive");
+    bench_pool::<SharedQueueThreadPool>(c, "shared_queue");
+    bench_pool::<RayonThreadPool>(c, "rayon");
+    bench_pool::<WorkStealingThreadPool>(c, "work_stealing");
+
+    bench_tiny_jobs::<SharedQueueThreadPool>(c, "shared_queue");
+    bench_tiny_jobs::<WorkStealingThreadPool>(c, "work_stealing");
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
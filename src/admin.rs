@@ -0,0 +1,389 @@
+//! Maintenance of data directories, run by `kvs-admin` while no server
+//! uses them, and of server configuration.
+
+use crate::auth;
+use crate::config::{data_dir_engine, engine_dir, mark_data_dir};
+use crate::error::KvsError;
+use crate::server::EngineType;
+use crate::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use crate::transfer::{self, BulkStore, EngineStore, Format, Import, DEFAULT_BATCH_SIZE};
+use crate::transport::{Address, Listener};
+use crate::{KvServer, KvStore, KvsEngine, Result, SledKvsEngine};
+use anyhow::bail;
+use clap::{Parser, Subcommand};
+use sha2::{Digest, Sha256};
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::thread;
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+/// Cli for maintenance of data directories.
+pub struct AdminCLI {
+    #[clap(subcommand)]
+    command: AdminCommands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum AdminCommands {
+    /// Copies every key to another engine and moves engine marker of the
+    /// target data directory to it.
+    Migrate {
+        /// Engine and data directory to copy from.
+        #[clap(long, value_name = "ENGINE:DIR")]
+        from: EngineLocation,
+        /// Engine and data directory to copy to, may be the source directory.
+        #[clap(long, value_name = "ENGINE:DIR")]
+        to: EngineLocation,
+        /// Serve the target engine on these addresses right away and copy
+        /// keys in the background. Keys written by clients meanwhile are not
+        /// overwritten, while keys they remove may come back if not copied yet.
+        #[clap(long, value_delimiter = ',', value_name = "ADDR")]
+        serve: Vec<Address>,
+    },
+    /// Sets keys read from JSON Lines or CSV file directly in the engine,
+    /// `-` for standard input.
+    Import {
+        file: PathBuf,
+        /// Engine and data directory to import to.
+        #[clap(long, value_name = "ENGINE:DIR")]
+        to: EngineLocation,
+        /// `jsonl` or `csv`, guessed from file extension by default.
+        #[clap(long, value_name = "FORMAT")]
+        format: Option<Format>,
+        /// Records written between progress reports.
+        #[clap(long, default_value_t = DEFAULT_BATCH_SIZE)]
+        batch_size: usize,
+        /// Only validate the file, nothing is set.
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Writes keys starting with the prefix, with their values, as JSON
+    /// Lines or CSV.
+    Export {
+        /// Engine and data directory to export from.
+        #[clap(long, value_name = "ENGINE:DIR")]
+        from: EngineLocation,
+        #[clap(long, default_value = "")]
+        prefix: String,
+        /// `jsonl` or `csv`, guessed from output extension by default.
+        #[clap(long, value_name = "FORMAT")]
+        format: Option<Format>,
+        /// File to write to instead of standard output.
+        #[clap(long, short, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Prints hash of the password read from the first line of standard
+    /// input, for `password_hash` of users in the auth configuration.
+    HashPassword,
+}
+
+impl AdminCLI {
+    pub fn run(&self) -> Result<()> {
+        match &self.command {
+            AdminCommands::Migrate { from, to, serve } => {
+                let migration = Migration {
+                    from: from.clone(),
+                    to: to.clone(),
+                    serve: serve.clone(),
+                };
+                migration.run()
+            }
+            AdminCommands::Import {
+                file,
+                to,
+                format,
+                batch_size,
+                dry_run,
+            } => {
+                let mut store = match dry_run {
+                    true => None,
+                    false => Some(open_store(to, true)?),
+                };
+                let import = Import {
+                    path: file,
+                    format: *format,
+                    batch_size: *batch_size,
+                };
+                let imported = import.run(store.as_mut().map(|store| store.as_mut() as _))?;
+                if *dry_run {
+                    println!("Dry run: {} valid records", imported);
+                } else {
+                    mark_data_dir(&to.data_dir, to.engine)?;
+                    println!("Imported {} records to {}", imported, to);
+                }
+                Ok(())
+            }
+            AdminCommands::Export {
+                from,
+                prefix,
+                format,
+                output,
+            } => {
+                let mut store = open_store(from, false)?;
+                let format = Format::resolve(*format, output.as_deref());
+                let exported = match output {
+                    Some(path) => transfer::export(
+                        store.as_mut(),
+                        prefix.clone(),
+                        format,
+                        File::create(path)?,
+                    )?,
+                    None => transfer::export(
+                        store.as_mut(),
+                        prefix.clone(),
+                        format,
+                        io::stdout().lock(),
+                    )?,
+                };
+                info!("Exported {} keys from {}", exported, from);
+                Ok(())
+            }
+            AdminCommands::HashPassword => {
+                let mut password = String::new();
+                io::stdin().read_line(&mut password)?;
+                let password = password.trim_end_matches(['\r', '\n']);
+                if password.is_empty() {
+                    bail!("no password on standard input");
+                }
+                println!("{}", auth::hash_password(password)?);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Opens engine of the location for import or export, checking that its
+/// data directory holds that engine. Only `create` may open engine that
+/// has no data yet.
+fn open_store(location: &EngineLocation, create: bool) -> Result<Box<dyn BulkStore>> {
+    match data_dir_engine(&location.data_dir)? {
+        Some(engine) if engine != location.engine => bail!(
+            "data directory {} holds {} engine, not {}",
+            location.data_dir.display(),
+            engine,
+            location.engine
+        ),
+        _ => {}
+    }
+    let dir = engine_dir(&location.data_dir, location.engine);
+    if !create && !dir.exists() {
+        bail!(
+            "no {} data in {}",
+            location.engine,
+            location.data_dir.display()
+        );
+    }
+    Ok(match location.engine {
+        EngineType::Kvs => Box::new(EngineStore(KvStore::open(dir)?)),
+        EngineType::Sled => Box::new(EngineStore(SledKvsEngine::new(dir)?)),
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Engine with its data directory, given as `ENGINE:DIR`.
+pub struct EngineLocation {
+    pub engine: EngineType,
+    pub data_dir: PathBuf,
+}
+
+impl FromStr for EngineLocation {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, KvsError> {
+        match s.split_once(':') {
+            Some((engine, data_dir)) if !data_dir.is_empty() => Ok(Self {
+                engine: engine.parse()?,
+                data_dir: PathBuf::from(data_dir),
+            }),
+            _ => Err(KvsError::Parse),
+        }
+    }
+}
+
+impl Display for EngineLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.engine, self.data_dir.display())
+    }
+}
+
+/// Copy of every key from one engine to another.
+struct Migration {
+    from: EngineLocation,
+    to: EngineLocation,
+    serve: Vec<Address>,
+}
+
+impl Migration {
+    fn run(&self) -> Result<()> {
+        let same_dir = self.from.data_dir == self.to.data_dir;
+        if same_dir && self.from.engine == self.to.engine {
+            bail!("source and target of migration are the same");
+        }
+        // marker already moved to the target when migration is resumed.
+        match data_dir_engine(&self.from.data_dir)? {
+            Some(engine) if engine == self.from.engine => {}
+            Some(engine) if same_dir && engine == self.to.engine => {}
+            Some(engine) => bail!(
+                "data directory {} holds {} engine, not {}",
+                self.from.data_dir.display(),
+                engine,
+                self.from.engine
+            ),
+            None => {}
+        }
+        if !engine_dir(&self.from.data_dir, self.from.engine).exists() {
+            bail!(
+                "no {} data in {}",
+                self.from.engine,
+                self.from.data_dir.display()
+            );
+        }
+        match data_dir_engine(&self.to.data_dir)? {
+            Some(engine) if engine == self.to.engine => {}
+            Some(engine) if same_dir && engine == self.from.engine => {}
+            Some(engine) => bail!(
+                "data directory {} holds {} engine, not {}",
+                self.to.data_dir.display(),
+                engine,
+                self.to.engine
+            ),
+            None => {}
+        }
+
+        match self.from.engine {
+            EngineType::Kvs => self.with_source(KvStore::open(engine_dir(
+                &self.from.data_dir,
+                self.from.engine,
+            ))?),
+            EngineType::Sled => self.with_source(SledKvsEngine::new(engine_dir(
+                &self.from.data_dir,
+                self.from.engine,
+            ))?),
+        }
+    }
+
+    fn with_source<S: KvsEngine>(&self, source: S) -> Result<()> {
+        let dir = engine_dir(&self.to.data_dir, self.to.engine);
+        match self.to.engine {
+            EngineType::Kvs => self.migrate(source, KvStore::open(dir)?),
+            EngineType::Sled => self.migrate(source, SledKvsEngine::new(dir)?),
+        }
+    }
+
+    fn migrate<S: KvsEngine, T: KvsEngine>(&self, source: S, target: T) -> Result<()> {
+        if !self.serve.is_empty() {
+            return self.migrate_online(source, target);
+        }
+        if !target.scan(String::new())?.is_empty() {
+            bail!("target {} already holds keys", self.to);
+        }
+
+        let mut copied = Checksum::default();
+        for key in source.scan(String::new())? {
+            if let Some(value) = source.get_reader(key.clone())? {
+                let mut writer = target.set_writer(key.clone())?;
+                copied.add(&key, value, &mut writer)?;
+                writer.commit()?;
+            }
+        }
+
+        let mut verified = Checksum::default();
+        for key in target.scan(String::new())? {
+            match target.get_reader(key.clone())? {
+                Some(value) => verified.add(&key, value, io::sink())?,
+                None => bail!("key {:?} disappeared from target", key),
+            }
+        }
+        let (copied, verified) = (copied.finish(), verified.finish());
+        if copied != verified {
+            bail!(
+                "verification failed: copied {} keys with checksum {}, target holds {} keys with checksum {}",
+                copied.0,
+                copied.1,
+                verified.0,
+                verified.1
+            );
+        }
+
+        mark_data_dir(&self.to.data_dir, self.to.engine)?;
+        println!(
+            "Migrated {} keys from {} to {}, sha256 {}",
+            copied.0, self.from, self.to, copied.1
+        );
+        Ok(())
+    }
+
+    /// Serves target engine while keys are copied to it.
+    fn migrate_online<S: KvsEngine, T: KvsEngine>(&self, source: S, target: T) -> Result<()> {
+        let listeners = self
+            .serve
+            .iter()
+            .map(Listener::bind)
+            .collect::<Result<Vec<_>>>()?;
+        // target becomes the engine of its directory from now on, so
+        // interrupted migration is resumed with the same command.
+        mark_data_dir(&self.to.data_dir, self.to.engine)?;
+
+        let engine = target.clone();
+        thread::spawn(move || match backfill(source, engine) {
+            Ok(copied) => info!("Backfilled {} keys, migration finished", copied),
+            Err(e) => error!("Backfill failed: {}", e),
+        });
+
+        let threads = thread::available_parallelism().map_or(4, |n| n.get());
+        KvServer::new(target, SharedQueueThreadPool::new(threads)?).run_on_many(listeners)
+    }
+}
+
+/// Copies keys missing from the target, returns how many were copied.
+fn backfill<S: KvsEngine, T: KvsEngine>(source: S, target: T) -> Result<u64> {
+    let mut copied = 0;
+    for key in source.scan(String::new())? {
+        if let Some(value) = source.get(key.clone())? {
+            if target.compare_and_set(key, None, value)? {
+                copied += 1;
+            }
+        }
+    }
+    Ok(copied)
+}
+
+#[derive(Default)]
+/// Number and sha256 of keys with their values, in scan order.
+struct Checksum {
+    keys: u64,
+    hasher: Sha256,
+}
+
+impl Checksum {
+    /// Adds key with its value, passing the value on to `out`.
+    fn add(&mut self, key: &str, mut value: impl Read, mut out: impl Write) -> Result<()> {
+        self.keys += 1;
+        self.hasher.update((key.len() as u64).to_le_bytes());
+        self.hasher.update(key.as_bytes());
+
+        let mut buf = [0u8; 8192];
+        let mut len = 0u64;
+        loop {
+            let n = value.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            self.hasher.update(&buf[..n]);
+            out.write_all(&buf[..n])?;
+            len += n as u64;
+        }
+        self.hasher.update(len.to_le_bytes());
+        Ok(())
+    }
+
+    fn finish(self) -> (u64, String) {
+        let digest = self.hasher.finalize();
+        let hex = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        (self.keys, hex)
+    }
+}
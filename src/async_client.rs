@@ -0,0 +1,624 @@
+//! Async client on tokio and a pool of its connections.
+//!
+//! `AsyncKvsClient` speaks the same framed protocol as `KvsClient` and
+//! follows Raft leader redirects the same way. Requests can be given a
+//! timeout, after which the connection is marked broken since a late
+//! response would be read as an answer to the next request.
+//!
+//! `Pool` hands out connections to one server, opening at most `max_size`
+//! of them. Connections idle for too long are closed and those idle for a
+//! while are pinged before reuse.
+
+use crate::client::{unexpected, ELECTION_WAIT, MAX_IN_FLIGHT, MAX_REDIRECTS};
+use crate::cmd::{Response, ResponseError, CMD};
+use crate::error::KvsError;
+use crate::protocol::{self, Codec, Credentials, RequestFrame, ResponseFrame};
+use crate::raft::MembershipChange;
+use crate::replication::{LogBatch, LogPosition};
+#[cfg(not(unix))]
+use crate::transport;
+use crate::transport::Address;
+use crate::Result;
+use rustls::{ClientConfig, ServerName};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time;
+use tokio_rustls::TlsConnector;
+
+/// Connection the client talks over, plain tcp or TLS.
+trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for S {}
+
+/// Async client speaking framed protocol over a single connection.
+pub struct AsyncKvsClient {
+    stream: BufReader<Box<dyn AsyncStream>>,
+    codec: Codec,
+    next_id: u64,
+    credentials: Credentials,
+    /// How to reach the leader on redirect, `None` when connection was
+    /// opened by the caller.
+    redirect: Option<Redirect>,
+    timeout: Option<Duration>,
+    /// Set while request is in progress, stays set if it did not finish.
+    broken: bool,
+}
+
+/// Connection settings reused when following leader redirect.
+#[derive(Clone)]
+enum Redirect {
+    Plain,
+    Tls {
+        server_name: ServerName,
+        connector: TlsConnector,
+    },
+}
+
+impl Redirect {
+    fn tls(server_name: &str, config: Arc<ClientConfig>) -> Result<Self> {
+        Ok(Redirect::Tls {
+            server_name: ServerName::try_from(server_name)?,
+            connector: TlsConnector::from(config),
+        })
+    }
+
+    async fn connect(&self, addr: impl ToSocketAddrs) -> Result<Box<dyn AsyncStream>> {
+        let tcp = TcpStream::connect(addr).await?;
+        Ok(match self {
+            Redirect::Plain => Box::new(tcp),
+            Redirect::Tls {
+                server_name,
+                connector,
+            } => Box::new(connector.connect(server_name.clone(), tcp).await?),
+        })
+    }
+
+    /// Connects to tcp or Unix socket address, TLS is tcp only.
+    async fn connect_to(&self, addr: &Address) -> Result<Box<dyn AsyncStream>> {
+        match (addr, self) {
+            (Address::Tcp(addr), _) => self.connect(addr.as_str()).await,
+            #[cfg(unix)]
+            (Address::Unix(path), Redirect::Plain) => {
+                Ok(Box::new(UnixStream::connect(path).await?))
+            }
+            #[cfg(not(unix))]
+            (Address::Unix(_), Redirect::Plain) => Err(transport::unix_unsupported()),
+            (Address::Unix(_), Redirect::Tls { .. }) => {
+                Err(KvsError::Tls("TLS is not supported over Unix sockets".to_owned()).into())
+            }
+        }
+    }
+}
+
+impl AsyncKvsClient {
+    /// Connects to the server and performs the protocol handshake.
+    pub async fn connect(addr: impl ToSocketAddrs, codec: Codec) -> Result<Self> {
+        let stream = Redirect::Plain.connect(addr).await?;
+        Self::open(stream, codec, &Credentials::Anonymous, Redirect::Plain).await
+    }
+
+    /// Connects to tcp or Unix socket address, see `Address`.
+    pub async fn connect_to(addr: &Address, codec: Codec) -> Result<Self> {
+        let stream = Redirect::Plain.connect_to(addr).await?;
+        Self::open(stream, codec, &Credentials::Anonymous, Redirect::Plain).await
+    }
+
+    /// Connects to the server over TLS, `server_name` is verified against
+    /// the server certificate.
+    pub async fn connect_tls(
+        addr: impl ToSocketAddrs,
+        server_name: &str,
+        codec: Codec,
+        config: Arc<ClientConfig>,
+    ) -> Result<Self> {
+        let redirect = Redirect::tls(server_name, config)?;
+        let stream = redirect.connect(addr).await?;
+        Self::open(stream, codec, &Credentials::Anonymous, redirect).await
+    }
+
+    /// Performs the protocol handshake over already opened connection,
+    /// authenticating with given credentials.
+    pub async fn handshake<S>(stream: S, codec: Codec, credentials: &Credentials) -> Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut stream: BufReader<Box<dyn AsyncStream>> = BufReader::new(Box::new(stream));
+        stream
+            .write_all(&protocol::client_hello(codec, credentials)?)
+            .await?;
+        stream.flush().await?;
+
+        let mut reply = [0u8; 6];
+        stream.read_exact(&mut reply).await?;
+        protocol::check_server_reply(&reply, codec)?;
+        protocol::check_authentication(read_frame(&mut stream, codec).await?)?;
+
+        Ok(Self {
+            stream,
+            codec,
+            next_id: 0,
+            credentials: credentials.clone(),
+            redirect: None,
+            timeout: None,
+            broken: false,
+        })
+    }
+
+    async fn open(
+        stream: Box<dyn AsyncStream>,
+        codec: Codec,
+        credentials: &Credentials,
+        redirect: Redirect,
+    ) -> Result<Self> {
+        let mut client = Self::handshake(stream, codec, credentials).await?;
+        client.redirect = Some(redirect);
+        Ok(client)
+    }
+
+    /// Fails requests not answered in time with `KvsError::Timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Whether a request was interrupted, the connection can't be used
+    /// anymore then.
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    /// Sets the value of a string key to a string.
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        match self.request(CMD::Set { key, value }).await? {
+            Response::Ok => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Gets the string value of a given string key.
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.request(CMD::Get { key }).await? {
+            Response::Value(value) => Ok(value),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Gets local value of the cluster node without asking the leader, it
+    /// may miss latest writes.
+    pub async fn get_stale(&mut self, key: String) -> Result<Option<String>> {
+        match self.request(CMD::StaleGet { key }).await? {
+            Response::Value(value) => Ok(value),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Lists keys starting with the prefix, in ascending order.
+    pub async fn scan(&mut self, prefix: String) -> Result<Vec<String>> {
+        match self.request(CMD::Scan { prefix }).await? {
+            Response::Keys(keys) => Ok(keys),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Lists at most `limit` keys starting with the prefix that follow `after`.
+    pub async fn scan_page(
+        &mut self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        match self
+            .request(CMD::ScanPage {
+                prefix,
+                after,
+                limit,
+            })
+            .await?
+        {
+            Response::Keys(keys) => Ok(keys),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Removes a given key.
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        match self.request(CMD::Rm { key }).await? {
+            Response::Ok => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Sets the value only if the current one equals `expected`.
+    pub async fn compare_and_set(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<bool> {
+        match self
+            .request(CMD::CompareAndSet {
+                key,
+                expected,
+                value,
+            })
+            .await?
+        {
+            Response::Swapped(swapped) => Ok(swapped),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Removes the key only if its value equals `expected`.
+    pub async fn compare_and_remove(&mut self, key: String, expected: String) -> Result<bool> {
+        match self
+            .request(CMD::CompareAndRemove { key, expected })
+            .await?
+        {
+            Response::Swapped(swapped) => Ok(swapped),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Fetches leader log records following the position.
+    pub async fn replicate(&mut self, from: Option<LogPosition>) -> Result<LogBatch> {
+        match self.request(CMD::Replicate { from }).await? {
+            Response::Log(batch) => Ok(batch),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Fetches part of leader snapshot following the key.
+    pub async fn replicate_snapshot(&mut self, at: LogPosition, after: String) -> Result<LogBatch> {
+        match self.request(CMD::ReplicateSnapshot { at, after }).await? {
+            Response::Log(batch) => Ok(batch),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Adds or removes Raft cluster member.
+    pub async fn change_membership(&mut self, change: MembershipChange) -> Result<()> {
+        match self.request(CMD::ChangeMembership { change }).await? {
+            Response::Ok => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Checks that server still answers. Any response will do, even denied
+    /// access to the probed key.
+    pub async fn ping(&mut self) -> Result<()> {
+        self.exchange(vec![CMD::StaleGet { key: String::new() }])
+            .await
+            .map(|_| ())
+    }
+
+    /// Sends all commands pipelined over the connection and returns their
+    /// responses in the same order as commands, see `KvsClient::batch`.
+    pub async fn batch(&mut self, cmds: Vec<CMD>) -> Result<Vec<Response>> {
+        self.exchange(cmds).await
+    }
+
+    /// Sends single command and waits for its response, following leader
+    /// redirects and waiting out elections.
+    async fn request(&mut self, cmd: CMD) -> Result<Response> {
+        let mut attempts = 0;
+        loop {
+            let response = self
+                .exchange(vec![cmd.clone()])
+                .await?
+                .pop()
+                .expect("single response");
+
+            match response {
+                Response::Err(ResponseError::NotLeader { leader })
+                    if attempts < MAX_REDIRECTS && self.redirect.is_some() =>
+                {
+                    attempts += 1;
+                    match leader {
+                        Some(leader) => self.reconnect(&leader).await?,
+                        None => time::sleep(ELECTION_WAIT).await,
+                    }
+                }
+                Response::Err(e) => return Err(KvsError::from(e).into()),
+                response => return Ok(response),
+            }
+        }
+    }
+
+    /// Runs the commands within the timeout, if any.
+    async fn exchange(&mut self, cmds: Vec<CMD>) -> Result<Vec<Response>> {
+        if self.broken {
+            return Err(KvsError::Protocol("connection is broken".to_string()).into());
+        }
+        self.broken = true;
+        let responses = match self.timeout {
+            Some(timeout) => time::timeout(timeout, self.pipeline(cmds))
+                .await
+                .map_err(|_| KvsError::Timeout)?,
+            None => self.pipeline(cmds).await,
+        }?;
+        self.broken = false;
+        Ok(responses)
+    }
+
+    /// Writes commands in windows of `MAX_IN_FLIGHT` and collects responses
+    /// in order of commands.
+    async fn pipeline(&mut self, cmds: Vec<CMD>) -> Result<Vec<Response>> {
+        let first_id = self.next_id;
+        let mut responses = vec![None; cmds.len()];
+        let mut cmds = cmds.into_iter().peekable();
+        let mut out = vec![];
+
+        while cmds.peek().is_some() {
+            out.clear();
+            let mut in_flight = 0;
+            for cmd in cmds.by_ref().take(MAX_IN_FLIGHT) {
+                let id = self.next_id;
+                self.next_id += 1;
+                debug!("writing request {}: {:?}", id, cmd);
+                protocol::write_frame(&mut out, self.codec, &RequestFrame { id, cmd })?;
+                in_flight += 1;
+            }
+            self.stream.write_all(&out).await?;
+            self.stream.flush().await?;
+
+            for _ in 0..in_flight {
+                let frame: ResponseFrame = read_frame(&mut self.stream, self.codec)
+                    .await?
+                    .ok_or_else(|| KvsError::Protocol("connection closed by server".to_string()))?;
+                debug!("read response {}: {:?}", frame.id, frame.response);
+                let slot = frame
+                    .id
+                    .checked_sub(first_id)
+                    .and_then(|idx| responses.get_mut(idx as usize))
+                    .filter(|slot| slot.is_none())
+                    .ok_or_else(|| {
+                        KvsError::Protocol(format!("unexpected response id {}", frame.id))
+                    })?;
+                *slot = Some(frame.response);
+            }
+        }
+        Ok(responses.into_iter().flatten().collect())
+    }
+
+    /// Replaces connection with one to the leader.
+    async fn reconnect(&mut self, leader: &str) -> Result<()> {
+        debug!("redirected to leader {}", leader);
+        let redirect = self.redirect.clone().unwrap_or(Redirect::Plain);
+        let timeout = self.timeout;
+        let stream = redirect.connect_to(&leader.parse()?).await?;
+        *self = Self::open(stream, self.codec, &self.credentials, redirect).await?;
+        self.timeout = timeout;
+        Ok(())
+    }
+}
+
+/// Reads single length-prefixed frame, see `protocol::read_frame`.
+async fn read_frame<R, T>(reader: &mut R, codec: Codec) -> Result<Option<T>>
+where
+    R: AsyncRead + Unpin,
+    T: serde::de::DeserializeOwned,
+{
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let len = u32::from_be_bytes(len);
+    if len > protocol::MAX_FRAME_SIZE {
+        return Err(KvsError::Protocol(format!("frame of {} bytes is too large", len)).into());
+    }
+
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(codec.decode(&payload)?))
+}
+
+#[derive(Debug, Clone)]
+/// Configuration of a `Pool`.
+pub struct PoolOptions {
+    /// Maximum number of open connections, callers wait for one to be
+    /// returned when all are in use.
+    pub max_size: usize,
+    /// Idle connections are closed after that long.
+    pub idle_timeout: Duration,
+    /// Connections idle for that long are pinged before reuse.
+    pub health_check_after: Duration,
+    pub connect_timeout: Duration,
+    /// Timeout of every request, `None` waits forever.
+    pub request_timeout: Option<Duration>,
+    pub credentials: Credentials,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            max_size: 16,
+            idle_timeout: Duration::from_secs(60),
+            health_check_after: Duration::from_secs(5),
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Some(Duration::from_secs(5)),
+            credentials: Credentials::Anonymous,
+        }
+    }
+}
+
+/// Pool of connections to a single server, cheap to clone.
+#[derive(Clone)]
+pub struct Pool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    addr: String,
+    codec: Codec,
+    options: PoolOptions,
+    redirect: Redirect,
+    /// Returned connections, the most recently used last.
+    idle: Mutex<Vec<Idle>>,
+    /// One permit per connection that may be handed out.
+    permits: Arc<Semaphore>,
+}
+
+struct Idle {
+    client: AsyncKvsClient,
+    since: Instant,
+}
+
+impl Pool {
+    /// Creates empty pool, connections are opened on demand. Address is
+    /// `host:port` or `unix:/path`.
+    pub fn new(addr: impl Into<String>, codec: Codec, options: PoolOptions) -> Self {
+        Self::with_redirect(addr.into(), codec, options, Redirect::Plain)
+    }
+
+    /// Creates empty pool of TLS connections.
+    pub fn new_tls(
+        addr: impl Into<String>,
+        server_name: &str,
+        codec: Codec,
+        config: Arc<ClientConfig>,
+        options: PoolOptions,
+    ) -> Result<Self> {
+        let redirect = Redirect::tls(server_name, config)?;
+        Ok(Self::with_redirect(addr.into(), codec, options, redirect))
+    }
+
+    fn with_redirect(addr: String, codec: Codec, options: PoolOptions, redirect: Redirect) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                addr,
+                codec,
+                permits: Arc::new(Semaphore::new(options.max_size)),
+                options,
+                redirect,
+                idle: Mutex::default(),
+            }),
+        }
+    }
+
+    /// Number of open connections not in use.
+    pub fn idle(&self) -> usize {
+        self.shared.idle.lock().unwrap().len()
+    }
+
+    /// Takes connection from the pool, waiting while all are in use. It is
+    /// returned to the pool when dropped, unless broken.
+    pub async fn acquire(&self) -> Result<PooledClient> {
+        let permit = self.shared.permits.clone().acquire_owned().await?;
+        let options = &self.shared.options;
+
+        loop {
+            let idle = {
+                let mut idle = self.shared.idle.lock().unwrap();
+                idle.retain(|idle| idle.since.elapsed() < options.idle_timeout);
+                idle.pop()
+            };
+            let mut idle = match idle {
+                Some(idle) => idle,
+                None => break,
+            };
+            if idle.since.elapsed() >= options.health_check_after {
+                if let Err(e) = idle.client.ping().await {
+                    debug!("dropping unhealthy connection: {}", e);
+                    continue;
+                }
+            }
+            return Ok(self.pooled(idle.client, permit));
+        }
+
+        let client = time::timeout(options.connect_timeout, self.connect())
+            .await
+            .map_err(|_| KvsError::Timeout)??;
+        Ok(self.pooled(client, permit))
+    }
+
+    async fn connect(&self) -> Result<AsyncKvsClient> {
+        let shared = &self.shared;
+        let stream = shared.redirect.connect_to(&shared.addr.parse()?).await?;
+        let mut client = AsyncKvsClient::open(
+            stream,
+            shared.codec,
+            &shared.options.credentials,
+            shared.redirect.clone(),
+        )
+        .await?;
+        client.timeout = shared.options.request_timeout;
+        Ok(client)
+    }
+
+    fn pooled(&self, client: AsyncKvsClient, permit: OwnedSemaphorePermit) -> PooledClient {
+        PooledClient {
+            client: Some(client),
+            shared: self.shared.clone(),
+            _permit: permit,
+        }
+    }
+
+    /// Sets the value using pooled connection.
+    pub async fn set(&self, key: String, value: String) -> Result<()> {
+        self.acquire().await?.set(key, value).await
+    }
+
+    /// Gets the value using pooled connection.
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        self.acquire().await?.get(key).await
+    }
+
+    /// Removes the key using pooled connection.
+    pub async fn remove(&self, key: String) -> Result<()> {
+        self.acquire().await?.remove(key).await
+    }
+
+    /// Lists keys using pooled connection.
+    pub async fn scan(&self, prefix: String) -> Result<Vec<String>> {
+        self.acquire().await?.scan(prefix).await
+    }
+
+    /// Sends pipelined commands over pooled connection.
+    pub async fn batch(&self, cmds: Vec<CMD>) -> Result<Vec<Response>> {
+        self.acquire().await?.batch(cmds).await
+    }
+}
+
+/// Connection taken from a `Pool`.
+pub struct PooledClient {
+    client: Option<AsyncKvsClient>,
+    shared: Arc<Shared>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Deref for PooledClient {
+    type Target = AsyncKvsClient;
+
+    fn deref(&self) -> &AsyncKvsClient {
+        self.client.as_ref().expect("client is taken on drop only")
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut AsyncKvsClient {
+        self.client.as_mut().expect("client is taken on drop only")
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        match self.client.take() {
+            Some(client) if !client.is_broken() => {
+                self.shared.idle.lock().unwrap().push(Idle {
+                    client,
+                    since: Instant::now(),
+                });
+            }
+            _ => {}
+        }
+    }
+}
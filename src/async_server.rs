@@ -0,0 +1,738 @@
+//! Tokio runtime for the main listener.
+//!
+//! Every connection is a task on a non-blocking socket, so idle clients cost
+//! a buffer instead of a thread. Engines are synchronous, their calls are
+//! handed to the blocking pool of the runtime. Framed and legacy clients are
+//! served the same way as by the threaded runtime, RESP and HTTP listeners
+//! stay on the thread pool.
+
+use crate::auth::{self, Auth, Principal};
+use crate::cmd::{Response, ResponseError, CMD};
+use crate::error::KvsError;
+use crate::limits::{self, Limits, Overflow, Slots};
+use crate::protocol::{self, Codec, Credentials, Hello, RequestFrame, ResponseFrame, MAGIC};
+use crate::server::{self, KvServer, Uploads, ValueStream, MAX_WINDOW};
+use crate::stats::Stats;
+use crate::thread_pool::ThreadPool;
+use crate::tls;
+use crate::transport::{Listener, REJECT_LINGER};
+use crate::{KvsEngine, Result};
+use serde_json::Deserializer;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{self, Cursor};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::task;
+use tokio::time;
+use tokio_rustls::TlsAcceptor;
+
+impl<E, TP> KvServer<E, TP>
+where
+    E: KvsEngine,
+    TP: ThreadPool + Send + Sync + 'static,
+{
+    /// Binds to given address and serves incoming connections on tokio runtime.
+    pub fn run_async(&self, addr: impl ToSocketAddrs) -> Result<()> {
+        self.run_async_on(TcpListener::bind(addr)?)
+    }
+
+    /// Serves connections from already bound listener on tokio runtime
+    /// instead of the thread pool, see module docs.
+    pub fn run_async_on(&self, listener: impl Into<Listener>) -> Result<()> {
+        self.run_async_on_many(vec![listener.into()])
+    }
+
+    /// Serves connections from several listeners on one tokio runtime.
+    pub fn run_async_on_many(&self, listeners: Vec<Listener>) -> Result<()> {
+        if listeners.is_empty() {
+            return Err(KvsError::Server("no address to listen on".to_owned()).into());
+        }
+        self.start_replication();
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        let acceptor = Acceptor {
+            engine: self.engine.clone(),
+            stats: self.stats.clone(),
+            auth: self.auth.clone(),
+            read_only: self.read_only(),
+            tls: self.tls.clone().map(TlsAcceptor::from),
+            limits: self.limits.clone(),
+            slots: self.slots.clone(),
+        };
+        runtime.block_on(async move {
+            let mut tasks = vec![];
+            for listener in listeners {
+                info!(
+                    "Server listening on {} with async runtime",
+                    listener.local_addr()?
+                );
+                let listener = AsyncListener::new(listener)?;
+                tasks.push(tokio::spawn(acceptor.clone().run(listener)));
+            }
+            for task in tasks {
+                task.await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Listener of the tokio runtime.
+enum AsyncListener {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl AsyncListener {
+    fn new(listener: Listener) -> Result<Self> {
+        Ok(match listener {
+            Listener::Tcp(listener) => {
+                listener.set_nonblocking(true)?;
+                AsyncListener::Tcp(tokio::net::TcpListener::from_std(listener)?)
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                listener.set_nonblocking(true)?;
+                AsyncListener::Unix(tokio::net::UnixListener::from_std(listener)?)
+            }
+        })
+    }
+}
+
+/// Everything connection tasks need from the server, owned so that accept
+/// loops of several listeners can run as tasks.
+#[derive(Clone)]
+struct Acceptor<E: KvsEngine> {
+    engine: E,
+    stats: Arc<Stats>,
+    auth: Option<Arc<Auth>>,
+    read_only: bool,
+    tls: Option<TlsAcceptor>,
+    limits: Limits,
+    slots: Arc<Slots>,
+}
+
+impl<E: KvsEngine> Acceptor<E> {
+    /// Accepts connections and serves each of them as a task.
+    async fn run(self, listener: AsyncListener) {
+        loop {
+            let accepted = match &listener {
+                AsyncListener::Tcp(listener) => listener
+                    .accept()
+                    .await
+                    .map(|(tcp, peer_addr)| (Accepted::Tcp(tcp), peer_addr.to_string())),
+                #[cfg(unix)]
+                AsyncListener::Unix(listener) => listener
+                    .accept()
+                    .await
+                    .map(|(unix, _)| (Accepted::Unix(unix), "unix socket client".to_owned())),
+            };
+            let (accepted, peer_addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Connection failed: {}", e);
+                    continue;
+                }
+            };
+            let slot = match self.slots.try_acquire() {
+                Some(slot) => slot,
+                None if self.limits.overflow == Overflow::Queue => {
+                    let slots = self.slots.clone();
+                    match task::spawn_blocking(move || slots.acquire()).await {
+                        Ok(slot) => slot,
+                        Err(e) => {
+                            error!("Waiting for connection slot failed: {}", e);
+                            continue;
+                        }
+                    }
+                }
+                None => {
+                    debug!("Rejecting connection over the limit");
+                    self.stats.rejected_connection();
+                    if self.tls.is_none() {
+                        tokio::spawn(accepted.reject(protocol::busy_reply().to_vec()));
+                    }
+                    continue;
+                }
+            };
+            let acceptor = self.tls.clone();
+            let mut connection = Connection {
+                engine: self.engine.clone(),
+                stats: self.stats.clone(),
+                auth: self.auth.clone(),
+                read_only: self.read_only,
+                limits: self.limits.clone(),
+                peer_addr,
+            };
+
+            tokio::spawn(async move {
+                let _slot = slot;
+                let _connection = connection.stats.connection();
+                let read_timeout = connection.limits.read_timeout;
+                let result = match (accepted, acceptor) {
+                    (Accepted::Tcp(tcp), Some(acceptor)) => {
+                        match with_timeout(read_timeout, acceptor.accept(tcp)).await {
+                            Ok(stream) => {
+                                let identity = stream
+                                    .get_ref()
+                                    .1
+                                    .peer_certificates()
+                                    .and_then(|certs| certs.first())
+                                    .and_then(tls::certificate_identity);
+                                if let Some(identity) = &identity {
+                                    debug!("Client authenticated as {}", identity);
+                                }
+                                connection.serve(stream, identity).await
+                            }
+                            Err(e) => Err(e.into()),
+                        }
+                    }
+                    (Accepted::Tcp(tcp), None) => connection.serve(tcp, None).await,
+                    // Unix sockets are never wrapped in TLS, see `Stream`.
+                    #[cfg(unix)]
+                    (Accepted::Unix(unix), _) => connection.serve(unix, None).await,
+                };
+                match result {
+                    Err(e) if limits::is_timeout(&e) => {
+                        debug!("Closing client on socket timeout: {}", e);
+                        connection.stats.io_timeout();
+                    }
+                    Err(e) => error!("Error on serving client: {}", e),
+                    Ok(()) => {}
+                }
+            });
+        }
+    }
+}
+
+/// Connection accepted by `AsyncListener`.
+enum Accepted {
+    Tcp(tokio::net::TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+impl Accepted {
+    /// Sends reply and closes connection, see `Stream::reject`.
+    async fn reject(self, reply: Vec<u8>) {
+        match self {
+            Accepted::Tcp(tcp) => reject(tcp, &reply).await,
+            #[cfg(unix)]
+            Accepted::Unix(unix) => reject(unix, &reply).await,
+        }
+    }
+}
+
+async fn reject<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, reply: &[u8]) {
+    let linger = async {
+        stream.write_all(reply).await?;
+        stream.shutdown().await?;
+        let mut buf = [0u8; 1024];
+        while stream.read(&mut buf).await? > 0 {}
+        io::Result::Ok(())
+    };
+    let _ = time::timeout(REJECT_LINGER, linger).await;
+}
+
+/// State of single connection, owned by its task.
+struct Connection<E: KvsEngine> {
+    engine: E,
+    stats: Arc<Stats>,
+    auth: Option<Arc<Auth>>,
+    read_only: bool,
+    limits: Limits,
+    peer_addr: String,
+}
+
+impl<E: KvsEngine> Connection<E> {
+    /// Detects protocol by the first byte sent by the client and serves the connection.
+    async fn serve<S>(&mut self, stream: S, identity: Option<String>) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut stream = BufReader::new(stream);
+        // client disconnected or stayed idle without sending anything.
+        if !self.wait_for_request(&mut stream).await? {
+            return Ok(());
+        }
+
+        if stream.buffer()[0] == MAGIC[0] {
+            self.serve_framed(stream, identity).await
+        } else {
+            // legacy clients can't send credentials.
+            let principal = auth::authenticate(
+                self.auth.as_deref(),
+                &Credentials::Anonymous,
+                identity.as_deref(),
+            )?;
+            self.serve_legacy(stream, Arc::new(principal)).await
+        }
+    }
+
+    /// Serves framed protocol, see `protocol` module. Pipelined requests are
+    /// read together and executed concurrently like by the threaded runtime.
+    async fn serve_framed<S>(
+        &mut self,
+        mut stream: BufReader<S>,
+        identity: Option<String>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        // handshake is parsed by the blocking implementation once all of
+        // it has arrived.
+        let read_timeout = self.limits.read_timeout;
+        let mut hello = [0u8; 6];
+        with_timeout(read_timeout, stream.read_exact(&mut hello)).await?;
+        let mut handshake = hello.to_vec();
+        if protocol::hello_has_credentials(&hello) {
+            let credentials = with_timeout(read_timeout, read_frame(&mut stream))
+                .await?
+                .ok_or_else(|| KvsError::Protocol("missing credentials".to_string()))?;
+            handshake.extend(credentials);
+        }
+
+        let mut out = vec![];
+        // reply is sent even when the handshake is rejected.
+        let hello = protocol::server_handshake(&mut Cursor::new(handshake), &mut out);
+        if hello.is_err() {
+            self.send(&mut stream, &out).await?;
+        }
+        let Hello { codec, credentials } = hello?;
+
+        debug!("{} negotiated codec {}", self.peer_addr, codec);
+
+        let principal = auth::authenticate(
+            self.auth.as_deref(),
+            credentials.as_ref().unwrap_or(&Credentials::Anonymous),
+            identity.as_deref(),
+        );
+        if credentials.is_some() {
+            let response = match &principal {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Err(ResponseError::PermissionDenied(e.to_string())),
+            };
+            protocol::write_frame(&mut out, codec, &response)?;
+        }
+        self.send(&mut stream, &out).await?;
+        let principal = principal?;
+        if let Some(name) = principal.name() {
+            debug!("{} authenticated as {}", self.peer_addr, name);
+        }
+
+        let mut uploads = Uploads::default();
+        loop {
+            if !self.wait_for_request(&mut stream).await? {
+                return Ok(());
+            }
+            let max_frame_size = self.limits.frame_size();
+            let read = read_requests(&mut stream, codec, max_frame_size);
+            let window = with_timeout(read_timeout, read).await?;
+            if window.is_empty() {
+                return Ok(());
+            }
+            let deadline = self.limits.deadline();
+
+            debug!("Receive {} requests from {}", window.len(), self.peer_addr);
+
+            // refused requests are answered right away, streaming ones in
+            // order with the rest of the window.
+            out.clear();
+            let mut allowed = Vec::with_capacity(window.len());
+            for frame in window {
+                let frame = match frame {
+                    Ok(frame) => frame,
+                    Err(response) => {
+                        self.respond(&mut out, codec, response)?;
+                        continue;
+                    }
+                };
+                let admitted = server::authorize(&principal, self.read_only, &frame.cmd)
+                    .and_then(|()| self.limits.check(&frame.cmd));
+                if let Err(e) = admitted {
+                    let response = ResponseFrame {
+                        id: frame.id,
+                        response: Response::Err(e.into()),
+                    };
+                    self.respond(&mut out, codec, response)?;
+                    continue;
+                }
+
+                match frame.cmd {
+                    CMD::SetChunk { key, chunk, last } => {
+                        let window = std::mem::take(&mut allowed);
+                        self.execute(&mut out, codec, window, deadline).await?;
+                        let engine = self.engine.clone();
+                        let limits = self.limits.clone();
+                        let mut current = std::mem::take(&mut uploads);
+                        let (current, response) = task::spawn_blocking(move || {
+                            let response = current.chunk(&engine, &limits, key, chunk, last);
+                            (current, response)
+                        })
+                        .await?;
+                        uploads = current;
+                        let response = ResponseFrame {
+                            id: frame.id,
+                            response,
+                        };
+                        self.respond(&mut out, codec, response)?;
+                    }
+                    CMD::AbortUpload { key } => {
+                        let window = std::mem::take(&mut allowed);
+                        self.execute(&mut out, codec, window, deadline).await?;
+                        let response = ResponseFrame {
+                            id: frame.id,
+                            response: uploads.abort(&key),
+                        };
+                        self.respond(&mut out, codec, response)?;
+                    }
+                    CMD::StreamGet { key } => {
+                        let window = std::mem::take(&mut allowed);
+                        self.execute(&mut out, codec, window, deadline).await?;
+                        // chunks are written out one by one.
+                        self.send(&mut stream, &out).await?;
+                        out.clear();
+                        let engine = self.engine.clone();
+                        let mut value =
+                            task::spawn_blocking(move || ValueStream::new(&engine, key)).await?;
+                        let mut first = true;
+                        loop {
+                            let (current, response) = task::spawn_blocking(move || {
+                                let response = value.next();
+                                (value, response)
+                            })
+                            .await?;
+                            value = current;
+                            let response = match response {
+                                Some(response) => response,
+                                None => break,
+                            };
+                            if first {
+                                self.stats.response(&response);
+                                first = false;
+                            }
+                            let response = ResponseFrame {
+                                id: frame.id,
+                                response,
+                            };
+                            protocol::write_frame(&mut out, codec, &response)?;
+                            self.send(&mut stream, &out).await?;
+                            out.clear();
+                        }
+                    }
+                    cmd => allowed.push(RequestFrame { id: frame.id, cmd }),
+                }
+            }
+            self.execute(&mut out, codec, allowed, deadline).await?;
+            self.send(&mut stream, &out).await?;
+        }
+    }
+
+    /// Executes pipelined requests and encodes their responses.
+    async fn execute(
+        &mut self,
+        out: &mut Vec<u8>,
+        codec: Codec,
+        window: Vec<RequestFrame>,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        if window.is_empty() {
+            return Ok(());
+        }
+        for response in execute_window(self.engine.clone(), window, deadline).await? {
+            self.respond(out, codec, response)?;
+        }
+        Ok(())
+    }
+
+    /// Encodes response of the framed protocol and counts it.
+    fn respond(&mut self, out: &mut Vec<u8>, codec: Codec, response: ResponseFrame) -> Result<()> {
+        self.stats.response(&response.response);
+        protocol::write_frame(out, codec, &response)
+    }
+
+    /// Serves stream of raw json `CMD`s, kept for clients predating framed protocol.
+    async fn serve_legacy<S>(
+        &mut self,
+        mut stream: BufReader<S>,
+        principal: Arc<Principal>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut buf = vec![];
+        let max = self.limits.frame_size();
+
+        loop {
+            let mut end = JsonEnd::default();
+            let cmd = loop {
+                // skip whitespace between commands.
+                let start = buf
+                    .iter()
+                    .position(|b: &u8| !b.is_ascii_whitespace())
+                    .unwrap_or(buf.len());
+                buf.drain(..start);
+
+                // parsed only once complete, not again on every read.
+                if !buf.is_empty() && end.scan(&buf) {
+                    let mut cmds = Deserializer::from_slice(&buf).into_iter();
+                    match cmds.next() {
+                        Some(Ok(cmd)) => {
+                            let end = cmds.byte_offset();
+                            buf.drain(..end);
+                            break cmd;
+                        }
+                        Some(Err(e)) => return Err(e.into()),
+                        None => {}
+                    }
+                }
+                if buf.len() > max as usize {
+                    return Err(server::legacy_request_too_large(max));
+                }
+
+                if buf.is_empty() && !self.wait_for_request(&mut stream).await? {
+                    return Ok(());
+                }
+                let read = with_timeout(self.limits.read_timeout, stream.read_buf(&mut buf));
+                if read.await? == 0 {
+                    if buf.is_empty() {
+                        return Ok(());
+                    }
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+            };
+
+            debug!("Receive legacy request from {}: {:?}", self.peer_addr, cmd);
+
+            let engine = self.engine.clone();
+            let principal = principal.clone();
+            let read_only = self.read_only;
+            let limits = self.limits.clone();
+            let response = task::spawn_blocking(move || {
+                server::legacy_response(&engine, &principal, read_only, &limits, cmd)
+            })
+            .await??;
+            self.send(&mut stream, &response).await?;
+        }
+    }
+
+    /// Waits for the client to start sending next request, for at most idle
+    /// timeout. Returns `false` when client disconnected or was idle for too
+    /// long.
+    async fn wait_for_request<S>(&mut self, stream: &mut BufReader<S>) -> Result<bool>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let fill = async { stream.fill_buf().await.map(|buf| !buf.is_empty()) };
+        match self.limits.wait_timeout() {
+            Some(timeout) => match time::timeout(timeout, fill).await {
+                Ok(ready) => Ok(ready?),
+                Err(_) => {
+                    debug!("Closing idle client {}", self.peer_addr);
+                    self.stats.idle_timeout();
+                    Ok(false)
+                }
+            },
+            None => Ok(fill.await?),
+        }
+    }
+
+    /// Writes and flushes the buffer within write timeout.
+    async fn send<S>(&mut self, stream: &mut BufReader<S>, buf: &[u8]) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        with_timeout(self.limits.write_timeout, async {
+            stream.write_all(buf).await?;
+            stream.flush().await
+        })
+        .await
+    }
+}
+
+/// Runs socket operation, failing it with `io::ErrorKind::TimedOut` once
+/// timeout elapses.
+async fn with_timeout<T, E, F>(timeout: Option<Duration>, operation: F) -> std::result::Result<T, E>
+where
+    F: Future<Output = std::result::Result<T, E>>,
+    E: From<io::Error>,
+{
+    match timeout {
+        Some(timeout) => time::timeout(timeout, operation)
+            .await
+            .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut).into())),
+        None => operation.await,
+    }
+}
+
+/// Reads single frame together with its length prefix. Returns `None` when
+/// peer closed connection on frame boundary.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let size = u32::from_be_bytes(len);
+    if size > protocol::MAX_FRAME_SIZE {
+        return Err(KvsError::Protocol(format!("frame of {} bytes is too large", size)).into());
+    }
+
+    let mut frame = vec![0u8; 4 + size as usize];
+    frame[..4].copy_from_slice(&len);
+    reader.read_exact(&mut frame[4..]).await?;
+    Ok(Some(frame))
+}
+
+/// Reads one request, waiting if needed, followed by every complete request
+/// that is already buffered. Requests over `max_frame_size` are skipped, see
+/// `protocol::read_requests`.
+async fn read_requests<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+    codec: Codec,
+    max_frame_size: u32,
+) -> Result<Vec<std::result::Result<RequestFrame, ResponseFrame>>> {
+    let mut frames = vec![];
+    loop {
+        let mut len = [0u8; 4];
+        match reader.read_exact(&mut len).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+
+        let len = u32::from_be_bytes(len);
+        if len > max_frame_size {
+            let mut prefix = vec![0u8; len.min(protocol::ID_PREFIX_SIZE) as usize];
+            reader.read_exact(&mut prefix).await?;
+            let rest = (len - prefix.len() as u32).into();
+            tokio::io::copy(&mut (&mut *reader).take(rest), &mut tokio::io::sink()).await?;
+            frames.push(Err(protocol::oversized(
+                codec,
+                &prefix,
+                len,
+                max_frame_size,
+            )?));
+        } else {
+            let mut payload = vec![0u8; len as usize];
+            reader.read_exact(&mut payload).await?;
+            frames.push(Ok(codec.decode(&payload)?));
+        }
+
+        if frames.len() >= MAX_WINDOW || !protocol::frame_buffered(reader.buffer()) {
+            break;
+        }
+    }
+    Ok(frames)
+}
+
+/// Executes pipelined requests on the blocking pool. Requests for the same
+/// key are run in order they were sent, independent keys run concurrently.
+/// Requests not answered before the deadline get `ResponseError::Timeout`,
+/// see `server::execute_window`.
+async fn execute_window<E: KvsEngine>(
+    engine: E,
+    window: Vec<RequestFrame>,
+    deadline: Option<Instant>,
+) -> Result<Vec<ResponseFrame>> {
+    let mut groups: Vec<Vec<RequestFrame>> = vec![];
+    if window.iter().any(|frame| frame.cmd.key().is_none()) {
+        groups.push(window);
+    } else {
+        let mut group_by_key: HashMap<String, usize> = HashMap::new();
+        for frame in window {
+            let key = frame.cmd.key().unwrap_or_default().to_owned();
+            let idx = *group_by_key.entry(key).or_insert_with(|| {
+                groups.push(vec![]);
+                groups.len() - 1
+            });
+            groups[idx].push(frame);
+        }
+    }
+
+    let tasks: Vec<_> = groups
+        .into_iter()
+        .map(|group| {
+            let engine = engine.clone();
+            let ids: Vec<u64> = group.iter().map(|frame| frame.id).collect();
+            let task = task::spawn_blocking(move || {
+                group
+                    .into_iter()
+                    .map(|frame| server::execute_frame(&engine, frame, deadline))
+                    .collect::<Vec<_>>()
+            });
+            (ids, task)
+        })
+        .collect();
+
+    let mut responses = vec![];
+    for (ids, task) in tasks {
+        let finished = match deadline {
+            Some(deadline) => time::timeout_at(deadline.into(), task).await.ok(),
+            None => Some(task.await),
+        };
+        match finished {
+            Some(group) => responses.extend(group?),
+            // blocking task can't be cancelled, its responses are dropped.
+            None => responses.extend(ids.into_iter().map(|id| ResponseFrame {
+                id,
+                response: Response::Err(ResponseError::Timeout),
+            })),
+        }
+    }
+    Ok(responses)
+}
+
+#[derive(Default)]
+/// Finds where json value of a legacy request ends, scanning every byte
+/// once however many reads the value takes.
+struct JsonEnd {
+    scanned: usize,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl JsonEnd {
+    /// Scans bytes added to `buf` since the last call, returns whether the
+    /// value starting at its beginning may be complete. Values that are
+    /// not objects or arrays are left to the parser right away.
+    fn scan(&mut self, buf: &[u8]) -> bool {
+        if !matches!(buf[0], b'{' | b'[') {
+            return true;
+        }
+        for (idx, b) in buf.iter().enumerate().skip(self.scanned) {
+            if self.in_string {
+                match b {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => self.in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match b {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' => {
+                    self.depth -= 1;
+                    if self.depth == 0 {
+                        self.scanned = idx + 1;
+                        return true;
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.scanned = buf.len();
+        false
+    }
+}
@@ -0,0 +1,266 @@
+//! Authentication and per-key-prefix authorization.
+//!
+//! Configuration is a json file listing users, each authenticated by a token
+//! (possibly shared by many clients), a password or, over mutual TLS, by
+//! certificate common name equal to the user name:
+//!
+//! ```json
+//! {
+//!   "users": {
+//!     "ci": { "token": "s3cret", "grants": [{ "prefix": "", "access": "read" }] },
+//!     "alice": {
+//!       "password_hash": "$argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>",
+//!       "grants": [{ "prefix": "alice:", "access": "write" }]
+//!     }
+//!   },
+//!   "anonymous": []
+//! }
+//! ```
+//!
+//! Grants of unauthenticated clients are listed in `anonymous`. Password
+//! hashes are argon2 PHC strings, `kvs-admin hash-password` prints one. Every
+//! token belongs to a single user.
+
+use crate::error::KvsError;
+use crate::protocol::Credentials;
+use crate::Result;
+use anyhow::{anyhow, bail, Context};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// Access level, every level includes the lower ones.
+pub enum Access {
+    /// Reading values and listing keys.
+    Read,
+    /// Setting and removing values.
+    Write,
+    /// Server wide operations like statistics.
+    Admin,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+/// Grants access to every key starting with the prefix.
+pub struct Grant {
+    pub prefix: String,
+    pub access: Access,
+}
+
+#[derive(Debug, Deserialize)]
+// unknown fields fail loading, so that no misspelled or outdated credential
+// is silently ignored.
+#[serde(deny_unknown_fields)]
+struct User {
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    password_hash: Option<String>,
+    #[serde(default)]
+    grants: Vec<Grant>,
+}
+
+#[derive(Debug, Deserialize)]
+/// Content of the configuration file.
+struct AuthFile {
+    #[serde(default)]
+    users: HashMap<String, User>,
+    #[serde(default)]
+    anonymous: Vec<Grant>,
+}
+
+#[derive(Debug)]
+/// Users and their grants loaded from the configuration file.
+pub struct Auth {
+    users: HashMap<String, User>,
+    /// Sha256 of every token with the name of its user, tokens are matched
+    /// by comparing digests in constant time.
+    tokens: Vec<([u8; 32], String)>,
+    anonymous: Vec<Grant>,
+}
+
+impl Auth {
+    /// Loads configuration from json file. Fails on malformed password
+    /// hashes and on tokens of more than one user.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content =
+            fs::read(path).with_context(|| format!("could not open {}", path.display()))?;
+        let file: AuthFile = serde_json::from_slice(&content)
+            .with_context(|| format!("invalid auth configuration {}", path.display()))?;
+
+        let mut tokens: Vec<([u8; 32], String)> = vec![];
+        for (name, user) in &file.users {
+            if let Some(hash) = &user.password_hash {
+                PasswordHash::new(hash)
+                    .map_err(|e| anyhow!("invalid password hash of user {}: {}", name, e))?;
+            }
+            if let Some(token) = &user.token {
+                let digest = token_digest(token);
+                if let Some((_, other)) = tokens.iter().find(|(other, _)| *other == digest) {
+                    bail!("users {} and {} have the same token", other, name);
+                }
+                tokens.push((digest, name.clone()));
+            }
+        }
+        Ok(Self {
+            users: file.users,
+            tokens,
+            anonymous: file.anonymous,
+        })
+    }
+
+    /// Authenticates client. Clients without credentials are identified by
+    /// `identity`, the common name of verified client certificate, and
+    /// fall back to anonymous.
+    pub fn authenticate(
+        &self,
+        credentials: &Credentials,
+        identity: Option<&str>,
+    ) -> Result<Principal> {
+        let name = match credentials {
+            Credentials::Token(token) => self.find_token(token),
+            Credentials::Password { user, password } => self
+                .users
+                .get_key_value(user)
+                .filter(|(_, user)| {
+                    user.password_hash
+                        .as_deref()
+                        .is_some_and(|hash| verify_password(password, hash))
+                })
+                .map(|(name, _)| name),
+            Credentials::Anonymous => {
+                return Ok(match identity.and_then(|id| self.users.get_key_value(id)) {
+                    Some((name, user)) => Principal::user(name, user),
+                    None => self.anonymous(),
+                })
+            }
+        };
+
+        match name {
+            Some(name) => Ok(Principal::user(name, &self.users[name])),
+            None => Err(KvsError::PermissionDenied("invalid credentials".to_owned()).into()),
+        }
+    }
+
+    /// User of the token. Every stored token is compared, so timing tells
+    /// neither whether nor where the token matched.
+    fn find_token(&self, token: &str) -> Option<&String> {
+        let digest = token_digest(token);
+        self.tokens.iter().fold(None, |found, (other, name)| {
+            if constant_time_eq(&digest, other) {
+                Some(name)
+            } else {
+                found
+            }
+        })
+    }
+
+    /// Principal of clients that did not authenticate.
+    pub fn anonymous(&self) -> Principal {
+        Principal {
+            name: None,
+            grants: Some(self.anonymous.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+/// Authenticated client and its grants.
+pub struct Principal {
+    name: Option<String>,
+    /// `None` when authorization is disabled.
+    grants: Option<Vec<Grant>>,
+}
+
+impl Principal {
+    fn user(name: &str, user: &User) -> Self {
+        Self {
+            name: Some(name.to_owned()),
+            grants: Some(user.grants.clone()),
+        }
+    }
+
+    /// Principal allowed to do anything, used when server has no auth configured.
+    pub fn unrestricted() -> Self {
+        Self {
+            name: None,
+            grants: None,
+        }
+    }
+
+    /// User name, `None` for anonymous clients.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Whether principal has at least `access` to the key.
+    pub fn can(&self, key: &str, access: Access) -> bool {
+        match &self.grants {
+            Some(grants) => grants
+                .iter()
+                .any(|grant| grant.access >= access && key.starts_with(&grant.prefix)),
+            None => true,
+        }
+    }
+
+    /// Fails with `KvsError::PermissionDenied` unless principal has `access` to the key.
+    pub fn check(&self, key: &str, access: Access) -> Result<()> {
+        if self.can(key, access) {
+            return Ok(());
+        }
+        Err(KvsError::PermissionDenied(format!(
+            "{} has no {:?} access to key {:?}",
+            self.name().unwrap_or("anonymous"),
+            access,
+            key
+        ))
+        .into())
+    }
+}
+
+/// Authenticates client against optional configuration, everyone is
+/// unrestricted when server has no auth configured.
+pub fn authenticate(
+    auth: Option<&Auth>,
+    credentials: &Credentials,
+    identity: Option<&str>,
+) -> Result<Principal> {
+    match auth {
+        Some(auth) => auth.authenticate(credentials, identity),
+        None => Ok(Principal::unrestricted()),
+    }
+}
+
+/// Argon2id hash of the password with random salt, as PHC string expected
+/// in `password_hash` of users.
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("could not hash password: {}", e))?;
+    Ok(hash.to_string())
+}
+
+/// Checks password against PHC string, comparing hashes in constant time.
+fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+fn token_digest(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+/// Compares secrets without leaking position of the first difference.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,523 @@
+//! Load generator of `kvs-bench`, drives a running server from several
+//! connections and reports throughput and latency percentiles.
+
+use crate::cmd::CMD;
+use crate::error::KvsError;
+use crate::shard::expect_ok;
+use crate::transport::Address;
+use crate::{Codec, KvsClient, Result};
+use anyhow::bail;
+use clap::Parser;
+use serde::Serialize;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Keys set at once while preloading.
+const PRELOAD_BATCH: usize = 1000;
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+/// Cli of load generator for kvs-server.
+pub struct BenchCLI {
+    #[clap(long, default_value = "127.0.0.1:4000", value_name = "ADDR")]
+    addr: Address,
+    /// Encoding of protocol frames.
+    #[clap(long, default_value_t = Codec::Bincode, value_name = "CODEC")]
+    codec: Codec,
+    /// Concurrent connections, each sending one request at a time.
+    #[clap(long, default_value_t = 8)]
+    clients: usize,
+    /// Seconds to run for, unless `--requests` is given.
+    #[clap(long, default_value_t = 10.0, value_name = "SECS")]
+    duration: f64,
+    /// Total number of requests to send instead of running for `--duration`.
+    #[clap(long)]
+    requests: Option<u64>,
+    /// Number of distinct keys.
+    #[clap(long, default_value_t = 10_000)]
+    keys: u64,
+    /// Prefix of generated keys.
+    #[clap(long, default_value = "bench:")]
+    prefix: String,
+    /// Key size in bytes, `N` or `MIN-MAX` for uniform distribution.
+    #[clap(long, default_value = "16", value_name = "SIZE")]
+    key_size: SizeDist,
+    /// Value size in bytes, `N` or `MIN-MAX` for uniform distribution.
+    #[clap(long, default_value = "100", value_name = "SIZE")]
+    value_size: SizeDist,
+    /// Fraction of requests that are reads, the rest are writes.
+    #[clap(long, default_value_t = 0.9, value_name = "RATIO")]
+    read_ratio: f64,
+    /// Zipfian exponent of key popularity, key 0 being the hottest.
+    /// 0 picks keys uniformly.
+    #[clap(long, default_value_t = 0.0, value_name = "S")]
+    zipf: f64,
+    /// Set every key before measuring, so that reads find values.
+    #[clap(long)]
+    preload: bool,
+    /// Seed of random choices, defaults to current time.
+    #[clap(long)]
+    seed: Option<u64>,
+    /// Print report as JSON.
+    #[clap(long)]
+    json: bool,
+}
+
+impl BenchCLI {
+    pub fn run(&self) -> Result<()> {
+        if self.clients == 0 || self.keys == 0 {
+            bail!("--clients and --keys must be positive");
+        }
+        if !(0.0..=1.0).contains(&self.read_ratio) {
+            bail!("--read-ratio must be between 0 and 1");
+        }
+        if !self.zipf.is_finite() || self.zipf < 0.0 {
+            bail!("--zipf must not be negative");
+        }
+        let seed = self.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64)
+        });
+        let workload = Workload {
+            prefix: self.prefix.clone(),
+            key_size: self.key_size,
+            value_size: self.value_size,
+            read_ratio: self.read_ratio,
+            keys: KeyChooser::new(self.keys, self.zipf),
+            seed,
+        };
+
+        if self.preload {
+            let mut client = KvsClient::connect_to(&self.addr, self.codec)?;
+            workload.preload(&mut client, self.keys)?;
+        }
+
+        let report = self.measure(&workload)?;
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            print!("{}", report);
+        }
+        Ok(())
+    }
+
+    /// Runs all clients until the duration passes or requests are sent.
+    fn measure(&self, workload: &Workload) -> Result<Report> {
+        let deadline = Duration::from_secs_f64(self.duration.max(0.0));
+        let budget = AtomicU64::new(self.requests.unwrap_or(u64::MAX));
+        let started = Instant::now();
+
+        let results = thread::scope(|scope| {
+            let handles: Vec<_> = (0..self.clients)
+                .map(|idx| {
+                    let budget = &budget;
+                    scope.spawn(move || -> Result<Samples> {
+                        let mut client = KvsClient::connect_to(&self.addr, self.codec)?;
+                        let mut rng = Rng::new(workload.seed.wrapping_add(idx as u64 + 1));
+                        let mut samples = Samples::default();
+                        loop {
+                            if self.requests.is_none() && started.elapsed() >= deadline {
+                                break;
+                            }
+                            // claims one request of the budget.
+                            if budget
+                                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                                    left.checked_sub(1)
+                                })
+                                .is_err()
+                            {
+                                break;
+                            }
+                            if let Err(e) = workload.request(&mut client, &mut rng, &mut samples) {
+                                debug!("request failed: {}", e);
+                                // connection may be broken, continue on a new one.
+                                client = KvsClient::connect_to(&self.addr, self.codec)?;
+                            }
+                        }
+                        Ok(samples)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("bench client panicked"))
+                .collect::<Result<Vec<_>>>()
+        })?;
+        let elapsed = started.elapsed();
+
+        let mut samples = Samples::default();
+        for result in results {
+            samples.merge(result);
+        }
+        Ok(Report::new(self, workload.seed, elapsed, samples))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "dist", rename_all = "lowercase")]
+/// Distribution of key or value sizes.
+pub enum SizeDist {
+    Fixed { size: usize },
+    Uniform { min: usize, max: usize },
+}
+
+impl SizeDist {
+    fn sample(&self, rng: &mut Rng) -> usize {
+        match *self {
+            SizeDist::Fixed { size } => size,
+            SizeDist::Uniform { min, max } => min + rng.below((max - min + 1) as u64) as usize,
+        }
+    }
+}
+
+impl Display for SizeDist {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SizeDist::Fixed { size } => write!(f, "{}", size),
+            SizeDist::Uniform { min, max } => write!(f, "{}-{}", min, max),
+        }
+    }
+}
+
+impl FromStr for SizeDist {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> std::result::Result<SizeDist, KvsError> {
+        let parse = |s: &str| s.trim().parse::<usize>().map_err(|_| KvsError::Parse);
+        match s.split_once('-') {
+            Some((min, max)) => {
+                let (min, max) = (parse(min)?, parse(max)?);
+                if min > max {
+                    return Err(KvsError::Parse);
+                }
+                Ok(SizeDist::Uniform { min, max })
+            }
+            None => Ok(SizeDist::Fixed { size: parse(s)? }),
+        }
+    }
+}
+
+/// What clients send.
+struct Workload {
+    prefix: String,
+    key_size: SizeDist,
+    value_size: SizeDist,
+    read_ratio: f64,
+    keys: KeyChooser,
+    seed: u64,
+}
+
+impl Workload {
+    /// Key with the index, its size is the same every time.
+    fn key(&self, idx: u64) -> String {
+        let mut key = format!("{}{}", self.prefix, idx);
+        let size = self.key_size.sample(&mut Rng::new(self.seed ^ idx));
+        while key.len() < size {
+            key.push('_');
+        }
+        key
+    }
+
+    fn value(&self, rng: &mut Rng) -> String {
+        let size = self.value_size.sample(rng);
+        (0..size)
+            .map(|_| (b'a' + rng.below(26) as u8) as char)
+            .collect()
+    }
+
+    /// Sets every key, pipelining the writes.
+    fn preload(&self, client: &mut KvsClient, keys: u64) -> Result<()> {
+        let mut rng = Rng::new(self.seed);
+        let mut idx = 0;
+        while idx < keys {
+            let end = (idx + PRELOAD_BATCH as u64).min(keys);
+            let cmds = (idx..end)
+                .map(|i| CMD::Set {
+                    key: self.key(i),
+                    value: self.value(&mut rng),
+                })
+                .collect();
+            expect_ok(client.batch(cmds)?)?;
+            idx = end;
+        }
+        info!("Preloaded {} keys", keys);
+        Ok(())
+    }
+
+    /// Sends single random request and records its latency.
+    fn request(&self, client: &mut KvsClient, rng: &mut Rng, samples: &mut Samples) -> Result<()> {
+        let key = self.key(self.keys.choose(rng));
+        if rng.next_f64() < self.read_ratio {
+            let started = Instant::now();
+            let result = client.get(key);
+            samples.gets.record(started.elapsed(), result.is_ok());
+            result.map(|_| ())
+        } else {
+            let value = self.value(rng);
+            let started = Instant::now();
+            let result = client.set(key, value);
+            samples.sets.record(started.elapsed(), result.is_ok());
+            result
+        }
+    }
+}
+
+/// Picks key indexes uniformly or following Zipf distribution.
+struct KeyChooser {
+    keys: u64,
+    /// Cumulative probabilities of key indexes, empty for uniform choice.
+    cdf: Vec<f64>,
+}
+
+impl KeyChooser {
+    fn new(keys: u64, exponent: f64) -> Self {
+        if exponent == 0.0 {
+            return Self { keys, cdf: vec![] };
+        }
+        let mut total = 0.0;
+        let mut cdf: Vec<f64> = (1..=keys)
+            .map(|rank| {
+                total += 1.0 / (rank as f64).powf(exponent);
+                total
+            })
+            .collect();
+        for p in &mut cdf {
+            *p /= total;
+        }
+        Self { keys, cdf }
+    }
+
+    fn choose(&self, rng: &mut Rng) -> u64 {
+        if self.cdf.is_empty() {
+            return rng.below(self.keys);
+        }
+        let p = rng.next_f64();
+        (self.cdf.partition_point(|&c| c < p) as u64).min(self.keys - 1)
+    }
+}
+
+/// SplitMix64, enough for choosing keys and sizes.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Uniform in `[0, n)`.
+    fn below(&mut self, n: u64) -> u64 {
+        ((self.next_u64() as u128 * n as u128) >> 64) as u64
+    }
+}
+
+#[derive(Default)]
+/// Latencies recorded by clients.
+struct Samples {
+    gets: Latencies,
+    sets: Latencies,
+}
+
+impl Samples {
+    fn merge(&mut self, other: Samples) {
+        self.gets.merge(other.gets);
+        self.sets.merge(other.sets);
+    }
+}
+
+#[derive(Default)]
+/// Latencies of one operation in microseconds, failed requests are only
+/// counted.
+struct Latencies {
+    micros: Vec<u64>,
+    errors: u64,
+}
+
+impl Latencies {
+    fn record(&mut self, latency: Duration, ok: bool) {
+        if ok {
+            self.micros.push(latency.as_micros() as u64);
+        } else {
+            self.errors += 1;
+        }
+    }
+
+    fn merge(&mut self, other: Latencies) {
+        self.micros.extend(other.micros);
+        self.errors += other.errors;
+    }
+}
+
+#[derive(Debug, Serialize)]
+/// Settings and results of a run.
+struct Report {
+    addr: String,
+    codec: Codec,
+    clients: usize,
+    keys: u64,
+    key_size: SizeDist,
+    value_size: SizeDist,
+    read_ratio: f64,
+    zipf: f64,
+    seed: u64,
+    elapsed_secs: f64,
+    requests: u64,
+    errors: u64,
+    /// Successful requests per second.
+    throughput: f64,
+    get: OpReport,
+    set: OpReport,
+}
+
+#[derive(Debug, Serialize)]
+/// Results of one operation, latencies in microseconds.
+struct OpReport {
+    requests: u64,
+    errors: u64,
+    mean: f64,
+    percentiles: Percentiles,
+    /// Power of two buckets that have some requests, in ascending order.
+    histogram: Vec<Bucket>,
+}
+
+#[derive(Debug, Serialize)]
+struct Percentiles {
+    p50: u64,
+    p90: u64,
+    p99: u64,
+    p999: u64,
+    max: u64,
+}
+
+#[derive(Debug, Serialize)]
+/// Requests that took at most `le` microseconds and more than `le / 2`.
+struct Bucket {
+    le: u64,
+    count: u64,
+}
+
+impl OpReport {
+    fn new(mut latencies: Latencies) -> Self {
+        latencies.micros.sort_unstable();
+        let micros = &latencies.micros;
+        let requests = micros.len() as u64;
+
+        let mean = if micros.is_empty() {
+            0.0
+        } else {
+            micros.iter().sum::<u64>() as f64 / micros.len() as f64
+        };
+        // nearest-rank percentile of sorted latencies.
+        let percentile = |q: f64| {
+            let rank = ((micros.len() as f64 * q).ceil() as usize).max(1);
+            micros.get(rank - 1).copied().unwrap_or_default()
+        };
+        let percentiles = Percentiles {
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+            p999: percentile(0.999),
+            max: percentile(1.0),
+        };
+
+        let mut histogram: Vec<Bucket> = vec![];
+        for &latency in micros {
+            let le = latency.max(1).next_power_of_two();
+            match histogram.last_mut() {
+                Some(bucket) if bucket.le == le => bucket.count += 1,
+                _ => histogram.push(Bucket { le, count: 1 }),
+            }
+        }
+
+        Self {
+            requests,
+            errors: latencies.errors,
+            mean,
+            percentiles,
+            histogram,
+        }
+    }
+}
+
+impl Report {
+    fn new(cli: &BenchCLI, seed: u64, elapsed: Duration, samples: Samples) -> Self {
+        let get = OpReport::new(samples.gets);
+        let set = OpReport::new(samples.sets);
+        let requests = get.requests + set.requests;
+        Self {
+            addr: cli.addr.to_string(),
+            codec: cli.codec,
+            clients: cli.clients,
+            keys: cli.keys,
+            key_size: cli.key_size,
+            value_size: cli.value_size,
+            read_ratio: cli.read_ratio,
+            zipf: cli.zipf,
+            seed,
+            elapsed_secs: elapsed.as_secs_f64(),
+            requests,
+            errors: get.errors + set.errors,
+            throughput: requests as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+            get,
+            set,
+        }
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} clients, {} keys of {} bytes, values of {} bytes, {:.0}% reads, zipf {}",
+            self.clients,
+            self.keys,
+            self.key_size,
+            self.value_size,
+            self.read_ratio * 100.0,
+            self.zipf
+        )?;
+        writeln!(
+            f,
+            "{} requests, {} errors in {:.2}s, {:.0} req/s",
+            self.requests, self.errors, self.elapsed_secs, self.throughput
+        )?;
+        for (name, op) in [("get", &self.get), ("set", &self.set)] {
+            if op.requests == 0 && op.errors == 0 {
+                continue;
+            }
+            write!(
+                f,
+                "{}: {} requests, {} errors, mean {:.0}us",
+                name, op.requests, op.errors, op.mean
+            )?;
+            let p = &op.percentiles;
+            writeln!(
+                f,
+                ", p50 {}us, p90 {}us, p99 {}us, p999 {}us, max {}us",
+                p.p50, p.p90, p.p99, p.p999, p.max
+            )?;
+            let most = op.histogram.iter().map(|b| b.count).max().unwrap_or(1);
+            for bucket in &op.histogram {
+                let bar = "#".repeat((bucket.count * 40).div_ceil(most) as usize);
+                writeln!(f, "  <= {:>8}us {:>9} {}", bucket.le, bucket.count, bar)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,11 @@
+use clap::Parser;
+use kvs::AdminCLI;
+use kvs::Result;
+use log::LevelFilter;
+
+fn main() -> Result<()> {
+    env_logger::builder().filter_level(LevelFilter::Info).init();
+
+    let cli = AdminCLI::parse();
+    cli.run()
+}
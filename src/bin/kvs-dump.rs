@@ -0,0 +1,11 @@
+use clap::Parser;
+use kvs::DumpCLI;
+use kvs::Result;
+use log::LevelFilter;
+
+fn main() -> Result<()> {
+    env_logger::builder().filter_level(LevelFilter::Info).init();
+
+    let cli = DumpCLI::parse();
+    cli.run()
+}
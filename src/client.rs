@@ -1,14 +1,27 @@
 use crate::{
-    cmd::{GetResponse, RemoveResponse, SetResponse, CMD},
+    cmd::{Response, ResponseError, CMD},
+    error::KvsError,
+    protocol::{self, Chunks, Codec, Credentials, RequestFrame, ResponseFrame},
+    raft::{Member, MembershipChange, NodeId},
+    replication::{LogBatch, LogPosition},
+    shard::ShardedClient,
+    shell::Shell,
+    tls,
+    transfer::{self, BulkStore, Format, Import, DEFAULT_BATCH_SIZE},
+    transport::{Address, Stream},
     Result,
 };
-use anyhow::bail;
 use clap::{Parser, Subcommand};
+use rustls::ClientConfig;
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
 use std::{
-    io::{BufReader, BufWriter, Write},
-    net::{Ipv4Addr, SocketAddrV4, TcpStream},
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    net::ToSocketAddrs,
+    path::PathBuf,
+    sync::Arc,
+    thread,
+    time::Duration,
 };
 
 /*
@@ -25,11 +38,142 @@ use std::{
 pub struct ClientCLI {
     #[clap(subcommand)]
     command: Commands,
+    /// Encoding of protocol frames.
+    #[clap(long, global = true, default_value_t = Codec::Bincode, value_name = "CODEC")]
+    codec: Codec,
+    /// PEM CA certificates, connects over TLS verifying server against them.
+    #[clap(long, global = true, value_name = "PATH")]
+    ca: Option<PathBuf>,
+    /// PEM client certificate chain for servers requiring mutual TLS.
+    #[clap(long, global = true, requires_all = &["ca", "key"], value_name = "PATH")]
+    cert: Option<PathBuf>,
+    /// PEM private key matching `--cert`.
+    #[clap(long, global = true, requires = "cert", value_name = "PATH")]
+    key: Option<PathBuf>,
+    /// Name verified against server certificate, defaults to server ip.
+    #[clap(long, global = true, requires = "ca", value_name = "NAME")]
+    server_name: Option<String>,
+    /// Authentication token configured on the server.
+    #[clap(long, global = true, conflicts_with = "user", value_name = "TOKEN")]
+    token: Option<String>,
+    /// User to authenticate as, together with `--password`.
+    #[clap(long, global = true, requires = "password", value_name = "NAME")]
+    user: Option<String>,
+    #[clap(long, global = true, requires = "user", value_name = "PASSWORD")]
+    password: Option<String>,
+    /// Shard keys over these servers instead of using `--addr`.
+    #[clap(long, global = true, value_delimiter = ',', value_name = "ADDR,..")]
+    servers: Vec<Address>,
 }
 
 impl ClientCLI {
     pub fn run(&self) -> Result<()> {
-        self.command.run()
+        if !self.servers.is_empty() {
+            return self.run_sharded();
+        }
+        let addr = match self.command.addr() {
+            Some(addr) => addr,
+            None => return Err(KvsError::Server("rebalance requires --servers".to_owned()).into()),
+        };
+        let mut client = self.connect(addr)?;
+        self.command.run(&mut client)
+    }
+
+    /// Runs command against servers of `--servers`, routing keys over the ring.
+    fn run_sharded(&self) -> Result<()> {
+        let clients = self
+            .servers
+            .iter()
+            .map(|addr| Ok((addr.to_string(), self.connect(addr)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let mut client = ShardedClient::new(clients)?;
+
+        match &self.command {
+            Commands::Set { key, value, .. } => client.set(key.clone(), value.clone())?,
+            Commands::Get { key, stale, .. } => {
+                let value = if *stale {
+                    client.get_stale(key.clone())?
+                } else {
+                    client.get(key.clone())?
+                };
+                match value {
+                    Some(value) => println!("{}", value),
+                    None => println!("Key not found"),
+                }
+            }
+            Commands::Rm { key, .. } => client.remove(key.clone())?,
+            Commands::Scan { prefix, .. } => {
+                for key in client.scan(prefix.clone())? {
+                    println!("{}", key);
+                }
+            }
+            Commands::Rebalance { drain } => {
+                let drained = drain
+                    .iter()
+                    .map(|addr| Ok((addr.to_string(), self.connect(addr)?)))
+                    .collect::<Result<Vec<_>>>()?;
+                let moved = client.rebalance(drained)?;
+                println!("Moved {} keys", moved);
+            }
+            Commands::Import { .. } | Commands::Export { .. } => {
+                self.command.transfer(&mut client)?
+            }
+            Commands::Shell { .. } => {
+                return Err(KvsError::Server("shell requires --addr".to_owned()).into())
+            }
+            Commands::AddNode { .. } | Commands::RemoveNode { .. } => {
+                return Err(KvsError::Server(
+                    "cluster membership can't be changed with --servers".to_owned(),
+                )
+                .into())
+            }
+        }
+        Ok(())
+    }
+
+    fn connect(&self, addr: &Address) -> Result<KvsClient> {
+        let credentials = match (&self.token, &self.user, &self.password) {
+            (Some(token), _, _) => Credentials::Token(token.clone()),
+            (None, Some(user), Some(password)) => Credentials::Password {
+                user: user.clone(),
+                password: password.clone(),
+            },
+            _ => Credentials::Anonymous,
+        };
+        let ca = match &self.ca {
+            Some(ca) => ca,
+            None => {
+                return KvsClient::handshake(Stream::connect_to(addr)?, self.codec, &credentials)
+            }
+        };
+        let identity = match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => Some((cert.as_path(), key.as_path())),
+            (None, None) => None,
+            _ => {
+                return Err(
+                    KvsError::Tls("--cert and --key must be given together".to_owned()).into(),
+                )
+            }
+        };
+        let server_name = match (&self.server_name, addr.host()) {
+            (Some(name), _) => name.clone(),
+            (None, Some(host)) => host.to_owned(),
+            (None, None) => {
+                return Err(KvsError::Tls("TLS requires tcp address".to_owned()).into());
+            }
+        };
+        let config = tls::client_config(ca, identity)?;
+        let mut client = KvsClient::handshake(
+            Stream::connect_tls_to(addr, &server_name, config.clone())?,
+            self.codec,
+            &credentials,
+        )?;
+        // redirects to the leader are opened over TLS as well.
+        client.redirect = Some(Redirect::Tls {
+            server_name,
+            config,
+        });
+        Ok(client)
     }
 }
 
@@ -38,119 +182,627 @@ pub enum Commands {
     Set {
         key: String,
         value: String,
-        #[clap(
-            action,
-            long,
-            default_value_t = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 4000),
-            value_parser,
-            value_name = "IP-PORT",
-        )]
-        addr: SocketAddrV4,
+        #[clap(long, default_value = "127.0.0.1:4000", value_name = "ADDR")]
+        addr: Address,
     },
     Get {
         key: String,
-        #[clap(
-            action,
-            long,
-            default_value_t = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 4000),
-            value_parser,
-            value_name = "IP-PORT",
-        )]
-        addr: SocketAddrV4,
+        /// Read local value of the cluster node, which may be out of date.
+        #[clap(long)]
+        stale: bool,
+        #[clap(long, default_value = "127.0.0.1:4000", value_name = "ADDR")]
+        addr: Address,
     },
     Rm {
         key: String,
-        #[clap(
-            action,
-            long,
-            default_value_t = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 4000),
-            value_parser,
-            value_name = "IP-PORT",
-        )]
-        addr: SocketAddrV4,
+        #[clap(long, default_value = "127.0.0.1:4000", value_name = "ADDR")]
+        addr: Address,
+    },
+    /// Lists keys starting with the prefix.
+    Scan {
+        #[clap(default_value = "")]
+        prefix: String,
+        #[clap(long, default_value = "127.0.0.1:4000", value_name = "ADDR")]
+        addr: Address,
+    },
+    /// Moves keys to their servers on the ring of `--servers`, after
+    /// servers were added or before they are removed.
+    Rebalance {
+        /// Servers being removed, all their keys are moved.
+        #[clap(long, value_delimiter = ',', value_name = "ADDR,..")]
+        drain: Vec<Address>,
+    },
+    /// Sets keys read from JSON Lines or CSV file, `-` for standard input.
+    Import {
+        file: PathBuf,
+        /// `jsonl` or `csv`, guessed from file extension by default.
+        #[clap(long, value_name = "FORMAT")]
+        format: Option<Format>,
+        /// Records sent pipelined at once.
+        #[clap(long, default_value_t = DEFAULT_BATCH_SIZE)]
+        batch_size: usize,
+        /// Only validate the file, nothing is set.
+        #[clap(long)]
+        dry_run: bool,
+        #[clap(long, default_value = "127.0.0.1:4000", value_name = "ADDR")]
+        addr: Address,
+    },
+    /// Writes keys starting with the prefix, with their values, as JSON
+    /// Lines or CSV.
+    Export {
+        #[clap(long, default_value = "")]
+        prefix: String,
+        /// `jsonl` or `csv`, guessed from output extension by default.
+        #[clap(long, value_name = "FORMAT")]
+        format: Option<Format>,
+        /// File to write to instead of standard output.
+        #[clap(long, short, value_name = "PATH")]
+        output: Option<PathBuf>,
+        #[clap(long, default_value = "127.0.0.1:4000", value_name = "ADDR")]
+        addr: Address,
+    },
+    /// Interactive shell running commands over a single connection.
+    Shell {
+        #[clap(long, default_value = "127.0.0.1:4000", value_name = "ADDR")]
+        addr: Address,
+    },
+    /// Adds node to Raft cluster, sent to the leader.
+    AddNode {
+        id: NodeId,
+        /// Address of the node Raft transport.
+        raft_addr: String,
+        /// Address clients are redirected to.
+        client_addr: String,
+        #[clap(long, default_value = "127.0.0.1:4000", value_name = "ADDR")]
+        addr: Address,
+    },
+    /// Removes node from Raft cluster, sent to the leader.
+    RemoveNode {
+        id: NodeId,
+        #[clap(long, default_value = "127.0.0.1:4000", value_name = "ADDR")]
+        addr: Address,
     },
 }
 
 impl Commands {
-    pub fn run(&self) -> Result<()> {
-        let client = Client::new(*self.addr());
-
-        let cmd = match self {
+    pub fn run(&self, client: &mut KvsClient) -> Result<()> {
+        match self {
             Commands::Set {
                 key,
                 value,
                 addr: _,
-            } => CMD::Set {
-                key: key.clone(),
-                value: value.clone(),
-            },
-            Commands::Get { key, addr: _ } => CMD::Get { key: key.clone() },
-            Commands::Rm { key, addr: _ } => CMD::Rm { key: key.clone() },
+            } => client.set(key.clone(), value.clone())?,
+            Commands::Get {
+                key,
+                stale,
+                addr: _,
+            } => {
+                let value = if *stale {
+                    client.get_stale(key.clone())?
+                } else {
+                    client.get(key.clone())?
+                };
+                match value {
+                    Some(value) => println!("{}", value),
+                    None => println!("Key not found"),
+                }
+            }
+            Commands::Rm { key, addr: _ } => client.remove(key.clone())?,
+            Commands::Scan { prefix, addr: _ } => {
+                for key in client.scan(prefix.clone())? {
+                    println!("{}", key);
+                }
+            }
+            Commands::Rebalance { .. } => {
+                return Err(KvsError::Server("rebalance requires --servers".to_owned()).into())
+            }
+            Commands::Import { .. } | Commands::Export { .. } => self.transfer(client)?,
+            Commands::Shell { addr } => Shell::new(client, addr.clone()).run()?,
+            Commands::AddNode {
+                id,
+                raft_addr,
+                client_addr,
+                addr: _,
+            } => client.change_membership(MembershipChange::Add {
+                id: *id,
+                member: Member {
+                    raft_addr: raft_addr.clone(),
+                    client_addr: client_addr.clone(),
+                },
+            })?,
+            Commands::RemoveNode { id, addr: _ } => {
+                client.change_membership(MembershipChange::Remove { id: *id })?
+            }
         };
 
-        client.write(&cmd)?;
+        Ok(())
+    }
 
+    /// Runs import or export against the store.
+    fn transfer(&self, store: &mut dyn BulkStore) -> Result<()> {
+        match self {
+            Commands::Import {
+                file,
+                format,
+                batch_size,
+                dry_run,
+                addr: _,
+            } => {
+                let import = Import {
+                    path: file,
+                    format: *format,
+                    batch_size: *batch_size,
+                };
+                let imported = import.run(if *dry_run { None } else { Some(store) })?;
+                if *dry_run {
+                    println!("Dry run: {} valid records", imported);
+                } else {
+                    println!("Imported {} records", imported);
+                }
+            }
+            Commands::Export {
+                prefix,
+                format,
+                output,
+                addr: _,
+            } => {
+                let format = Format::resolve(*format, output.as_deref());
+                let exported = match output {
+                    Some(path) => {
+                        transfer::export(store, prefix.clone(), format, File::create(path)?)?
+                    }
+                    None => transfer::export(store, prefix.clone(), format, io::stdout().lock())?,
+                };
+                info!("Exported {} keys", exported);
+            }
+            _ => unreachable!("only import and export transfer keys"),
+        }
         Ok(())
     }
 
-    fn addr(&self) -> &SocketAddrV4 {
+    /// Server the command is sent to, `None` for commands working on
+    /// all servers of `--servers`.
+    fn addr(&self) -> Option<&Address> {
         match self {
             Commands::Set {
                 key: _,
                 value: _,
                 addr,
-            } => addr,
-            Commands::Get { key: _, addr } => addr,
-            Commands::Rm { key: _, addr } => addr,
+            } => Some(addr),
+            Commands::Get { addr, .. } => Some(addr),
+            Commands::Rm { key: _, addr } => Some(addr),
+            Commands::Scan { prefix: _, addr } => Some(addr),
+            Commands::Rebalance { .. } => None,
+            Commands::Import { addr, .. } => Some(addr),
+            Commands::Export { addr, .. } => Some(addr),
+            Commands::Shell { addr } => Some(addr),
+            Commands::AddNode { addr, .. } => Some(addr),
+            Commands::RemoveNode { id: _, addr } => Some(addr),
         }
     }
 }
 
-/// Simple single-threaded tcp client.
-struct Client {
-    ip: SocketAddrV4,
+/// Blocking client speaking framed protocol over a single connection.
+/// Single requests sent to a Raft node that is not the leader are retried
+/// at the leader, pipelined ones are not.
+pub struct KvsClient {
+    reader: BufReader<Stream>,
+    /// Encoded requests not yet written to the connection.
+    pending: Vec<u8>,
+    codec: Codec,
+    next_id: u64,
+    credentials: Credentials,
+    /// How to reach the leader on redirect, `None` when connection was
+    /// opened by the caller over TLS.
+    redirect: Option<Redirect>,
+}
+
+/// Connection settings reused when following leader redirect.
+enum Redirect {
+    Plain,
+    Tls {
+        server_name: String,
+        config: Arc<ClientConfig>,
+    },
 }
 
-impl Client {
-    fn new(ip: SocketAddrV4) -> Self {
-        Self { ip }
+impl KvsClient {
+    /// Connects to the server and performs the protocol handshake.
+    pub fn connect(addr: impl ToSocketAddrs, codec: Codec) -> Result<Self> {
+        Self::handshake(Stream::connect(addr)?, codec, &Credentials::Anonymous)
+    }
+
+    /// Connects to tcp or Unix socket address, see `Address`.
+    pub fn connect_to(addr: &Address, codec: Codec) -> Result<Self> {
+        Self::handshake(Stream::connect_to(addr)?, codec, &Credentials::Anonymous)
+    }
+
+    /// Connects to the server over TLS, `server_name` is verified against
+    /// the server certificate.
+    pub fn connect_tls(
+        addr: impl ToSocketAddrs,
+        server_name: &str,
+        codec: Codec,
+        config: Arc<ClientConfig>,
+    ) -> Result<Self> {
+        let mut client = Self::handshake(
+            Stream::connect_tls(addr, server_name, config.clone())?,
+            codec,
+            &Credentials::Anonymous,
+        )?;
+        client.redirect = Some(Redirect::Tls {
+            server_name: server_name.to_owned(),
+            config,
+        });
+        Ok(client)
+    }
+
+    /// Performs the protocol handshake over already opened connection,
+    /// authenticating with given credentials.
+    pub fn handshake(mut stream: Stream, codec: Codec, credentials: &Credentials) -> Result<Self> {
+        protocol::client_handshake(&mut stream, codec, credentials)?;
+
+        let redirect = match stream {
+            Stream::Tcp(_) => Some(Redirect::Plain),
+            #[cfg(unix)]
+            Stream::Unix(_) => Some(Redirect::Plain),
+            _ => None,
+        };
+        Ok(Self {
+            reader: BufReader::new(stream),
+            pending: vec![],
+            codec,
+            next_id: 0,
+            credentials: credentials.clone(),
+            redirect,
+        })
+    }
+
+    /// Sets the value of a string key to a string.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        match self.request(CMD::Set { key, value })? {
+            Response::Ok => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Gets the string value of a given string key.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.request(CMD::Get { key })? {
+            Response::Value(value) => Ok(value),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Gets local value of the cluster node without asking the leader, it
+    /// may miss latest writes.
+    pub fn get_stale(&mut self, key: String) -> Result<Option<String>> {
+        match self.request(CMD::StaleGet { key })? {
+            Response::Value(value) => Ok(value),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Lists keys starting with the prefix, in ascending order.
+    pub fn scan(&mut self, prefix: String) -> Result<Vec<String>> {
+        match self.request(CMD::Scan { prefix })? {
+            Response::Keys(keys) => Ok(keys),
+            other => Err(unexpected(other)),
+        }
     }
 
-    fn write(&self, cmd: &CMD) -> Result<()> {
-        let stream = TcpStream::connect(self.ip)?;
-        // let tcp_writer = tcp_reader.try_clone()?;
+    /// Lists at most `limit` keys starting with the prefix that follow
+    /// `after`, in ascending order. Pass the last key of a page as `after`
+    /// of the next one.
+    pub fn scan_page(
+        &mut self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        match self.request(CMD::ScanPage {
+            prefix,
+            after,
+            limit,
+        })? {
+            Response::Keys(keys) => Ok(keys),
+            other => Err(unexpected(other)),
+        }
+    }
 
-        let mut reader = Deserializer::from_reader(BufReader::new(stream.try_clone()?));
-        let mut writer = BufWriter::new(stream);
+    /// Removes a given key.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        match self.request(CMD::Rm { key })? {
+            Response::Ok => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
 
-        debug!("writing cmd");
+    /// Sets the value only if the current one equals `expected`, `None`
+    /// meaning the key must not exist. Returns whether the value was set.
+    pub fn compare_and_set(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<bool> {
+        match self.request(CMD::CompareAndSet {
+            key,
+            expected,
+            value,
+        })? {
+            Response::Swapped(swapped) => Ok(swapped),
+            other => Err(unexpected(other)),
+        }
+    }
 
-        serde_json::to_writer(&mut writer, cmd)?;
-        writer.flush()?;
-        drop(writer);
+    /// Removes the key only if its value equals `expected`. Returns whether
+    /// the key was removed.
+    pub fn compare_and_remove(&mut self, key: String, expected: String) -> Result<bool> {
+        match self.request(CMD::CompareAndRemove { key, expected })? {
+            Response::Swapped(swapped) => Ok(swapped),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Sets the key to UTF-8 value read from `value`. Value is uploaded in
+    /// pipelined chunks, so it never has to be in memory whole.
+    pub fn set_from(&mut self, key: String, value: impl Read) -> Result<()> {
+        let mut chunks = Chunks::new(value);
+        let mut in_flight = 0;
+        let mut failure = None;
 
-        debug!("cmd written");
+        loop {
+            let (chunk, last) = match chunks.next_chunk() {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                Err(e) => {
+                    self.send(CMD::AbortUpload { key })?;
+                    for _ in 0..=in_flight {
+                        self.receive()?;
+                    }
+                    return Err(e);
+                }
+            };
+            if in_flight == MAX_IN_FLIGHT {
+                self.receive_ack(&mut failure)?;
+                in_flight -= 1;
+            }
+            self.send(CMD::SetChunk {
+                key: key.clone(),
+                chunk,
+                last,
+            })?;
+            in_flight += 1;
+        }
+        for _ in 0..in_flight {
+            self.receive_ack(&mut failure)?;
+        }
+
+        match failure {
+            Some(e) => Err(KvsError::from(e).into()),
+            None => Ok(()),
+        }
+    }
 
-        match cmd {
-            CMD::Set { key: _, value: _ } => {
-                debug!("reading set response");
-                let resp = SetResponse::deserialize(&mut reader)?;
-                debug!("{:?}", resp);
+    /// Writes value of the key to `out` as it arrives in chunks. Returns
+    /// whether the key exists.
+    pub fn get_to(&mut self, key: String, mut out: impl Write) -> Result<bool> {
+        let id = self.send(CMD::StreamGet { key })?;
+        // rest of the stream is read even when `out` fails.
+        let mut written: io::Result<()> = Ok(());
+        loop {
+            let (response_id, response) = self.receive()?;
+            if response_id != id {
+                return Err(KvsError::Protocol(format!(
+                    "expected response for request {}, got {}",
+                    id, response_id
+                ))
+                .into());
             }
-            CMD::Get { key: _ } => {
-                debug!("reading get response");
-                let resp = GetResponse::deserialize(&mut reader)?;
-                println!("{}", resp);
+            match response {
+                Response::Chunk { data, last } => {
+                    if written.is_ok() {
+                        written = out.write_all(data.as_bytes());
+                    }
+                    if last {
+                        written?;
+                        return Ok(true);
+                    }
+                }
+                Response::Value(None) => return Ok(false),
+                Response::Err(e) => return Err(KvsError::from(e).into()),
+                other => return Err(unexpected(other)),
             }
-            CMD::Rm { key: _ } => {
-                debug!("reading remove response");
-                let resp = RemoveResponse::deserialize(&mut reader)?;
-                if let RemoveResponse::Err(e) = resp {
-                    bail!(e)
-                };
+        }
+    }
+
+    /// Fetches leader log records following the position.
+    pub fn replicate(&mut self, from: Option<LogPosition>) -> Result<LogBatch> {
+        match self.request(CMD::Replicate { from })? {
+            Response::Log(batch) => Ok(batch),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Fetches part of leader snapshot following the key.
+    pub fn replicate_snapshot(&mut self, at: LogPosition, after: String) -> Result<LogBatch> {
+        match self.request(CMD::ReplicateSnapshot { at, after })? {
+            Response::Log(batch) => Ok(batch),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Adds or removes Raft cluster member.
+    pub fn change_membership(&mut self, change: MembershipChange) -> Result<()> {
+        match self.request(CMD::ChangeMembership { change })? {
+            Response::Ok => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Sends all commands pipelined over the connection and returns their
+    /// responses in the same order as commands. Failed commands are returned
+    /// as `Response::Err` instead of failing whole batch.
+    pub fn batch(&mut self, cmds: Vec<CMD>) -> Result<Vec<Response>> {
+        let first_id = self.next_id;
+        let mut responses = vec![None; cmds.len()];
+        let mut in_flight = 0;
+
+        for cmd in cmds {
+            if in_flight == MAX_IN_FLIGHT {
+                self.receive_into(first_id, &mut responses)?;
+                in_flight -= 1;
             }
+            self.send(cmd)?;
+            in_flight += 1;
         }
+        for _ in 0..in_flight {
+            self.receive_into(first_id, &mut responses)?;
+        }
+
+        Ok(responses.into_iter().flatten().collect())
+    }
+
+    /// Sends command without waiting for its response, returns request id
+    /// that the response will be tagged with.
+    pub fn send(&mut self, cmd: CMD) -> Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
 
+        debug!("writing request {}: {:?}", id, cmd);
+        protocol::write_frame(&mut self.pending, self.codec, &RequestFrame { id, cmd })?;
+        if self.pending.len() >= PENDING_LIMIT {
+            self.flush()?;
+        }
+        Ok(id)
+    }
+
+    /// Flushes sent commands and waits for the next response. Server may
+    /// answer pipelined requests in different order than they were sent.
+    pub fn receive(&mut self) -> Result<(u64, Response)> {
+        self.flush()?;
+
+        let frame: ResponseFrame = protocol::read_frame(&mut self.reader, self.codec)?
+            .ok_or_else(|| KvsError::Protocol("connection closed by server".to_string()))?;
+        debug!("read response {}: {:?}", frame.id, frame.response);
+
+        Ok((frame.id, frame.response))
+    }
+
+    /// Writes pending requests to the connection.
+    fn flush(&mut self) -> Result<()> {
+        if !self.pending.is_empty() {
+            self.reader.get_mut().write_all(&self.pending)?;
+            self.reader.get_mut().flush()?;
+            self.pending.clear();
+        }
         Ok(())
     }
+
+    /// Receives response to uploaded chunk, keeping the first failure.
+    fn receive_ack(&mut self, failure: &mut Option<ResponseError>) -> Result<()> {
+        match self.receive()? {
+            (_, Response::Ok) => {}
+            (_, Response::Err(e)) => {
+                failure.get_or_insert(e);
+            }
+            (_, other) => return Err(unexpected(other)),
+        }
+        Ok(())
+    }
+
+    /// Receives response of batched request and puts it in its slot.
+    fn receive_into(&mut self, first_id: u64, responses: &mut [Option<Response>]) -> Result<()> {
+        let (id, response) = self.receive()?;
+        let slot = id
+            .checked_sub(first_id)
+            .and_then(|idx| responses.get_mut(idx as usize))
+            .filter(|slot| slot.is_none())
+            .ok_or_else(|| KvsError::Protocol(format!("unexpected response id {}", id)))?;
+        *slot = Some(response);
+        Ok(())
+    }
+
+    /// Sends single command and waits for its response, following leader
+    /// redirects and waiting out elections.
+    fn request(&mut self, cmd: CMD) -> Result<Response> {
+        let mut attempts = 0;
+        loop {
+            let id = self.send(cmd.clone())?;
+            let (response_id, response) = self.receive()?;
+
+            if response_id != id {
+                return Err(KvsError::Protocol(format!(
+                    "expected response for request {}, got {}",
+                    id, response_id
+                ))
+                .into());
+            }
+
+            match response {
+                Response::Err(ResponseError::NotLeader { leader })
+                    if attempts < MAX_REDIRECTS && self.redirect.is_some() =>
+                {
+                    attempts += 1;
+                    match leader {
+                        Some(leader) => self.reconnect(&leader)?,
+                        None => thread::sleep(ELECTION_WAIT),
+                    }
+                }
+                Response::Err(e) => return Err(KvsError::from(e).into()),
+                response => return Ok(response),
+            }
+        }
+    }
+
+    /// Replaces connection with one to the leader.
+    fn reconnect(&mut self, leader: &str) -> Result<()> {
+        debug!("redirected to leader {}", leader);
+        let leader: Address = leader.parse()?;
+        let stream = match &self.redirect {
+            Some(Redirect::Tls {
+                server_name,
+                config,
+            }) => Stream::connect_tls_to(&leader, server_name, config.clone())?,
+            _ => Stream::connect_to(&leader)?,
+        };
+        let redirect = self.redirect.take();
+        *self = Self::handshake(stream, self.codec, &self.credentials)?;
+        self.redirect = redirect;
+        Ok(())
+    }
+}
+
+/// Upper bound of redirects and retries of a single request.
+pub(crate) const MAX_REDIRECTS: usize = 20;
+
+/// How long to wait before retrying when cluster has no leader.
+pub(crate) const ELECTION_WAIT: Duration = Duration::from_millis(100);
+
+/// Maximum number of pipelined requests waiting for response.
+pub(crate) const MAX_IN_FLIGHT: usize = 128;
+
+/// Pending requests are written out once they reach that size.
+const PENDING_LIMIT: usize = 8 * 1024;
+
+impl From<ResponseError> for KvsError {
+    fn from(e: ResponseError) -> Self {
+        match e {
+            ResponseError::KeyNotFound => KvsError::KeyNotFound,
+            ResponseError::PermissionDenied(e) => KvsError::PermissionDenied(e),
+            ResponseError::ReadOnly => KvsError::ReadOnly,
+            ResponseError::Internal(e) => KvsError::Server(e),
+            ResponseError::NotLeader { leader } => KvsError::NotLeader(leader),
+            ResponseError::Timeout => KvsError::Timeout,
+            ResponseError::KeyTooLarge { size, max } => KvsError::KeyTooLarge { size, max },
+            ResponseError::ValueTooLarge { size, max } => KvsError::ValueTooLarge { size, max },
+            ResponseError::FrameTooLarge { size, max } => KvsError::FrameTooLarge { size, max },
+        }
+    }
+}
+
+/// Error for response that does not match sent command.
+pub(crate) fn unexpected(response: Response) -> anyhow::Error {
+    KvsError::Protocol(format!("unexpected response: {:?}", response)).into()
 }
@@ -1,13 +1,107 @@
 use std::fmt::Display;
 
+use crate::raft::MembershipChange;
+use crate::replication::{LogBatch, LogPosition};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 /// Shared command between client and server, use for simpler communication.
+#[allow(clippy::upper_case_acronyms)]
 pub enum CMD {
-    Set { key: String, value: String },
-    Get { key: String },
-    Rm { key: String },
+    Set {
+        key: String,
+        value: String,
+    },
+    Get {
+        key: String,
+    },
+    Rm {
+        key: String,
+    },
+    /// Asks leader for log records after the position, sent by followers.
+    /// Followers without position ask for a snapshot.
+    Replicate {
+        from: Option<LogPosition>,
+    },
+    /// Asks leader for the part of snapshot taken at `at` that follows key
+    /// `after`, sent by followers bootstrapping from it.
+    ReplicateSnapshot {
+        at: LogPosition,
+        after: String,
+    },
+    /// Reads local value of a cluster node without asking the leader.
+    StaleGet {
+        key: String,
+    },
+    /// Adds or removes Raft cluster member, sent to the leader.
+    ChangeMembership {
+        change: MembershipChange,
+    },
+    /// Lists keys starting with the prefix.
+    Scan {
+        prefix: String,
+    },
+    /// Appends chunk to the value uploaded over the connection, `last`
+    /// chunk sets the key. Every chunk is answered with `Response::Ok` or
+    /// error of the whole upload.
+    SetChunk {
+        key: String,
+        chunk: String,
+        last: bool,
+    },
+    /// Discards unfinished upload of the key, sent when client can't read
+    /// the rest of the value.
+    AbortUpload {
+        key: String,
+    },
+    /// Gets value as `Response::Chunk`s sharing request id, or
+    /// `Response::Value(None)` when the key does not exist.
+    StreamGet {
+        key: String,
+    },
+    /// Sets the value only if the current one equals `expected`, `None`
+    /// meaning the key must not exist. Answered with `Response::Swapped`.
+    CompareAndSet {
+        key: String,
+        expected: Option<String>,
+        value: String,
+    },
+    /// Removes the key only if its value equals `expected`. Answered with
+    /// `Response::Swapped`.
+    CompareAndRemove {
+        key: String,
+        expected: String,
+    },
+    /// Lists at most `limit` keys starting with the prefix that follow
+    /// `after`, for listing keys in pages.
+    ScanPage {
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    },
+}
+
+impl CMD {
+    /// Key that command operates on, pipelined commands for the same key
+    /// are executed in order.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            CMD::Set { key, .. }
+            | CMD::Get { key }
+            | CMD::Rm { key }
+            | CMD::StaleGet { key }
+            | CMD::SetChunk { key, .. }
+            | CMD::AbortUpload { key }
+            | CMD::StreamGet { key }
+            | CMD::CompareAndSet { key, .. }
+            | CMD::CompareAndRemove { key, .. } => Some(key),
+            CMD::Replicate { .. }
+            | CMD::ReplicateSnapshot { .. }
+            | CMD::ChangeMembership { .. }
+            | CMD::Scan { .. }
+            | CMD::ScanPage { .. } => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -36,3 +130,80 @@ pub enum RemoveResponse {
     Ok(()),
     Err(String),
 }
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Single response type of the framed protocol, answers every `CMD`.
+pub enum Response {
+    /// Command succeeded and has nothing to return.
+    Ok,
+    /// Result of `CMD::Get`, `None` when the key does not exist.
+    Value(Option<String>),
+    /// Result of `CMD::Replicate` and `CMD::ReplicateSnapshot`.
+    Log(LogBatch),
+    /// Result of `CMD::Scan` and `CMD::ScanPage`.
+    Keys(Vec<String>),
+    /// Part of value streamed for `CMD::StreamGet`.
+    Chunk { data: String, last: bool },
+    /// Whether `CMD::CompareAndSet` or `CMD::CompareAndRemove` changed the key.
+    Swapped(bool),
+    /// Command failed on the server side.
+    Err(ResponseError),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Errors that server reports back to the client.
+pub enum ResponseError {
+    /// Key was not found during removal.
+    KeyNotFound,
+    /// Client is not allowed to run the command.
+    PermissionDenied(String),
+    /// Write was sent to a read-only replica.
+    ReadOnly,
+    /// Any other engine or server error, carries its message.
+    Internal(String),
+    /// Raft node is not the leader, client should retry at `leader`.
+    NotLeader {
+        leader: Option<String>,
+    },
+    /// Request was not processed in time, it may or may not have been
+    /// applied.
+    Timeout,
+    KeyTooLarge {
+        size: u64,
+        max: u64,
+    },
+    ValueTooLarge {
+        size: u64,
+        max: u64,
+    },
+    /// Request frame was over the limit and skipped unread.
+    FrameTooLarge {
+        size: u64,
+        max: u64,
+    },
+}
+
+impl Display for ResponseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResponseError::KeyNotFound => f.write_str("Key not found"),
+            ResponseError::PermissionDenied(e) => write!(f, "Permission denied: {}", e),
+            ResponseError::ReadOnly => f.write_str("Server is a read-only replica"),
+            ResponseError::Internal(e) => f.write_str(e),
+            ResponseError::NotLeader { leader } => match leader {
+                Some(leader) => write!(f, "Not the cluster leader, leader is {}", leader),
+                None => f.write_str("Not the cluster leader"),
+            },
+            ResponseError::Timeout => f.write_str("Request timed out"),
+            ResponseError::KeyTooLarge { size, max } => {
+                write!(f, "Key of {} bytes exceeds limit of {}", size, max)
+            }
+            ResponseError::ValueTooLarge { size, max } => {
+                write!(f, "Value of {} bytes exceeds limit of {}", size, max)
+            }
+            ResponseError::FrameTooLarge { size, max } => {
+                write!(f, "Request of {} bytes exceeds limit of {}", size, max)
+            }
+        }
+    }
+}
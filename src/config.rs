@@ -0,0 +1,257 @@
+//! Configuration of `kvs-server`.
+//!
+//! Settings are read from TOML file given with `--config`, then overridden
+//! by `KVS_*` environment variables and command line flags, see `ServerCLI`.
+//! Settings set nowhere keep their defaults.
+//!
+//! Data directory holds engine data together with `meta.json`, which records
+//! the engine it was created with and version of the layout.
+
+use crate::engines::kv::StoreOptions;
+use crate::error::KvsError;
+use crate::limits::Limits;
+use crate::server::{EngineType, Runtime};
+use crate::thread_pool::QueuePolicy;
+use crate::transport::Address;
+use crate::Result;
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+
+/// Metadata file of the data directory.
+const METADATA_FILE: &str = "meta.json";
+
+/// Engine marker written to working directory by older versions.
+const LEGACY_CONF_FILE: &str = "conf";
+
+/// Version of data directory layout written by this build.
+pub const METADATA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// Effective settings of the server.
+pub struct Config {
+    /// Addresses of framed protocol listeners.
+    pub addr: Vec<Address>,
+    /// Addresses of Redis RESP2 listeners.
+    pub resp_addr: Vec<Address>,
+    /// Addresses of HTTP/JSON listeners.
+    pub http_addr: Vec<Address>,
+    /// Directory with engine data, Raft state and metadata.
+    pub data_dir: PathBuf,
+    pub engine: EngineType,
+    pub runtime: Runtime,
+    /// JSON file with users and their grants, enables authentication.
+    pub auth: Option<PathBuf>,
+    pub thread_pool: ThreadPoolConfig,
+    /// Settings of kvs engine, sled flushes every write.
+    pub storage: StoreOptions,
+    pub limits: Limits,
+    /// Enables TLS on every listener.
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            addr: vec!["127.0.0.1:4000".parse().expect("default address is valid")],
+            resp_addr: vec![],
+            http_addr: vec![],
+            data_dir: PathBuf::from("."),
+            engine: EngineType::Kvs,
+            runtime: Runtime::Threads,
+            auth: None,
+            thread_pool: ThreadPoolConfig::default(),
+            storage: StoreOptions::default(),
+            limits: Limits::default(),
+            tls: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+/// Thread pool serving connections of the threaded runtime.
+pub enum ThreadPoolKind {
+    /// Thread per job.
+    #[default]
+    Naive,
+    SharedQueue,
+    Rayon,
+    WorkStealing,
+}
+
+impl Display for ThreadPoolKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ThreadPoolKind::Naive => "naive",
+            ThreadPoolKind::SharedQueue => "shared-queue",
+            ThreadPoolKind::Rayon => "rayon",
+            ThreadPoolKind::WorkStealing => "work-stealing",
+        })
+    }
+}
+
+impl FromStr for ThreadPoolKind {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> std::result::Result<ThreadPoolKind, KvsError> {
+        match s.to_lowercase().as_str() {
+            "naive" => Ok(Self::Naive),
+            "shared-queue" => Ok(Self::SharedQueue),
+            "rayon" => Ok(Self::Rayon),
+            "work-stealing" => Ok(Self::WorkStealing),
+            _ => Err(KvsError::Parse),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThreadPoolConfig {
+    pub kind: ThreadPoolKind,
+    /// Threads of the pool, defaults to number of CPUs.
+    pub threads: usize,
+    /// Connections waiting for a thread of shared-queue pool, unbounded
+    /// when not set.
+    pub queue_size: Option<usize>,
+    /// What happens to connections accepted while the queue is full.
+    pub queue_policy: QueuePolicy,
+}
+
+impl Default for ThreadPoolConfig {
+    fn default() -> Self {
+        Self {
+            kind: ThreadPoolKind::default(),
+            threads: thread::available_parallelism().map_or(4, |n| n.get()),
+            queue_size: None,
+            queue_policy: QueuePolicy::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain.
+    pub cert: PathBuf,
+    /// PEM private key matching `cert`.
+    pub key: PathBuf,
+    /// PEM CA certificates, requires clients to authenticate with
+    /// certificate signed by them.
+    pub client_ca: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+/// Contents of `meta.json`.
+struct Metadata {
+    version: u32,
+    engine: EngineType,
+}
+
+impl Config {
+    /// Reads configuration from TOML file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("could not read config {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("invalid config {}", path.display()))
+    }
+
+    /// Formats configuration as TOML accepted by `load`.
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+
+    /// Checks that data directory was created with configured engine and by
+    /// compatible version, marking new directories. Engine marker of older
+    /// versions is moved into metadata.
+    pub fn check_data_dir(&self) -> Result<()> {
+        if let Some(engine) = data_dir_engine(&self.data_dir)? {
+            if engine != self.engine {
+                bail!(
+                    "Invalid configuration: data directory {} holds {} engine, not {}, \
+                     use `kvs-admin migrate` to move the data",
+                    self.data_dir.display(),
+                    engine,
+                    self.engine
+                );
+            }
+        }
+        if !self.data_dir.join(METADATA_FILE).exists() {
+            mark_data_dir(&self.data_dir, self.engine)?;
+        }
+        Ok(())
+    }
+}
+
+/// Directory of engine data inside the data directory.
+pub(crate) fn engine_dir(data_dir: &Path, engine: EngineType) -> PathBuf {
+    data_dir.join(match engine {
+        EngineType::Kvs => "kv",
+        EngineType::Sled => "sled",
+    })
+}
+
+/// KvStore directory given either directly or as the data directory
+/// holding one.
+pub(crate) fn kv_store_dir(dir: &Path) -> PathBuf {
+    let kv_dir = engine_dir(dir, EngineType::Kvs);
+    if kv_dir.is_dir() {
+        kv_dir
+    } else {
+        dir.to_owned()
+    }
+}
+
+/// Engine recorded in the data directory, None for new directories.
+pub(crate) fn data_dir_engine(data_dir: &Path) -> Result<Option<EngineType>> {
+    let path = data_dir.join(METADATA_FILE);
+    let legacy = data_dir.join(LEGACY_CONF_FILE);
+
+    if path.exists() {
+        let metadata: Metadata = serde_json::from_slice(&fs::read(&path)?)
+            .with_context(|| format!("invalid metadata {}", path.display()))?;
+        if metadata.version > METADATA_VERSION {
+            bail!(
+                "data directory {} has version {}, newest supported is {}",
+                data_dir.display(),
+                metadata.version,
+                METADATA_VERSION
+            );
+        }
+        Ok(Some(metadata.engine))
+    } else if legacy.exists() {
+        // marker holds engine as json string, it's empty when older
+        // version failed right after creating it.
+        match fs::read_to_string(&legacy)?.trim().trim_matches('"') {
+            "" => Ok(None),
+            engine => Ok(Some(engine.parse()?)),
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+/// Records engine of the data directory, replacing marker of older versions.
+pub(crate) fn mark_data_dir(data_dir: &Path, engine: EngineType) -> Result<()> {
+    fs::create_dir_all(data_dir)?;
+    let metadata = Metadata {
+        version: METADATA_VERSION,
+        engine,
+    };
+    // written aside and renamed, so marker is never half written.
+    let tmp = data_dir.join(format!("{}.tmp", METADATA_FILE));
+    fs::write(&tmp, serde_json::to_vec_pretty(&metadata)?)?;
+    fs::rename(tmp, data_dir.join(METADATA_FILE))?;
+
+    let legacy = data_dir.join(LEGACY_CONF_FILE);
+    if legacy.exists() {
+        fs::remove_file(legacy)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,243 @@
+//! Inspection of `KvStore` logs, run by `kvs-dump`.
+
+use crate::config::kv_store_dir;
+use crate::engines::kv::{generations, replay, CommandPos, LogEntries, LogEntry};
+use crate::replication::LogRecord;
+use crate::Result;
+use clap::{Parser, Subcommand};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+/// Cli for inspecting logs of KvStore data directories.
+pub struct DumpCLI {
+    /// KvStore directory, or server data directory holding one.
+    #[clap(value_parser)]
+    dir: PathBuf,
+    /// Print one JSON object per line.
+    #[clap(long, global = true)]
+    json: bool,
+    #[clap(subcommand)]
+    command: DumpCommands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DumpCommands {
+    /// Lists generations with their sizes and record counts.
+    Generations,
+    /// Prints records with their place in the log and whether they are
+    /// still live in the index rebuilt from the log.
+    Records {
+        /// Print only records of this key.
+        #[clap(long)]
+        key: Option<String>,
+        /// Print only records of this generation.
+        #[clap(long)]
+        gen: Option<u64>,
+        /// Print values of Set records too.
+        #[clap(long)]
+        values: bool,
+    },
+}
+
+#[derive(Debug, Serialize)]
+/// Line of `kvs-dump generations`.
+struct GenerationInfo {
+    gen: u64,
+    bytes: u64,
+    records: u64,
+    sets: u64,
+    removes: u64,
+    live: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+/// Whether record is still needed, stale ones are dropped by compaction.
+enum Status {
+    Live,
+    Stale,
+    /// Index could not be rebuilt.
+    Unknown,
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!("{:?}", self).to_lowercase())
+    }
+}
+
+#[derive(Debug, Serialize)]
+/// Line of `kvs-dump records`.
+struct RecordInfo<'a> {
+    gen: u64,
+    offset: u64,
+    len: u64,
+    op: &'static str,
+    key: &'a str,
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<&'a str>,
+}
+
+impl DumpCLI {
+    pub fn run(&self) -> Result<()> {
+        let dir = kv_store_dir(&self.dir);
+        let dump = Dump {
+            gens: generations(&dir)?,
+            dir,
+            json: self.json,
+        };
+
+        match &self.command {
+            DumpCommands::Generations => dump.generations(),
+            DumpCommands::Records { key, gen, values } => {
+                dump.records(key.as_deref(), *gen, *values)
+            }
+        }
+    }
+}
+
+/// Logs of a single KvStore directory.
+struct Dump {
+    dir: PathBuf,
+    gens: Vec<u64>,
+    json: bool,
+}
+
+impl Dump {
+    /// Rebuilds the index the same way `KvStore::open` does, None when
+    /// the log can't be replayed.
+    fn index(&self) -> Option<BTreeMap<String, CommandPos>> {
+        match replay(&self.dir, &self.gens, |_| {}) {
+            Ok(index) => Some(index),
+            Err(e) => {
+                eprintln!("warning: could not rebuild index: {:#}", e);
+                None
+            }
+        }
+    }
+
+    fn generations(&self) -> Result<()> {
+        let index = self.index();
+        if !self.json {
+            println!("gen\tbytes\trecords\tsets\tremoves\tlive");
+        }
+
+        for gen in &self.gens {
+            let mut info = GenerationInfo {
+                gen: *gen,
+                bytes: fs::metadata(self.dir.join(format!("{}.log", gen)))?.len(),
+                records: 0,
+                sets: 0,
+                removes: 0,
+                live: 0,
+                error: None,
+            };
+            for entry in LogEntries::open(&self.dir, *gen)? {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        info.error = Some(format!("{:#}", e));
+                        break;
+                    }
+                };
+                info.records += 1;
+                match &entry.record {
+                    LogRecord::Set { .. } => info.sets += 1,
+                    LogRecord::Rm { .. } => info.removes += 1,
+                }
+                if let Some(Status::Live) = index.as_ref().map(|index| status(index, &entry)) {
+                    info.live += 1;
+                }
+            }
+
+            if self.json {
+                println!("{}", serde_json::to_string(&info)?);
+            } else {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    info.gen, info.bytes, info.records, info.sets, info.removes, info.live
+                );
+                if let Some(error) = &info.error {
+                    eprintln!("error: {}", error);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn records(&self, key: Option<&str>, gen: Option<u64>, values: bool) -> Result<()> {
+        let index = self.index();
+        if !self.json {
+            println!("gen\toffset\tlen\tstatus\top\tkey");
+        }
+
+        for g in self
+            .gens
+            .iter()
+            .filter(|g| gen.is_none() || gen == Some(**g))
+        {
+            for entry in LogEntries::open(&self.dir, *g)? {
+                let entry = entry?;
+                let (op, record_key, value) = match &entry.record {
+                    LogRecord::Set { key, value } => ("set", key, Some(value.as_str())),
+                    LogRecord::Rm { key } => ("rm", key, None),
+                };
+                if key.is_some_and(|key| key != record_key) {
+                    continue;
+                }
+
+                let info = RecordInfo {
+                    gen: entry.gen,
+                    offset: entry.pos,
+                    len: entry.len,
+                    op,
+                    key: record_key,
+                    status: index
+                        .as_ref()
+                        .map_or(Status::Unknown, |index| status(index, &entry)),
+                    value: value.filter(|_| values),
+                };
+                if self.json {
+                    println!("{}", serde_json::to_string(&info)?);
+                } else {
+                    print!(
+                        "{}\t{}\t{}\t{}\t{}\t{:?}",
+                        info.gen, info.offset, info.len, info.status, info.op, info.key
+                    );
+                    match info.value {
+                        Some(value) => println!("\t{:?}", value),
+                        None => println!(),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Set record is live while the index points at it, Rm records are never
+/// needed once replayed.
+fn status(index: &BTreeMap<String, CommandPos>, entry: &LogEntry) -> Status {
+    let key = match &entry.record {
+        LogRecord::Set { key, .. } => key,
+        LogRecord::Rm { .. } => return Status::Stale,
+    };
+    let pos = CommandPos {
+        gen: entry.gen,
+        pos: entry.pos,
+        len: entry.len,
+    };
+    if index.get(key) == Some(&pos) {
+        Status::Live
+    } else {
+        Status::Stale
+    }
+}
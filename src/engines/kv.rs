@@ -1,33 +1,159 @@
+use super::stream::{self, EscapingWriter};
+use super::ValueWriter;
 use crate::error::{KvsError, Result};
 use crate::reader::{BufReaderWithPos, BufWriterWithPos};
+use crate::replication::{LogBatch, LogPosition, LogRecord};
 use crate::KvsEngine;
 use anyhow::{bail, Context};
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
+use serde_json::de::IoRead;
+use serde_json::{Deserializer, StreamDeserializer};
 use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fmt::Display;
 use std::fs::{self, File, OpenOptions};
-use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
-use std::{collections::HashMap, path::PathBuf};
+use thiserror::Error;
 
-/// Compaction process will be started after reaching this many entries.
+/// Compaction process will be started after reaching this many entries,
+/// unless configured otherwise.
 const CAPACITY: u64 = 1000;
 
+/// Numbers spool files of values written in parts.
+static UPLOADS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// When writes reach the disk.
+pub enum Durability {
+    /// Every write is handed to the OS, it survives crash of the process.
+    #[default]
+    Flush,
+    /// Every write is synced to the disk, it survives power loss too.
+    Sync,
+}
+
+impl Display for Durability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!("{:?}", self).to_lowercase())
+    }
+}
+
+impl FromStr for Durability {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> std::result::Result<Durability, KvsError> {
+        match s.to_lowercase().as_str() {
+            "flush" => Ok(Self::Flush),
+            "sync" => Ok(Self::Sync),
+            _ => Err(KvsError::Parse),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// Settings of `KvStore`.
+pub struct StoreOptions {
+    pub durability: Durability,
+    /// Records written since the last compaction that start the next one.
+    pub compaction_threshold: u64,
+}
+
+impl Default for StoreOptions {
+    fn default() -> Self {
+        Self {
+            durability: Durability::default(),
+            compaction_threshold: CAPACITY,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
+/// Record of the log. `crc` is checksum of the rest of the record, records
+/// written before checksums were introduced have none and aren't verified.
 enum Command {
-    Set { key: String, value: String },
-    Rm { key: String },
+    Set {
+        key: String,
+        value: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        crc: Option<u32>,
+    },
+    Rm {
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        crc: Option<u32>,
+    },
 }
 
-#[derive(Debug)]
+impl Command {
+    fn set(key: String, value: String) -> Self {
+        let mut hasher = record_hasher(&key);
+        hasher.update(value.as_bytes());
+        Command::Set {
+            key,
+            value,
+            crc: Some(hasher.finalize()),
+        }
+    }
+
+    fn rm(key: String) -> Self {
+        let crc = Some(record_hasher(&key).finalize());
+        Command::Rm { key, crc }
+    }
+
+    /// Whether the record matches its checksum, if it has one.
+    fn verify(&self) -> bool {
+        let (key, value, crc) = match self {
+            Command::Set { key, value, crc } => (key, value.as_str(), crc),
+            Command::Rm { key, crc } => (key, "", crc),
+        };
+        crc.is_none_or(|crc| {
+            let mut hasher = record_hasher(key);
+            hasher.update(value.as_bytes());
+            hasher.finalize() == crc
+        })
+    }
+
+    fn into_record(self) -> LogRecord {
+        match self {
+            Command::Set { key, value, .. } => LogRecord::Set { key, value },
+            Command::Rm { key, .. } => LogRecord::Rm { key },
+        }
+    }
+}
+
+/// Checksum of record of the key, value bytes follow if it has one.
+fn record_hasher(key: &str) -> crc32fast::Hasher {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&(key.len() as u64).to_le_bytes());
+    hasher.update(key.as_bytes());
+    hasher
+}
+
+#[derive(Debug, Error)]
+#[error("checksum mismatch of record in generation {gen} at offset {pos}")]
+/// Record decodes fine but its content is not the one written.
+pub(crate) struct ChecksumMismatch {
+    pub gen: u64,
+    pub pos: u64,
+    pub len: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
 /// Represents the position and length of a json-serialized command in the log.d
-struct CommandPos {
+pub(crate) struct CommandPos {
     /// We will 'go' to another generation when we reach CAPACITY limit thus
     /// we need to track generation here.
-    gen: u64,
-    pos: u64,
-    len: u64,
+    pub gen: u64,
+    pub pos: u64,
+    pub len: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -45,6 +171,12 @@ pub struct KvStore {
 
     /// Tracks amount of already written data to latest generation file.
     uncompacted: Arc<RwLock<u64>>,
+
+    /// Held while compacting, concurrent compactions would copy entries
+    /// from compaction file that is not flushed yet.
+    compaction: Arc<Mutex<()>>,
+
+    options: StoreOptions,
     // Thread pool responsible for multi-threaded functionalities.
     // thread_pool: TP,
 }
@@ -53,8 +185,14 @@ unsafe impl Send for KvStore {}
 
 impl KvStore {
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with(path, StoreOptions::default())
+    }
+
+    /// Opens the store with given durability and compaction settings.
+    pub fn open_with(path: impl Into<PathBuf>, options: StoreOptions) -> Result<Self> {
         let path: PathBuf = path.into();
         std::fs::create_dir_all(&path)?;
+        remove_uploads(&path)?;
 
         // read already created generation files.
         let mut readers = open_generation_readers(&path)?;
@@ -93,13 +231,14 @@ impl KvStore {
             writer: Arc::new(RwLock::new(BufWriterWithPos::new(
                 OpenOptions::new()
                     .create(true)
-                    .write(true)
                     .append(true)
                     .open(path.join(format!("{}.log", generation)))?,
             )?)),
             index: Arc::default(),
             current_gen: Arc::new(RwLock::new(generation)),
             uncompacted: Arc::default(), // will be set in read_generation_data method.
+            compaction: Arc::default(),
+            options,
         };
 
         // read all data from all readers.
@@ -116,45 +255,25 @@ impl KvStore {
 
     /// Goes through all existing generation data and reads their content and Command.
     fn read_generation_data(&mut self) -> Result<()> {
+        let current_gen = *self.current_gen.read().unwrap();
+        let gens: Vec<u64> = self
+            .readers
+            .read()
+            .unwrap()
+            .keys()
+            .copied()
+            .sorted()
+            .collect();
+
         // size of latest generation.
         let mut size = 0;
-
-        for gen in self.readers.clone().read().unwrap().keys().sorted() {
-            let mut reader = BufReader::new(File::open(self.gen_path(*gen))?);
-            let mut pos = reader.seek(SeekFrom::Start(0))?;
-            let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-
-            while let Some(cmd) = stream.next() {
-                let new_pos = stream.byte_offset() as u64;
-
-                debug!("gen:{}, cmd: {:?}", gen, cmd);
-
-                match cmd? {
-                    Command::Set { key, .. } => {
-                        if *gen == self.current_gen.read().unwrap().to_owned() {
-                            size += 1
-                        }
-
-                        self.index.write().unwrap().insert(
-                            key,
-                            CommandPos {
-                                pos,
-                                len: new_pos - pos,
-                                gen: *gen,
-                            },
-                        );
-                    }
-                    Command::Rm { key } => {
-                        self.index
-                            .write()
-                            .unwrap()
-                            .remove(&key)
-                            .ok_or(KvsError::KeyNotFound)?;
-                    }
-                }
-                pos = new_pos;
+        let index = replay(&self.path, &gens, |entry| {
+            if entry.gen == current_gen && matches!(entry.record, LogRecord::Set { .. }) {
+                size += 1;
             }
-        }
+        })?;
+
+        *self.index.write().unwrap() = index;
         *self.uncompacted.write().unwrap() = size;
         Ok(())
     }
@@ -166,17 +285,28 @@ impl KvStore {
     /// Takes values from latest generation and writes compacted version of it to another file.
     fn compact(&self) -> Result<()> {
         // increase current gen by 2. current_gen + 1 is for the compaction file.
-        let compaction_gen = self.current_gen.read().unwrap().to_owned() + 1;
-        *self.current_gen.write().unwrap() += 2;
-
-        *self.writer.write().unwrap() =
-            self.new_log_file(self.current_gen.read().unwrap().to_owned())?;
+        // generation is switched under writer lock, so that writer position
+        // always belongs to current generation.
+        // index is locked before the writer is released, so that every record
+        // of the new generation follows the copies in replay. Otherwise a key
+        // removed meanwhile would be missing from the copies while its Rm
+        // record stays in the log. Index stays locked until the copies are
+        // flushed, readers must not follow new positions before that.
+        let (compaction_gen, mut index) = {
+            let mut writer = self.writer.write().unwrap();
+            let mut current_gen = self.current_gen.write().unwrap();
+            let compaction_gen = *current_gen + 1;
+            *current_gen += 2;
+            *writer = self.new_log_file(*current_gen)?;
+            drop(current_gen);
+            (compaction_gen, self.index.write().unwrap())
+        };
 
         let mut compaction_writer = self.new_log_file(compaction_gen)?;
 
         let mut new_pos = 0; // pos in the new log file.
 
-        for cmd_pos in self.index.write().unwrap().values_mut() {
+        for cmd_pos in index.values_mut() {
             let mut readers = self.readers.write().unwrap();
             let reader = readers
                 .get_mut(&cmd_pos.gen)
@@ -195,7 +325,8 @@ impl KvStore {
             };
             new_pos += len;
         }
-        compaction_writer.flush()?;
+        self.persist(&mut compaction_writer)?;
+        drop(index);
 
         // remove stale log files.
         let stale_gens: Vec<_> = self
@@ -216,44 +347,44 @@ impl KvStore {
         Ok(())
     }
 
-    fn new_log_file(&self, gen: u64) -> Result<BufWriterWithPos<File>> {
-        let path = self.gen_path(gen);
-        let writer = BufWriterWithPos::new(
-            OpenOptions::new()
-                .create(true)
-                .write(true)
-                .append(true)
-                .open(&path)?,
-        )?;
-        self.readers
-            .write()
-            .unwrap()
-            .insert(gen, BufReaderWithPos::new(File::open(path)?, 0)?);
-        Ok(writer)
+    /// Starts compaction once enough entries were written to current generation.
+    fn compact_if_needed(&self) -> Result<()> {
+        let threshold = self.options.compaction_threshold;
+        if self.uncompacted.read().unwrap().ge(&threshold) {
+            let _compaction = self.compaction.lock().unwrap();
+            // another writer may have compacted while we waited.
+            if self.uncompacted.read().unwrap().ge(&threshold) {
+                self.compact()?;
+            }
+        }
+        Ok(())
     }
-}
 
-impl KvsEngine for KvStore {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        if self.uncompacted.read().unwrap().ge(&CAPACITY) {
-            self.compact()?;
-        }
+    /// Appends Set command to the log and points index at it.
+    fn append_set(
+        &self,
+        writer: &mut BufWriterWithPos<File>,
+        key: String,
+        value: String,
+    ) -> Result<()> {
+        let record = serde_json::to_vec(&Command::set(key.clone(), value))?;
+        self.append_record(writer, key, record.as_slice())
+    }
 
-        let mut writer = self.writer.write().unwrap();
-        let mut index = self.index.write().unwrap();
+    /// Appends serialized Set command of the key to the log and points index at it.
+    fn append_record(
+        &self,
+        writer: &mut BufWriterWithPos<File>,
+        key: String,
+        mut record: impl Read,
+    ) -> Result<()> {
+        // writers are serialized by the writer lock, readers keep seeing the
+        // previous value until the record is persisted.
         let pos = writer.pos;
+        io::copy(&mut record, writer)?;
+        self.persist(writer)?;
 
-        write!(
-            writer,
-            "{}",
-            serde_json::to_string(&Command::Set {
-                key: key.clone(),
-                value
-            })?
-        )?;
-        writer.flush()?;
-
-        index.insert(
+        self.index.write().unwrap().insert(
             key,
             CommandPos {
                 gen: self.current_gen.read().unwrap().to_owned(),
@@ -266,6 +397,88 @@ impl KvsEngine for KvStore {
         Ok(())
     }
 
+    /// Flushes written records, syncing them when durability requires it.
+    fn persist(&self, writer: &mut BufWriterWithPos<File>) -> Result<()> {
+        writer.flush()?;
+        if self.options.durability == Durability::Sync {
+            writer.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// End of the log.
+    fn head(&self) -> LogPosition {
+        let writer = self.writer.read().unwrap();
+        LogPosition {
+            gen: *self.current_gen.read().unwrap(),
+            pos: writer.pos,
+        }
+    }
+
+    /// Values of keys following `after`, around `max_bytes` of them. Values
+    /// are read without blocking writes, so they may be newer than `at`;
+    /// follower replays the log from `at` over them, which makes them
+    /// consistent again.
+    fn snapshot(
+        &self,
+        at: LogPosition,
+        mut after: Option<String>,
+        max_bytes: u64,
+    ) -> Result<LogBatch> {
+        loop {
+            let (keys, done) = {
+                let index = self.index.read().unwrap();
+                let start = after.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+                let mut range = index.range::<str, _>((start, Bound::Unbounded)).peekable();
+                let mut keys = vec![];
+                let mut size = 0;
+                while let Some((key, cmd_pos)) = range.next_if(|_| size < max_bytes) {
+                    size += cmd_pos.len;
+                    keys.push(key.clone());
+                }
+                (keys, range.peek().is_none())
+            };
+
+            let last = keys.last().cloned();
+            let mut entries = Vec::with_capacity(keys.len());
+            for key in keys {
+                // removed meanwhile, follower replays the removal.
+                if let Some(value) = self.get(key.clone())? {
+                    entries.push((key, value));
+                }
+            }
+            // follower continues after the last entry, so part can't be empty.
+            if done || !entries.is_empty() {
+                return Ok(LogBatch::Snapshot {
+                    entries,
+                    next: at,
+                    done,
+                });
+            }
+            after = last;
+        }
+    }
+
+    fn new_log_file(&self, gen: u64) -> Result<BufWriterWithPos<File>> {
+        let path = self.gen_path(gen);
+        let writer =
+            BufWriterWithPos::new(OpenOptions::new().create(true).append(true).open(&path)?)?;
+        self.readers
+            .write()
+            .unwrap()
+            .insert(gen, BufReaderWithPos::new(File::open(path)?, 0)?);
+        Ok(writer)
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.compact_if_needed()?;
+
+        let mut writer = self.writer.write().unwrap();
+        self.append_set(&mut writer, key, value)
+    }
+
     fn get(&self, key: String) -> Result<Option<String>> {
         match self.index.clone().read().unwrap().get(&key) {
             Some(cmd_pos) => {
@@ -278,12 +491,19 @@ impl KvsEngine for KvStore {
                 let mut content = String::new();
                 reader.take(cmd_pos.len).read_to_string(&mut content)?;
 
-                if let Command::Set { value, .. } =
-                    serde_json::from_str(&content).context(format!(
-                        "could not decode from reader, content: {} cmd_pos: {:?}",
-                        &content, cmd_pos,
-                    ))?
-                {
+                let cmd: Command = serde_json::from_str(&content).context(format!(
+                    "could not decode from reader, content: {} cmd_pos: {:?}",
+                    &content, cmd_pos,
+                ))?;
+                if !cmd.verify() {
+                    return Err(ChecksumMismatch {
+                        gen: cmd_pos.gen,
+                        pos: cmd_pos.pos,
+                        len: cmd_pos.len,
+                    }
+                    .into());
+                }
+                if let Command::Set { value, .. } = cmd {
                     return Ok(Some(value));
                 }
                 bail!("Key not found")
@@ -293,42 +513,383 @@ impl KvsEngine for KvStore {
     }
 
     fn remove(&self, key: String) -> Result<()> {
+        // writer is taken before the index is updated, so that no Set of the
+        // key can land between the update and the Rm record.
+        let mut writer = self.writer.write().unwrap();
         if self.index.write().unwrap().remove(&key).is_none() {
             return Err(KvsError::KeyNotFound.into());
         }
+        write!(writer, "{}", serde_json::to_string(&Command::rm(key))?)?;
+        self.persist(&mut writer)
+    }
+
+    fn compare_and_set(
+        &self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<bool> {
+        self.compact_if_needed()?;
 
+        // holding the writer makes check and write atomic against other writes.
         let mut writer = self.writer.write().unwrap();
-        write!(writer, "{}", serde_json::to_string(&Command::Rm { key })?)?;
-        writer.flush()?;
-        Ok(())
+        if self.get(key.clone())? != expected {
+            return Ok(false);
+        }
+        self.append_set(&mut writer, key, value)?;
+        Ok(true)
+    }
+
+    fn compare_and_remove(&self, key: String, expected: String) -> Result<bool> {
+        let mut writer = self.writer.write().unwrap();
+        if self.get(key.clone())?.as_ref() != Some(&expected) {
+            return Ok(false);
+        }
+        self.index.write().unwrap().remove(&key);
+        write!(writer, "{}", serde_json::to_string(&Command::rm(key))?)?;
+        self.persist(&mut writer)?;
+        Ok(true)
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<String>> {
+        Ok(self
+            .index
+            .read()
+            .unwrap()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    fn scan_page(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        let start = match &after {
+            Some(after) if after.as_str() >= prefix.as_str() => Bound::Excluded(after.as_str()),
+            _ => Bound::Included(prefix.as_str()),
+        };
+        Ok(self
+            .index
+            .read()
+            .unwrap()
+            .range::<str, _>((start, Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .take(limit)
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    fn set_writer(&self, key: String) -> Result<Box<dyn ValueWriter>> {
+        let path = self.path.join(format!(
+            "{}.upload",
+            UPLOADS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        // spool is removed by drop, also when the prefix can't be written.
+        let mut upload = Upload {
+            store: self.clone(),
+            crc: record_hasher(&key),
+            key,
+            path,
+            record: None,
+        };
+        let mut record = BufWriter::new(file);
+        stream::write_set_prefix(&mut record, &upload.key)?;
+        upload.record = Some(EscapingWriter::new(record));
+        Ok(Box::new(upload))
+    }
+
+    fn get_reader(&self, key: String) -> Result<Option<Box<dyn Read + Send>>> {
+        let index = self.index.read().unwrap();
+        let cmd_pos = match index.get(&key) {
+            Some(cmd_pos) => cmd_pos,
+            None => return Ok(None),
+        };
+        // compaction removes generations only after index is released.
+        // Streamed values are not verified against checksum, `kvs-fsck` is.
+        let mut file = File::open(self.gen_path(cmd_pos.gen))?;
+        file.seek(SeekFrom::Start(cmd_pos.pos))?;
+        let record = BufReader::new(file.take(cmd_pos.len));
+        drop(index);
+        Ok(Some(Box::new(stream::value_reader(record)?)))
+    }
+
+    fn read_log(&self, from: Option<LogPosition>, max_bytes: u64) -> Result<LogBatch> {
+        let mut from = match from {
+            Some(from) => from,
+            None => return self.snapshot(self.head(), None, max_bytes),
+        };
+        let head = self.head();
+
+        loop {
+            if from.gen > head.gen || (from.gen == head.gen && from.pos > head.pos) {
+                return self.snapshot(head, None, max_bytes);
+            }
+            let file = match File::open(self.gen_path(from.gen)) {
+                Ok(file) => file,
+                // generation was removed by compaction.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    return self.snapshot(head, None, max_bytes)
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let end = if from.gen == head.gen {
+                head.pos
+            } else {
+                file.metadata()?.len()
+            };
+            if from.pos > end {
+                return self.snapshot(head, None, max_bytes);
+            }
+
+            let (records, pos) = read_records(file, from.pos, end, max_bytes)?;
+            let next = LogPosition { gen: from.gen, pos };
+            if !records.is_empty() || from.gen == head.gen {
+                return Ok(LogBatch::Records {
+                    records,
+                    next,
+                    head,
+                });
+            }
+
+            // generation is finished, writes continued in the one following
+            // its compaction output, which follower doesn't need.
+            from = LogPosition {
+                gen: from.gen + 2,
+                pos: 0,
+            };
+        }
+    }
+
+    fn read_snapshot(&self, at: LogPosition, after: String, max_bytes: u64) -> Result<LogBatch> {
+        self.snapshot(at, Some(after), max_bytes)
     }
 }
 
+/// Reads commands of the log file between `start` and `end` offsets, stops
+/// once `max_bytes` were read. Returns records and offset after the last one.
+fn read_records(
+    mut file: File,
+    start: u64,
+    end: u64,
+    max_bytes: u64,
+) -> Result<(Vec<LogRecord>, u64)> {
+    file.seek(SeekFrom::Start(start))?;
+    let mut stream =
+        Deserializer::from_reader(BufReader::new(file.take(end - start))).into_iter::<Command>();
+
+    let mut records = vec![];
+    let mut read = 0;
+    while read < max_bytes {
+        match stream.next() {
+            Some(cmd) => {
+                let cmd = cmd?;
+                if !cmd.verify() {
+                    bail!("checksum mismatch of record at offset {}", start + read);
+                }
+                records.push(cmd.into_record());
+                read = stream.byte_offset() as u64;
+            }
+            None => break,
+        }
+    }
+    Ok((records, start + read))
+}
+
+/// Value of `KvStore::set_writer`, its whole Set record is spooled to a file
+/// next to the log and copied into the log on commit.
+struct Upload {
+    store: KvStore,
+    /// Checksum of the record so far.
+    crc: crc32fast::Hasher,
+    key: String,
+    path: PathBuf,
+    record: Option<EscapingWriter<BufWriter<File>>>,
+}
+
+impl Write for Upload {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.record.as_mut().expect("upload is open").write(buf)?;
+        self.crc.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.record.as_mut().expect("upload is open").flush()
+    }
+}
+
+impl ValueWriter for Upload {
+    fn commit(mut self: Box<Self>) -> Result<()> {
+        let record = self.record.take().expect("upload is open");
+        let mut record = record.finish()?;
+        stream::write_set_suffix(&mut record, self.crc.clone().finalize())?;
+        let mut file = record.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+
+        self.store.compact_if_needed()?;
+        let mut writer = self.store.writer.write().unwrap();
+        self.store
+            .append_record(&mut writer, self.key.clone(), BufReader::new(file))
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Removes spool files left by uploads interrupted by a crash.
+fn remove_uploads(path: &PathBuf) -> Result<()> {
+    for file in fs::read_dir(path)? {
+        let path = file?.path();
+        if path.extension().is_some_and(|ext| ext == "upload") {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
 fn open_generation_readers(
     path: impl Into<PathBuf>,
 ) -> Result<HashMap<u64, BufReaderWithPos<File>>> {
+    let path = path.into();
     let mut readers = HashMap::default();
-    for file in fs::read_dir(path.into())? {
-        let file = file?;
+    for gen in generations(&path)? {
+        let f = File::open(path.join(format!("{}.log", gen)))?;
+        readers.insert(gen, BufReaderWithPos::new(f, 0)?);
+    }
+    Ok(readers)
+}
 
-        if !file
-            .file_name()
-            .to_str()
-            .context("could not read file name")?
-            .contains(".log")
-        {
+/// Generations of the log in the store directory, in ascending order.
+pub(crate) fn generations(path: &Path) -> Result<Vec<u64>> {
+    let mut gens = vec![];
+    for file in fs::read_dir(path)? {
+        let file = file?;
+        let name = file.file_name();
+        let name = name.to_str().context("could not read file name")?;
+        if !name.contains(".log") {
             continue;
         }
+        gens.push(name.replace(".log", "").parse()?);
+    }
+    gens.sort_unstable();
+    Ok(gens)
+}
 
-        let file_generation: u64 = file
-            .file_name()
-            .to_str()
-            .context("could not read file name")?
-            .replace(".log", "")
-            .parse()?;
-        let f = File::open(file.path())?;
+/// Replays logs of the generations in order, passing every entry to `visit`,
+/// and returns index of the live Set records.
+pub(crate) fn replay(
+    path: &Path,
+    gens: &[u64],
+    mut visit: impl FnMut(&LogEntry),
+) -> Result<BTreeMap<String, CommandPos>> {
+    let mut index = BTreeMap::new();
+    for gen in gens {
+        for entry in LogEntries::open(path, *gen)? {
+            let entry = entry?;
+            visit(&entry);
+            debug!("gen:{}, record: {:?}", gen, entry.record);
+
+            match entry.record {
+                LogRecord::Set { key, .. } => {
+                    let cmd_pos = CommandPos {
+                        gen: entry.gen,
+                        pos: entry.pos,
+                        len: entry.len,
+                    };
+                    index.insert(key, cmd_pos);
+                }
+                LogRecord::Rm { key } => {
+                    index.remove(&key).ok_or(KvsError::KeyNotFound)?;
+                }
+            }
+        }
+    }
+    Ok(index)
+}
+
+#[derive(Debug)]
+/// Record decoded from generation log together with its place there.
+pub(crate) struct LogEntry {
+    pub gen: u64,
+    pub pos: u64,
+    pub len: u64,
+    pub record: LogRecord,
+}
 
-        readers.insert(file_generation, BufReaderWithPos::new(f, 0)?);
+/// Decodes records of a generation log one by one, stops after the first
+/// one that can't be decoded. Records not matching their checksum are
+/// returned as `ChecksumMismatch` errors and decoding goes on.
+pub(crate) struct LogEntries {
+    gen: u64,
+    pos: u64,
+    stream: StreamDeserializer<'static, IoRead<BufReader<File>>, Command>,
+    failed: bool,
+}
+
+impl LogEntries {
+    pub fn open(path: &Path, gen: u64) -> Result<Self> {
+        let file = File::open(path.join(format!("{}.log", gen)))?;
+        Ok(Self {
+            gen,
+            pos: 0,
+            stream: Deserializer::from_reader(BufReader::new(file)).into_iter(),
+            failed: false,
+        })
+    }
+
+    /// Offset following the last decoded record.
+    pub fn pos(&self) -> u64 {
+        self.pos
+    }
+}
+
+impl Iterator for LogEntries {
+    type Item = Result<LogEntry>;
+
+    fn next(&mut self) -> Option<Result<LogEntry>> {
+        if self.failed {
+            return None;
+        }
+        let cmd = match self.stream.next()? {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                self.failed = true;
+                let context = format!(
+                    "invalid record in generation {} at offset {}",
+                    self.gen, self.pos
+                );
+                return Some(Err(anyhow::Error::from(e).context(context)));
+            }
+        };
+        let end = self.stream.byte_offset() as u64;
+        let (pos, len) = (self.pos, end - self.pos);
+        self.pos = end;
+        // record is whole, entries following it can still be decoded.
+        if !cmd.verify() {
+            return Some(Err(ChecksumMismatch {
+                gen: self.gen,
+                pos,
+                len,
+            }
+            .into()));
+        }
+        Some(Ok(LogEntry {
+            gen: self.gen,
+            pos,
+            len,
+            record: cmd.into_record(),
+        }))
     }
-    Ok(readers)
 }
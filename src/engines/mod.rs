@@ -1,7 +1,12 @@
+use crate::error::KvsError;
+use crate::raft::MembershipChange;
+use crate::replication::{LogBatch, LogPosition};
 use crate::Result;
+use std::io::{self, Cursor, Read, Write};
 
 pub mod kv;
 pub mod sled;
+mod stream;
 
 pub trait KvsEngine: Clone + Send + 'static {
     /// Set the value of a string key to a string.
@@ -12,7 +17,114 @@ pub trait KvsEngine: Clone + Send + 'static {
     /// Return an error if the value is not read successfully.
     fn get(&self, key: String) -> Result<Option<String>>;
 
+    /// Get the value from local state, which may lag behind the rest of a
+    /// cluster. Same as `get` for engines that are not replicated.
+    fn get_stale(&self, key: String) -> Result<Option<String>> {
+        self.get(key)
+    }
+
     /// Remove a given string key.
     /// Return an error if the key does not exit or value is not read successfully.
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Set the value of a key only if its current value equals `expected`,
+    /// `None` meaning that key must not exist. Return whether value was set.
+    fn compare_and_set(&self, key: String, expected: Option<String>, value: String)
+        -> Result<bool>;
+
+    /// Remove a key only if its current value equals `expected`. Return
+    /// whether the key was removed.
+    fn compare_and_remove(&self, key: String, expected: String) -> Result<bool>;
+
+    /// Return all keys starting with given prefix, in ascending order.
+    fn scan(&self, prefix: String) -> Result<Vec<String>>;
+
+    /// Return at most `limit` keys starting with given prefix that follow
+    /// `after`, in ascending order. Engines that can't do better scan all
+    /// keys of the prefix.
+    fn scan_page(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        Ok(self
+            .scan(prefix)?
+            .into_iter()
+            .filter(|key| after.as_ref().is_none_or(|after| key > after))
+            .take(limit)
+            .collect())
+    }
+
+    /// Return log records written after `from`, at most around `max_bytes` of them,
+    /// or a snapshot when `from` is `None` or no longer available. Used by the
+    /// leader of replication, engines that have no log can't be replicated.
+    fn read_log(&self, from: Option<LogPosition>, max_bytes: u64) -> Result<LogBatch> {
+        let _ = (from, max_bytes);
+        Err(KvsError::Replication("engine does not support replication".to_owned()).into())
+    }
+
+    /// Return values of keys following `after` in the snapshot `read_log`
+    /// started at `at`, at most around `max_bytes` of them.
+    fn read_snapshot(&self, at: LogPosition, after: String, max_bytes: u64) -> Result<LogBatch> {
+        let _ = (at, after, max_bytes);
+        Err(KvsError::Replication("engine does not support replication".to_owned()).into())
+    }
+
+    /// Add or remove member of a Raft cluster, engines that are not
+    /// clustered can't do that.
+    fn change_membership(&self, change: MembershipChange) -> Result<()> {
+        let _ = change;
+        Err(KvsError::Server("engine is not clustered".to_owned()).into())
+    }
+
+    /// Start writing value of the key in parts, it is set once committed.
+    /// Engines that can't do better buffer the value in memory.
+    fn set_writer(&self, key: String) -> Result<Box<dyn ValueWriter>> {
+        Ok(Box::new(BufferedValue {
+            engine: self.clone(),
+            key,
+            value: vec![],
+        }))
+    }
+
+    /// Get the value as a stream of UTF-8 bytes, or None if the key does not
+    /// exist. Engines that can't do better read the whole value first.
+    fn get_reader(&self, key: String) -> Result<Option<Box<dyn Read + Send>>> {
+        Ok(self
+            .get(key)?
+            .map(|value| Box::new(Cursor::new(value.into_bytes())) as Box<dyn Read + Send>))
+    }
+}
+
+/// Value written in parts by `KvsEngine::set_writer`. Written bytes must
+/// form valid UTF-8 once all of them are written. Dropping the writer
+/// without commit discards the value.
+pub trait ValueWriter: Write + Send {
+    /// Sets the key to the written value.
+    fn commit(self: Box<Self>) -> Result<()>;
+}
+
+/// Value collected in memory, see `KvsEngine::set_writer`.
+struct BufferedValue<E> {
+    engine: E,
+    key: String,
+    value: Vec<u8>,
+}
+
+impl<E: KvsEngine> Write for BufferedValue<E> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.value.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<E: KvsEngine> ValueWriter for BufferedValue<E> {
+    fn commit(self: Box<Self>) -> Result<()> {
+        self.engine.set(self.key, String::from_utf8(self.value)?)
+    }
 }
@@ -1,7 +1,7 @@
 use std::ops::Deref;
 
+use crate::error::KvsError;
 use crate::{KvsEngine, Result};
-use anyhow::Context;
 use sled::Db;
 
 #[derive(Debug, Clone)]
@@ -28,14 +28,54 @@ impl KvsEngine for SledKvsEngine {
     }
 
     fn get(&self, key: String) -> Result<Option<String>> {
-        let value = self.db.get(key)?.context("Key not found")?;
-        let a = value.deref();
-        Ok(Some(String::from_utf8(a.to_vec())?))
+        match self.db.get(key)? {
+            Some(value) => Ok(Some(String::from_utf8(value.deref().to_vec())?)),
+            None => Ok(None),
+        }
     }
 
     fn remove(&self, key: String) -> Result<()> {
-        self.db.remove(&key)?.context("Key not found")?;
+        self.db.remove(&key)?.ok_or(KvsError::KeyNotFound)?;
         self.db.flush()?;
         Ok(())
     }
+
+    fn compare_and_set(
+        &self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<bool> {
+        let swapped = self
+            .db
+            .compare_and_swap(
+                key,
+                expected.map(String::into_bytes),
+                Some(value.into_bytes()),
+            )?
+            .is_ok();
+        if swapped {
+            self.db.flush()?;
+        }
+        Ok(swapped)
+    }
+
+    fn compare_and_remove(&self, key: String, expected: String) -> Result<bool> {
+        let removed = self
+            .db
+            .compare_and_swap(key, Some(expected.into_bytes()), None as Option<Vec<u8>>)?
+            .is_ok();
+        if removed {
+            self.db.flush()?;
+        }
+        Ok(removed)
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<String>> {
+        self.db
+            .scan_prefix(prefix)
+            .keys()
+            .map(|key| Ok(String::from_utf8(key?.to_vec())?))
+            .collect()
+    }
 }
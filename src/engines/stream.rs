@@ -0,0 +1,238 @@
+//! Streaming access to values inside json log records of `KvStore`.
+//!
+//! Records are `{"Set":{"key":..,"value":..,"crc":..}}` as written by
+//! serde_json.
+//! Large values are escaped into and unescaped from the record as they
+//! flow through, so they never have to be in memory whole.
+
+use std::io::{self, BufRead, Read, Write};
+
+/// Record bytes preceding escaped value.
+pub(super) fn write_set_prefix<W: Write>(w: &mut W, key: &str) -> io::Result<()> {
+    write!(
+        w,
+        r#"{{"Set":{{"key":{},"value":""#,
+        serde_json::to_string(key)?
+    )
+}
+
+/// Record bytes following escaped value, `crc` is checksum of the record.
+pub(super) fn write_set_suffix<W: Write>(w: &mut W, crc: u32) -> io::Result<()> {
+    write!(w, r#"","crc":{}}}}}"#, crc)
+}
+
+/// Escapes UTF-8 text written in arbitrary pieces into json string content,
+/// the same way serde_json does.
+pub(super) struct EscapingWriter<W> {
+    inner: W,
+    /// Bytes of character split between writes.
+    partial: Vec<u8>,
+}
+
+impl<W: Write> EscapingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            partial: vec![],
+        }
+    }
+
+    /// Checks that text ended on character boundary and returns the writer.
+    pub fn finish(self) -> io::Result<W> {
+        if !self.partial.is_empty() {
+            return Err(invalid_utf8());
+        }
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EscapingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.partial.extend_from_slice(buf);
+        let valid = match std::str::from_utf8(&self.partial) {
+            Ok(text) => text.len(),
+            // character continues in the next write.
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => return Err(invalid_utf8()),
+        };
+        escape(&mut self.inner, &self.partial[..valid])?;
+        self.partial.drain(..valid);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn escape<W: Write>(w: &mut W, text: &[u8]) -> io::Result<()> {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    let mut start = 0;
+    for (idx, &b) in text.iter().enumerate() {
+        let escaped: &[u8] = match b {
+            b'"' => br#"\""#,
+            b'\\' => br"\\",
+            b'\x08' => br"\b",
+            b'\x0c' => br"\f",
+            b'\n' => br"\n",
+            b'\r' => br"\r",
+            b'\t' => br"\t",
+            0..=0x1f => &[
+                b'\\',
+                b'u',
+                b'0',
+                b'0',
+                HEX[(b >> 4) as usize],
+                HEX[(b & 0xf) as usize],
+            ],
+            _ => continue,
+        };
+        w.write_all(&text[start..idx])?;
+        w.write_all(escaped)?;
+        start = idx + 1;
+    }
+    w.write_all(&text[start..])
+}
+
+/// Positions reader of Set record at its value and unescapes it.
+pub(super) fn value_reader<R: BufRead>(mut record: R) -> io::Result<UnescapingReader<R>> {
+    expect(&mut record, br#"{"Set":{"key":""#)?;
+    // key is skipped without decoding.
+    let mut key = UnescapingReader::new(&mut record);
+    io::copy(&mut key, &mut io::sink())?;
+    expect(&mut record, br#","value":""#)?;
+    Ok(UnescapingReader::new(record))
+}
+
+fn expect<R: BufRead>(reader: &mut R, literal: &[u8]) -> io::Result<()> {
+    let mut buf = vec![0u8; literal.len()];
+    reader.read_exact(&mut buf)?;
+    if buf != literal {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected log record format",
+        ));
+    }
+    Ok(())
+}
+
+/// Reads json string content following its opening quote, stops at the
+/// closing one.
+pub(super) struct UnescapingReader<R> {
+    inner: R,
+    /// Decoded bytes of escape sequence not returned yet.
+    pending: Vec<u8>,
+    done: bool,
+}
+
+impl<R: BufRead> UnescapingReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            pending: vec![],
+            done: false,
+        }
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        let b = match self.inner.fill_buf()?.first() {
+            Some(b) => *b,
+            None => return Err(io::ErrorKind::UnexpectedEof.into()),
+        };
+        self.inner.consume(1);
+        Ok(b)
+    }
+
+    fn hex4(&mut self) -> io::Result<u32> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = (self.byte()? as char)
+                .to_digit(16)
+                .ok_or_else(invalid_escape)?;
+            code = code * 16 + digit;
+        }
+        Ok(code)
+    }
+
+    /// Decodes escape sequence following backslash into `pending`.
+    fn unescape(&mut self) -> io::Result<()> {
+        let b = match self.byte()? {
+            b @ (b'"' | b'\\' | b'/') => b,
+            b'b' => b'\x08',
+            b'f' => b'\x0c',
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            b'u' => {
+                let mut code = self.hex4()?;
+                if (0xd800..0xdc00).contains(&code) {
+                    if self.byte()? != b'\\' || self.byte()? != b'u' {
+                        return Err(invalid_escape());
+                    }
+                    let low = self.hex4()?;
+                    if !(0xdc00..0xe000).contains(&low) {
+                        return Err(invalid_escape());
+                    }
+                    code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                }
+                let c = char::from_u32(code).ok_or_else(invalid_escape)?;
+                self.pending
+                    .extend_from_slice(c.encode_utf8(&mut [0u8; 4]).as_bytes());
+                return Ok(());
+            }
+            _ => return Err(invalid_escape()),
+        };
+        self.pending.push(b);
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for UnescapingReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        while n < out.len() {
+            if !self.pending.is_empty() {
+                let len = self.pending.len().min(out.len() - n);
+                out[n..n + len].copy_from_slice(&self.pending[..len]);
+                self.pending.drain(..len);
+                n += len;
+                continue;
+            }
+            if self.done {
+                break;
+            }
+
+            let buf = self.inner.fill_buf()?;
+            if buf.is_empty() {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let plain = buf
+                .iter()
+                .position(|b| *b == b'"' || *b == b'\\')
+                .unwrap_or(buf.len())
+                .min(out.len() - n);
+            if plain > 0 {
+                out[n..n + plain].copy_from_slice(&buf[..plain]);
+                self.inner.consume(plain);
+                n += plain;
+                continue;
+            }
+            let special = buf[0];
+            self.inner.consume(1);
+            if special == b'"' {
+                self.done = true;
+            } else {
+                self.unescape()?;
+            }
+        }
+        Ok(n)
+    }
+}
+
+fn invalid_utf8() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "value is not valid UTF-8")
+}
+
+fn invalid_escape() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid escape in log record")
+}
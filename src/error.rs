@@ -7,6 +7,41 @@ pub enum KvsError {
     KeyNotFound,
     #[error("Could not parse")]
     Parse,
+    #[error("Protocol error: {0}")]
+    /// Peer violated the wire protocol or handshake failed.
+    Protocol(String),
+    #[error("{0}")]
+    /// Error reported by the server.
+    Server(String),
+    #[error("Permission denied: {0}")]
+    /// Client is not authenticated or not allowed to run the command.
+    PermissionDenied(String),
+    #[error("Server is a read-only replica")]
+    /// Write sent to a follower.
+    ReadOnly,
+    #[error("Replication error: {0}")]
+    /// Engine can't be replicated or leader sent invalid data.
+    Replication(String),
+    #[error("TLS error: {0}")]
+    /// Invalid TLS certificates or keys.
+    Tls(String),
+    #[error("Not the cluster leader{}", .0.as_ref().map(|l| format!(", leader is {}", l)).unwrap_or_default())]
+    /// Write or linearizable read sent to a Raft node that is not the
+    /// leader, carries client address of the leader when known.
+    NotLeader(Option<String>),
+    #[error("Request timed out")]
+    /// Request was not committed or processed in time.
+    Timeout,
+    #[error("Server is busy, too many connections")]
+    /// Server rejected connection over its limit.
+    Busy,
+    #[error("Key of {size} bytes exceeds limit of {max}")]
+    KeyTooLarge { size: u64, max: u64 },
+    #[error("Value of {size} bytes exceeds limit of {max}")]
+    ValueTooLarge { size: u64, max: u64 },
+    #[error("Request of {size} bytes exceeds limit of {max}")]
+    /// Request frame was skipped by the server without reading it.
+    FrameTooLarge { size: u64, max: u64 },
 }
 
 pub type Result<T> = std::result::Result<T, anyhow::Error>;
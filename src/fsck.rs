@@ -0,0 +1,296 @@
+//! Verification and repair of `KvStore` data directories, run by `kvs-fsck`
+//! while no server uses them.
+//!
+//! Records carry checksums of their content, corruption that still decodes
+//! is found by them. Records written before checksums were introduced are
+//! checked only for decoding.
+
+use crate::config::kv_store_dir;
+use crate::engines::kv::{ChecksumMismatch, CommandPos, LogEntries};
+use crate::replication::LogRecord;
+use crate::Result;
+use anyhow::{bail, Context};
+use clap::Parser;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Directory inside the store that repair moves bad files to.
+const QUARANTINE_DIR: &str = "quarantine";
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+/// Cli for verifying and repairing KvStore data directories.
+pub struct FsckCLI {
+    /// KvStore directory, or server data directory holding one.
+    #[clap(value_parser)]
+    dir: PathBuf,
+    /// Fix found problems. Corrupt tails are truncated, bad files moved to
+    /// `quarantine` directory and live records rewritten to a clean generation.
+    #[clap(long)]
+    repair: bool,
+}
+
+impl FsckCLI {
+    pub fn run(&self) -> Result<()> {
+        let dir = kv_store_dir(&self.dir);
+        let report = check(&dir)?;
+        for problem in &report.problems {
+            println!("{}", problem);
+        }
+        println!(
+            "{}: {} generations, {} records, {} live keys, {} problems",
+            dir.display(),
+            report.gens.len(),
+            report.records,
+            report.index.len(),
+            report.problems.len()
+        );
+
+        if report.problems.is_empty() {
+            return Ok(());
+        }
+        if !self.repair {
+            bail!(
+                "found {} problems, run with --repair to fix them",
+                report.problems.len()
+            );
+        }
+        repair(&dir, report)?;
+        println!("Repaired {}", dir.display());
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Problem found in the store directory.
+enum Problem {
+    /// `*.log` file whose name is not a generation number, store fails to
+    /// open with it.
+    BadFileName(PathBuf),
+    /// Generation has a record that can't be decoded at `offset`, it and
+    /// everything after it is lost.
+    CorruptTail { gen: u64, offset: u64, len: u64 },
+    /// Record that decodes but doesn't match its checksum, store fails to
+    /// open with it. Repair drops it, the key keeps its previous value.
+    ChecksumMismatch { gen: u64, offset: u64 },
+    /// Remove of a key that is not set, store fails to open with it.
+    DanglingRemove { gen: u64, offset: u64, key: String },
+    /// Generation left behind by compaction that was interrupted before
+    /// removing it.
+    OrphanedGeneration(u64),
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::BadFileName(path) => {
+                write!(f, "{}: not a generation log", path.display())
+            }
+            Problem::CorruptTail { gen, offset, len } => write!(
+                f,
+                "generation {}: {} bytes from offset {} can't be decoded",
+                gen, len, offset
+            ),
+            Problem::ChecksumMismatch { gen, offset } => write!(
+                f,
+                "generation {} offset {}: record doesn't match its checksum",
+                gen, offset
+            ),
+            Problem::DanglingRemove { gen, offset, key } => write!(
+                f,
+                "generation {} offset {}: remove of key {:?} that is not set",
+                gen, offset, key
+            ),
+            Problem::OrphanedGeneration(gen) => {
+                write!(
+                    f,
+                    "generation {}: left behind by interrupted compaction",
+                    gen
+                )
+            }
+        }
+    }
+}
+
+/// Result of checking the store directory.
+struct Report {
+    /// Generations with valid file names, in ascending order.
+    gens: Vec<u64>,
+    records: u64,
+    /// Live Set records among the decodable ones.
+    index: BTreeMap<String, CommandPos>,
+    problems: Vec<Problem>,
+}
+
+/// Replays every generation like `KvStore::open` does, but collects
+/// problems instead of failing on the first one.
+fn check(dir: &Path) -> Result<Report> {
+    if !dir.is_dir() {
+        bail!("{} is not a directory", dir.display());
+    }
+
+    let mut report = Report {
+        gens: vec![],
+        records: 0,
+        index: BTreeMap::new(),
+        problems: vec![],
+    };
+    for file in fs::read_dir(dir)? {
+        let path = file?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let name = path.file_name().and_then(|name| name.to_str());
+        // same files as `KvStore::open` tries to read.
+        match name {
+            Some(name) if name.contains(".log") => match name.replace(".log", "").parse() {
+                Ok(gen) => report.gens.push(gen),
+                Err(_) => report.problems.push(Problem::BadFileName(path)),
+            },
+            Some(_) => {}
+            None => report.problems.push(Problem::BadFileName(path)),
+        }
+    }
+    report.gens.sort_unstable();
+
+    for gen in &report.gens {
+        let mut entries = LogEntries::open(dir, *gen)?;
+        for entry in entries.by_ref() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => match e.downcast_ref::<ChecksumMismatch>() {
+                    Some(mismatch) => {
+                        report.problems.push(Problem::ChecksumMismatch {
+                            gen: mismatch.gen,
+                            offset: mismatch.pos,
+                        });
+                        continue;
+                    }
+                    None => break,
+                },
+            };
+            report.records += 1;
+            match entry.record {
+                LogRecord::Set { key, .. } => {
+                    let cmd_pos = CommandPos {
+                        gen: entry.gen,
+                        pos: entry.pos,
+                        len: entry.len,
+                    };
+                    report.index.insert(key, cmd_pos);
+                }
+                LogRecord::Rm { key } => {
+                    if report.index.remove(&key).is_none() {
+                        report.problems.push(Problem::DanglingRemove {
+                            gen: entry.gen,
+                            offset: entry.pos,
+                            key,
+                        });
+                    }
+                }
+            }
+        }
+
+        let size = fs::metadata(gen_path(dir, *gen))?.len();
+        if entries.pos() < size {
+            report.problems.push(Problem::CorruptTail {
+                gen: *gen,
+                offset: entries.pos(),
+                len: size - entries.pos(),
+            });
+        }
+    }
+
+    // compaction writes generation preceding the current one and removes
+    // all older ones once done.
+    if let Some(&current) = report.gens.last() {
+        if current > 0 && report.gens.contains(&(current - 1)) {
+            for gen in report.gens.iter().filter(|&&gen| gen < current - 1) {
+                report.problems.push(Problem::OrphanedGeneration(*gen));
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Fixes problems of the report, see `FsckCLI::repair`.
+fn repair(dir: &Path, report: Report) -> Result<()> {
+    let mut rewrite = false;
+    for problem in &report.problems {
+        match problem {
+            Problem::BadFileName(path) => quarantine(dir, path)?,
+            Problem::CorruptTail { gen, offset, .. } => truncate(dir, *gen, *offset)?,
+            Problem::ChecksumMismatch { .. }
+            | Problem::DanglingRemove { .. }
+            | Problem::OrphanedGeneration(_) => rewrite = true,
+        }
+    }
+    if rewrite {
+        rewrite_generation(dir, &report)?;
+    }
+    Ok(())
+}
+
+/// Moves corrupt tail of the generation to quarantine.
+fn truncate(dir: &Path, gen: u64, offset: u64) -> Result<()> {
+    let path = gen_path(dir, gen);
+    let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let tail_path = quarantine_path(dir, &format!("{}.log.{}.tail", gen, offset))?;
+    io::copy(&mut file, &mut File::create(tail_path)?)?;
+    file.set_len(offset)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Writes live records to a generation following all others and moves the
+/// others to quarantine.
+fn rewrite_generation(dir: &Path, report: &Report) -> Result<()> {
+    let clean_gen = report.gens.last().map_or(0, |gen| gen + 1);
+    // written aside and renamed, so store never sees half written generation.
+    let tmp = dir.join(format!("{}.fsck", clean_gen));
+    let mut writer = File::create(&tmp)?;
+    for cmd_pos in report.index.values() {
+        let mut file = File::open(gen_path(dir, cmd_pos.gen))?;
+        file.seek(SeekFrom::Start(cmd_pos.pos))?;
+        io::copy(&mut file.take(cmd_pos.len), &mut writer)?;
+    }
+    writer.flush()?;
+    writer.sync_all()?;
+    fs::rename(&tmp, gen_path(dir, clean_gen))?;
+
+    for gen in &report.gens {
+        quarantine(dir, &gen_path(dir, *gen))?;
+    }
+    Ok(())
+}
+
+/// Moves the file to quarantine directory of the store.
+fn quarantine(dir: &Path, path: &Path) -> Result<()> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context("could not read file name")?;
+    fs::rename(path, quarantine_path(dir, name)?)?;
+    Ok(())
+}
+
+/// Free path in quarantine directory for the file name.
+fn quarantine_path(dir: &Path, name: &str) -> Result<PathBuf> {
+    let quarantine = dir.join(QUARANTINE_DIR);
+    fs::create_dir_all(&quarantine)?;
+    let mut path = quarantine.join(name);
+    let mut n = 1;
+    while path.exists() {
+        path = quarantine.join(format!("{}.{}", name, n));
+        n += 1;
+    }
+    Ok(path)
+}
+
+fn gen_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
+}
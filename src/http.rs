@@ -0,0 +1,493 @@
+//! Minimal HTTP/1.1 gateway exposing the engine as a JSON REST api:
+//!
+//! - `GET /keys/{key}` returns value, `404` when key is missing,
+//! - `PUT /keys/{key}` sets value from the body, `If-Match` / `If-None-Match: *`
+//!   turn it into compare-and-set answered with `409` on conflict,
+//! - `DELETE /keys/{key}` removes key,
+//! - `GET /keys?prefix=` lists keys,
+//! - `GET /stats` returns server statistics.
+//!
+//! When server has authentication configured, requests carry credentials in
+//! `Authorization` header, either `Bearer <token>` or `Basic` user and password.
+
+use crate::auth::{self, Access, Auth, Principal};
+use crate::error::KvsError;
+use crate::limits::Limits;
+use crate::protocol::Credentials;
+use crate::stats::Stats;
+use crate::transport::Stream;
+use crate::{KvsEngine, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::io::{BufRead, BufReader, Read, Write};
+
+/// Bodies bigger than that are rejected with `413`.
+pub const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// Limits size of request line and headers.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Parsed request line and headers.
+struct Head {
+    method: String,
+    target: String,
+    http10: bool,
+    headers: Vec<(String, String)>,
+}
+
+impl Head {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn keep_alive(&self) -> bool {
+        match self.header("connection") {
+            Some(c) if c.eq_ignore_ascii_case("close") => false,
+            Some(c) if c.eq_ignore_ascii_case("keep-alive") => true,
+            _ => !self.http10,
+        }
+    }
+}
+
+/// Response with JSON body.
+struct Response {
+    status: u16,
+    body: Value,
+    etag: Option<String>,
+}
+
+impl Response {
+    fn new(status: u16, body: Value) -> Self {
+        Self {
+            status,
+            body,
+            etag: None,
+        }
+    }
+
+    fn error(status: u16, msg: impl ToString) -> Self {
+        Self::new(status, json!({ "error": msg.to_string() }))
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W, keep_alive: bool) -> Result<()> {
+        let body = if self.status == 204 {
+            vec![]
+        } else {
+            serde_json::to_vec(&self.body)?
+        };
+        // head and body are sent with a single write.
+        let mut out = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        if !body.is_empty() {
+            out.push_str("Content-Type: application/json\r\n");
+        }
+        out.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        if let Some(etag) = &self.etag {
+            out.push_str(&format!("ETag: {}\r\n", etag));
+        }
+        if !keep_alive {
+            out.push_str("Connection: close\r\n");
+        }
+        out.push_str("\r\n");
+        let mut out = out.into_bytes();
+        out.extend_from_slice(&body);
+        w.write_all(&out)?;
+        w.flush()?;
+        Ok(())
+    }
+}
+
+/// Response sent to connections over the server limit.
+pub fn busy_response() -> Vec<u8> {
+    let mut out = vec![];
+    Response::error(503, KvsError::Busy)
+        .write_to(&mut out, false)
+        .expect("write to vec");
+    out
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Serves HTTP connection until the client disconnects or asks to close it.
+pub fn serve_http<E: KvsEngine>(
+    engine: E,
+    stats: &Stats,
+    limits: &Limits,
+    auth: Option<&Auth>,
+    read_only: bool,
+    stream: Stream,
+) -> Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    let identity = stream.peer_identity();
+    let mut reader = BufReader::new(stream);
+
+    loop {
+        let head = match read_head(&mut reader)? {
+            Ok(Some(head)) => head,
+            Ok(None) => return Ok(()),
+            Err(response) => return response.write_to(reader.get_mut(), false),
+        };
+        debug!(
+            "Receive HTTP request from {}: {} {}",
+            peer_addr, head.method, head.target
+        );
+
+        let len = match head.header("content-length").map(str::parse::<usize>) {
+            Some(Ok(len)) => len,
+            Some(Err(_)) => {
+                return Response::error(400, "invalid Content-Length")
+                    .write_to(reader.get_mut(), false)
+            }
+            None if head.header("transfer-encoding").is_some() => {
+                return Response::error(411, "chunked bodies are not supported")
+                    .write_to(reader.get_mut(), false)
+            }
+            None => 0,
+        };
+        if len > MAX_BODY_SIZE {
+            stats.request(true);
+            // body is left unread, so connection can't be reused.
+            return Response::error(
+                413,
+                format!("body of {} bytes exceeds limit of {}", len, MAX_BODY_SIZE),
+            )
+            .write_to(reader.get_mut(), false);
+        }
+        let mut body = vec![0u8; len];
+        reader.read_exact(&mut body)?;
+
+        let response = credentials(&head)
+            .and_then(|credentials| {
+                auth::authenticate(auth, &credentials, identity.as_deref())
+                    .map_err(|e| Response::error(401, e))
+            })
+            .and_then(|principal| {
+                handle(&engine, stats, limits, &principal, read_only, &head, body).map_err(|e| {
+                    match e.downcast_ref::<KvsError>() {
+                        Some(KvsError::KeyNotFound) => Response::error(404, e),
+                        Some(KvsError::PermissionDenied(_) | KvsError::ReadOnly) => {
+                            Response::error(403, e)
+                        }
+                        Some(KvsError::NotLeader(_)) => Response::error(503, e),
+                        Some(KvsError::Timeout) => Response::error(504, e),
+                        Some(KvsError::KeyTooLarge { .. } | KvsError::ValueTooLarge { .. }) => {
+                            Response::error(413, e)
+                        }
+                        _ => Response::error(500, e),
+                    }
+                })
+            })
+            .unwrap_or_else(|response| response);
+        stats.request(response.status >= 400);
+
+        let keep_alive = head.keep_alive();
+        response.write_to(reader.get_mut(), keep_alive)?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads request line and headers. Inner error is a response that should be
+/// sent before closing malformed connection.
+fn read_head<R: BufRead>(reader: &mut R) -> Result<std::result::Result<Option<Head>, Response>> {
+    let mut lines = vec![];
+    let mut size = 0;
+    loop {
+        let mut line = String::new();
+        let read = reader.read_line(&mut line)?;
+        if read == 0 {
+            return Ok(if lines.is_empty() {
+                Ok(None)
+            } else {
+                Err(Response::error(400, "unexpected end of request"))
+            });
+        }
+        size += read;
+        if size > MAX_HEAD_SIZE {
+            return Ok(Err(Response::error(431, "request head too large")));
+        }
+        let line = line.trim_end_matches(['\r', '\n']).to_owned();
+        if line.is_empty() {
+            if lines.is_empty() {
+                continue; // tolerate empty lines between requests.
+            }
+            break;
+        }
+        lines.push(line);
+    }
+
+    let mut request_line = lines[0].split(' ');
+    let (method, target, version) = match (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) {
+        (Some(m), Some(t), Some(v)) if v.starts_with("HTTP/1.") => (m, t, v),
+        _ => return Ok(Err(Response::error(400, "malformed request line"))),
+    };
+
+    let mut headers = vec![];
+    for line in &lines[1..] {
+        match line.split_once(':') {
+            Some((name, value)) => headers.push((name.trim().to_owned(), value.trim().to_owned())),
+            None => return Ok(Err(Response::error(400, "malformed header"))),
+        }
+    }
+
+    Ok(Ok(Some(Head {
+        method: method.to_owned(),
+        target: target.to_owned(),
+        http10: version == "HTTP/1.0",
+        headers,
+    })))
+}
+
+/// Credentials from `Authorization` header.
+fn credentials(head: &Head) -> std::result::Result<Credentials, Response> {
+    let header = match head.header("authorization") {
+        Some(header) => header,
+        None => return Ok(Credentials::Anonymous),
+    };
+    let malformed = || Response::error(401, "malformed Authorization header");
+    match header.split_once(' ') {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => {
+            Ok(Credentials::Token(token.trim().to_owned()))
+        }
+        Some((scheme, encoded)) if scheme.eq_ignore_ascii_case("basic") => {
+            let decoded = base64_decode(encoded.trim())
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .ok_or_else(malformed)?;
+            let (user, password) = decoded.split_once(':').ok_or_else(malformed)?;
+            Ok(Credentials::Password {
+                user: user.to_owned(),
+                password: password.to_owned(),
+            })
+        }
+        _ => Err(malformed()),
+    }
+}
+
+/// Routes request to the engine.
+fn handle<E: KvsEngine>(
+    engine: &E,
+    stats: &Stats,
+    limits: &Limits,
+    principal: &Principal,
+    read_only: bool,
+    head: &Head,
+    body: Vec<u8>,
+) -> Result<Response> {
+    let (path, query) = match head.target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (head.target.as_str(), None),
+    };
+
+    if path == "/stats" {
+        return Ok(match head.method.as_str() {
+            "GET" => {
+                principal.check("", Access::Admin)?;
+                Response::new(
+                    200,
+                    serde_json::to_value(stats.snapshot(engine.scan(String::new())?.len()))?,
+                )
+            }
+            _ => Response::error(405, "method not allowed"),
+        });
+    }
+
+    if path == "/keys" || path == "/keys/" {
+        let prefix = match query
+            .into_iter()
+            .flat_map(|q| q.split('&'))
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(name, _)| *name == "prefix")
+        {
+            Some((_, prefix)) => match query_decode(prefix) {
+                Some(prefix) => prefix,
+                None => return Ok(Response::error(400, "malformed prefix")),
+            },
+            None => String::new(),
+        };
+        return Ok(match head.method.as_str() {
+            "GET" => {
+                // keys the client can't read are left out.
+                let mut keys = engine.scan(prefix)?;
+                keys.retain(|key| principal.can(key, Access::Read));
+                Response::new(200, json!({ "keys": keys }))
+            }
+            _ => Response::error(405, "method not allowed"),
+        });
+    }
+
+    let key = match path.strip_prefix("/keys/").map(percent_decode) {
+        Some(Some(key)) if !key.is_empty() => key,
+        Some(None) => return Ok(Response::error(400, "malformed key")),
+        _ => return Ok(Response::error(404, "not found")),
+    };
+
+    if head.method == "GET" {
+        principal.check(&key, Access::Read)?;
+    } else if read_only {
+        return Err(KvsError::ReadOnly.into());
+    } else {
+        principal.check(&key, Access::Write)?;
+    }
+    limits.check_key(&key)?;
+
+    match head.method.as_str() {
+        "GET" => Ok(match engine.get(key.clone())? {
+            Some(value) => Response {
+                etag: Some(etag(&value)),
+                ..Response::new(200, json!({ "key": key, "value": value }))
+            },
+            None => Response::error(404, KvsError::KeyNotFound),
+        }),
+        "PUT" => put(engine, limits, head, key, body),
+        "DELETE" => {
+            engine.remove(key)?;
+            Ok(Response::new(204, Value::Null))
+        }
+        _ => Ok(Response::error(405, "method not allowed")),
+    }
+}
+
+#[derive(Deserialize)]
+struct PutBody {
+    value: String,
+}
+
+/// Sets the value, body is either raw value or `{"value": ...}` json when
+/// sent with json content type.
+fn put<E: KvsEngine>(
+    engine: &E,
+    limits: &Limits,
+    head: &Head,
+    key: String,
+    body: Vec<u8>,
+) -> Result<Response> {
+    let is_json = head
+        .header("content-type")
+        .is_some_and(|c| c.starts_with("application/json"));
+    let value = if is_json {
+        match serde_json::from_slice::<PutBody>(&body) {
+            Ok(body) => body.value,
+            Err(e) => return Ok(Response::error(400, e)),
+        }
+    } else {
+        match String::from_utf8(body) {
+            Ok(value) => value,
+            Err(_) => return Ok(Response::error(400, "value must be valid utf-8")),
+        }
+    };
+    limits.check_value(value.len())?;
+    let new_etag = etag(&value);
+    let body = json!({ "key": key });
+
+    let created = match (head.header("if-match"), head.header("if-none-match")) {
+        (None, Some("*")) => {
+            if !engine.compare_and_set(key, None, value)? {
+                return Ok(Response::error(409, "key already exists"));
+            }
+            true
+        }
+        (Some(expected), None) => {
+            let current = engine.get(key.clone())?;
+            let matches = match &current {
+                Some(current) => expected == "*" || expected == etag(current),
+                None => false,
+            };
+            if !matches || !engine.compare_and_set(key, current, value)? {
+                return Ok(Response::error(409, "value was modified"));
+            }
+            false
+        }
+        (None, None) => {
+            engine.set(key, value)?;
+            false
+        }
+        _ => return Ok(Response::error(400, "unsupported precondition")),
+    };
+
+    Ok(Response {
+        etag: Some(new_etag),
+        ..Response::new(if created { 201 } else { 200 }, body)
+    })
+}
+
+/// Strong entity tag of the value.
+fn etag(value: &str) -> String {
+    let digest = Sha256::digest(value.as_bytes());
+    let hex: String = digest[..16].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("\"{}\"", hex)
+}
+
+/// Decodes standard base64 with padding.
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() / 4 * 3);
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in s.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Decodes `%XX` escapes of url path segment, `+` is taken literally.
+fn percent_decode(s: &str) -> Option<String> {
+    decode(s, false)
+}
+
+/// Decodes `%XX` escapes and `+` standing for space of query component.
+fn query_decode(s: &str) -> Option<String> {
+    decode(s, true)
+}
+
+fn decode(s: &str, plus_is_space: bool) -> Option<String> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                out.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b'+' if plus_is_space => out.push(b' '),
+            b => out.push(b),
+        }
+    }
+    String::from_utf8(out).ok()
+}
@@ -1,17 +1,52 @@
+mod admin;
+#[cfg(feature = "async")]
+pub mod async_client;
+#[cfg(feature = "async")]
+mod async_server;
+pub mod auth;
+mod bench;
 mod client;
 mod cmd;
+mod config;
+mod dump;
 mod engines;
 mod error;
+mod fsck;
+mod http;
+mod limits;
+pub mod protocol;
+pub mod raft;
 mod reader;
+pub mod replication;
+mod resp;
 mod server;
+pub mod shard;
+mod shell;
+mod stats;
 pub mod thread_pool;
+pub mod tls;
+mod transfer;
+mod transport;
 
-pub use client::ClientCLI;
-pub use engines::kv::KvStore;
+pub use admin::{AdminCLI, EngineLocation};
+#[cfg(feature = "async")]
+pub use async_client::AsyncKvsClient;
+pub use bench::BenchCLI;
+pub use client::{ClientCLI, KvsClient};
+pub use cmd::{Response, ResponseError, CMD};
+pub use config::{Config, ThreadPoolConfig, ThreadPoolKind, TlsConfig};
+pub use dump::DumpCLI;
+pub use engines::kv::{Durability, KvStore, StoreOptions};
 pub use engines::sled::SledKvsEngine;
-pub use engines::KvsEngine;
-pub use error::Result;
-pub use server::ServerCLI;
+pub use engines::{KvsEngine, ValueWriter};
+pub use error::{KvsError, Result};
+pub use fsck::FsckCLI;
+pub use limits::{Limits, Overflow};
+pub use protocol::Codec;
+pub use server::{EngineType, KvServer, Runtime, ServerCLI};
+pub use stats::StatsSnapshot;
+pub use transfer::Format;
+pub use transport::{Address, Listener, Stream};
 
 #[macro_use]
 extern crate log;
@@ -0,0 +1,227 @@
+//! Connection limits and timeouts of the server.
+//!
+//! Connections over `max_connections` are either rejected right after
+//! accept or wait until another connection closes. Reads and writes on the
+//! socket fail after their timeouts, connections waiting for the next
+//! request longer than `idle_timeout` are closed. Pipelined requests not
+//! answered within `request_timeout` get `ResponseError::Timeout`.
+//!
+//! Keys, values and request frames over their size limits are refused with
+//! typed errors. Values uploaded in chunks are checked as they grow.
+
+use crate::cmd::CMD;
+use crate::error::KvsError;
+use crate::protocol::MAX_FRAME_SIZE;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::io;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// What happens to connections accepted over the limit.
+pub enum Overflow {
+    /// Client gets busy error and connection is closed.
+    #[default]
+    Reject,
+    /// Listener stops accepting until a connection closes.
+    Queue,
+}
+
+impl Display for Overflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!("{:?}", self).to_lowercase())
+    }
+}
+
+impl FromStr for Overflow {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> std::result::Result<Overflow, KvsError> {
+        match s.to_lowercase().as_str() {
+            "reject" => Ok(Self::Reject),
+            "queue" => Ok(Self::Queue),
+            _ => Err(KvsError::Parse),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// Limits shared by every listener of the server, `None` means unlimited.
+/// Timeouts are configured in milliseconds.
+pub struct Limits {
+    /// Connections served at once.
+    pub max_connections: Option<usize>,
+    pub overflow: Overflow,
+    /// Single read from the socket once request started arriving, also
+    /// bounds the TLS and protocol handshakes.
+    #[serde(rename = "read_timeout_ms", with = "millis")]
+    pub read_timeout: Option<Duration>,
+    /// Single write to the socket.
+    #[serde(rename = "write_timeout_ms", with = "millis")]
+    pub write_timeout: Option<Duration>,
+    /// Wait for the next request, `read_timeout` is used when not set.
+    #[serde(rename = "idle_timeout_ms", with = "millis")]
+    pub idle_timeout: Option<Duration>,
+    /// Processing of a request, counted from the moment it was read.
+    #[serde(rename = "request_timeout_ms", with = "millis")]
+    pub request_timeout: Option<Duration>,
+    /// Bytes of a key.
+    pub max_key_size: Option<usize>,
+    /// Bytes of a value, including values uploaded in chunks.
+    pub max_value_size: Option<usize>,
+    /// Bytes of a request frame payload, never more than `MAX_FRAME_SIZE`.
+    pub max_frame_size: Option<u32>,
+}
+
+impl Limits {
+    /// Timeout of waiting for the next request.
+    pub fn wait_timeout(&self) -> Option<Duration> {
+        self.idle_timeout.or(self.read_timeout)
+    }
+
+    /// Deadline of requests read now.
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.request_timeout.map(|timeout| Instant::now() + timeout)
+    }
+
+    /// Largest request frame accepted.
+    pub fn frame_size(&self) -> u32 {
+        self.max_frame_size
+            .map_or(MAX_FRAME_SIZE, |max| max.min(MAX_FRAME_SIZE))
+    }
+
+    /// Checks sizes of the key and value of the command.
+    pub fn check(&self, cmd: &CMD) -> Result<()> {
+        if let Some(key) = cmd.key() {
+            self.check_key(key)?;
+        }
+        match cmd {
+            CMD::Set { value, .. } | CMD::CompareAndSet { value, .. } => {
+                self.check_value(value.len())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks size of the key.
+    pub fn check_key(&self, key: &str) -> Result<()> {
+        match self.max_key_size {
+            Some(max) if key.len() > max => Err(KvsError::KeyTooLarge {
+                size: key.len() as u64,
+                max: max as u64,
+            }
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks size of the value, or of its part uploaded so far.
+    pub fn check_value(&self, size: usize) -> Result<()> {
+        match self.max_value_size {
+            Some(max) if size > max => Err(KvsError::ValueTooLarge {
+                size: size as u64,
+                max: max as u64,
+            }
+            .into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Optional durations as whole milliseconds.
+mod millis {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => serializer.serialize_u64(duration.as_millis() as u64),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<u64>::deserialize(deserializer)?.map(Duration::from_millis))
+    }
+}
+
+/// Checks whether request read at the time is past its deadline.
+pub(crate) fn expired(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// Checks whether error comes from socket timeout.
+pub(crate) fn is_timeout(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>().is_some_and(|e| {
+        matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        )
+    })
+}
+
+/// Connection slots of the server, shared by its listeners.
+#[derive(Debug)]
+pub(crate) struct Slots {
+    max: Option<usize>,
+    used: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl Slots {
+    pub fn new(max: Option<usize>) -> Self {
+        Self {
+            max,
+            used: Mutex::new(0),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Takes a slot if one is free.
+    pub fn try_acquire(self: &Arc<Self>) -> Option<Slot> {
+        let max = match self.max {
+            Some(max) => max,
+            None => return Some(Slot(None)),
+        };
+        let mut used = self.used.lock().unwrap();
+        if *used >= max {
+            return None;
+        }
+        *used += 1;
+        Some(Slot(Some(self.clone())))
+    }
+
+    /// Takes a slot, waiting until one is freed.
+    pub fn acquire(self: &Arc<Self>) -> Slot {
+        let max = match self.max {
+            Some(max) => max,
+            None => return Slot(None),
+        };
+        let used = self.used.lock().unwrap();
+        let mut used = self.freed.wait_while(used, |used| *used >= max).unwrap();
+        *used += 1;
+        Slot(Some(self.clone()))
+    }
+}
+
+/// Slot held for the lifetime of a connection.
+pub(crate) struct Slot(Option<Arc<Slots>>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some(slots) = &self.0 {
+            *slots.used.lock().unwrap() -= 1;
+            slots.freed.notify_one();
+        }
+    }
+}
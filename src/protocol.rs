@@ -0,0 +1,446 @@
+//! Framed wire protocol used between `kvs-server` and `kvs-client`.
+//!
+//! Connection starts with a handshake: client sends `MAGIC`, protocol version and
+//! requested codec, server answers with `MAGIC`, its version and a status byte.
+//! After that both sides exchange length-prefixed frames (u32 big endian length
+//! followed by payload encoded with the negotiated codec).
+//!
+//! Since version 2 client follows the hello with `Credentials` frame, answered
+//! with `Response` frame right after the server status byte. Version 1 clients
+//! are served as anonymous.
+//!
+//! Values too large for a single frame are uploaded with `CMD::SetChunk` and
+//! read back with `CMD::StreamGet`, in chunks of at most `CHUNK_SIZE` bytes.
+
+use crate::cmd::{Response, ResponseError, CMD};
+use crate::error::KvsError;
+use crate::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::io::{self, BufReader, Read, Write};
+use std::str::FromStr;
+
+/// First bytes of every framed connection. Legacy json clients start with `{`.
+pub const MAGIC: [u8; 4] = *b"KVSP";
+
+/// Current version of the framed protocol.
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// Oldest version server still accepts.
+const MIN_PROTOCOL_VERSION: u8 = 1;
+
+/// Frames bigger than that are rejected before allocating a buffer for them.
+pub const MAX_FRAME_SIZE: u32 = 64 * 1024 * 1024;
+
+/// Bytes of value carried by single chunk of streamed value.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Bytes of oversized request searched for its id.
+pub(crate) const ID_PREFIX_SIZE: u32 = 32;
+
+/// Handshake status sent by the server.
+const STATUS_OK: u8 = 0;
+const STATUS_UNSUPPORTED_VERSION: u8 = 1;
+const STATUS_UNSUPPORTED_CODEC: u8 = 2;
+const STATUS_BUSY: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+/// Encoding of frame payloads, negotiated during handshake.
+pub enum Codec {
+    /// Human readable, handy for debugging.
+    Json,
+    /// Compact binary encoding.
+    Bincode,
+}
+
+impl Codec {
+    fn to_byte(self) -> u8 {
+        match self {
+            Codec::Json => 0,
+            Codec::Bincode => 1,
+        }
+    }
+
+    fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            0 => Some(Codec::Json),
+            1 => Some(Codec::Bincode),
+            _ => None,
+        }
+    }
+
+    /// Encodes value into bytes.
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>> {
+        Ok(match self {
+            Codec::Json => serde_json::to_vec(value)?,
+            Codec::Bincode => bincode::serialize(value)?,
+        })
+    }
+
+    /// Decodes value from bytes.
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T> {
+        Ok(match self {
+            Codec::Json => serde_json::from_slice(bytes)?,
+            Codec::Bincode => bincode::deserialize(bytes)?,
+        })
+    }
+}
+
+impl Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!("{:?}", self).to_lowercase())
+    }
+}
+
+impl FromStr for Codec {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> std::result::Result<Codec, KvsError> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "bincode" | "binary" => Ok(Self::Bincode),
+            _ => Err(KvsError::Parse),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Client request, `id` is echoed back in matching `ResponseFrame`.
+pub struct RequestFrame {
+    pub id: u64,
+    pub cmd: CMD,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Server response for request with the same `id`.
+pub struct ResponseFrame {
+    pub id: u64,
+    pub response: Response,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Credentials client authenticates with during handshake.
+pub enum Credentials {
+    /// No credentials, client may still be identified by its TLS certificate.
+    Anonymous,
+    /// Token configured for a user, may be shared by many clients.
+    Token(String),
+    Password {
+        user: String,
+        password: String,
+    },
+}
+
+/// Client hello accepted by the server.
+pub struct Hello {
+    pub codec: Codec,
+    /// `None` for version 1 clients, which don't expect authentication result.
+    pub credentials: Option<Credentials>,
+}
+
+/// Performs client side of the handshake.
+pub fn client_handshake<S: Read + Write>(
+    stream: &mut S,
+    codec: Codec,
+    credentials: &Credentials,
+) -> Result<()> {
+    stream.write_all(&client_hello(codec, credentials)?)?;
+    stream.flush()?;
+
+    let mut reply = [0u8; 6];
+    stream.read_exact(&mut reply)?;
+    check_server_reply(&reply, codec)?;
+    check_authentication(read_frame(stream, codec)?)
+}
+
+/// Hello followed by credentials frame, first bytes client sends.
+pub fn client_hello(codec: Codec, credentials: &Credentials) -> Result<Vec<u8>> {
+    let mut hello = vec![0u8; 6];
+    hello[..4].copy_from_slice(&MAGIC);
+    hello[4] = PROTOCOL_VERSION;
+    hello[5] = codec.to_byte();
+    write_frame(&mut hello, codec, credentials)?;
+    Ok(hello)
+}
+
+/// Checks server reply to the hello. On success the reply is followed by
+/// authentication result, see `check_authentication`.
+pub fn check_server_reply(reply: &[u8; 6], codec: Codec) -> Result<()> {
+    if reply[..4] != MAGIC {
+        return Err(KvsError::Protocol("invalid magic in server reply".to_string()).into());
+    }
+    match reply[5] {
+        STATUS_OK => Ok(()),
+        STATUS_UNSUPPORTED_VERSION => Err(KvsError::Protocol(format!(
+            "server speaks version {}, client {}",
+            reply[4], PROTOCOL_VERSION
+        ))
+        .into()),
+        STATUS_UNSUPPORTED_CODEC => {
+            Err(KvsError::Protocol(format!("server does not support codec {}", codec)).into())
+        }
+        STATUS_BUSY => Err(KvsError::Busy.into()),
+        status => Err(KvsError::Protocol(format!("unknown handshake status {}", status)).into()),
+    }
+}
+
+/// Reply sent instead of reading the hello when server is over its
+/// connection limit.
+pub fn busy_reply() -> [u8; 6] {
+    let mut reply = [0u8; 6];
+    reply[..4].copy_from_slice(&MAGIC);
+    reply[4] = PROTOCOL_VERSION;
+    reply[5] = STATUS_BUSY;
+    reply
+}
+
+/// Checks authentication result that ends the handshake.
+pub fn check_authentication(response: Option<Response>) -> Result<()> {
+    match response {
+        Some(Response::Ok) => Ok(()),
+        Some(Response::Err(e)) => Err(KvsError::from(e).into()),
+        _ => Err(KvsError::Protocol("invalid authentication reply".to_string()).into()),
+    }
+}
+
+/// Checks whether client hello is followed by `Credentials` frame, so that
+/// servers reading the connection on their own know where the handshake ends.
+pub fn hello_has_credentials(hello: &[u8; 6]) -> bool {
+    hello[..4] == MAGIC
+        && (2..=PROTOCOL_VERSION).contains(&hello[4])
+        && Codec::from_byte(hello[5]).is_some()
+}
+
+/// Performs server side of the handshake. Authentication result has to be
+/// sent by the caller when hello carries credentials.
+pub fn server_handshake<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<Hello> {
+    let mut hello = [0u8; 6];
+    reader.read_exact(&mut hello)?;
+    if hello[..4] != MAGIC {
+        return Err(KvsError::Protocol("invalid magic".to_string()).into());
+    }
+
+    let codec = Codec::from_byte(hello[5]);
+    let version = hello[4];
+    let status = if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        STATUS_UNSUPPORTED_VERSION
+    } else if codec.is_none() {
+        STATUS_UNSUPPORTED_CODEC
+    } else {
+        STATUS_OK
+    };
+
+    let credentials = match codec {
+        Some(codec) if status == STATUS_OK && version >= 2 => Some(
+            read_frame(reader, codec)?
+                .ok_or_else(|| KvsError::Protocol("missing credentials".to_string()))?,
+        ),
+        _ => None,
+    };
+
+    let mut reply = [0u8; 6];
+    reply[..4].copy_from_slice(&MAGIC);
+    reply[4] = PROTOCOL_VERSION;
+    reply[5] = status;
+    writer.write_all(&reply)?;
+    writer.flush()?;
+
+    match codec {
+        Some(codec) if status == STATUS_OK => Ok(Hello { codec, credentials }),
+        _ => Err(KvsError::Protocol(format!(
+            "rejected handshake, version: {}, codec: {}",
+            hello[4], hello[5]
+        ))
+        .into()),
+    }
+}
+
+/// Writes single length-prefixed frame. Caller is responsible for flushing.
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, codec: Codec, value: &T) -> Result<()> {
+    let payload = codec.encode(value)?;
+    let len: u32 = payload
+        .len()
+        .try_into()
+        .map_err(|_| KvsError::Protocol("frame too large".to_string()))?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(&payload)?;
+    Ok(())
+}
+
+/// Reads single length-prefixed frame. Returns `None` when peer closed
+/// connection on frame boundary.
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R, codec: Codec) -> Result<Option<T>> {
+    read_frame_within(reader, codec, MAX_FRAME_SIZE)
+}
+
+/// Reads single frame like `read_frame`, failing before anything is
+/// allocated when it is longer than `max`.
+pub fn read_frame_within<R: Read, T: DeserializeOwned>(
+    reader: &mut R,
+    codec: Codec,
+    max: u32,
+) -> Result<Option<T>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let len = u32::from_be_bytes(len);
+    if len > max.min(MAX_FRAME_SIZE) {
+        return Err(KvsError::Protocol(format!("frame of {} bytes is too large", len)).into());
+    }
+
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(codec.decode(&payload)?))
+}
+
+/// Reads one frame, blocking if needed, followed by every complete frame that
+/// is already buffered, up to `max` frames. This way requests pipelined by the
+/// client are picked up together. Empty result means peer closed connection.
+pub fn read_pipelined<R: Read, T: DeserializeOwned>(
+    reader: &mut BufReader<R>,
+    codec: Codec,
+    max: usize,
+) -> Result<Vec<T>> {
+    let mut frames = vec![];
+    while let Some(frame) = read_frame(reader, codec)? {
+        frames.push(frame);
+        if frames.len() >= max || !frame_buffered(reader.buffer()) {
+            break;
+        }
+    }
+    Ok(frames)
+}
+
+/// Checks whether buffer holds whole frame.
+pub(crate) fn frame_buffered(buf: &[u8]) -> bool {
+    if buf.len() < 4 {
+        return false;
+    }
+    let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    buf.len() >= 4 + len
+}
+
+/// Reads pipelined requests like `read_pipelined`. Requests over
+/// `max_frame_size` are skipped without buffering them and answered with
+/// `ResponseError::FrameTooLarge` in place of the request. Connection fails
+/// when id of such request can't be found.
+pub fn read_requests<R: Read>(
+    reader: &mut BufReader<R>,
+    codec: Codec,
+    max: usize,
+    max_frame_size: u32,
+) -> Result<Vec<std::result::Result<RequestFrame, ResponseFrame>>> {
+    let mut frames = vec![];
+    loop {
+        let mut len = [0u8; 4];
+        match reader.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+
+        let len = u32::from_be_bytes(len);
+        if len > max_frame_size {
+            let mut prefix = vec![0u8; len.min(ID_PREFIX_SIZE) as usize];
+            reader.read_exact(&mut prefix)?;
+            io::copy(
+                &mut reader.take((len - prefix.len() as u32).into()),
+                &mut io::sink(),
+            )?;
+            frames.push(Err(oversized(codec, &prefix, len, max_frame_size)?));
+        } else {
+            let mut payload = vec![0u8; len as usize];
+            reader.read_exact(&mut payload)?;
+            frames.push(Ok(codec.decode(&payload)?));
+        }
+
+        if frames.len() >= max || !frame_buffered(reader.buffer()) {
+            break;
+        }
+    }
+    Ok(frames)
+}
+
+/// Answer to request skipped for its size, `prefix` holds the first bytes
+/// of its payload.
+pub(crate) fn oversized(codec: Codec, prefix: &[u8], len: u32, max: u32) -> Result<ResponseFrame> {
+    let error = KvsError::FrameTooLarge {
+        size: len.into(),
+        max: max.into(),
+    };
+    let id = request_id(codec, prefix).ok_or(error)?;
+    Ok(ResponseFrame {
+        id,
+        response: Response::Err(ResponseError::FrameTooLarge {
+            size: len.into(),
+            max: max.into(),
+        }),
+    })
+}
+
+/// Finds id of `RequestFrame` in the first bytes of its payload, it is
+/// encoded before the command by both codecs.
+fn request_id(codec: Codec, prefix: &[u8]) -> Option<u64> {
+    match codec {
+        Codec::Bincode => Some(u64::from_le_bytes(prefix.get(..8)?.try_into().ok()?)),
+        Codec::Json => {
+            let rest = prefix.strip_prefix(br#"{"id":"#)?;
+            let end = rest.iter().position(|b| !b.is_ascii_digit())?;
+            std::str::from_utf8(&rest[..end]).ok()?.parse().ok()
+        }
+    }
+}
+
+/// Splits UTF-8 value read from `R` into chunks of at most `CHUNK_SIZE`
+/// bytes, ending on character boundaries.
+pub(crate) struct Chunks<R> {
+    reader: R,
+    /// Bytes of character split at the end of previous chunk.
+    partial: Vec<u8>,
+    done: bool,
+}
+
+impl<R: Read> Chunks<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            partial: vec![],
+            done: false,
+        }
+    }
+
+    /// Reads next chunk and whether it is the last one, `None` after the
+    /// last one was returned.
+    pub fn next_chunk(&mut self) -> Result<Option<(String, bool)>> {
+        if self.done {
+            return Ok(None);
+        }
+        let mut buf = std::mem::take(&mut self.partial);
+        let mut filled = buf.len();
+        buf.resize(CHUNK_SIZE, 0);
+        while filled < CHUNK_SIZE {
+            match self.reader.read(&mut buf[filled..])? {
+                0 => {
+                    self.done = true;
+                    break;
+                }
+                n => filled += n,
+            }
+        }
+        buf.truncate(filled);
+
+        let valid = match std::str::from_utf8(&buf) {
+            Ok(chunk) => chunk.len(),
+            // rest of the character follows in the next chunk.
+            Err(e) if !self.done && e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => return Err(e.into()),
+        };
+        self.partial = buf.split_off(valid);
+        Ok(Some((String::from_utf8(buf)?, self.done)))
+    }
+}
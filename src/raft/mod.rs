@@ -0,0 +1,361 @@
+//! Raft-replicated engine.
+//!
+//! `RaftEngine` wraps a local engine of every cluster node. Writes are
+//! appended to the Raft log by the leader and applied to local engines once
+//! committed by a majority, so all nodes apply the same writes in the same
+//! order. Reads are linearizable by default: leader confirms it is still
+//! leader with a heartbeat round and waits until its engine applied
+//! everything committed when the read arrived (the "read index"). Stale
+//! reads go straight to the local engine of any node.
+//!
+//! Nodes that are not leaders fail requests with `KvsError::NotLeader`
+//! carrying client address of the leader, if known. The log is compacted
+//! into snapshots taken from the engine, followers too far behind receive
+//! the snapshot instead of log entries.
+//!
+//! Membership is changed one node at a time by committing new configuration
+//! through the log. A node started with empty membership waits for the
+//! leader to contact it after it was added.
+
+mod node;
+mod storage;
+mod transport;
+
+pub use transport::{Mailbox, SimulatedNetwork, TcpTransport, Transport};
+
+use crate::error::KvsError;
+use crate::{KvsEngine, Result};
+use crossbeam::channel::{self, Receiver, Sender};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+/// Identifier of a node, unique within the cluster.
+pub type NodeId = u64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Addresses of a cluster node.
+pub struct Member {
+    /// Address of Raft transport, used by other nodes.
+    pub raft_addr: String,
+    /// Address clients are redirected to when node is the leader.
+    pub client_addr: String,
+}
+
+/// Voting members of the cluster.
+pub type Membership = BTreeMap<NodeId, Member>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Change of cluster membership, one node at a time.
+pub enum MembershipChange {
+    Add { id: NodeId, member: Member },
+    Remove { id: NodeId },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Operation stored in the log.
+pub enum Operation {
+    /// Appended by every new leader to commit entries of previous terms.
+    Noop,
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    CompareAndSet {
+        key: String,
+        expected: Option<String>,
+        value: String,
+    },
+    CompareAndRemove {
+        key: String,
+        expected: String,
+    },
+    /// New membership, takes effect as soon as it is appended.
+    Membership(Membership),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Entry of the replicated log.
+pub struct Entry {
+    pub term: u64,
+    pub index: u64,
+    pub operation: Operation,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Every value of the engine after applying entries up to `index`.
+pub struct Snapshot {
+    pub index: u64,
+    pub term: u64,
+    pub membership: Membership,
+    pub entries: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Snapshot is sent in chunks of bounded size, `entries` follow the first
+/// `offset` entries of the snapshot. Follower installs it after the chunk
+/// that is `done`.
+pub struct SnapshotChunk {
+    pub index: u64,
+    pub term: u64,
+    pub membership: Membership,
+    pub offset: u64,
+    pub entries: Vec<(String, String)>,
+    pub done: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Message exchanged between nodes.
+pub struct Message {
+    pub from: NodeId,
+    /// Raft address of the sender, lets nodes that don't know the leader
+    /// yet answer it.
+    pub from_addr: String,
+    pub to: NodeId,
+    pub term: u64,
+    pub body: MessageBody,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MessageBody {
+    RequestVote {
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    Vote {
+        granted: bool,
+    },
+    /// Log entries following `prev_log_index`, empty for heartbeats. `seq`
+    /// is echoed back so the leader knows which heartbeat round was acked.
+    AppendEntries {
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+        seq: u64,
+    },
+    /// Part of the leader's snapshot, see `SnapshotChunk`.
+    InstallSnapshot {
+        chunk: SnapshotChunk,
+        seq: u64,
+    },
+    /// Answer to a snapshot chunk other than the last one, `offset` is the
+    /// number of entries follower holds so far.
+    SnapshotAck {
+        index: u64,
+        offset: u64,
+        seq: u64,
+    },
+    /// Answer to `AppendEntries` and the last `InstallSnapshot`. On success
+    /// `match_index` is the last index known to match the leader, on
+    /// failure the index leader should retry after.
+    AppendResponse {
+        success: bool,
+        match_index: u64,
+        seq: u64,
+    },
+}
+
+#[derive(Debug, Clone)]
+/// Configuration of a Raft node.
+pub struct RaftOptions {
+    pub id: NodeId,
+    /// Initial membership, used until the log holds another one. Empty for
+    /// nodes joining existing cluster.
+    pub members: Membership,
+    /// Directory persisting term, vote, log and snapshot. State is kept in
+    /// memory only when `None`.
+    pub dir: Option<PathBuf>,
+    /// Number of applied entries after which a snapshot is taken.
+    pub snapshot_threshold: u64,
+    /// Minimal election timeout, actual timeout is random between it and
+    /// twice as much.
+    pub election_timeout: Duration,
+    pub heartbeat_interval: Duration,
+    /// How long client requests wait for commit before failing.
+    pub request_timeout: Duration,
+}
+
+impl RaftOptions {
+    pub fn new(id: NodeId, members: Membership) -> Self {
+        Self {
+            id,
+            members,
+            dir: None,
+            snapshot_threshold: 1000,
+            election_timeout: Duration::from_millis(300),
+            heartbeat_interval: Duration::from_millis(50),
+            request_timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Persists state in the directory.
+    pub fn with_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = Some(dir.into());
+        self
+    }
+
+    pub fn with_snapshot_threshold(mut self, threshold: u64) -> Self {
+        self.snapshot_threshold = threshold;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// State of a node as seen by itself.
+pub struct RaftStatus {
+    pub id: NodeId,
+    pub term: u64,
+    pub leader: Option<NodeId>,
+    pub commit_index: u64,
+    pub applied_index: u64,
+    pub membership: Membership,
+}
+
+/// Request to the node thread.
+pub(crate) enum Event {
+    Message(Message),
+    Propose(Operation, Sender<Result<bool>>),
+    ChangeMembership(MembershipChange, Sender<Result<bool>>),
+    Read(Sender<Result<()>>),
+    Status(Sender<RaftStatus>),
+}
+
+/// Engine whose writes are replicated through Raft, see module docs.
+pub struct RaftEngine<E: KvsEngine> {
+    local: E,
+    events: Sender<Event>,
+    request_timeout: Duration,
+}
+
+impl<E: KvsEngine> Clone for RaftEngine<E> {
+    fn clone(&self) -> Self {
+        Self {
+            local: self.local.clone(),
+            events: self.events.clone(),
+            request_timeout: self.request_timeout,
+        }
+    }
+}
+
+impl<E: KvsEngine> RaftEngine<E> {
+    /// Starts the node on a background thread. Local engine is rebuilt from
+    /// the persisted snapshot and log, so it must not be written to directly.
+    /// Messages from other nodes have to be delivered to `mailbox()`.
+    pub fn start<T: Transport>(options: RaftOptions, local: E, transport: T) -> Result<Self> {
+        let (events, inbox) = channel::unbounded();
+        let request_timeout = options.request_timeout;
+        let node = node::Node::new(options, local.clone(), transport)?;
+        thread::spawn(move || node.run(inbox));
+        Ok(Self {
+            local,
+            events,
+            request_timeout,
+        })
+    }
+
+    /// Where transports deliver messages for this node.
+    pub fn mailbox(&self) -> Mailbox {
+        Mailbox::new(self.events.clone())
+    }
+
+    /// Current state of the node.
+    pub fn status(&self) -> Result<RaftStatus> {
+        let (sender, receiver) = channel::bounded(1);
+        self.send(Event::Status(sender))?;
+        Ok(receiver.recv()?)
+    }
+
+    /// Adds or removes a voting member, must be sent to the leader.
+    pub fn change_membership(&self, change: MembershipChange) -> Result<()> {
+        let (sender, receiver) = channel::bounded(1);
+        self.send(Event::ChangeMembership(change, sender))?;
+        self.wait(receiver).map(|_| ())
+    }
+
+    /// Waits until the read index is applied, see module docs.
+    fn read_barrier(&self) -> Result<()> {
+        let (sender, receiver) = channel::bounded(1);
+        self.send(Event::Read(sender))?;
+        self.wait(receiver)
+    }
+
+    /// Commits operation through the log, returns result of applying it.
+    fn propose(&self, operation: Operation) -> Result<bool> {
+        let (sender, receiver) = channel::bounded(1);
+        self.send(Event::Propose(operation, sender))?;
+        self.wait(receiver)
+    }
+
+    fn send(&self, event: Event) -> Result<()> {
+        self.events
+            .send(event)
+            .map_err(|_| KvsError::Server("raft node stopped".to_owned()).into())
+    }
+
+    fn wait<T>(&self, receiver: Receiver<Result<T>>) -> Result<T> {
+        receiver
+            .recv_timeout(self.request_timeout)
+            .map_err(|_| anyhow::Error::from(KvsError::Timeout))?
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for RaftEngine<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.propose(Operation::Set { key, value }).map(|_| ())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.read_barrier()?;
+        self.local.get(key)
+    }
+
+    fn get_stale(&self, key: String) -> Result<Option<String>> {
+        self.local.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.propose(Operation::Remove { key }).map(|_| ())
+    }
+
+    fn compare_and_set(
+        &self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<bool> {
+        self.propose(Operation::CompareAndSet {
+            key,
+            expected,
+            value,
+        })
+    }
+
+    fn compare_and_remove(&self, key: String, expected: String) -> Result<bool> {
+        self.propose(Operation::CompareAndRemove { key, expected })
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<String>> {
+        self.read_barrier()?;
+        self.local.scan(prefix)
+    }
+
+    fn scan_page(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        self.read_barrier()?;
+        self.local.scan_page(prefix, after, limit)
+    }
+
+    fn change_membership(&self, change: MembershipChange) -> Result<()> {
+        RaftEngine::change_membership(self, change)
+    }
+}
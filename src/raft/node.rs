@@ -0,0 +1,1012 @@
+//! Raft state machine of a single node, driven by one thread.
+
+use super::storage::{HardState, Storage};
+use super::{
+    Entry, Event, Membership, MembershipChange, Message, MessageBody, NodeId, Operation,
+    RaftOptions, RaftStatus, Snapshot, SnapshotChunk, Transport,
+};
+use crate::error::KvsError;
+use crate::{replication, KvsEngine, Result};
+use crossbeam::channel::{Receiver, RecvTimeoutError, Sender};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::thread::JoinHandle;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Upper bound of entries sent in one `AppendEntries`.
+const MAX_ENTRIES: usize = 256;
+
+/// Bytes of keys and values sent in one `InstallSnapshot`, a single larger
+/// value is still sent whole.
+const SNAPSHOT_CHUNK_SIZE: usize = 1024 * 1024;
+
+enum Role {
+    Follower,
+    Candidate { votes: HashSet<NodeId> },
+    Leader(Leader),
+}
+
+struct Leader {
+    progress: HashMap<NodeId, Progress>,
+    /// Last heartbeat round sent to followers.
+    seq: u64,
+    /// Index of the `Noop` appended on election, reads wait for it.
+    start_index: u64,
+    /// Reads waiting for a quorum to ack heartbeat round `seq`.
+    reads: Vec<(u64, u64, Sender<Result<()>>)>,
+}
+
+/// Replication state of a follower, kept by the leader.
+struct Progress {
+    /// Next index to send, advanced optimistically.
+    next: u64,
+    /// Highest index known to be replicated.
+    matched: u64,
+    /// Highest heartbeat round follower answered.
+    acked_seq: u64,
+    /// Entries of the snapshot follower acknowledged, while sending it.
+    snapshot_offset: u64,
+}
+
+pub(super) struct Node<E: KvsEngine, T: Transport> {
+    id: NodeId,
+    engine: E,
+    transport: T,
+    storage: Storage,
+    options: RaftOptions,
+    state: HardState,
+    /// Latest snapshot, the log holds entries following it.
+    snapshot: Snapshot,
+    /// Snapshot being received from the leader, chunk by chunk.
+    incoming: Option<Snapshot>,
+    /// Snapshot being written in the background, the log keeps the entries
+    /// it covers until it is saved.
+    saving: Option<JoinHandle<Result<Snapshot>>>,
+    log: Vec<Entry>,
+    /// Latest membership in the log.
+    membership: Membership,
+    /// Addresses of nodes that sent messages, for nodes outside membership.
+    addresses: HashMap<NodeId, String>,
+    commit: u64,
+    applied: u64,
+    leader: Option<NodeId>,
+    role: Role,
+    heard_from_leader: Instant,
+    election_deadline: Instant,
+    heartbeat_deadline: Instant,
+    /// Reply channels of proposals by log index, with term of their entry.
+    proposals: BTreeMap<u64, (u64, Sender<Result<bool>>)>,
+    /// Confirmed reads waiting for their index to be applied.
+    reads: Vec<(u64, Sender<Result<()>>)>,
+    rng: u64,
+}
+
+impl<E: KvsEngine, T: Transport> Node<E, T> {
+    pub fn new(options: RaftOptions, engine: E, transport: T) -> Result<Self> {
+        let (storage, persisted) = Storage::open(options.dir.clone())?;
+        let snapshot = persisted.snapshot.unwrap_or_else(|| Snapshot {
+            index: 0,
+            term: 0,
+            membership: options.members.clone(),
+            entries: vec![],
+        });
+        // engine is rebuilt by replaying log over the snapshot.
+        replication::restore(&engine, snapshot.entries.clone())?;
+
+        let now = Instant::now();
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        let rng = (seed ^ options.id.wrapping_mul(0x9e37_79b9_7f4a_7c15)) | 1;
+        let mut node = Self {
+            id: options.id,
+            engine,
+            transport,
+            storage,
+            state: persisted.state,
+            commit: snapshot.index,
+            applied: snapshot.index,
+            membership: Membership::new(),
+            addresses: HashMap::new(),
+            snapshot,
+            incoming: None,
+            saving: None,
+            log: persisted.entries,
+            options,
+            leader: None,
+            role: Role::Follower,
+            heard_from_leader: now,
+            election_deadline: now,
+            heartbeat_deadline: now,
+            proposals: BTreeMap::new(),
+            reads: vec![],
+            rng,
+        };
+        node.refresh_membership();
+        node.reset_election_timer();
+        info!(
+            "Raft node {} starting at term {} with {} log entries",
+            node.id,
+            node.state.term,
+            node.log.len()
+        );
+        Ok(node)
+    }
+
+    /// Handles events until every sender is gone.
+    pub fn run(mut self, inbox: Receiver<Event>) {
+        loop {
+            let deadline = match self.role {
+                Role::Leader(_) => self.heartbeat_deadline,
+                _ => self.election_deadline,
+            };
+            let result = match inbox.recv_deadline(deadline) {
+                Ok(event) => self.handle(event),
+                Err(RecvTimeoutError::Timeout) => Ok(()),
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+            if let Err(e) = result.and_then(|_| self.tick()).and_then(|_| self.apply()) {
+                error!("Raft node {} failed: {}", self.id, e);
+            }
+        }
+    }
+
+    fn handle(&mut self, event: Event) -> Result<()> {
+        match event {
+            Event::Message(message) => self.step(message)?,
+            Event::Propose(operation, reply) => {
+                if !self.is_leader() {
+                    let _ = reply.send(Err(self.not_leader()));
+                    return Ok(());
+                }
+                let index = self.append(operation)?;
+                self.proposals.insert(index, (self.state.term, reply));
+                self.broadcast_append();
+                self.advance_commit();
+            }
+            Event::ChangeMembership(change, reply) => {
+                if !self.is_leader() {
+                    let _ = reply.send(Err(self.not_leader()));
+                    return Ok(());
+                }
+                if self.membership_index() > self.commit {
+                    let _ = reply.send(Err(KvsError::Server(
+                        "another membership change is in progress".to_owned(),
+                    )
+                    .into()));
+                    return Ok(());
+                }
+                let mut membership = self.membership.clone();
+                match change {
+                    MembershipChange::Add { id, member } => membership.insert(id, member),
+                    MembershipChange::Remove { id } => membership.remove(&id),
+                };
+                let index = self.append(Operation::Membership(membership))?;
+                self.proposals.insert(index, (self.state.term, reply));
+                self.broadcast_append();
+                self.advance_commit();
+            }
+            Event::Read(reply) => {
+                let (seq, index) = match &self.role {
+                    Role::Leader(leader) => (leader.seq + 1, self.commit.max(leader.start_index)),
+                    _ => {
+                        let _ = reply.send(Err(self.not_leader()));
+                        return Ok(());
+                    }
+                };
+                if let Role::Leader(leader) = &mut self.role {
+                    leader.reads.push((seq, index, reply));
+                }
+                self.broadcast_append();
+                self.confirm_reads();
+            }
+            Event::Status(reply) => {
+                let _ = reply.send(RaftStatus {
+                    id: self.id,
+                    term: self.state.term,
+                    leader: self.leader,
+                    commit_index: self.commit,
+                    applied_index: self.applied,
+                    membership: self.membership.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts elections and sends heartbeats when their time comes.
+    fn tick(&mut self) -> Result<()> {
+        let now = Instant::now();
+        if self.is_leader() {
+            if now >= self.heartbeat_deadline {
+                self.broadcast_append();
+            }
+        } else if now >= self.election_deadline {
+            if self.membership.contains_key(&self.id) {
+                self.campaign()?;
+            } else {
+                self.reset_election_timer();
+            }
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, message: Message) -> Result<()> {
+        let Message {
+            from,
+            from_addr,
+            term,
+            body,
+            ..
+        } = message;
+        if !from_addr.is_empty() {
+            self.addresses.insert(from, from_addr);
+        }
+
+        if term > self.state.term {
+            // node removed from membership or partitioned away doesn't
+            // disrupt leader that is still alive.
+            if matches!(body, MessageBody::RequestVote { .. }) && self.leader_alive() {
+                return Ok(());
+            }
+            self.become_follower(term, None)?;
+        }
+        if term < self.state.term {
+            match body {
+                MessageBody::RequestVote { .. } => {
+                    self.send(from, MessageBody::Vote { granted: false })
+                }
+                MessageBody::AppendEntries { seq, .. }
+                | MessageBody::InstallSnapshot { seq, .. } => self.send(
+                    from,
+                    MessageBody::AppendResponse {
+                        success: false,
+                        match_index: 0,
+                        seq,
+                    },
+                ),
+                _ => {}
+            }
+            return Ok(());
+        }
+
+        match body {
+            MessageBody::RequestVote {
+                last_log_index,
+                last_log_term,
+            } => {
+                let can_vote = self.state.voted_for.is_none_or(|id| id == from);
+                let up_to_date =
+                    (last_log_term, last_log_index) >= (self.last_term(), self.last_index());
+                let granted = can_vote && up_to_date;
+                if granted {
+                    self.state.voted_for = Some(from);
+                    self.storage.save_state(self.state)?;
+                    self.reset_election_timer();
+                }
+                self.send(from, MessageBody::Vote { granted });
+            }
+            MessageBody::Vote { granted } => {
+                let won = match &mut self.role {
+                    Role::Candidate { votes } if granted => {
+                        votes.insert(from);
+                        let votes = votes.clone();
+                        self.quorum(|id| votes.contains(&id))
+                    }
+                    _ => false,
+                };
+                if won {
+                    self.become_leader()?;
+                }
+            }
+            MessageBody::AppendEntries {
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+                seq,
+            } => {
+                self.follow(from)?;
+                let (success, match_index) =
+                    self.append_entries(prev_log_index, prev_log_term, entries, leader_commit)?;
+                self.send(
+                    from,
+                    MessageBody::AppendResponse {
+                        success,
+                        match_index,
+                        seq,
+                    },
+                );
+            }
+            MessageBody::InstallSnapshot { chunk, seq } => {
+                self.follow(from)?;
+                let body = self.receive_snapshot(chunk, seq)?;
+                self.send(from, body);
+            }
+            MessageBody::SnapshotAck { index, offset, seq } => {
+                self.handle_snapshot_ack(from, index, offset, seq)
+            }
+            MessageBody::AppendResponse {
+                success,
+                match_index,
+                seq,
+            } => self.handle_append_response(from, success, match_index, seq),
+        }
+        Ok(())
+    }
+
+    fn campaign(&mut self) -> Result<()> {
+        self.state.term += 1;
+        self.state.voted_for = Some(self.id);
+        self.storage.save_state(self.state)?;
+        self.fail_pending();
+        self.leader = None;
+        self.role = Role::Candidate {
+            votes: HashSet::from([self.id]),
+        };
+        self.reset_election_timer();
+        debug!("Node {} campaigning in term {}", self.id, self.state.term);
+
+        let body = MessageBody::RequestVote {
+            last_log_index: self.last_index(),
+            last_log_term: self.last_term(),
+        };
+        for id in self.peers() {
+            self.send(id, body.clone());
+        }
+        if self.quorum(|id| id == self.id) {
+            self.become_leader()?;
+        }
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> Result<()> {
+        info!("Node {} is leader of term {}", self.id, self.state.term);
+        let next = self.last_index() + 1;
+        let progress = self
+            .peers()
+            .into_iter()
+            .map(|id| {
+                let progress = Progress {
+                    next,
+                    matched: 0,
+                    acked_seq: 0,
+                    snapshot_offset: 0,
+                };
+                (id, progress)
+            })
+            .collect();
+        self.role = Role::Leader(Leader {
+            progress,
+            seq: 0,
+            start_index: next,
+            reads: vec![],
+        });
+        self.leader = Some(self.id);
+        self.append(Operation::Noop)?;
+        self.broadcast_append();
+        self.advance_commit();
+        Ok(())
+    }
+
+    /// Moves to the term, if newer, as a follower.
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) -> Result<()> {
+        if term > self.state.term {
+            self.state = HardState {
+                term,
+                voted_for: None,
+            };
+            self.storage.save_state(self.state)?;
+        }
+        if !matches!(self.role, Role::Follower) {
+            self.fail_pending();
+            self.role = Role::Follower;
+        }
+        self.leader = leader;
+        Ok(())
+    }
+
+    /// Accepts `from` as the leader of current term.
+    fn follow(&mut self, from: NodeId) -> Result<()> {
+        self.become_follower(self.state.term, Some(from))?;
+        self.heard_from_leader = Instant::now();
+        self.reset_election_timer();
+        Ok(())
+    }
+
+    /// Appends entries from the leader, returns whether log matched and
+    /// `match_index` of `AppendResponse`.
+    fn append_entries(
+        &mut self,
+        mut prev_log_index: u64,
+        mut prev_log_term: u64,
+        mut entries: Vec<Entry>,
+        leader_commit: u64,
+    ) -> Result<(bool, u64)> {
+        // entries covered by the snapshot are committed, hence matching.
+        if prev_log_index < self.snapshot.index {
+            entries.retain(|entry| entry.index > self.snapshot.index);
+            prev_log_index = self.snapshot.index;
+            prev_log_term = self.snapshot.term;
+        }
+        if self.term_at(prev_log_index) != Some(prev_log_term) {
+            return Ok((false, self.last_index().min(prev_log_index - 1)));
+        }
+
+        let match_index = prev_log_index + entries.len() as u64;
+        let mut new = vec![];
+        for entry in entries {
+            match self.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    debug!("Node {} truncating log from {}", self.id, entry.index);
+                    self.log
+                        .truncate((entry.index - self.snapshot.index - 1) as usize);
+                    self.storage.rewrite_log(&self.log)?;
+                    self.fail_proposals_from(entry.index);
+                    new.push(entry);
+                }
+                None => new.push(entry),
+            }
+        }
+        if !new.is_empty() {
+            self.storage.append(&new)?;
+            self.log.extend(new);
+            self.refresh_membership();
+        }
+        if leader_commit > self.commit {
+            self.commit = leader_commit.min(match_index).max(self.commit);
+        }
+        Ok((true, match_index))
+    }
+
+    /// Collects chunks of the leader's snapshot, installs it once the last
+    /// one arrives. Returns answer to the chunk.
+    fn receive_snapshot(&mut self, chunk: SnapshotChunk, seq: u64) -> Result<MessageBody> {
+        let index = chunk.index;
+        if chunk.offset == 0 {
+            self.incoming = Some(Snapshot {
+                index,
+                term: chunk.term,
+                membership: chunk.membership,
+                entries: vec![],
+            });
+        }
+        let offset = match &mut self.incoming {
+            Some(snapshot)
+                if snapshot.index == index && snapshot.entries.len() as u64 == chunk.offset =>
+            {
+                snapshot.entries.extend(chunk.entries);
+                snapshot.entries.len() as u64
+            }
+            // chunk is lost or duplicated, leader resends from the offset.
+            Some(snapshot) if snapshot.index == index => {
+                return Ok(MessageBody::SnapshotAck {
+                    index,
+                    offset: snapshot.entries.len() as u64,
+                    seq,
+                })
+            }
+            _ => {
+                return Ok(MessageBody::SnapshotAck {
+                    index,
+                    offset: 0,
+                    seq,
+                })
+            }
+        };
+        if !chunk.done {
+            return Ok(MessageBody::SnapshotAck { index, offset, seq });
+        }
+
+        if let Some(snapshot) = self.incoming.take() {
+            if snapshot.index > self.commit {
+                self.install_snapshot(snapshot)?;
+            }
+        }
+        Ok(MessageBody::AppendResponse {
+            success: true,
+            match_index: index,
+            seq,
+        })
+    }
+
+    fn install_snapshot(&mut self, snapshot: Snapshot) -> Result<()> {
+        info!(
+            "Node {} installing snapshot of {} values at {}",
+            self.id,
+            snapshot.entries.len(),
+            snapshot.index
+        );
+        self.finish_snapshot(true)?;
+        if self.term_at(snapshot.index) == Some(snapshot.term) {
+            let covered = (snapshot.index - self.snapshot.index) as usize;
+            self.log.drain(..covered);
+        } else {
+            self.log.clear();
+        }
+        replication::restore(&self.engine, snapshot.entries.clone())?;
+        self.storage.save_snapshot(&snapshot)?;
+        self.storage.rewrite_log(&self.log)?;
+        self.commit = snapshot.index;
+        self.applied = snapshot.index;
+        self.snapshot = snapshot;
+        self.refresh_membership();
+        Ok(())
+    }
+
+    fn handle_append_response(&mut self, from: NodeId, success: bool, index: u64, seq: u64) {
+        let retry = match &mut self.role {
+            Role::Leader(leader) => match leader.progress.get_mut(&from) {
+                Some(progress) => {
+                    progress.acked_seq = progress.acked_seq.max(seq);
+                    if success {
+                        progress.matched = progress.matched.max(index);
+                        progress.next = progress.next.max(progress.matched + 1);
+                        false
+                    } else {
+                        progress.next = (index + 1).max(progress.matched + 1);
+                        true
+                    }
+                }
+                None => return,
+            },
+            _ => return,
+        };
+        if retry {
+            self.send_append(from);
+        }
+        self.advance_commit();
+        self.confirm_reads();
+    }
+
+    /// Sends the next snapshot chunk once follower acknowledged the previous
+    /// one. Other acks only move the offset back, the chunk is resent with
+    /// the next heartbeat.
+    fn handle_snapshot_ack(&mut self, from: NodeId, index: u64, offset: u64, seq: u64) {
+        let current = index == self.snapshot.index;
+        let advanced = match &mut self.role {
+            Role::Leader(leader) => match leader.progress.get_mut(&from) {
+                Some(progress) => {
+                    progress.acked_seq = progress.acked_seq.max(seq);
+                    let advanced = current && offset > progress.snapshot_offset;
+                    progress.snapshot_offset = if current { offset } else { 0 };
+                    advanced
+                }
+                None => return,
+            },
+            _ => return,
+        };
+        if advanced {
+            self.send_append(from);
+        }
+        self.confirm_reads();
+    }
+
+    /// Sends entries or heartbeat to every follower, starting a new round.
+    fn broadcast_append(&mut self) {
+        if let Role::Leader(leader) = &mut self.role {
+            leader.seq += 1;
+        }
+        for id in self.peers() {
+            self.send_append(id);
+        }
+        self.heartbeat_deadline = Instant::now() + self.options.heartbeat_interval;
+    }
+
+    fn send_append(&mut self, to: NodeId) {
+        let (next, offset, seq) = match &self.role {
+            Role::Leader(leader) => match leader.progress.get(&to) {
+                Some(progress) => (progress.next, progress.snapshot_offset, leader.seq),
+                None => return,
+            },
+            _ => return,
+        };
+
+        let (body, sent) = if next <= self.snapshot.index {
+            let chunk = self.snapshot_chunk(offset as usize);
+            // follower is sent the same chunk again until it acks it.
+            let sent = if chunk.done {
+                self.snapshot.index
+            } else {
+                next - 1
+            };
+            if chunk.done {
+                if let Role::Leader(leader) = &mut self.role {
+                    if let Some(progress) = leader.progress.get_mut(&to) {
+                        progress.snapshot_offset = 0;
+                    }
+                }
+            }
+            (MessageBody::InstallSnapshot { chunk, seq }, sent)
+        } else {
+            let start = (next - self.snapshot.index - 1) as usize;
+            let entries: Vec<Entry> = self.log[start.min(self.log.len())..]
+                .iter()
+                .take(MAX_ENTRIES)
+                .cloned()
+                .collect();
+            let sent = next - 1 + entries.len() as u64;
+            let body = MessageBody::AppendEntries {
+                prev_log_index: next - 1,
+                prev_log_term: self.term_at(next - 1).unwrap_or_default(),
+                entries,
+                leader_commit: self.commit,
+                seq,
+            };
+            (body, sent)
+        };
+        if let Role::Leader(leader) = &mut self.role {
+            if let Some(progress) = leader.progress.get_mut(&to) {
+                progress.next = sent + 1;
+            }
+        }
+        self.send(to, body);
+    }
+
+    /// Entries of the snapshot from `offset` on, up to `SNAPSHOT_CHUNK_SIZE`
+    /// bytes.
+    fn snapshot_chunk(&self, offset: usize) -> SnapshotChunk {
+        let entries = &self.snapshot.entries;
+        let start = offset.min(entries.len());
+        let mut end = start;
+        let mut size = 0;
+        while let Some((key, value)) = entries.get(end) {
+            if end > start && size + key.len() + value.len() > SNAPSHOT_CHUNK_SIZE {
+                break;
+            }
+            size += key.len() + value.len();
+            end += 1;
+        }
+        SnapshotChunk {
+            index: self.snapshot.index,
+            term: self.snapshot.term,
+            membership: self.snapshot.membership.clone(),
+            offset: start as u64,
+            entries: entries[start..end].to_vec(),
+            done: end == entries.len(),
+        }
+    }
+
+    /// Commits highest index of current term replicated on a quorum.
+    fn advance_commit(&mut self) {
+        let leader = match &self.role {
+            Role::Leader(leader) => leader,
+            _ => return,
+        };
+        let last_index = self.last_index();
+        for index in (self.commit + 1..=last_index).rev() {
+            if self.term_at(index) != Some(self.state.term) {
+                break;
+            }
+            let replicated = self.quorum(|id| {
+                id == self.id
+                    || leader
+                        .progress
+                        .get(&id)
+                        .is_some_and(|progress| progress.matched >= index)
+            });
+            if replicated {
+                self.commit = index;
+                break;
+            }
+        }
+    }
+
+    /// Moves reads whose heartbeat round was acked by a quorum to wait for apply.
+    fn confirm_reads(&mut self) {
+        let leader = match &mut self.role {
+            Role::Leader(leader) => leader,
+            _ => return,
+        };
+        let acked: HashMap<NodeId, u64> = leader
+            .progress
+            .iter()
+            .map(|(id, progress)| (*id, progress.acked_seq))
+            .collect();
+        let reads = std::mem::take(&mut leader.reads);
+        let mut waiting = vec![];
+        for (seq, index, reply) in reads {
+            if self.quorum(|id| id == self.id || acked.get(&id).is_some_and(|acked| *acked >= seq))
+            {
+                self.reads.push((index, reply));
+            } else {
+                waiting.push((seq, index, reply));
+            }
+        }
+        if let Role::Leader(leader) = &mut self.role {
+            leader.reads = waiting;
+        }
+    }
+
+    /// Applies committed entries to the engine and answers their proposals.
+    fn apply(&mut self) -> Result<()> {
+        while self.applied < self.commit {
+            let entry = self.entry(self.applied + 1).clone();
+            let result = self.apply_operation(&entry.operation);
+            self.applied = entry.index;
+
+            match self.proposals.remove(&entry.index) {
+                Some((term, reply)) if term == entry.term => {
+                    let _ = reply.send(result);
+                }
+                Some((_, reply)) => {
+                    let _ = reply.send(Err(self.not_leader()));
+                }
+                None => {
+                    if let Err(e) = result {
+                        if !matches!(e.downcast_ref(), Some(KvsError::KeyNotFound)) {
+                            error!("Node {} failed to apply {}: {}", self.id, entry.index, e);
+                        }
+                    }
+                }
+            }
+
+            // leader removed from the cluster steps down once removal committed.
+            if let Operation::Membership(membership) = &entry.operation {
+                if self.is_leader() && !membership.contains_key(&self.id) {
+                    info!("Node {} removed from cluster, stepping down", self.id);
+                    self.become_follower(self.state.term, None)?;
+                }
+            }
+        }
+
+        let applied = self.applied;
+        let (ready, waiting) = std::mem::take(&mut self.reads)
+            .into_iter()
+            .partition(|(index, _)| *index <= applied);
+        self.reads = waiting;
+        for (_, reply) in ready {
+            let _ = reply.send(Ok(()));
+        }
+
+        self.finish_snapshot(false)?;
+        if self.saving.is_none()
+            && self.applied - self.snapshot.index >= self.options.snapshot_threshold
+        {
+            self.take_snapshot()?;
+        }
+        Ok(())
+    }
+
+    fn apply_operation(&self, operation: &Operation) -> Result<bool> {
+        match operation.clone() {
+            Operation::Noop | Operation::Membership(_) => Ok(true),
+            Operation::Set { key, value } => self.engine.set(key, value).map(|_| true),
+            Operation::Remove { key } => self.engine.remove(key).map(|_| true),
+            Operation::CompareAndSet {
+                key,
+                expected,
+                value,
+            } => self.engine.compare_and_set(key, expected, value),
+            Operation::CompareAndRemove { key, expected } => {
+                self.engine.compare_and_remove(key, expected)
+            }
+        }
+    }
+
+    /// Starts replacing applied part of the log with snapshot of the engine.
+    ///
+    /// Values are collected on the node thread and held in memory, so the
+    /// dataset has to fit in memory and be read well within the election
+    /// timeout; writing the snapshot file is left to a background thread.
+    fn take_snapshot(&mut self) -> Result<()> {
+        let mut entries = vec![];
+        for key in self.engine.scan(String::new())? {
+            if let Some(value) = self.engine.get(key.clone())? {
+                entries.push((key, value));
+            }
+        }
+        let applied = (self.applied - self.snapshot.index) as usize;
+        let membership = self.log[..applied]
+            .iter()
+            .rev()
+            .find_map(|entry| match &entry.operation {
+                Operation::Membership(membership) => Some(membership.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| self.snapshot.membership.clone());
+        let snapshot = Snapshot {
+            index: self.applied,
+            term: self.entry(self.applied).term,
+            membership,
+            entries,
+        };
+        debug!(
+            "Node {} took snapshot of {} values at {}",
+            self.id,
+            snapshot.entries.len(),
+            snapshot.index
+        );
+
+        self.saving = Some(self.storage.spawn_save_snapshot(snapshot));
+        Ok(())
+    }
+
+    /// Drops log entries covered by the snapshot written in the background
+    /// once it is saved, waiting for it when `wait` is set.
+    fn finish_snapshot(&mut self, wait: bool) -> Result<()> {
+        let handle = match self.saving.take() {
+            Some(handle) if wait || handle.is_finished() => handle,
+            saving => {
+                self.saving = saving;
+                return Ok(());
+            }
+        };
+        let snapshot = handle
+            .join()
+            .map_err(|_| KvsError::Replication("snapshot writer panicked".to_owned()))??;
+        let covered = (snapshot.index - self.snapshot.index) as usize;
+        self.log.drain(..covered);
+        self.storage.rewrite_log(&self.log)?;
+        self.snapshot = snapshot;
+        Ok(())
+    }
+
+    /// Appends entry of current term to own log, returns its index.
+    fn append(&mut self, operation: Operation) -> Result<u64> {
+        let entry = Entry {
+            term: self.state.term,
+            index: self.last_index() + 1,
+            operation,
+        };
+        let index = entry.index;
+        self.storage.append(std::slice::from_ref(&entry))?;
+        self.log.push(entry);
+        self.refresh_membership();
+        Ok(index)
+    }
+
+    /// Switches to the latest membership in the log.
+    fn refresh_membership(&mut self) {
+        let membership = self
+            .log
+            .iter()
+            .rev()
+            .find_map(|entry| match &entry.operation {
+                Operation::Membership(membership) => Some(membership.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| self.snapshot.membership.clone());
+
+        // leader starts replicating to new members and stops for removed ones.
+        if let Role::Leader(leader) = &mut self.role {
+            let next = self.log.last().map_or(self.snapshot.index, |e| e.index) + 1;
+            leader.progress.retain(|id, _| membership.contains_key(id));
+            for id in membership.keys().filter(|id| **id != self.id) {
+                leader.progress.entry(*id).or_insert(Progress {
+                    next,
+                    matched: 0,
+                    acked_seq: 0,
+                    snapshot_offset: 0,
+                });
+            }
+        }
+        self.membership = membership;
+    }
+
+    /// Index of the entry holding current membership.
+    fn membership_index(&self) -> u64 {
+        self.log
+            .iter()
+            .rev()
+            .find(|entry| matches!(entry.operation, Operation::Membership(_)))
+            .map_or(self.snapshot.index, |entry| entry.index)
+    }
+
+    /// Answers every pending request after losing leadership.
+    fn fail_pending(&mut self) {
+        self.fail_proposals_from(0);
+        let mut reads = std::mem::take(&mut self.reads);
+        if let Role::Leader(leader) = &mut self.role {
+            reads.extend(
+                leader
+                    .reads
+                    .drain(..)
+                    .map(|(_, index, reply)| (index, reply)),
+            );
+        }
+        for (_, reply) in reads {
+            let _ = reply.send(Err(self.not_leader()));
+        }
+    }
+
+    fn fail_proposals_from(&mut self, index: u64) {
+        for (_, (_, reply)) in self.proposals.split_off(&index) {
+            let _ = reply.send(Err(self.not_leader()));
+        }
+    }
+
+    fn not_leader(&self) -> anyhow::Error {
+        let leader = self
+            .leader
+            .filter(|id| *id != self.id)
+            .and_then(|id| self.membership.get(&id))
+            .map(|member| member.client_addr.clone());
+        KvsError::NotLeader(leader).into()
+    }
+
+    fn send(&self, to: NodeId, body: MessageBody) {
+        let addr = match self.membership.get(&to) {
+            Some(member) => &member.raft_addr,
+            None => match self.addresses.get(&to) {
+                Some(addr) => addr,
+                None => return,
+            },
+        };
+        let from_addr = self
+            .membership
+            .get(&self.id)
+            .map(|member| member.raft_addr.clone())
+            .unwrap_or_default();
+        self.transport.send(
+            addr,
+            Message {
+                from: self.id,
+                from_addr,
+                to,
+                term: self.state.term,
+                body,
+            },
+        );
+    }
+
+    fn is_leader(&self) -> bool {
+        matches!(self.role, Role::Leader(_))
+    }
+
+    fn leader_alive(&self) -> bool {
+        self.is_leader()
+            || (self.leader.is_some()
+                && self.heard_from_leader.elapsed() < self.options.election_timeout)
+    }
+
+    /// Other members of the cluster.
+    fn peers(&self) -> Vec<NodeId> {
+        self.membership
+            .keys()
+            .copied()
+            .filter(|id| *id != self.id)
+            .collect()
+    }
+
+    /// Whether majority of members satisfies the condition.
+    fn quorum(&self, condition: impl Fn(NodeId) -> bool) -> bool {
+        let votes = self.membership.keys().filter(|id| condition(**id)).count();
+        votes > self.membership.len() / 2
+    }
+
+    fn reset_election_timer(&mut self) {
+        // xorshift, randomized timeouts keep nodes from splitting votes forever.
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let base = self.options.election_timeout;
+        let jitter = base.mul_f64((self.rng % 1000) as f64 / 1000.0);
+        self.election_deadline = Instant::now() + base + jitter;
+    }
+
+    fn last_index(&self) -> u64 {
+        self.log.last().map_or(self.snapshot.index, |e| e.index)
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log.last().map_or(self.snapshot.term, |e| e.term)
+    }
+
+    /// Term of the entry at index, `None` when it's not in the log.
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.index {
+            return Some(self.snapshot.term);
+        }
+        if index < self.snapshot.index {
+            return None;
+        }
+        self.log
+            .get((index - self.snapshot.index - 1) as usize)
+            .map(|e| e.term)
+    }
+
+    fn entry(&self, index: u64) -> &Entry {
+        &self.log[(index - self.snapshot.index - 1) as usize]
+    }
+}
@@ -0,0 +1,189 @@
+//! Persistent state of a Raft node.
+//!
+//! Term and vote are kept in `state`, the latest snapshot in `snapshot` and
+//! log entries following it in `log` as length prefixed bincode records.
+//! Files are replaced by renaming a temporary file, except for appends to
+//! the log which are synced before the node answers.
+
+use super::{Entry, NodeId, Snapshot};
+use crate::error::KvsError;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub(super) struct HardState {
+    pub term: u64,
+    pub voted_for: Option<NodeId>,
+}
+
+/// Everything needed to restart a node.
+pub(super) struct Persisted {
+    pub state: HardState,
+    pub snapshot: Option<Snapshot>,
+    pub entries: Vec<Entry>,
+}
+
+/// Files of the node, nothing is stored without directory.
+pub(super) struct Storage {
+    dir: Option<PathBuf>,
+    log: Option<File>,
+}
+
+impl Storage {
+    pub fn open(dir: Option<PathBuf>) -> Result<(Self, Persisted)> {
+        let mut persisted = Persisted {
+            state: HardState::default(),
+            snapshot: None,
+            entries: vec![],
+        };
+        let dir = match dir {
+            Some(dir) => dir,
+            None => {
+                return Ok((
+                    Self {
+                        dir: None,
+                        log: None,
+                    },
+                    persisted,
+                ))
+            }
+        };
+        fs::create_dir_all(&dir)?;
+
+        if let Some(state) = read_file(&dir.join("state"))? {
+            persisted.state = state;
+        }
+        persisted.snapshot = read_file(&dir.join("snapshot"))?;
+
+        let path = dir.join("log");
+        if path.exists() {
+            let mut reader = BufReader::new(File::open(&path)?);
+            loop {
+                let mut len = [0u8; 4];
+                match reader.read_exact(&mut len) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e.into()),
+                }
+                let mut record = vec![0u8; u32::from_be_bytes(len) as usize];
+                // entry torn by a crash was never acknowledged.
+                if reader.read_exact(&mut record).is_err() {
+                    break;
+                }
+                persisted.entries.push(bincode::deserialize(&record)?);
+            }
+        }
+        // entries covered by the snapshot may remain when node crashed
+        // between writing snapshot and log.
+        if let Some(snapshot) = &persisted.snapshot {
+            persisted
+                .entries
+                .retain(|entry| entry.index > snapshot.index);
+        }
+        if let Some(first) = persisted.entries.first() {
+            let expected = persisted.snapshot.as_ref().map_or(1, |s| s.index + 1);
+            if first.index != expected {
+                return Err(KvsError::Replication(format!(
+                    "raft log starts at {}, expected {}",
+                    first.index, expected
+                ))
+                .into());
+            }
+        }
+
+        let mut storage = Self {
+            dir: Some(dir),
+            log: None,
+        };
+        storage.rewrite_log(&persisted.entries)?;
+        Ok((storage, persisted))
+    }
+
+    pub fn save_state(&mut self, state: HardState) -> Result<()> {
+        match &self.dir {
+            Some(dir) => write_file(&dir.join("state"), &state),
+            None => Ok(()),
+        }
+    }
+
+    pub fn save_snapshot(&mut self, snapshot: &Snapshot) -> Result<()> {
+        match &self.dir {
+            Some(dir) => write_file(&dir.join("snapshot"), snapshot),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the snapshot on a new thread so a large one does not hold up
+    /// the node, the thread hands the snapshot back once it is durable.
+    pub fn spawn_save_snapshot(&self, snapshot: Snapshot) -> JoinHandle<Result<Snapshot>> {
+        let path = self.dir.as_ref().map(|dir| dir.join("snapshot"));
+        thread::spawn(move || {
+            if let Some(path) = path {
+                write_file(&path, &snapshot)?;
+            }
+            Ok(snapshot)
+        })
+    }
+
+    /// Appends entries to the end of the log file.
+    pub fn append(&mut self, entries: &[Entry]) -> Result<()> {
+        let file = match &mut self.log {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let mut writer = BufWriter::new(&*file);
+        for entry in entries {
+            write_record(&mut writer, entry)?;
+        }
+        writer.flush()?;
+        drop(writer);
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// Replaces log file with given entries, after truncation or compaction.
+    pub fn rewrite_log(&mut self, entries: &[Entry]) -> Result<()> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let tmp = dir.join("log.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        for entry in entries {
+            write_record(&mut writer, entry)?;
+        }
+        writer.into_inner()?.sync_all()?;
+        let path = dir.join("log");
+        fs::rename(&tmp, &path)?;
+        self.log = Some(OpenOptions::new().append(true).open(&path)?);
+        Ok(())
+    }
+}
+
+fn write_record<W: Write>(writer: &mut W, entry: &Entry) -> Result<()> {
+    let record = bincode::serialize(entry)?;
+    writer.write_all(&(record.len() as u32).to_be_bytes())?;
+    writer.write_all(&record)?;
+    Ok(())
+}
+
+fn read_file<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<Option<T>> {
+    match fs::read(path) {
+        Ok(content) => Ok(Some(bincode::deserialize(&content)?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_file<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(&bincode::serialize(value)?)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
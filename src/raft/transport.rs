@@ -0,0 +1,241 @@
+//! Delivery of messages between Raft nodes.
+
+use super::{Event, Message, NodeId};
+use crate::auth::constant_time_eq;
+use crate::error::KvsError;
+use crate::limits::Slots;
+use crate::protocol::{self, Codec};
+use crate::transport::{Address, Stream};
+use crate::Result;
+use crossbeam::channel::{self, Sender};
+use rustls::{ClientConfig, ServerConfig};
+use std::collections::{HashMap, HashSet};
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Sends messages to other nodes. Delivery is best effort, Raft copes with
+/// lost, duplicated and reordered messages.
+pub trait Transport: Send + 'static {
+    /// Sends message to `message.to` listening on `addr`.
+    fn send(&self, addr: &str, message: Message);
+}
+
+#[derive(Clone)]
+/// Receiving end of a node, transports deliver incoming messages into it.
+pub struct Mailbox(Sender<Event>);
+
+impl Mailbox {
+    pub(crate) fn new(events: Sender<Event>) -> Self {
+        Self(events)
+    }
+
+    /// Hands message to the node, returns false once the node stopped.
+    pub fn deliver(&self, message: Message) -> bool {
+        self.0.send(Event::Message(message)).is_ok()
+    }
+}
+
+#[derive(Clone, Default)]
+/// In-process network of nodes for tests, can isolate nodes to simulate
+/// partitions and crashes.
+pub struct SimulatedNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+
+#[derive(Default)]
+struct NetworkState {
+    mailboxes: HashMap<NodeId, Mailbox>,
+    isolated: HashSet<NodeId>,
+}
+
+impl SimulatedNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connects node to the network.
+    pub fn register(&self, id: NodeId, mailbox: Mailbox) {
+        self.state.lock().unwrap().mailboxes.insert(id, mailbox);
+    }
+
+    /// Drops every message from and to the node.
+    pub fn isolate(&self, id: NodeId) {
+        self.state.lock().unwrap().isolated.insert(id);
+    }
+
+    /// Reconnects isolated node.
+    pub fn heal(&self, id: NodeId) {
+        self.state.lock().unwrap().isolated.remove(&id);
+    }
+}
+
+impl Transport for SimulatedNetwork {
+    fn send(&self, _addr: &str, message: Message) {
+        let state = self.state.lock().unwrap();
+        if state.isolated.contains(&message.from) || state.isolated.contains(&message.to) {
+            return;
+        }
+        if let Some(mailbox) = state.mailboxes.get(&message.to) {
+            mailbox.deliver(message);
+        }
+    }
+}
+
+/// Bounded queue of messages waiting for a peer connection, older messages
+/// are superseded by newer ones anyway.
+const PEER_QUEUE: usize = 256;
+
+/// Connections of other nodes served at once by default.
+const MAX_CONNECTIONS: usize = 64;
+
+/// How long connecting node has to finish TLS and present the token.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest frame carrying the cluster token.
+const MAX_TOKEN_FRAME: u32 = 4096;
+
+#[derive(Clone)]
+/// Sends bincode frames over one TCP connection per peer. Every connection
+/// starts with the cluster token, nodes drop connections presenting another
+/// one.
+pub struct TcpTransport {
+    token: Arc<String>,
+    tls: Option<(Arc<ServerConfig>, Arc<ClientConfig>)>,
+    max_connections: usize,
+    peers: Arc<Mutex<HashMap<String, Sender<Message>>>>,
+}
+
+impl TcpTransport {
+    /// Transport of a cluster whose nodes share the `token`.
+    pub fn new(token: impl Into<String>) -> Self {
+        Self {
+            token: Arc::new(token.into()),
+            tls: None,
+            max_connections: MAX_CONNECTIONS,
+            peers: Arc::default(),
+        }
+    }
+
+    /// Wraps connections in TLS, `server` is used for accepted connections
+    /// and `client` verifies nodes connected to.
+    pub fn with_tls(mut self, server: Arc<ServerConfig>, client: Arc<ClientConfig>) -> Self {
+        self.tls = Some((server, client));
+        self
+    }
+
+    /// Limits connections of other nodes served at once, further ones are
+    /// closed right away.
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    /// Accepts connections of other nodes and delivers their messages to
+    /// the mailbox, on a background thread.
+    pub fn listen(&self, listener: TcpListener, mailbox: Mailbox) {
+        let transport = self.clone();
+        let slots = Arc::new(Slots::new(Some(self.max_connections)));
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        error!("Raft connection failed: {}", e);
+                        continue;
+                    }
+                };
+                // peer reconnects with its next message.
+                let slot = match slots.try_acquire() {
+                    Some(slot) => slot,
+                    None => {
+                        debug!("Too many Raft connections, closing new one");
+                        continue;
+                    }
+                };
+                let transport = transport.clone();
+                let mailbox = mailbox.clone();
+                thread::spawn(move || {
+                    let _slot = slot;
+                    if let Err(e) = transport.receive(stream, &mailbox) {
+                        debug!("Raft connection closed: {}", e);
+                    }
+                });
+            }
+        });
+    }
+
+    /// Opens connection to the node and presents the token.
+    fn connect(&self, addr: &str) -> Result<BufWriter<Stream>> {
+        let stream = match &self.tls {
+            Some((_, client)) => {
+                let server_name = Address::Tcp(addr.to_owned())
+                    .host()
+                    .unwrap_or(addr)
+                    .to_owned();
+                Stream::connect_tls(addr, &server_name, client.clone())?
+            }
+            None => Stream::connect(addr)?,
+        };
+        let mut writer = BufWriter::new(stream);
+        protocol::write_frame(&mut writer, Codec::Bincode, &*self.token)?;
+        Ok(writer)
+    }
+
+    fn receive(&self, stream: TcpStream, mailbox: &Mailbox) -> Result<()> {
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let stream = Stream::accept(stream, self.tls.as_ref().map(|(server, _)| server))?;
+        let mut reader = BufReader::new(stream);
+        let token: Option<String> =
+            protocol::read_frame_within(&mut reader, Codec::Bincode, MAX_TOKEN_FRAME)?;
+        if !token.is_some_and(|token| constant_time_eq(token.as_bytes(), self.token.as_bytes())) {
+            return Err(KvsError::PermissionDenied("invalid cluster token".to_owned()).into());
+        }
+        reader.get_ref().set_read_timeout(None)?;
+
+        while let Some(message) = protocol::read_frame::<_, Message>(&mut reader, Codec::Bincode)? {
+            if !mailbox.deliver(message) {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&self, addr: &str, message: Message) {
+        let mut peers = self.peers.lock().unwrap();
+        let sender = peers.entry(addr.to_owned()).or_insert_with(|| {
+            let (sender, receiver) = channel::bounded::<Message>(PEER_QUEUE);
+            let addr = addr.to_owned();
+            let transport = self.clone();
+            thread::spawn(move || {
+                let mut connection: Option<BufWriter<Stream>> = None;
+                for message in receiver {
+                    if connection.is_none() {
+                        connection = match transport.connect(&addr) {
+                            Ok(writer) => Some(writer),
+                            Err(e) => {
+                                debug!("Connecting to {} failed: {}", addr, e);
+                                None
+                            }
+                        };
+                    }
+                    // message is dropped when peer is unreachable.
+                    if let Some(writer) = &mut connection {
+                        let sent = protocol::write_frame(writer, Codec::Bincode, &message)
+                            .and_then(|_| Ok(writer.flush()?));
+                        if let Err(e) = sent {
+                            debug!("Sending to {} failed: {}", addr, e);
+                            connection = None;
+                        }
+                    }
+                }
+            });
+            sender
+        });
+        let _ = sender.try_send(message);
+    }
+}
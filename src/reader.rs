@@ -50,6 +50,10 @@ impl<W: Write + Seek> BufWriterWithPos<W> {
             pos,
         })
     }
+
+    pub fn get_ref(&self) -> &W {
+        self.writer.get_ref()
+    }
 }
 
 unsafe impl<W: Write + Seek> Send for BufWriterWithPos<W> {}
@@ -0,0 +1,297 @@
+//! Asynchronous leader-follower replication.
+//!
+//! Follower polls the leader with `CMD::Replicate` for log records written
+//! after its position and applies them to its own engine. Position is a
+//! generation and byte offset in the leader log, when that generation was
+//! already removed by compaction the leader answers with a snapshot of all
+//! values instead. Snapshot is sent in parts of bounded size, see
+//! `LogBatch::Snapshot`. Followers given a state file keep their position
+//! in it and continue from there after restart, others start every run by
+//! asking for a snapshot.
+
+use crate::error::KvsError;
+use crate::protocol::Credentials;
+use crate::stats::Stats;
+use crate::transport::{Address, Stream};
+use crate::{Codec, KvsClient, KvsEngine, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Upper bound of log bytes leader sends in one batch.
+pub const MAX_BATCH_SIZE: u64 = 1024 * 1024;
+
+/// How long follower waits before asking again once it caught up.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long follower waits before reconnecting to unreachable leader.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// Position in the leader log.
+pub struct LogPosition {
+    pub gen: u64,
+    pub pos: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Committed write shipped to followers.
+pub enum LogRecord {
+    Set { key: String, value: String },
+    Rm { key: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Leader answer to `CMD::Replicate`.
+pub enum LogBatch {
+    /// Records following requested position, empty when follower caught up.
+    /// `head` is the end of leader log at the time of reading.
+    Records {
+        records: Vec<LogRecord>,
+        next: LogPosition,
+        head: LogPosition,
+    },
+    /// Part of every value of the leader, in key order. Values are read
+    /// after `next` was taken, follower asks for parts following the last
+    /// key until one is `done`, discards its other data and replays the log
+    /// from `next` over the snapshot.
+    Snapshot {
+        entries: Vec<(String, String)>,
+        next: LogPosition,
+        done: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Replication state of a follower, reported in stats.
+pub struct ReplicationStatus {
+    pub leader: String,
+    pub connected: bool,
+    /// `None` until bootstrapped from snapshot.
+    pub position: Option<LogPosition>,
+    /// Time since follower last saw the end of leader log.
+    pub lag_millis: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+/// Content of the follower state file.
+struct FollowerState {
+    leader: Address,
+    position: LogPosition,
+}
+
+/// Replicates leader into the engine, never returns. Position is kept in
+/// `state` file when given.
+pub(crate) fn follow<E: KvsEngine>(
+    engine: E,
+    leader: Address,
+    credentials: Credentials,
+    state: Option<PathBuf>,
+    stats: Arc<Stats>,
+) {
+    let position = match state.as_deref().map(|path| load_position(path, &leader)) {
+        Some(Ok(position)) => position,
+        Some(Err(e)) => {
+            warn!("Could not load replication state: {}", e);
+            None
+        }
+        None => None,
+    };
+    let mut follower = Follower {
+        engine,
+        leader,
+        stats,
+        state,
+        position,
+        caught_up_at: Instant::now(),
+    };
+
+    loop {
+        if let Err(e) = follower.run(&credentials) {
+            warn!("Replication from {} failed: {}", follower.leader, e);
+        }
+        follower.report(false);
+        thread::sleep(RECONNECT_INTERVAL);
+    }
+}
+
+struct Follower<E: KvsEngine> {
+    engine: E,
+    leader: Address,
+    stats: Arc<Stats>,
+    state: Option<PathBuf>,
+    position: Option<LogPosition>,
+    caught_up_at: Instant,
+}
+
+impl<E: KvsEngine> Follower<E> {
+    /// Streams records until connection fails.
+    fn run(&mut self, credentials: &Credentials) -> Result<()> {
+        let mut client = KvsClient::handshake(
+            Stream::connect_to(&self.leader)?,
+            Codec::Bincode,
+            credentials,
+        )?;
+        info!("Replicating from {}", self.leader);
+
+        loop {
+            match client.replicate(self.position)? {
+                LogBatch::Records {
+                    records,
+                    next,
+                    head,
+                } => {
+                    let idle = records.is_empty();
+                    for record in records {
+                        self.apply(record)?;
+                    }
+                    self.set_position(Some(next))?;
+                    if next == head {
+                        self.caught_up_at = Instant::now();
+                    }
+                    self.report(true);
+                    if idle {
+                        thread::sleep(POLL_INTERVAL);
+                    }
+                }
+                LogBatch::Snapshot {
+                    entries,
+                    next,
+                    done,
+                } => {
+                    self.bootstrap(&mut client, entries, next, done)?;
+                    self.report(true);
+                }
+            }
+        }
+    }
+
+    /// Applies snapshot part by part and removes keys it doesn't have.
+    fn bootstrap(
+        &mut self,
+        client: &mut KvsClient,
+        mut entries: Vec<(String, String)>,
+        next: LogPosition,
+        mut done: bool,
+    ) -> Result<()> {
+        info!("Bootstrapping from snapshot at {:?}", next);
+        // data is inconsistent until the whole snapshot is applied.
+        self.set_position(None)?;
+
+        let mut stale: HashSet<String> = self.engine.scan(String::new())?.into_iter().collect();
+        let mut values = 0;
+        loop {
+            let last = entries.last().map(|(key, _)| key.clone());
+            values += entries.len();
+            for (key, value) in entries {
+                stale.remove(&key);
+                self.engine.set(key, value)?;
+            }
+            let after = match last {
+                Some(last) if !done => last,
+                _ => break,
+            };
+            match client.replicate_snapshot(next, after)? {
+                LogBatch::Snapshot {
+                    entries: part,
+                    done: last_part,
+                    ..
+                } => {
+                    entries = part;
+                    done = last_part;
+                }
+                LogBatch::Records { .. } => {
+                    return Err(KvsError::Replication("expected snapshot part".to_owned()).into())
+                }
+            }
+        }
+        for key in stale {
+            self.apply(LogRecord::Rm { key })?;
+        }
+
+        info!("Bootstrapped from snapshot of {} values", values);
+        self.set_position(Some(next))
+    }
+
+    /// Moves to the position, saving it to the state file.
+    fn set_position(&mut self, position: Option<LogPosition>) -> Result<()> {
+        if self.position == position {
+            return Ok(());
+        }
+        self.position = position;
+        let path = match &self.state {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        match position {
+            Some(position) => {
+                let state = FollowerState {
+                    leader: self.leader.clone(),
+                    position,
+                };
+                // written aside first, crash leaves either old or new state.
+                let tmp = path.with_extension("tmp");
+                fs::write(&tmp, serde_json::to_vec(&state)?)?;
+                fs::rename(&tmp, path)?;
+            }
+            None => match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            },
+        }
+        Ok(())
+    }
+
+    fn apply(&self, record: LogRecord) -> Result<()> {
+        match record {
+            LogRecord::Set { key, value } => self.engine.set(key, value),
+            // key may be already gone when it was removed after snapshot was taken.
+            LogRecord::Rm { key } => match self.engine.remove(key) {
+                Err(e) if matches!(e.downcast_ref(), Some(KvsError::KeyNotFound)) => Ok(()),
+                result => result,
+            },
+        }
+    }
+
+    fn report(&self, connected: bool) {
+        self.stats.set_replication(ReplicationStatus {
+            leader: self.leader.to_string(),
+            connected,
+            position: self.position,
+            lag_millis: self.caught_up_at.elapsed().as_millis() as u64,
+        });
+    }
+}
+
+/// Position saved by the follower of the leader, `None` when there is none.
+fn load_position(path: &Path, leader: &Address) -> Result<Option<LogPosition>> {
+    let state: FollowerState = match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    // position in log of another leader means nothing.
+    Ok(Some(state.position).filter(|_| state.leader == *leader))
+}
+
+/// Replaces every value of the engine with the snapshot.
+pub(crate) fn restore<E: KvsEngine>(engine: &E, entries: Vec<(String, String)>) -> Result<()> {
+    let keys: HashSet<&String> = entries.iter().map(|(key, _)| key).collect();
+    for key in engine.scan(String::new())? {
+        if !keys.contains(&key) {
+            engine.remove(key)?;
+        }
+    }
+    for (key, value) in entries {
+        // unchanged values are skipped so restarts do not rewrite the store.
+        if engine.get(key.clone())?.as_ref() != Some(&value) {
+            engine.set(key, value)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,635 @@
+//! Redis RESP2 compatibility layer, lets `redis-cli` and redis client
+//! libraries talk to kvs-server. Commands are mapped onto `KvsEngine`.
+
+use crate::auth::{self, Access, Auth, Principal};
+use crate::error::KvsError;
+use crate::limits::Limits;
+use crate::protocol::Credentials;
+use crate::stats::Stats;
+use crate::transport::Stream;
+use crate::{KvsEngine, Result};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Default number of keys visited by single SCAN call.
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Longest inline command or RESP header line accepted.
+const MAX_LINE_SIZE: usize = 64 * 1024;
+
+/// Smallest bulk string on the wire, `$0\r\n\r\n`.
+const MIN_BULK_SIZE: usize = 6;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Reply sent back to the RESP client.
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        match self {
+            Reply::Simple(s) => write!(w, "+{}\r\n", s),
+            // messages may quote client input, line breaks would end the reply.
+            Reply::Error(e) => write!(w, "-{}\r\n", e.replace(['\r', '\n'], " ")),
+            Reply::Integer(i) => write!(w, ":{}\r\n", i),
+            Reply::Bulk(None) => w.write_all(b"$-1\r\n"),
+            Reply::Bulk(Some(s)) => write!(w, "${}\r\n{}\r\n", s.len(), s),
+            Reply::Array(items) => {
+                write!(w, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|item| item.write_to(w))
+            }
+        }
+    }
+}
+
+/// Reply sent to connections over the server limit, same as Redis sends.
+pub fn busy_reply() -> Vec<u8> {
+    b"-ERR max number of clients reached\r\n".to_vec()
+}
+
+/// Expiration deadlines set with EXPIRE or `SET .. EX`. Engines have no notion
+/// of TTL, so deadlines live in memory of the RESP listener and expired keys
+/// are removed lazily, when they are accessed through it.
+#[derive(Debug, Default)]
+pub struct Expirations(Mutex<HashMap<String, Instant>>);
+
+impl Expirations {
+    /// Removes the key from the engine if its deadline has passed.
+    fn purge<E: KvsEngine>(&self, engine: &E, key: &str) -> Result<()> {
+        let mut deadlines = self.0.lock().unwrap();
+        match deadlines.get(key) {
+            Some(deadline) if *deadline <= Instant::now() => {
+                deadlines.remove(key);
+                ignore_not_found(engine.remove(key.to_owned()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn set(&self, key: String, ttl: Duration) {
+        self.0.lock().unwrap().insert(key, Instant::now() + ttl);
+    }
+
+    fn clear(&self, key: &str) {
+        self.0.lock().unwrap().remove(key);
+    }
+
+    fn ttl(&self, key: &str) -> Option<Duration> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+}
+
+/// Serves single RESP connection until the client disconnects.
+pub fn serve_resp<E: KvsEngine>(
+    engine: E,
+    expirations: &Expirations,
+    stats: &Stats,
+    limits: &Limits,
+    auth: Option<&Auth>,
+    read_only: bool,
+    stream: Stream,
+) -> Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    let mut principal = auth::authenticate(
+        auth,
+        &Credentials::Anonymous,
+        stream.peer_identity().as_deref(),
+    )?;
+    let mut reader = BufReader::new(stream);
+    let mut out = vec![];
+
+    loop {
+        let args = match read_command(&mut reader, limits) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(e) => {
+                // stream can't be resynced, tell the client why it's closed.
+                if let Some(KvsError::Protocol(msg)) = e.downcast_ref() {
+                    Reply::Error(format!("ERR Protocol error: {}", msg)).write_to(&mut out)?;
+                    let _ = reader.get_mut().write_all(&out);
+                }
+                return Err(e);
+            }
+        };
+        debug!("Receive RESP command from {}: {:?}", peer_addr, args);
+
+        if args.is_empty() {
+            continue;
+        }
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+
+        let reply = match args
+            .into_iter()
+            .map(String::from_utf8)
+            .collect::<std::result::Result<Vec<_>, _>>()
+        {
+            Ok(_) if quit => Reply::Simple("OK"),
+            Ok(args) if args[0].eq_ignore_ascii_case("AUTH") => {
+                authenticate(auth, &mut principal, args)
+            }
+            Ok(args) => execute(
+                &engine,
+                expirations,
+                stats,
+                limits,
+                &principal,
+                read_only,
+                args,
+            ),
+            Err(_) => Reply::Error("ERR arguments must be valid utf-8".to_owned()),
+        };
+        stats.request(matches!(reply, Reply::Error(_)));
+        reply.write_to(&mut out)?;
+
+        // pipelined commands are answered together.
+        if quit || reader.buffer().is_empty() {
+            reader.get_mut().write_all(&out)?;
+            reader.get_mut().flush()?;
+            out.clear();
+        }
+        if quit {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Reads either RESP array of bulk strings or an inline command.
+/// Returns `None` when client closed connection.
+///
+/// Argument count and lengths are checked against `limits` before anything
+/// is allocated for them, so a header alone can't exhaust memory.
+fn read_command<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    if line.first() != Some(&b'*') {
+        return Ok(Some(
+            line.split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| arg.to_vec())
+                .collect(),
+        ));
+    }
+
+    let mut remaining = limits.frame_size() as usize;
+    let count = parse_len(&line[1..])?;
+    if count > remaining / MIN_BULK_SIZE {
+        return Err(protocol_error("too many arguments"));
+    }
+    // keys and values are the only large arguments, the rest is bounded by
+    // the line size.
+    let max_len = match (limits.max_key_size, limits.max_value_size) {
+        (Some(key), Some(value)) => key.max(value).max(MAX_LINE_SIZE),
+        _ => usize::MAX,
+    };
+
+    // grows with arguments actually received.
+    let mut args = Vec::new();
+    for _ in 0..count {
+        let header = read_line(reader)?.ok_or_else(|| protocol_error("unexpected eof"))?;
+        if header.first() != Some(&b'$') {
+            return Err(protocol_error("expected bulk string"));
+        }
+        let len = parse_len(&header[1..])?;
+        if len > max_len || len > remaining {
+            return Err(protocol_error("invalid bulk length"));
+        }
+        remaining -= len;
+
+        let mut arg = vec![0u8; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string not terminated with CRLF"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Reads line without trailing CRLF, at most `MAX_LINE_SIZE` bytes long.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = vec![];
+    let read = reader
+        .by_ref()
+        .take(MAX_LINE_SIZE as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.len() > MAX_LINE_SIZE && !line.ends_with(b"\n") {
+        return Err(protocol_error("line too long"));
+    }
+    while matches!(line.last(), Some(b'\n' | b'\r')) {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(bytes: &[u8]) -> Result<usize> {
+    std::str::from_utf8(bytes)?
+        .parse()
+        .map_err(|_| protocol_error("invalid length"))
+}
+
+fn protocol_error(msg: &str) -> anyhow::Error {
+    KvsError::Protocol(msg.to_owned()).into()
+}
+
+/// Handles `AUTH token` and `AUTH user password`, switching principal of
+/// the connection on success.
+fn authenticate(auth: Option<&Auth>, principal: &mut Principal, args: Vec<String>) -> Reply {
+    let credentials = match &args[1..] {
+        [token] => Credentials::Token(token.clone()),
+        [user, password] => Credentials::Password {
+            user: user.clone(),
+            password: password.clone(),
+        },
+        _ => return Reply::Error("ERR wrong number of arguments for 'auth' command".to_owned()),
+    };
+    let auth = match auth {
+        Some(auth) => auth,
+        None => {
+            return Reply::Error("ERR AUTH called without any authentication configured".to_owned())
+        }
+    };
+    match auth.authenticate(&credentials, None) {
+        Ok(authenticated) => {
+            *principal = authenticated;
+            Reply::Simple("OK")
+        }
+        Err(_) => {
+            Reply::Error("WRONGPASS invalid username-password pair or user is disabled.".to_owned())
+        }
+    }
+}
+
+/// Runs single command, every failure is turned into RESP error reply.
+fn execute<E: KvsEngine>(
+    engine: &E,
+    expirations: &Expirations,
+    stats: &Stats,
+    limits: &Limits,
+    principal: &Principal,
+    read_only: bool,
+    mut args: Vec<String>,
+) -> Reply {
+    let name = args.remove(0).to_uppercase();
+
+    let arity = match name.as_str() {
+        "PING" => (0, 1),
+        "GET" | "TTL" => (1, 1),
+        "SET" => (2, 4),
+        "DEL" | "EXISTS" | "MGET" => (1, usize::MAX),
+        "MSET" => (2, usize::MAX),
+        "SCAN" => (1, 5),
+        "EXPIRE" => (2, 2),
+        "INFO" | "COMMAND" => (0, usize::MAX),
+        _ => {
+            return Reply::Error(format!(
+                "ERR unknown command '{}', with args beginning with: {}",
+                name.to_lowercase(),
+                args.iter()
+                    .take(3)
+                    .map(|arg| format!("'{}'", arg))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ))
+        }
+    };
+    if args.len() < arity.0
+        || args.len() > arity.1
+        || (name == "MSET" && !args.len().is_multiple_of(2))
+    {
+        return Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name.to_lowercase()
+        ));
+    }
+
+    let (keys, access): (Vec<&str>, _) = match name.as_str() {
+        "GET" | "EXISTS" | "MGET" | "TTL" => {
+            (args.iter().map(String::as_str).collect(), Access::Read)
+        }
+        "SET" | "EXPIRE" => (vec![args[0].as_str()], Access::Write),
+        "DEL" => (args.iter().map(String::as_str).collect(), Access::Write),
+        "MSET" => (
+            args.iter().step_by(2).map(String::as_str).collect(),
+            Access::Write,
+        ),
+        // server wide command, needs admin access to every key.
+        "INFO" => (vec![""], Access::Admin),
+        _ => (vec![], Access::Read),
+    };
+    if read_only && access == Access::Write {
+        return Reply::Error("READONLY You can't write against a read only replica.".to_owned());
+    }
+    if keys.iter().any(|key| !principal.can(key, access)) {
+        return Reply::Error(format!(
+            "NOPERM {} has no permissions to run the '{}' command on these keys",
+            principal.name().unwrap_or("anonymous"),
+            name.to_lowercase()
+        ));
+    }
+    let sizes = keys
+        .iter()
+        .try_for_each(|key| limits.check_key(key))
+        .and_then(|()| match name.as_str() {
+            "SET" => limits.check_value(args[1].len()),
+            "MSET" => args
+                .iter()
+                .skip(1)
+                .step_by(2)
+                .try_for_each(|value| limits.check_value(value.len())),
+            _ => Ok(()),
+        });
+    if let Err(e) = sizes {
+        return Reply::Error(format!("ERR {}", e));
+    }
+
+    let session = Session {
+        engine,
+        expirations,
+        stats,
+        principal,
+    };
+    let result = match name.as_str() {
+        "PING" => Ok(match args.pop() {
+            Some(message) => Reply::Bulk(Some(message)),
+            None => Reply::Simple("PONG"),
+        }),
+        "GET" => session.get(args.remove(0)).map(Reply::Bulk),
+        "SET" => session.set(args),
+        "DEL" => session.del(args),
+        "EXISTS" => session.exists(args),
+        "MGET" => args
+            .into_iter()
+            .map(|key| session.get(key).map(Reply::Bulk))
+            .collect::<Result<_>>()
+            .map(Reply::Array),
+        "MSET" => session.mset(args),
+        "SCAN" => session.scan(args),
+        "EXPIRE" => session.expire(args.remove(0), &args[0]),
+        "TTL" => session.ttl(args.remove(0)),
+        "INFO" => session.info(),
+        // redis-cli asks for command docs on startup, we have none.
+        "COMMAND" => Ok(Reply::Array(vec![])),
+        _ => unreachable!("arity is checked for every known command"),
+    };
+
+    result.unwrap_or_else(|e| match e.downcast_ref::<RespError>() {
+        Some(RespError(msg)) => Reply::Error(msg.to_string()),
+        None => Reply::Error(format!("ERR {}", e)),
+    })
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+/// Error with message that is sent to the client as is.
+struct RespError(&'static str);
+
+/// Engine together with expiration deadlines of the listener.
+struct Session<'a, E: KvsEngine> {
+    engine: &'a E,
+    expirations: &'a Expirations,
+    stats: &'a Stats,
+    principal: &'a Principal,
+}
+
+impl<'a, E: KvsEngine> Session<'a, E> {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.expirations.purge(self.engine, &key)?;
+        self.engine.get(key)
+    }
+
+    fn set(&self, mut args: Vec<String>) -> Result<Reply> {
+        let ttl = match args.len() {
+            2 => None,
+            4 => {
+                let amount: u64 = args[3].parse().map_err(|_| RespError(NOT_INTEGER))?;
+                match args[2].to_uppercase().as_str() {
+                    "EX" => Some(Duration::from_secs(amount)),
+                    "PX" => Some(Duration::from_millis(amount)),
+                    _ => return Err(RespError("ERR syntax error").into()),
+                }
+            }
+            _ => return Err(RespError("ERR syntax error").into()),
+        };
+        args.truncate(2);
+        let value = args.pop().unwrap_or_default();
+        let key = args.pop().unwrap_or_default();
+
+        self.engine.set(key.clone(), value)?;
+        match ttl {
+            Some(ttl) => self.expirations.set(key, ttl),
+            None => self.expirations.clear(&key),
+        }
+        Ok(Reply::Simple("OK"))
+    }
+
+    fn del(&self, keys: Vec<String>) -> Result<Reply> {
+        let mut removed = 0;
+        for key in keys {
+            self.expirations.purge(self.engine, &key)?;
+            self.expirations.clear(&key);
+            match self.engine.remove(key) {
+                Ok(()) => removed += 1,
+                Err(e) => ignore_not_found(Err(e))?,
+            }
+        }
+        Ok(Reply::Integer(removed))
+    }
+
+    fn exists(&self, keys: Vec<String>) -> Result<Reply> {
+        let mut existing = 0;
+        for key in keys {
+            if self.get(key)?.is_some() {
+                existing += 1;
+            }
+        }
+        Ok(Reply::Integer(existing))
+    }
+
+    fn mset(&self, args: Vec<String>) -> Result<Reply> {
+        let mut args = args.into_iter();
+        while let (Some(key), Some(value)) = (args.next(), args.next()) {
+            self.expirations.clear(&key);
+            self.engine.set(key, value)?;
+        }
+        Ok(Reply::Simple("OK"))
+    }
+
+    /// Cursor is an index into sorted keys matching literal prefix of the pattern.
+    fn scan(&self, args: Vec<String>) -> Result<Reply> {
+        let cursor: usize = args[0]
+            .parse()
+            .map_err(|_| RespError("ERR invalid cursor"))?;
+        let mut pattern = "*".to_owned();
+        let mut count = DEFAULT_SCAN_COUNT;
+
+        let mut options = args[1..].chunks(2);
+        for option in options.by_ref() {
+            match (option[0].to_uppercase().as_str(), option.get(1)) {
+                ("MATCH", Some(p)) => pattern = p.clone(),
+                ("COUNT", Some(c)) => {
+                    count = c.parse().map_err(|_| RespError(NOT_INTEGER))?;
+                    if count == 0 {
+                        return Err(RespError("ERR syntax error").into());
+                    }
+                }
+                _ => return Err(RespError("ERR syntax error").into()),
+            }
+        }
+
+        let prefix: String = pattern
+            .chars()
+            .take_while(|c| !matches!(c, '*' | '?' | '[' | '\\'))
+            .collect();
+        let keys = self.engine.scan(prefix)?;
+        let end = cursor.saturating_add(count).min(keys.len());
+
+        let mut matched = vec![];
+        for key in keys.get(cursor..end).unwrap_or_default() {
+            if !glob_match(pattern.as_bytes(), key.as_bytes())
+                || !self.principal.can(key, Access::Read)
+            {
+                continue;
+            }
+            self.expirations.purge(self.engine, key)?;
+            if self.engine.get(key.clone())?.is_some() {
+                matched.push(Reply::Bulk(Some(key.clone())));
+            }
+        }
+
+        let next = if end >= keys.len() { 0 } else { end };
+        Ok(Reply::Array(vec![
+            Reply::Bulk(Some(next.to_string())),
+            Reply::Array(matched),
+        ]))
+    }
+
+    fn expire(&self, key: String, seconds: &str) -> Result<Reply> {
+        let seconds: i64 = seconds.parse().map_err(|_| RespError(NOT_INTEGER))?;
+        if self.get(key.clone())?.is_none() {
+            return Ok(Reply::Integer(0));
+        }
+        if seconds <= 0 {
+            self.expirations.clear(&key);
+            ignore_not_found(self.engine.remove(key))?;
+        } else {
+            self.expirations
+                .set(key, Duration::from_secs(seconds as u64));
+        }
+        Ok(Reply::Integer(1))
+    }
+
+    fn ttl(&self, key: String) -> Result<Reply> {
+        if self.get(key.clone())?.is_none() {
+            return Ok(Reply::Integer(-2));
+        }
+        Ok(Reply::Integer(match self.expirations.ttl(&key) {
+            Some(ttl) => ((ttl.as_millis() + 500) / 1000) as i64,
+            None => -1,
+        }))
+    }
+
+    fn info(&self) -> Result<Reply> {
+        let stats = self.stats.snapshot(self.engine.scan(String::new())?.len());
+        let replication = match &stats.replication {
+            Some(status) => format!(
+                "role:slave\r\nmaster_host:{}\r\nmaster_link_status:{}\r\nmaster_lag_millis:{}\r\n",
+                status.leader,
+                if status.connected { "up" } else { "down" },
+                status.lag_millis
+            ),
+            None => "role:master\r\n".to_owned(),
+        };
+        let info = format!(
+            "# Server\r\nkvs_version:{}\r\nuptime_in_seconds:{}\r\n\r\n\
+             # Clients\r\nconnected_clients:{}\r\n\r\n\
+             # Stats\r\ntotal_connections_received:{}\r\ntotal_commands_processed:{}\r\n\
+             rejected_connections:{}\r\n\r\n\
+             # Replication\r\n{}\r\n\
+             # Keyspace\r\nkeys:{}\r\nexpires:{}\r\n",
+            stats.version,
+            stats.uptime_secs,
+            stats.active_connections,
+            stats.total_connections,
+            stats.total_requests,
+            stats.rejected_connections,
+            replication,
+            stats.keys,
+            self.expirations.len(),
+        );
+        Ok(Reply::Bulk(Some(info)))
+    }
+}
+
+const NOT_INTEGER: &str = "ERR value is not an integer or out of range";
+
+/// Treats removal of missing key as success.
+fn ignore_not_found(result: Result<()>) -> Result<()> {
+    match result {
+        Err(e) if matches!(e.downcast_ref(), Some(KvsError::KeyNotFound)) => Ok(()),
+        result => result,
+    }
+}
+
+/// Redis style glob matching, supports `*`, `?` and `\` escapes.
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while i < s.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, i));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => {
+                p += 1;
+                i += 1;
+                continue;
+            }
+            Some(b'\\') if pattern.get(p + 1) == Some(&s[i]) => {
+                p += 2;
+                i += 1;
+                continue;
+            }
+            Some(c) if *c != b'\\' && *c == s[i] => {
+                p += 1;
+                i += 1;
+                continue;
+            }
+            _ => {}
+        }
+        match backtrack {
+            Some((star, matched)) => {
+                p = star + 1;
+                i = matched + 1;
+                backtrack = Some((star, matched + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
@@ -1,20 +1,43 @@
 use super::error::KvsError;
-use crate::cmd::{GetResponse, SetResponse, CMD};
+use crate::auth::{self, Access, Auth, Principal};
+use crate::cmd::{GetResponse, RemoveResponse, Response, ResponseError, SetResponse, CMD};
+use crate::config::{engine_dir, Config, ThreadPoolKind, TlsConfig};
 use crate::engines::sled::SledKvsEngine;
-use crate::thread_pool::{NaiveThreadPool, ThreadPool};
-use crate::{KvStore, KvsEngine, Result};
+use crate::http;
+use crate::limits::{self, Limits, Overflow, Slots};
+use crate::protocol::{
+    self, Chunks, Codec, Credentials, Hello, RequestFrame, ResponseFrame, MAGIC,
+};
+use crate::raft::{Member, Membership, NodeId, RaftEngine, RaftOptions, TcpTransport};
+use crate::replication;
+use crate::resp::{self, Expirations};
+use crate::stats::{Stats, StatsSnapshot};
+use crate::thread_pool::{
+    NaiveThreadPool, QueuePolicy, RayonThreadPool, SharedQueueThreadPool, ThreadPool,
+    WorkStealingThreadPool,
+};
+use crate::tls;
+use crate::transport::{Address, Listener, Stream};
+use crate::{Durability, KvStore, KvsEngine, Result, ValueWriter};
 use anyhow::bail;
 use clap::Parser;
+use crossbeam::channel::{self, RecvTimeoutError, Sender};
+use itertools::Itertools;
+use rustls::ServerConfig;
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
-use std::fs::OpenOptions;
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Display;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
-use std::{fmt::Display, net::SocketAddrV4};
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 /// Simple enum for engine changing.
 pub enum EngineType {
     Kvs,
@@ -39,179 +62,1398 @@ impl FromStr for EngineType {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// How the main listener serves connections.
+pub enum Runtime {
+    /// Every connection is a job on the thread pool.
+    Threads,
+    /// Connections are tokio tasks, requires `async` feature.
+    Async,
+}
+
+impl Display for Runtime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!("{:?}", self).to_lowercase())
+    }
+}
+
+impl FromStr for Runtime {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> std::result::Result<Runtime, KvsError> {
+        match s.to_lowercase().as_str() {
+            "threads" => Ok(Self::Threads),
+            "async" => Ok(Self::Async),
+            _ => Err(KvsError::Parse),
+        }
+    }
+}
+
 /// Current version of cargo pkg.
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 /// Cli for running server side.
+///
+/// Settings are taken from `--config` file, overridden by `KVS_*`
+/// environment variables named after the flags and then by the flags.
 pub struct ServerCLI {
+    /// TOML file with server settings, see `--print-config` for its format.
+    #[clap(long, env = "KVS_CONFIG", value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Print effective settings as TOML and exit.
+    #[clap(long)]
+    print_config: bool,
+    /// Addresses to listen on: `host:port` or `unix:/path`, repeated or
+    /// separated by commas to listen on several of them [default: 127.0.0.1:4000].
+    #[clap(long, env = "KVS_ADDR", value_delimiter = ',', value_name = "ADDR")]
+    addr: Vec<Address>,
+    /// Engine storing the data, kvs or sled [default: kvs].
+    #[clap(short, long, env = "KVS_ENGINE", value_name = "ENGINE-NAME")]
+    engine: Option<EngineType>,
+    /// Directory with engine data and its metadata [default: .].
+    #[clap(long, env = "KVS_DATA_DIR", value_name = "PATH")]
+    data_dir: Option<PathBuf>,
+    /// Serve main listener from the thread pool or from tokio runtime.
+    #[clap(long, env = "KVS_RUNTIME", value_name = "threads|async")]
+    runtime: Option<Runtime>,
+    /// Thread pool serving connections of the threaded runtime.
+    #[clap(
+        long,
+        env = "KVS_THREAD_POOL",
+        value_name = "naive|shared-queue|rayon|work-stealing"
+    )]
+    thread_pool: Option<ThreadPoolKind>,
+    /// Threads of the pool, defaults to number of CPUs.
+    #[clap(long, env = "KVS_THREADS", value_name = "N")]
+    threads: Option<usize>,
+    /// Connections waiting for a thread of shared-queue pool, unbounded by default.
+    #[clap(long, env = "KVS_QUEUE_SIZE", value_name = "N")]
+    queue_size: Option<usize>,
+    /// Make the accept loop wait for room in full queue, answer new
+    /// connections busy or drop the oldest queued ones [default: block].
+    #[clap(
+        long,
+        env = "KVS_QUEUE_POLICY",
+        value_name = "block|reject|shed-oldest"
+    )]
+    queue_policy: Option<QueuePolicy>,
+    /// Flush kvs log writes to the OS or sync them to disk.
+    #[clap(long, env = "KVS_DURABILITY", value_name = "flush|sync")]
+    durability: Option<Durability>,
+    /// Compact kvs log once that many records were written since the last compaction.
+    #[clap(long, env = "KVS_COMPACTION_THRESHOLD", value_name = "RECORDS")]
+    compaction_threshold: Option<u64>,
+    /// Additionally listen for Redis RESP2 clients on these addresses.
+    #[clap(
+        long,
+        env = "KVS_RESP_ADDR",
+        value_delimiter = ',',
+        value_name = "ADDR"
+    )]
+    resp_addr: Vec<Address>,
+    /// Additionally serve HTTP/JSON api on these addresses.
+    #[clap(
+        long,
+        env = "KVS_HTTP_ADDR",
+        value_delimiter = ',',
+        value_name = "ADDR"
+    )]
+    http_addr: Vec<Address>,
+    /// Connections served at once by all listeners together.
+    #[clap(long, env = "KVS_MAX_CONNECTIONS", value_name = "N")]
+    max_connections: Option<usize>,
+    /// Reject connections over `--max-connections` or wait for a free slot.
+    #[clap(long, env = "KVS_OVERFLOW", value_name = "reject|queue")]
+    overflow: Option<Overflow>,
+    /// Fail connections blocked on reading a started request.
+    #[clap(long, env = "KVS_READ_TIMEOUT", value_parser = parse_millis, value_name = "MS")]
+    read_timeout: Option<Duration>,
+    /// Fail connections blocked on writing a response.
+    #[clap(long, env = "KVS_WRITE_TIMEOUT", value_parser = parse_millis, value_name = "MS")]
+    write_timeout: Option<Duration>,
+    /// Close connections waiting for the next request, defaults to `--read-timeout`.
+    #[clap(long, env = "KVS_IDLE_TIMEOUT", value_parser = parse_millis, value_name = "MS")]
+    idle_timeout: Option<Duration>,
+    /// Answer requests not processed in time with timeout error.
+    #[clap(long, env = "KVS_REQUEST_TIMEOUT", value_parser = parse_millis, value_name = "MS")]
+    request_timeout: Option<Duration>,
+    /// Refuse keys longer than that.
+    #[clap(long, env = "KVS_MAX_KEY_SIZE", value_name = "BYTES")]
+    max_key_size: Option<usize>,
+    /// Refuse values longer than that, also when uploaded in chunks.
+    #[clap(long, env = "KVS_MAX_VALUE_SIZE", value_name = "BYTES")]
+    max_value_size: Option<usize>,
+    /// Skip requests longer than that, at most 64 MiB.
+    #[clap(long, env = "KVS_MAX_FRAME_SIZE", value_name = "BYTES")]
+    max_frame_size: Option<u32>,
+    /// PEM certificate chain, enables TLS on every listener.
+    #[clap(long, env = "KVS_TLS_CERT", requires = "tls-key", value_name = "PATH")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key matching `--tls-cert`.
+    #[clap(long, env = "KVS_TLS_KEY", requires = "tls-cert", value_name = "PATH")]
+    tls_key: Option<PathBuf>,
+    /// PEM CA certificates, requires clients to authenticate with certificate signed by them.
+    #[clap(long, env = "KVS_TLS_CLIENT_CA", value_name = "PATH")]
+    tls_client_ca: Option<PathBuf>,
+    /// JSON file with users and their grants, enables authentication.
+    #[clap(long, env = "KVS_AUTH", value_name = "PATH")]
+    auth: Option<PathBuf>,
+    /// Run as read-only follower replicating the leader at this address.
+    #[clap(long, value_name = "ADDR")]
+    replica_of: Option<Address>,
+    /// Token follower authenticates with at the leader.
+    #[clap(long, requires = "replica-of", value_name = "TOKEN")]
+    replica_token: Option<String>,
+    /// Id of this node in Raft cluster, enables Raft replication.
+    #[clap(
+        long,
+        requires = "raft-addr",
+        conflicts_with = "replica-of",
+        value_name = "ID"
+    )]
+    raft_id: Option<NodeId>,
+    /// Address other cluster nodes connect to.
     #[clap(
         action,
         long,
-        default_value_t = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 4000),
+        requires = "raft-id",
         value_parser,
-        value_name = "IP-PORT",
+        value_name = "IP-PORT"
+    )]
+    raft_addr: Option<SocketAddr>,
+    /// Other initial member of the cluster, repeated for each of them.
+    #[clap(
+        long,
+        requires = "raft-id",
+        value_parser = parse_peer,
+        value_name = "ID,RAFT-ADDR,CLIENT-ADDR"
     )]
-    addr: SocketAddrV4,
+    raft_peer: Vec<(NodeId, Member)>,
+    /// Start without membership and wait to be added to existing cluster.
+    #[clap(long, requires = "raft-id", conflicts_with = "raft-peer")]
+    raft_join: bool,
+    /// Secret shared by cluster nodes, connections to the Raft address
+    /// have to present it.
     #[clap(
-        short,
         long,
-        default_value_t = EngineType::Kvs,
-        value_name = "ENGINE-NAME",
+        env = "KVS_RAFT_TOKEN",
+        requires = "raft-id",
+        value_name = "TOKEN"
     )]
-    engine: EngineType,
+    raft_token: Option<String>,
+}
+
+/// Parses `ID,RAFT-ADDR,CLIENT-ADDR` of `--raft-peer`.
+fn parse_peer(s: &str) -> std::result::Result<(NodeId, Member), String> {
+    match s.split(',').collect::<Vec<_>>()[..] {
+        [id, raft_addr, client_addr] => Ok((
+            id.parse()
+                .map_err(|_| format!("invalid node id {:?}", id))?,
+            Member {
+                raft_addr: raft_addr.to_owned(),
+                client_addr: client_addr.to_owned(),
+            },
+        )),
+        _ => Err("expected ID,RAFT-ADDR,CLIENT-ADDR".to_owned()),
+    }
+}
+
+/// Parses duration given in milliseconds.
+fn parse_millis(s: &str) -> std::result::Result<Duration, String> {
+    match s.parse() {
+        Ok(millis) if millis > 0 => Ok(Duration::from_millis(millis)),
+        _ => Err(format!(
+            "expected positive number of milliseconds, got {:?}",
+            s
+        )),
+    }
 }
 
 impl ServerCLI {
     /// Starts server with given configuration.
     pub fn run(&self) -> Result<()> {
+        let config = self.config()?;
+        if self.print_config {
+            print!("{}", config.to_toml()?);
+            return Ok(());
+        }
+
         info!(
-            "version: {}, ip: {}, engine: {}",
-            VERSION, self.addr, self.engine
+            "version: {}, addr: {}, engine: {}, data dir: {}",
+            VERSION,
+            config.addr.iter().join(","),
+            config.engine,
+            config.data_dir.display()
         );
 
-        match self.engine {
-            EngineType::Kvs => self.run_kvs(),
-            EngineType::Sled => self.run_sled(),
+        // fail before the data directory is touched.
+        #[cfg(not(feature = "async"))]
+        if config.runtime == Runtime::Async {
+            bail!("server was built without async runtime");
+        }
+        config.check_data_dir()?;
+
+        match config.engine {
+            EngineType::Kvs => self.run_engine(
+                &config,
+                KvStore::open_with(engine_dir(&config.data_dir, config.engine), config.storage)?,
+            ),
+            EngineType::Sled => self.run_engine(
+                &config,
+                SledKvsEngine::new(engine_dir(&config.data_dir, config.engine))?,
+            ),
         }
     }
 
-    /// Starts server with KvStore as an engine.
-    fn run_kvs(&self) -> Result<()> {
-        let engine = KvStore::open("kv")?;
-        let mut server = KvServer::new(self.addr, self.engine, engine, NaiveThreadPool::new(0)?);
-        server.run()?;
-        Ok(())
+    /// Effective configuration: defaults overridden by config file, then
+    /// by environment and flags.
+    pub fn config(&self) -> Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+
+        if !self.addr.is_empty() {
+            config.addr = self.addr.clone();
+        }
+        if !self.resp_addr.is_empty() {
+            config.resp_addr = self.resp_addr.clone();
+        }
+        if !self.http_addr.is_empty() {
+            config.http_addr = self.http_addr.clone();
+        }
+        if let Some(engine) = self.engine {
+            config.engine = engine;
+        }
+        if let Some(data_dir) = &self.data_dir {
+            config.data_dir = data_dir.clone();
+        }
+        if let Some(runtime) = self.runtime {
+            config.runtime = runtime;
+        }
+        if let Some(kind) = self.thread_pool {
+            config.thread_pool.kind = kind;
+        }
+        if let Some(threads) = self.threads {
+            config.thread_pool.threads = threads;
+        }
+        if let Some(queue_size) = self.queue_size {
+            config.thread_pool.queue_size = Some(queue_size);
+        }
+        if let Some(policy) = self.queue_policy {
+            config.thread_pool.queue_policy = policy;
+        }
+        if let Some(durability) = self.durability {
+            config.storage.durability = durability;
+        }
+        if let Some(threshold) = self.compaction_threshold {
+            config.storage.compaction_threshold = threshold;
+        }
+
+        let limits = &mut config.limits;
+        limits.max_connections = self.max_connections.or(limits.max_connections);
+        limits.overflow = self.overflow.unwrap_or(limits.overflow);
+        limits.read_timeout = self.read_timeout.or(limits.read_timeout);
+        limits.write_timeout = self.write_timeout.or(limits.write_timeout);
+        limits.idle_timeout = self.idle_timeout.or(limits.idle_timeout);
+        limits.request_timeout = self.request_timeout.or(limits.request_timeout);
+        limits.max_key_size = self.max_key_size.or(limits.max_key_size);
+        limits.max_value_size = self.max_value_size.or(limits.max_value_size);
+        limits.max_frame_size = self.max_frame_size.or(limits.max_frame_size);
+
+        if let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) {
+            let client_ca = config.tls.and_then(|tls| tls.client_ca);
+            config.tls = Some(TlsConfig {
+                cert: cert.clone(),
+                key: key.clone(),
+                client_ca,
+            });
+        }
+        if let Some(client_ca) = &self.tls_client_ca {
+            match &mut config.tls {
+                Some(tls) => tls.client_ca = Some(client_ca.clone()),
+                None => bail!("--tls-client-ca requires TLS certificate and key"),
+            }
+        }
+        if let Some(auth) = &self.auth {
+            config.auth = Some(auth.clone());
+        }
+        Ok(config)
     }
 
-    /// Starts server with SledKvsEngine as an engine.
-    fn run_sled(&self) -> Result<()> {
-        let engine = SledKvsEngine::new("sled")?;
-        let mut server = KvServer::new(self.addr, self.engine, engine, NaiveThreadPool::new(0)?);
-        server.run()?;
-        Ok(())
+    /// Starts all configured listeners on top of the engine, replicated
+    /// through Raft when the node has an id.
+    fn run_engine<E: KvsEngine>(&self, config: &Config, engine: E) -> Result<()> {
+        let (id, raft_addr) = match (self.raft_id, self.raft_addr) {
+            (Some(id), Some(raft_addr)) => (id, raft_addr),
+            _ => return self.run_listeners(config, engine),
+        };
+
+        let mut members: Membership = self.raft_peer.iter().cloned().collect();
+        if !self.raft_join {
+            let client_addr = match config.addr.first() {
+                Some(addr) => addr.to_string(),
+                None => bail!("Raft node requires client address"),
+            };
+            let member = Member {
+                raft_addr: raft_addr.to_string(),
+                client_addr,
+            };
+            members.insert(id, member);
+        }
+        let mut transport = match &self.raft_token {
+            Some(token) => TcpTransport::new(token.as_str()),
+            None => bail!("Raft node requires cluster token"),
+        };
+        if let Some(tls) = &config.tls {
+            // nodes present their server certificate to each other, verified
+            // by the client CA or else by the node's own certificate chain.
+            let ca = tls.client_ca.as_deref().unwrap_or(&tls.cert);
+            transport = transport.with_tls(
+                tls::server_config(&tls.cert, &tls.key, tls.client_ca.as_deref())?,
+                tls::client_config(ca, Some((&tls.cert, &tls.key)))?,
+            );
+        }
+        let listener = TcpListener::bind(raft_addr)?;
+        let options = RaftOptions::new(id, members).with_dir(config.data_dir.join("raft"));
+        let engine = RaftEngine::start(options, engine, transport.clone())?;
+        transport.listen(listener, engine.mailbox());
+        info!("Raft node {} listening on {}", id, raft_addr);
+        self.run_listeners(config, engine)
+    }
+
+    fn run_listeners<E: KvsEngine>(&self, config: &Config, engine: E) -> Result<()> {
+        let threads = config.thread_pool.threads;
+        let queue_size = config.thread_pool.queue_size;
+        if queue_size.is_some() && config.thread_pool.kind != ThreadPoolKind::SharedQueue {
+            bail!("queue size requires shared-queue thread pool");
+        }
+        match config.thread_pool.kind {
+            ThreadPoolKind::Naive => self.serve(
+                config,
+                KvServer::new(engine, NaiveThreadPool::new(threads)?),
+            ),
+            ThreadPoolKind::SharedQueue => self.serve(
+                config,
+                KvServer::new(
+                    engine,
+                    match queue_size {
+                        Some(capacity) => SharedQueueThreadPool::bounded(
+                            threads,
+                            capacity,
+                            config.thread_pool.queue_policy,
+                        )?,
+                        None => SharedQueueThreadPool::new(threads)?,
+                    },
+                ),
+            ),
+            ThreadPoolKind::Rayon => self.serve(
+                config,
+                KvServer::new(engine, RayonThreadPool::new(threads)?),
+            ),
+            ThreadPoolKind::WorkStealing => self.serve(
+                config,
+                KvServer::new(engine, WorkStealingThreadPool::new(threads)?),
+            ),
+        }
+    }
+
+    fn serve<E, TP>(&self, config: &Config, server: KvServer<E, TP>) -> Result<()>
+    where
+        E: KvsEngine,
+        TP: ThreadPool + Send + Sync + 'static,
+    {
+        let mut server = server.with_limits(config.limits.clone());
+
+        if let Some(tls) = &config.tls {
+            server = server.with_tls(tls::server_config(
+                &tls.cert,
+                &tls.key,
+                tls.client_ca.as_deref(),
+            )?);
+        }
+        if let Some(path) = &config.auth {
+            server = server.with_auth(Auth::load(path)?);
+        }
+        if let Some(leader) = &self.replica_of {
+            let credentials = match &self.replica_token {
+                Some(token) => Credentials::Token(token.clone()),
+                None => Credentials::Anonymous,
+            };
+            server = server
+                .with_replica_of(leader.clone(), credentials)
+                .with_replica_state(config.data_dir.join("replication.json"));
+        }
+
+        for resp_addr in &config.resp_addr {
+            let listener = Listener::bind(resp_addr)?;
+            let server = server.clone();
+            thread::spawn(move || {
+                if let Err(e) = server.run_resp_on(listener) {
+                    error!("RESP listener failed: {}", e);
+                }
+            });
+        }
+
+        for http_addr in &config.http_addr {
+            let listener = Listener::bind(http_addr)?;
+            let server = server.clone();
+            thread::spawn(move || {
+                if let Err(e) = server.run_http_on(listener) {
+                    error!("HTTP listener failed: {}", e);
+                }
+            });
+        }
+
+        let listeners = config
+            .addr
+            .iter()
+            .map(Listener::bind)
+            .collect::<Result<Vec<_>>>()?;
+        match config.runtime {
+            Runtime::Threads => server.run_on_many(listeners),
+            #[cfg(feature = "async")]
+            Runtime::Async => server.run_async_on_many(listeners),
+            #[cfg(not(feature = "async"))]
+            Runtime::Async => bail!("server was built without async runtime"),
+        }
     }
 }
 
-/// Simple single-threader tcp server.
-struct KvServer<E: KvsEngine, TP: ThreadPool> {
-    ip: SocketAddrV4,
-    engine_type: EngineType,
-    engine: Arc<Mutex<E>>,
-    thread_pool: TP,
+/// Tcp server handling every connection as a job on the thread pool.
+pub struct KvServer<E: KvsEngine, TP: ThreadPool> {
+    pub(crate) engine: E,
+    thread_pool: Arc<TP>,
+    pub(crate) stats: Arc<Stats>,
+    pub(crate) tls: Option<Arc<ServerConfig>>,
+    pub(crate) auth: Option<Arc<Auth>>,
+    /// Leader address and credentials, set on read-only followers.
+    replica_of: Option<(Address, Credentials)>,
+    /// File the follower keeps its log position in.
+    replica_state: Option<PathBuf>,
+    pub(crate) limits: Limits,
+    pub(crate) slots: Arc<Slots>,
 }
 
 impl<E, TP> KvServer<E, TP>
 where
     E: KvsEngine,
-    TP: ThreadPool,
+    TP: ThreadPool + Send + Sync + 'static,
 {
-    fn new(ip: SocketAddrV4, engine_type: EngineType, engine: E, thread_pool: TP) -> Self {
+    pub fn new(engine: E, thread_pool: TP) -> Self {
         Self {
-            ip,
-            engine_type,
-            engine: Arc::new(Mutex::new(engine)),
-            thread_pool,
+            engine,
+            thread_pool: Arc::new(thread_pool),
+            stats: Arc::default(),
+            tls: None,
+            auth: None,
+            replica_of: None,
+            replica_state: None,
+            limits: Limits::default(),
+            slots: Arc::new(Slots::new(None)),
         }
     }
 
-    /// Checks "conf" file for determining wether server was already
-    /// started and if so checks if EngineType matches.
-    fn verify_conf(&self) -> Result<()> {
-        let conf_file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open("conf")?;
+    /// Limits connections of every listener, see `Limits`.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.slots = Arc::new(Slots::new(limits.max_connections));
+        self.limits = limits;
+        self
+    }
 
-        let content = std::fs::read_to_string("conf")?;
+    /// Wraps connections of every listener in TLS.
+    pub fn with_tls(mut self, config: Arc<ServerConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
 
-        if content.is_empty() {
-            serde_json::to_writer(BufWriter::new(conf_file), &self.engine_type)?;
-            return Ok(());
+    /// Requires clients of every listener to authenticate and checks their
+    /// grants for each command.
+    pub fn with_auth(mut self, auth: Auth) -> Self {
+        self.auth = Some(Arc::new(auth));
+        self
+    }
+
+    /// Makes server a read-only follower of the leader. Replication starts
+    /// together with the main listener.
+    pub fn with_replica_of(mut self, leader: impl Into<Address>, credentials: Credentials) -> Self {
+        self.replica_of = Some((leader.into(), credentials));
+        self
+    }
+
+    /// Keeps log position of the follower in the file, so that after restart
+    /// it continues from there instead of asking for a snapshot.
+    pub fn with_replica_state(mut self, path: impl Into<PathBuf>) -> Self {
+        self.replica_state = Some(path.into());
+        self
+    }
+
+    /// Current statistics of the server.
+    pub fn stats(&self) -> Result<StatsSnapshot> {
+        Ok(self.stats.snapshot(self.engine.scan(String::new())?.len()))
+    }
+
+    pub(crate) fn read_only(&self) -> bool {
+        self.replica_of.is_some()
+    }
+
+    /// Binds to given address and serves incoming connections.
+    pub fn run(&self, addr: impl ToSocketAddrs) -> Result<()> {
+        self.run_on(TcpListener::bind(addr)?)
+    }
+
+    /// Serves connections from already bound listener, handy for ephemeral ports.
+    pub fn run_on(&self, listener: impl Into<Listener>) -> Result<()> {
+        self.run_on_many(vec![listener.into()])
+    }
+
+    /// Serves connections from several listeners at once, each of them
+    /// accepted on its own thread.
+    pub fn run_on_many(&self, mut listeners: Vec<Listener>) -> Result<()> {
+        self.start_replication();
+        let last = listeners
+            .pop()
+            .ok_or_else(|| KvsError::Server("no address to listen on".to_owned()))?;
+        for listener in listeners {
+            let server = self.clone();
+            thread::spawn(move || {
+                if let Err(e) = server.accept_main(listener) {
+                    error!("Listener failed: {}", e);
+                }
+            });
         }
+        self.accept_main(last)
+    }
 
-        let previous_engine_type: EngineType = serde_json::from_str(&content)?;
+    /// Accepts framed and legacy clients from the listener.
+    fn accept_main(&self, listener: Listener) -> Result<()> {
+        info!("Server listening on {}", listener.local_addr()?);
+        self.accept(listener, protocol::busy_reply().to_vec(), serve)
+    }
 
-        if previous_engine_type != self.engine_type {
-            bail!("Invalid configuration")
+    /// Follows the leader on a background thread when server is a replica.
+    pub(crate) fn start_replication(&self) {
+        if let Some((leader, credentials)) = self.replica_of.clone() {
+            let engine = self.engine.clone();
+            let state = self.replica_state.clone();
+            let stats = self.stats.clone();
+            thread::spawn(move || replication::follow(engine, leader, credentials, state, stats));
         }
+    }
 
-        Ok(())
+    /// Serves Redis RESP2 clients from the listener, sharing engine and
+    /// thread pool with the main protocol.
+    pub fn run_resp_on(&self, listener: impl Into<Listener>) -> Result<()> {
+        let listener = listener.into();
+        info!("RESP listening on {}", listener.local_addr()?);
+        let expirations = Arc::new(Expirations::default());
+        self.accept(listener, resp::busy_reply(), move |server, stream| {
+            stream.set_read_timeout(server.limits.wait_timeout())?;
+            resp::serve_resp(
+                server.engine.clone(),
+                &expirations,
+                &server.stats,
+                &server.limits,
+                server.auth.as_deref(),
+                server.read_only(),
+                stream,
+            )
+        })
     }
 
-    pub fn run(&mut self) -> Result<()> {
-        self.verify_conf()?;
+    /// Serves HTTP/JSON api from the listener, sharing engine and thread
+    /// pool with the main protocol.
+    pub fn run_http_on(&self, listener: impl Into<Listener>) -> Result<()> {
+        let listener = listener.into();
+        info!("HTTP listening on {}", listener.local_addr()?);
+        self.accept(listener, http::busy_response(), |server, stream| {
+            stream.set_read_timeout(server.limits.wait_timeout())?;
+            http::serve_http(
+                server.engine.clone(),
+                &server.stats,
+                &server.limits,
+                server.auth.as_deref(),
+                server.read_only(),
+                stream,
+            )
+        })
+    }
 
-        let listener = TcpListener::bind(self.ip)?;
-        // accept connections and process them, spawning a new thread for each one
-        info!("Server listening on {}", self.ip);
+    /// Accepts connections and serves each of them as a job on the thread pool.
+    /// Connections over the limit are sent `reject` reply, unless they have
+    /// to be queued, and so are connections rejected by the thread pool.
+    fn accept<F>(&self, listener: Listener, reject: Vec<u8>, handler: F) -> Result<()>
+    where
+        F: Fn(&Self, Stream) -> Result<()> + Send + Sync + 'static,
+    {
+        let handler = Arc::new(handler);
+        let reject = Arc::new(reject);
 
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let engine = self.engine.clone();
-                    self.thread_pool.spawn(move || {
-                        if let Err(e) = serve(engine, stream) {
-                            error!("Error on serving client: {}", e);
-                        }
-                    })
+        loop {
+            let stream = match listener.accept() {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Connection failed: {}", e);
+                    continue;
                 }
-                Err(e) => error!("Connection failed: {}", e),
+            };
+            let slot = match self.slots.try_acquire() {
+                Some(slot) => slot,
+                None if self.limits.overflow == Overflow::Queue => self.slots.acquire(),
+                None => {
+                    debug!("Rejecting connection over the limit");
+                    self.stats.rejected_connection();
+                    if self.tls.is_none() {
+                        stream.reject(&reject);
+                    }
+                    continue;
+                }
+            };
+
+            let server = self.clone();
+            let handler = handler.clone();
+            let reject = self.tls.is_none().then(|| reject.clone());
+            let queued = QueuedConnection::new(stream, reject, self.stats.clone());
+            // rejected job was dropped together with the connection.
+            let _ = self.thread_pool.try_spawn(move || {
+                let stream = queued.start();
+                let _slot = slot;
+                let _connection = server.stats.connection();
+                let result = server.accept_stream(stream).and_then(|stream| {
+                    if let Some(identity) = stream.peer_identity() {
+                        debug!("Client authenticated as {}", identity);
+                    }
+                    handler(&server, stream)
+                });
+                match result {
+                    Err(e) if limits::is_timeout(&e) => {
+                        debug!("Closing client on socket timeout: {}", e);
+                        server.stats.io_timeout();
+                    }
+                    Err(e) => error!("Error on serving client: {}", e),
+                    Ok(()) => {}
+                }
+            });
+        }
+    }
+
+    /// Applies socket timeouts and performs TLS handshake when configured.
+    fn accept_stream(&self, stream: Stream) -> Result<Stream> {
+        stream.set_read_timeout(self.limits.read_timeout)?;
+        stream.set_write_timeout(self.limits.write_timeout)?;
+        Stream::accept(stream, self.tls.as_ref())
+    }
+}
+
+/// Connection waiting in the thread pool queue. Client is sent `reject`
+/// reply when the pool drops it without serving.
+struct QueuedConnection {
+    stream: Option<Stream>,
+    /// `None` with TLS, reply can't be sent before the handshake.
+    reject: Option<Arc<Vec<u8>>>,
+    stats: Arc<Stats>,
+}
+
+impl QueuedConnection {
+    fn new(stream: Stream, reject: Option<Arc<Vec<u8>>>, stats: Arc<Stats>) -> Self {
+        stats.queued_job();
+        Self {
+            stream: Some(stream),
+            reject,
+            stats,
+        }
+    }
+
+    /// Takes the connection off the queue to serve it.
+    fn start(mut self) -> Stream {
+        self.stream.take().expect("connection is served once")
+    }
+}
+
+impl Drop for QueuedConnection {
+    fn drop(&mut self) {
+        let rejected = self.stream.is_some();
+        self.stats.dequeued_job(rejected);
+        if let Some(stream) = self.stream.take() {
+            debug!("Rejecting connection dropped by the thread pool");
+            if let Some(reject) = &self.reject {
+                stream.reject(reject);
             }
         }
-        Ok(())
     }
 }
 
-fn serve<E: KvsEngine>(engine: Arc<Mutex<E>>, tcp: TcpStream) -> Result<()> {
-    let peer_addr = tcp.peer_addr()?;
-    let reader = BufReader::new(&tcp);
-    let mut writer = BufWriter::new(&tcp);
-    let cmd_reader = Deserializer::from_reader(reader).into_iter::<CMD>();
+impl<E: KvsEngine, TP: ThreadPool> Clone for KvServer<E, TP> {
+    fn clone(&self) -> Self {
+        Self {
+            engine: self.engine.clone(),
+            thread_pool: self.thread_pool.clone(),
+            stats: self.stats.clone(),
+            tls: self.tls.clone(),
+            auth: self.auth.clone(),
+            replica_of: self.replica_of.clone(),
+            replica_state: self.replica_state.clone(),
+            limits: self.limits.clone(),
+            slots: self.slots.clone(),
+        }
+    }
+}
 
-    for cmd in cmd_reader {
-        let cmd = cmd?;
+/// Detects protocol by the first byte sent by the client and serves the connection.
+fn serve<E, TP>(server: &KvServer<E, TP>, stream: Stream) -> Result<()>
+where
+    E: KvsEngine,
+    TP: ThreadPool + Send + Sync + 'static,
+{
+    let identity = stream.peer_identity();
+    let mut reader = BufReader::new(stream);
 
-        debug!("Receive request from {}: {:?}", peer_addr, cmd);
+    // client disconnected or stayed idle without sending anything.
+    if !wait_for_request(&mut reader, &server.limits, &server.stats)? {
+        return Ok(());
+    }
 
-        match cmd {
-            CMD::Set { key, value } => {
-                debug!("creating response");
+    if reader.buffer()[0] == MAGIC[0] {
+        serve_framed(server, identity, reader)
+    } else {
+        // legacy clients can't send credentials.
+        let principal = auth::authenticate(
+            server.auth.as_deref(),
+            &Credentials::Anonymous,
+            identity.as_deref(),
+        )?;
+        serve_legacy(server, &principal, reader)
+    }
+}
 
-                let response = match engine.lock().unwrap().set(key, value) {
-                    Ok(_) => SetResponse::Ok(()),
-                    Err(e) => SetResponse::Err(e.to_string()),
-                };
+/// Waits for the client to start sending next request, for at most idle
+/// timeout. Returns `false` when client disconnected or was idle for too long.
+fn wait_for_request(
+    reader: &mut BufReader<Stream>,
+    limits: &Limits,
+    stats: &Stats,
+) -> Result<bool> {
+    let wait_timeout = limits.wait_timeout();
+    if wait_timeout != limits.read_timeout {
+        reader.get_ref().set_read_timeout(wait_timeout)?;
+    }
+    let ready = match reader.fill_buf() {
+        Ok(buf) => !buf.is_empty(),
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            debug!("Closing idle client");
+            stats.idle_timeout();
+            return Ok(false);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if wait_timeout != limits.read_timeout {
+        reader.get_ref().set_read_timeout(limits.read_timeout)?;
+    }
+    Ok(ready)
+}
 
-                debug!("writing response");
+/// Serves framed protocol, see `protocol` module. Requests that client pipelined
+/// are read together and executed concurrently, responses are written in
+/// completion order.
+fn serve_framed<E, TP>(
+    server: &KvServer<E, TP>,
+    identity: Option<String>,
+    mut reader: BufReader<Stream>,
+) -> Result<()>
+where
+    E: KvsEngine,
+    TP: ThreadPool + Send + Sync + 'static,
+{
+    let peer_addr = reader.get_ref().peer_addr()?;
+    let mut out = vec![];
+    // reply is sent even when the handshake is rejected.
+    let hello = protocol::server_handshake(&mut reader, &mut out);
+    if hello.is_err() {
+        reader.get_mut().write_all(&out)?;
+    }
+    let Hello { codec, credentials } = hello?;
 
-                serde_json::to_writer(&mut writer, &response)?;
-                writer.flush()?;
+    debug!("{} negotiated codec {}", peer_addr, codec);
 
-                debug!("response written");
-            }
-            CMD::Get { key } => {
-                let response = match engine.lock().unwrap().get(key) {
-                    Ok(v) => match v {
-                        Some(v) => GetResponse::Ok(v),
-                        None => GetResponse::Err(String::from("Key not found")),
+    let principal = auth::authenticate(
+        server.auth.as_deref(),
+        credentials.as_ref().unwrap_or(&Credentials::Anonymous),
+        identity.as_deref(),
+    );
+    if credentials.is_some() {
+        let response = match &principal {
+            Ok(_) => Response::Ok,
+            Err(e) => Response::Err(ResponseError::PermissionDenied(e.to_string())),
+        };
+        protocol::write_frame(&mut out, codec, &response)?;
+    }
+    reader.get_mut().write_all(&out)?;
+    reader.get_mut().flush()?;
+    let principal = principal?;
+    if let Some(name) = principal.name() {
+        debug!("{} authenticated as {}", peer_addr, name);
+    }
+
+    let stats = &server.stats;
+    let mut uploads = Uploads::default();
+    loop {
+        if !wait_for_request(&mut reader, &server.limits, stats)? {
+            return Ok(());
+        }
+        let window =
+            protocol::read_requests(&mut reader, codec, MAX_WINDOW, server.limits.frame_size())?;
+        if window.is_empty() {
+            return Ok(());
+        }
+        let deadline = server.limits.deadline();
+
+        debug!("Receive {} requests from {}", window.len(), peer_addr);
+
+        out.clear();
+        let execute = |out: &mut Vec<u8>, window| {
+            execute_window(
+                &server.engine,
+                server.thread_pool.as_ref(),
+                window,
+                deadline,
+                |response| respond(out, stats, codec, response),
+            )
+        };
+
+        // refused requests are answered right away, streaming ones in order
+        // with the rest of the window.
+        let mut allowed = Vec::with_capacity(window.len());
+        for frame in window {
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(response) => {
+                    respond(&mut out, stats, codec, response)?;
+                    continue;
+                }
+            };
+            let admitted = authorize(&principal, server.read_only(), &frame.cmd)
+                .and_then(|()| server.limits.check(&frame.cmd));
+            if let Err(e) = admitted {
+                respond(
+                    &mut out,
+                    stats,
+                    codec,
+                    ResponseFrame {
+                        id: frame.id,
+                        response: Response::Err(e.into()),
                     },
-                    Err(e) => GetResponse::Err(e.to_string()),
-                };
-                serde_json::to_writer(&mut writer, &response)?;
-                writer.flush()?;
+                )?;
+                continue;
             }
-            CMD::Rm { key } => {
-                let response = match engine.lock().unwrap().remove(key) {
-                    Ok(_) => SetResponse::Ok(()),
-                    Err(e) => SetResponse::Err(e.to_string()),
+
+            match frame.cmd {
+                CMD::SetChunk { key, chunk, last } => {
+                    execute(&mut out, std::mem::take(&mut allowed))?;
+                    let response = uploads.chunk(&server.engine, &server.limits, key, chunk, last);
+                    let response = ResponseFrame {
+                        id: frame.id,
+                        response,
+                    };
+                    respond(&mut out, stats, codec, response)?;
+                }
+                CMD::AbortUpload { key } => {
+                    execute(&mut out, std::mem::take(&mut allowed))?;
+                    let response = ResponseFrame {
+                        id: frame.id,
+                        response: uploads.abort(&key),
+                    };
+                    respond(&mut out, stats, codec, response)?;
+                }
+                CMD::StreamGet { key } => {
+                    execute(&mut out, std::mem::take(&mut allowed))?;
+                    // chunks are written out one by one.
+                    reader.get_mut().write_all(&out)?;
+                    out.clear();
+                    for (idx, response) in ValueStream::new(&server.engine, key).enumerate() {
+                        if idx == 0 {
+                            stats.response(&response);
+                        }
+                        let response = ResponseFrame {
+                            id: frame.id,
+                            response,
+                        };
+                        protocol::write_frame(&mut out, codec, &response)?;
+                        reader.get_mut().write_all(&out)?;
+                        out.clear();
+                    }
+                }
+                cmd => allowed.push(RequestFrame { id: frame.id, cmd }),
+            }
+        }
+        execute(&mut out, allowed)?;
+        reader.get_mut().write_all(&out)?;
+        reader.get_mut().flush()?;
+    }
+}
+
+/// Encodes response of the framed protocol and counts it.
+fn respond(out: &mut Vec<u8>, stats: &Stats, codec: Codec, response: ResponseFrame) -> Result<()> {
+    stats.response(&response.response);
+    protocol::write_frame(out, codec, &response)
+}
+
+/// Values uploaded in chunks over single connection, by key.
+#[derive(Default)]
+pub(crate) struct Uploads(HashMap<String, Upload>);
+
+enum Upload {
+    Open {
+        writer: Box<dyn ValueWriter>,
+        size: usize,
+    },
+    /// Remaining chunks are answered with the error until the last one.
+    Failed(ResponseError),
+}
+
+impl Uploads {
+    /// Appends chunk to the upload of the key, sets the key on the last one.
+    pub fn chunk<E: KvsEngine>(
+        &mut self,
+        engine: &E,
+        limits: &Limits,
+        key: String,
+        chunk: String,
+        last: bool,
+    ) -> Response {
+        let upload = match self.0.remove(&key) {
+            Some(upload) => upload,
+            None => match engine.set_writer(key.clone()) {
+                Ok(writer) => Upload::Open { writer, size: 0 },
+                Err(e) => Upload::Failed(e.into()),
+            },
+        };
+        let upload = match upload {
+            Upload::Open { mut writer, size } => {
+                let size = size + chunk.len();
+                let written = limits
+                    .check_value(size)
+                    .and_then(|()| Ok(writer.write_all(chunk.as_bytes())?));
+                match written {
+                    Ok(()) => Upload::Open { writer, size },
+                    Err(e) => Upload::Failed(e.into()),
+                }
+            }
+            failed => failed,
+        };
+
+        match upload {
+            Upload::Open { writer, .. } if last => match writer.commit() {
+                Ok(()) => Response::Ok,
+                Err(e) => Response::Err(e.into()),
+            },
+            Upload::Failed(e) if last => Response::Err(e),
+            upload => {
+                let response = match &upload {
+                    Upload::Open { .. } => Response::Ok,
+                    Upload::Failed(e) => Response::Err(e.clone()),
                 };
-                serde_json::to_writer(&mut writer, &response)?;
-                writer.flush()?;
+                self.0.insert(key, upload);
+                response
             }
+        }
+    }
+
+    /// Discards unfinished upload of the key.
+    pub fn abort(&mut self, key: &str) -> Response {
+        self.0.remove(key);
+        Response::Ok
+    }
+}
+
+/// Responses of `CMD::StreamGet`, value is read from the engine one chunk
+/// at a time. Read failure ends the stream with an error response.
+pub(crate) struct ValueStream {
+    chunks: Option<Chunks<Box<dyn Read + Send>>>,
+    /// Sent instead of chunks when there is nothing to stream.
+    reply: Option<Response>,
+}
+
+impl ValueStream {
+    pub fn new<E: KvsEngine>(engine: &E, key: String) -> Self {
+        let (chunks, reply) = match engine.get_reader(key) {
+            Ok(Some(reader)) => (Some(Chunks::new(reader)), None),
+            Ok(None) => (None, Some(Response::Value(None))),
+            Err(e) => (None, Some(Response::Err(e.into()))),
+        };
+        Self { chunks, reply }
+    }
+}
+
+impl Iterator for ValueStream {
+    type Item = Response;
+
+    fn next(&mut self) -> Option<Response> {
+        if let Some(reply) = self.reply.take() {
+            return Some(reply);
+        }
+        match self.chunks.as_mut()?.next_chunk() {
+            Ok(Some((data, last))) => Some(Response::Chunk { data, last }),
+            Ok(None) => None,
+            Err(e) => {
+                self.chunks = None;
+                Some(Response::Err(e.into()))
+            }
+        }
+    }
+}
+
+/// Checks that principal is allowed to run the command and that writes
+/// are not sent to a read-only replica.
+pub(crate) fn authorize(principal: &Principal, read_only: bool, cmd: &CMD) -> Result<()> {
+    let (key, access) = match cmd {
+        CMD::Get { key } | CMD::StaleGet { key } | CMD::StreamGet { key } => {
+            (key.as_str(), Access::Read)
+        }
+        // listing requires access to every key under the prefix.
+        CMD::Scan { prefix } | CMD::ScanPage { prefix, .. } => (prefix.as_str(), Access::Read),
+        CMD::Set { key, .. }
+        | CMD::Rm { key }
+        | CMD::CompareAndSet { key, .. }
+        | CMD::CompareAndRemove { key, .. }
+        | CMD::SetChunk { key, .. }
+        | CMD::AbortUpload { key } => (key.as_str(), Access::Write),
+        // log contains every key.
+        CMD::Replicate { .. } | CMD::ReplicateSnapshot { .. } | CMD::ChangeMembership { .. } => {
+            ("", Access::Admin)
+        }
+    };
+    if read_only && access == Access::Write {
+        return Err(KvsError::ReadOnly.into());
+    }
+    principal.check(key, access)
+}
+
+/// Upper bound of pipelined requests executed at once for single connection.
+pub(crate) const MAX_WINDOW: usize = 128;
+
+/// Executes pipelined requests. Requests for the same key are run in order
+/// they were sent, independent keys run concurrently on the thread pool.
+/// Connection thread takes part in execution too so that busy pool can't
+/// deadlock it.
+///
+/// Requests not started before the deadline are not run, requests still
+/// running on the pool at the deadline are not waited for. Both are
+/// answered with `ResponseError::Timeout`.
+fn execute_window<E, TP>(
+    engine: &E,
+    thread_pool: &TP,
+    window: Vec<RequestFrame>,
+    deadline: Option<Instant>,
+    mut on_response: impl FnMut(ResponseFrame) -> Result<()>,
+) -> Result<()>
+where
+    E: KvsEngine,
+    TP: ThreadPool,
+{
+    if window.len() <= 1 || window.iter().any(|frame| frame.cmd.key().is_none()) {
+        for frame in window {
+            on_response(execute_frame(engine, frame, deadline))?;
+        }
+        return Ok(());
+    }
+
+    let mut pending: Vec<u64> = window.iter().map(|frame| frame.id).collect();
+    let mut groups: Vec<Vec<RequestFrame>> = vec![];
+    let mut group_by_key: HashMap<String, usize> = HashMap::new();
+    for frame in window {
+        let key = frame.cmd.key().unwrap_or_default().to_owned();
+        let idx = *group_by_key.entry(key).or_insert_with(|| {
+            groups.push(vec![]);
+            groups.len() - 1
+        });
+        groups[idx].push(frame);
+    }
+
+    let helpers = groups.len() - 1;
+    let queue = Arc::new(Mutex::new(VecDeque::from(groups)));
+    let (sender, receiver) = channel::unbounded();
+
+    // helpers must not wait for room in a bounded queue: workers blocked on
+    // it would never drain it. Groups they don't take are run right here.
+    for _ in 0..helpers {
+        let engine = engine.clone();
+        let queue = queue.clone();
+        let sender = sender.clone();
+        let spawned =
+            thread_pool.try_spawn_now(move || run_groups(&engine, &queue, deadline, &sender));
+        if spawned.is_err() {
+            break;
+        }
+    }
+    run_groups(engine, &queue, deadline, &sender);
+    drop(sender);
+
+    while !pending.is_empty() {
+        let response = match deadline {
+            Some(deadline) => match receiver.recv_deadline(deadline) {
+                Ok(response) => response,
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(e) => return Err(e.into()),
+            },
+            None => receiver.recv()?,
         };
+        if let Some(idx) = pending.iter().position(|id| *id == response.id) {
+            pending.swap_remove(idx);
+        }
+        on_response(response)?;
+    }
+    for id in pending {
+        on_response(ResponseFrame {
+            id,
+            response: Response::Err(ResponseError::Timeout),
+        })?;
     }
     Ok(())
 }
+
+/// Takes groups of requests from the queue until it's empty.
+fn run_groups<E: KvsEngine>(
+    engine: &E,
+    queue: &Mutex<VecDeque<Vec<RequestFrame>>>,
+    deadline: Option<Instant>,
+    sender: &Sender<ResponseFrame>,
+) {
+    loop {
+        let group = match queue.lock().unwrap().pop_front() {
+            Some(group) => group,
+            None => return,
+        };
+        for frame in group {
+            if sender.send(execute_frame(engine, frame, deadline)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Runs pipelined request unless it's past the deadline.
+pub(crate) fn execute_frame<E: KvsEngine>(
+    engine: &E,
+    frame: RequestFrame,
+    deadline: Option<Instant>,
+) -> ResponseFrame {
+    let response = if limits::expired(deadline) {
+        Response::Err(ResponseError::Timeout)
+    } else {
+        execute(engine, frame.cmd)
+    };
+    ResponseFrame {
+        id: frame.id,
+        response,
+    }
+}
+
+/// Error of legacy json request that is not complete within `max` bytes.
+/// Its real size is unknown, the request is never read whole.
+pub(crate) fn legacy_request_too_large(max: u32) -> anyhow::Error {
+    KvsError::FrameTooLarge {
+        size: max as u64 + 1,
+        max: max as u64,
+    }
+    .into()
+}
+
+/// Runs single command against the engine.
+pub(crate) fn execute<E: KvsEngine>(engine: &E, cmd: CMD) -> Response {
+    let result = match cmd {
+        CMD::Set { key, value } => engine.set(key, value).map(|_| Response::Ok),
+        CMD::Get { key } => engine.get(key).map(Response::Value),
+        CMD::Rm { key } => engine.remove(key).map(|_| Response::Ok),
+        CMD::Replicate { from } => engine
+            .read_log(from, replication::MAX_BATCH_SIZE)
+            .map(Response::Log),
+        CMD::ReplicateSnapshot { at, after } => engine
+            .read_snapshot(at, after, replication::MAX_BATCH_SIZE)
+            .map(Response::Log),
+        CMD::StaleGet { key } => engine.get_stale(key).map(Response::Value),
+        CMD::ChangeMembership { change } => engine.change_membership(change).map(|_| Response::Ok),
+        CMD::Scan { prefix } => engine.scan(prefix).map(Response::Keys),
+        CMD::ScanPage {
+            prefix,
+            after,
+            limit,
+        } => engine.scan_page(prefix, after, limit).map(Response::Keys),
+        CMD::CompareAndSet {
+            key,
+            expected,
+            value,
+        } => engine
+            .compare_and_set(key, expected, value)
+            .map(Response::Swapped),
+        CMD::CompareAndRemove { key, expected } => engine
+            .compare_and_remove(key, expected)
+            .map(Response::Swapped),
+        // connection keeps state of the stream, see `Uploads` and `ValueStream`.
+        CMD::SetChunk { .. } | CMD::AbortUpload { .. } | CMD::StreamGet { .. } => {
+            Err(KvsError::Server("command requires streaming connection".to_owned()).into())
+        }
+    };
+
+    result.unwrap_or_else(|e| Response::Err(e.into()))
+}
+
+impl From<anyhow::Error> for ResponseError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast_ref::<KvsError>() {
+            Some(KvsError::KeyNotFound) => ResponseError::KeyNotFound,
+            Some(KvsError::PermissionDenied(e)) => ResponseError::PermissionDenied(e.clone()),
+            Some(KvsError::ReadOnly) => ResponseError::ReadOnly,
+            Some(KvsError::NotLeader(leader)) => ResponseError::NotLeader {
+                leader: leader.clone(),
+            },
+            Some(KvsError::Timeout) => ResponseError::Timeout,
+            Some(KvsError::KeyTooLarge { size, max }) => ResponseError::KeyTooLarge {
+                size: *size,
+                max: *max,
+            },
+            Some(KvsError::ValueTooLarge { size, max }) => ResponseError::ValueTooLarge {
+                size: *size,
+                max: *max,
+            },
+            Some(KvsError::FrameTooLarge { size, max }) => ResponseError::FrameTooLarge {
+                size: *size,
+                max: *max,
+            },
+            _ => ResponseError::Internal(e.to_string()),
+        }
+    }
+}
+
+/// Serves stream of raw json `CMD`s, kept for clients predating framed protocol.
+fn serve_legacy<E, TP>(
+    server: &KvServer<E, TP>,
+    principal: &Principal,
+    mut reader: BufReader<Stream>,
+) -> Result<()>
+where
+    E: KvsEngine,
+    TP: ThreadPool + Send + Sync + 'static,
+{
+    let peer_addr = reader.get_ref().peer_addr()?;
+
+    loop {
+        if !wait_for_request(&mut reader, &server.limits, &server.stats)? {
+            return Ok(());
+        }
+        // skip whitespace between commands and stop on eof.
+        loop {
+            let buf = reader.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(());
+            }
+            match buf.iter().position(|b| !b.is_ascii_whitespace()) {
+                Some(idx) => {
+                    reader.consume(idx);
+                    break;
+                }
+                None => {
+                    let len = buf.len();
+                    reader.consume(len);
+                }
+            }
+        }
+
+        // serde_json reads only bytes of the single value from the reader,
+        // one byte over the limit tells that the value doesn't fit.
+        let max = server.limits.frame_size();
+        let mut limited = (&mut reader).take(max as u64 + 1);
+        let cmd = CMD::deserialize(&mut Deserializer::from_reader(&mut limited));
+        if limited.limit() == 0 {
+            return Err(legacy_request_too_large(max));
+        }
+        let cmd = cmd?;
+
+        debug!("Receive legacy request from {}: {:?}", peer_addr, cmd);
+
+        let response = legacy_response(
+            &server.engine,
+            principal,
+            server.read_only(),
+            &server.limits,
+            cmd,
+        )?;
+        reader.get_mut().write_all(&response)?;
+        reader.get_mut().flush()?;
+    }
+}
+
+/// Executes legacy command and encodes its response.
+pub(crate) fn legacy_response<E: KvsEngine>(
+    engine: &E,
+    principal: &Principal,
+    read_only: bool,
+    limits: &Limits,
+    cmd: CMD,
+) -> Result<Vec<u8>> {
+    let admitted = authorize(principal, read_only, &cmd).and_then(|()| limits.check(&cmd));
+    if let Err(e) = admitted {
+        return Ok(match cmd {
+            CMD::Set { .. }
+            | CMD::SetChunk { .. }
+            | CMD::AbortUpload { .. }
+            | CMD::CompareAndSet { .. } => serde_json::to_vec(&SetResponse::Err(e.to_string()))?,
+            CMD::Get { .. }
+            | CMD::StaleGet { .. }
+            | CMD::Replicate { .. }
+            | CMD::ReplicateSnapshot { .. }
+            | CMD::ChangeMembership { .. }
+            | CMD::Scan { .. }
+            | CMD::ScanPage { .. }
+            | CMD::StreamGet { .. } => serde_json::to_vec(&GetResponse::Err(e.to_string()))?,
+            CMD::Rm { .. } | CMD::CompareAndRemove { .. } => {
+                serde_json::to_vec(&RemoveResponse::Err(e.to_string()))?
+            }
+        });
+    }
+
+    let response = match cmd {
+        CMD::Set { key, value } => serde_json::to_vec(&match engine.set(key, value) {
+            Ok(_) => SetResponse::Ok(()),
+            Err(e) => SetResponse::Err(e.to_string()),
+        })?,
+        CMD::Get { key } => serde_json::to_vec(&match engine.get(key) {
+            Ok(Some(v)) => GetResponse::Ok(v),
+            Ok(None) => GetResponse::Err(String::from("Key not found")),
+            Err(e) => GetResponse::Err(e.to_string()),
+        })?,
+        CMD::Rm { key } => serde_json::to_vec(&match engine.remove(key) {
+            Ok(_) => RemoveResponse::Ok(()),
+            Err(e) => RemoveResponse::Err(e.to_string()),
+        })?,
+        CMD::StaleGet { key } => serde_json::to_vec(&match engine.get_stale(key) {
+            Ok(Some(v)) => GetResponse::Ok(v),
+            Ok(None) => GetResponse::Err(String::from("Key not found")),
+            Err(e) => GetResponse::Err(e.to_string()),
+        })?,
+        CMD::SetChunk { .. } | CMD::AbortUpload { .. } | CMD::CompareAndSet { .. } => {
+            serde_json::to_vec(&SetResponse::Err(
+                "command requires framed protocol".to_owned(),
+            ))?
+        }
+        CMD::CompareAndRemove { .. } => serde_json::to_vec(&RemoveResponse::Err(
+            "command requires framed protocol".to_owned(),
+        ))?,
+        CMD::Replicate { .. }
+        | CMD::ReplicateSnapshot { .. }
+        | CMD::ChangeMembership { .. }
+        | CMD::Scan { .. }
+        | CMD::ScanPage { .. }
+        | CMD::StreamGet { .. } => serde_json::to_vec(&GetResponse::Err(
+            "command requires framed protocol".to_owned(),
+        ))?,
+    };
+    Ok(response)
+}
@@ -0,0 +1,323 @@
+//! Client-side sharding over independent servers.
+//!
+//! Keys are assigned to servers by a consistent-hash ring: every server is
+//! hashed onto the ring at many points (virtual nodes) and a key belongs to
+//! the server owning the first point at or after the hash of the key. Adding
+//! or removing a server only moves keys of the ring segments it gains or
+//! loses, `ShardedClient::rebalance` moves them.
+
+use crate::client::unexpected;
+use crate::cmd::{Response, CMD};
+use crate::error::KvsError;
+use crate::{Codec, KvsClient, Result};
+use std::collections::{BTreeMap, HashSet};
+use std::thread;
+
+/// Virtual nodes of every server, enough for keys to spread evenly.
+pub const DEFAULT_VNODES: usize = 160;
+
+/// Keys listed from a server at once during rebalance.
+const REBALANCE_PAGE_SIZE: usize = 1000;
+
+#[derive(Debug, Clone)]
+/// Consistent-hash ring mapping keys to nodes.
+pub struct HashRing {
+    nodes: Vec<String>,
+    /// Hash of every virtual node and index of its node.
+    points: BTreeMap<u64, usize>,
+}
+
+impl HashRing {
+    /// Places every node at `vnodes` points of the ring.
+    pub fn new(nodes: impl IntoIterator<Item = String>, vnodes: usize) -> Self {
+        let nodes: Vec<String> = nodes.into_iter().collect();
+        let mut points = BTreeMap::new();
+        for (idx, node) in nodes.iter().enumerate() {
+            for vnode in 0..vnodes {
+                points.insert(fnv1a(format!("{}#{}", node, vnode).as_bytes()), idx);
+            }
+        }
+        Self { nodes, points }
+    }
+
+    pub fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+
+    /// Node owning the key, `None` for empty ring.
+    pub fn node(&self, key: &str) -> Option<&str> {
+        self.index(key).map(|idx| self.nodes[idx].as_str())
+    }
+
+    fn index(&self, key: &str) -> Option<usize> {
+        let hash = fnv1a(key.as_bytes());
+        self.points
+            .range(hash..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, idx)| *idx)
+    }
+}
+
+/// 64-bit FNV-1a, stable across processes and platforms unlike `DefaultHasher`.
+/// Finished with murmur3 finalizer, FNV alone barely changes high bits for
+/// keys differing in the last bytes and those bits decide ring position.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash = bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// Client routing every key to its server on the ring. Multi-key operations
+/// are pipelined to each server and run on all servers concurrently.
+pub struct ShardedClient {
+    ring: HashRing,
+    /// Connections in the order of ring nodes.
+    clients: Vec<KvsClient>,
+}
+
+impl ShardedClient {
+    /// Connects to every server.
+    pub fn connect(addrs: &[String], codec: Codec) -> Result<Self> {
+        let clients = addrs
+            .iter()
+            .map(|addr| Ok((addr.clone(), KvsClient::connect_to(&addr.parse()?, codec)?)))
+            .collect::<Result<Vec<_>>>()?;
+        Self::new(clients)
+    }
+
+    /// Builds client from already opened connections, named by address.
+    pub fn new(clients: Vec<(String, KvsClient)>) -> Result<Self> {
+        if clients.is_empty() {
+            return Err(KvsError::Server("no servers to shard over".to_owned()).into());
+        }
+        let (addrs, clients): (Vec<_>, Vec<_>) = clients.into_iter().unzip();
+        Ok(Self {
+            ring: HashRing::new(addrs, DEFAULT_VNODES),
+            clients,
+        })
+    }
+
+    pub fn ring(&self) -> &HashRing {
+        &self.ring
+    }
+
+    /// Sets the value on the server owning the key.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.shard(&key).set(key, value)
+    }
+
+    /// Gets the value from the server owning the key.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.shard(&key).get(key)
+    }
+
+    /// Gets local value of the owning server, see `KvsClient::get_stale`.
+    pub fn get_stale(&mut self, key: String) -> Result<Option<String>> {
+        self.shard(&key).get_stale(key)
+    }
+
+    /// Removes the key from the server owning it.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.shard(&key).remove(key)
+    }
+
+    /// Gets values of all keys, in the same order as keys.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let cmds = keys.into_iter().map(|key| CMD::Get { key }).collect();
+        self.fan_out(cmds)?
+            .into_iter()
+            .map(|response| match response {
+                Response::Value(value) => Ok(value),
+                Response::Err(e) => Err(KvsError::from(e).into()),
+                other => Err(unexpected(other)),
+            })
+            .collect()
+    }
+
+    /// Sets all values, fails with the first error after trying every one.
+    pub fn set_many(&mut self, entries: Vec<(String, String)>) -> Result<()> {
+        let cmds = entries
+            .into_iter()
+            .map(|(key, value)| CMD::Set { key, value })
+            .collect();
+        expect_ok(self.fan_out(cmds)?)
+    }
+
+    /// Removes all keys, fails with the first error after trying every one.
+    pub fn remove_many(&mut self, keys: Vec<String>) -> Result<()> {
+        let cmds = keys.into_iter().map(|key| CMD::Rm { key }).collect();
+        expect_ok(self.fan_out(cmds)?)
+    }
+
+    /// Keys starting with the prefix on every server, in ascending order.
+    pub fn scan(&mut self, prefix: String) -> Result<Vec<String>> {
+        let mut keys: Vec<String> = thread::scope(|scope| {
+            let scans: Vec<_> = self
+                .clients
+                .iter_mut()
+                .map(|client| {
+                    let prefix = prefix.clone();
+                    scope.spawn(move || client.scan(prefix))
+                })
+                .collect();
+            scans
+                .into_iter()
+                .map(|scan| scan.join().expect("scan thread panicked"))
+                .collect::<Result<Vec<_>>>()
+        })?
+        .into_iter()
+        .flatten()
+        .collect();
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    /// Moves every key to the server owning it on the ring. Keys are looked
+    /// for on ring servers and on `drained` ones, which are being removed.
+    /// Returns number of keys moved.
+    pub fn rebalance(&mut self, drained: Vec<(String, KvsClient)>) -> Result<usize> {
+        let ring_nodes: HashSet<String> = self.ring.nodes().iter().cloned().collect();
+        let mut moved = 0;
+
+        for idx in 0..self.clients.len() {
+            let source = self.ring.nodes()[idx].clone();
+            moved += self.move_misplaced(&source, Source::Shard(idx))?;
+        }
+
+        for (addr, mut client) in drained {
+            if ring_nodes.contains(&addr) {
+                continue;
+            }
+            let drained = self.move_misplaced(&addr, Source::Drained(&mut client))?;
+            info!("Drained {} keys from {}", drained, addr);
+            moved += drained;
+        }
+        Ok(moved)
+    }
+
+    /// Moves keys the ring doesn't assign to the `name`d source, listing
+    /// them a page at a time.
+    fn move_misplaced(&mut self, name: &str, mut source: Source) -> Result<usize> {
+        let mut moved = 0;
+        let mut after = None;
+        loop {
+            let page = self.source(&mut source).scan_page(
+                String::new(),
+                after.take(),
+                REBALANCE_PAGE_SIZE,
+            )?;
+            let full = page.len() == REBALANCE_PAGE_SIZE;
+            after = page.last().cloned();
+            for key in page {
+                if self.ring.node(&key) != Some(name) && self.move_key(&mut source, key)? {
+                    moved += 1;
+                }
+            }
+            if !full {
+                return Ok(moved);
+            }
+        }
+    }
+
+    /// Copies the key from the source to its owner, then removes it from the
+    /// source unless it changed meanwhile, in which case the copy is retried.
+    /// Value the owner got through the ring meanwhile is newer and is kept.
+    /// Returns whether the key was moved.
+    fn move_key(&mut self, source: &mut Source, key: String) -> Result<bool> {
+        // value written to the owner by previous attempt.
+        let mut written = None;
+        loop {
+            let value = match self.source(source).get(key.clone())? {
+                Some(value) => value,
+                // key removed concurrently has nothing to move.
+                None => return Ok(false),
+            };
+            let copied =
+                self.shard(&key)
+                    .compare_and_set(key.clone(), written.take(), value.clone())?;
+            let removed = self
+                .source(source)
+                .compare_and_remove(key.clone(), value.clone())?;
+            if !copied {
+                return Ok(false);
+            }
+            if removed {
+                return Ok(true);
+            }
+            written = Some(value);
+        }
+    }
+
+    fn source<'s>(&'s mut self, source: &'s mut Source) -> &'s mut KvsClient {
+        match source {
+            Source::Shard(idx) => &mut self.clients[*idx],
+            Source::Drained(client) => client,
+        }
+    }
+
+    fn shard(&mut self, key: &str) -> &mut KvsClient {
+        let idx = self.ring.index(key).expect("ring is never empty");
+        &mut self.clients[idx]
+    }
+
+    /// Sends commands pipelined to their servers, all servers at once, and
+    /// returns responses in the order of commands.
+    fn fan_out(&mut self, cmds: Vec<CMD>) -> Result<Vec<Response>> {
+        let total = cmds.len();
+        let mut batches: Vec<(Vec<usize>, Vec<CMD>)> = vec![(vec![], vec![]); self.clients.len()];
+        for (pos, cmd) in cmds.into_iter().enumerate() {
+            let idx = self
+                .ring
+                .index(cmd.key().unwrap_or_default())
+                .expect("ring is never empty");
+            batches[idx].0.push(pos);
+            batches[idx].1.push(cmd);
+        }
+
+        let results = thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .clients
+                .iter_mut()
+                .zip(batches)
+                .filter(|(_, (positions, _))| !positions.is_empty())
+                .map(|(client, (positions, cmds))| {
+                    scope.spawn(move || client.batch(cmds).map(|responses| (positions, responses)))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("batch thread panicked"))
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        let mut ordered = vec![None; total];
+        for (positions, responses) in results {
+            for (pos, response) in positions.into_iter().zip(responses) {
+                ordered[pos] = Some(response);
+            }
+        }
+        Ok(ordered.into_iter().flatten().collect())
+    }
+}
+
+/// Server keys are moved from during rebalance.
+enum Source<'a> {
+    Shard(usize),
+    Drained(&'a mut KvsClient),
+}
+
+pub(crate) fn expect_ok(responses: Vec<Response>) -> Result<()> {
+    responses
+        .into_iter()
+        .try_for_each(|response| match response {
+            Response::Ok => Ok(()),
+            Response::Err(e) => Err(KvsError::from(e).into()),
+            other => Err(unexpected(other)),
+        })
+}
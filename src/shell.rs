@@ -0,0 +1,252 @@
+//! Interactive shell of `kvs-client`, running commands over a single
+//! connection.
+//!
+//! Arguments are separated by whitespace and may be quoted. Double quoted
+//! and bare arguments understand `\n`, `\r`, `\t`, `\0`, `\\`, `\"`, `\'`
+//! and `\xHH` escapes, single quoted ones are taken literally. Values are
+//! printed quoted with the same escapes, so they can be pasted back.
+
+use crate::client::KvsClient;
+use crate::error::KvsError;
+use crate::transport::Address;
+use crate::Result;
+use anyhow::bail;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Set by Ctrl-C, stops `\watch`.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Default interval of `\watch`.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+const HELP: &str = "\
+get KEY                  print value of the key
+set KEY VALUE            set value of the key
+rm KEY                   remove the key
+scan [PREFIX]            list keys starting with the prefix
+info                     show connection details and number of keys
+\\watch KEY [SECONDS]     get the key every SECONDS until Ctrl-C
+help, \\?                 show this help
+quit, exit, \\q           leave the shell";
+
+/// Shell session over a connection to `addr`.
+pub(crate) struct Shell<'a> {
+    client: &'a mut KvsClient,
+    addr: Address,
+    /// Commands run in this session.
+    commands: u64,
+}
+
+impl<'a> Shell<'a> {
+    pub fn new(client: &'a mut KvsClient, addr: Address) -> Self {
+        Self {
+            client,
+            addr,
+            commands: 0,
+        }
+    }
+
+    /// Reads and runs commands until end of input or `quit`. History is
+    /// kept in `~/.kvs_history`.
+    pub fn run(mut self) -> Result<()> {
+        ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst))?;
+        let mut editor = DefaultEditor::new()?;
+        let history = history_path();
+        if let Some(history) = &history {
+            // there is no history before the first session.
+            let _ = editor.load_history(history);
+        }
+
+        loop {
+            let line = match editor.readline(&format!("{}> ", self.addr)) {
+                Ok(line) => line,
+                // Ctrl-C drops the line being edited.
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(e) => return Err(e.into()),
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            editor.add_history_entry(line.as_str())?;
+
+            let args = match split(&line) {
+                Ok(args) => args,
+                Err(e) => {
+                    println!("(error) {}", e);
+                    continue;
+                }
+            };
+            if matches!(args[0].as_str(), "quit" | "exit" | "\\q") {
+                break;
+            }
+            let started = Instant::now();
+            match self.execute(&args) {
+                Ok(()) => println!("({:.3} ms)", started.elapsed().as_secs_f64() * 1000.0),
+                Err(e) => println!("(error) {}", e),
+            }
+        }
+
+        if let Some(history) = &history {
+            editor.save_history(history)?;
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, args: &[String]) -> Result<()> {
+        self.commands += 1;
+        match (args[0].to_lowercase().as_str(), &args[1..]) {
+            ("get", [key]) => match self.client.get(key.clone())? {
+                Some(value) => println!("{}", quote(&value)),
+                None => println!("(nil)"),
+            },
+            ("set", [key, value]) => {
+                self.client.set(key.clone(), value.clone())?;
+                println!("OK");
+            }
+            ("rm", [key]) => {
+                self.client.remove(key.clone())?;
+                println!("OK");
+            }
+            ("scan", []) | ("scan", [_]) => {
+                let prefix = args.get(1).cloned().unwrap_or_default();
+                let keys = self.client.scan(prefix)?;
+                for (idx, key) in keys.iter().enumerate() {
+                    println!("{}) {}", idx + 1, quote(key));
+                }
+                if keys.is_empty() {
+                    println!("(empty)");
+                }
+            }
+            ("info", []) => {
+                println!("server: {}", self.addr);
+                println!("keys: {}", self.client.scan(String::new())?.len());
+                println!("commands: {}", self.commands);
+            }
+            ("\\watch", [key]) => self.watch(key, WATCH_INTERVAL)?,
+            ("\\watch", [key, secs]) => {
+                let secs: f64 = secs.parse().map_err(|_| KvsError::Parse)?;
+                if !secs.is_finite() || secs <= 0.0 {
+                    bail!("interval must be positive");
+                }
+                self.watch(key, Duration::from_secs_f64(secs))?
+            }
+            ("help", []) | ("\\?", []) => println!("{}", HELP),
+            ("get" | "set" | "rm" | "scan" | "info" | "\\watch" | "help" | "\\?", _) => {
+                bail!("wrong number of arguments for '{}'", args[0])
+            }
+            (cmd, _) => bail!("unknown command '{}', try 'help'", cmd),
+        }
+        Ok(())
+    }
+
+    /// Gets the key every interval until interrupted.
+    fn watch(&mut self, key: &str, interval: Duration) -> Result<()> {
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        let started = Instant::now();
+        while !INTERRUPTED.load(Ordering::SeqCst) {
+            let polled = Instant::now();
+            let value = self.client.get(key.to_owned())?;
+            println!(
+                "[{:.1}s] {} ({:.3} ms)",
+                polled.duration_since(started).as_secs_f64(),
+                value.as_deref().map_or("(nil)".to_owned(), quote),
+                polled.elapsed().as_secs_f64() * 1000.0
+            );
+            // sleep in steps, so Ctrl-C is noticed quickly.
+            let deadline = polled + interval;
+            while !INTERRUPTED.load(Ordering::SeqCst) && Instant::now() < deadline {
+                thread::sleep((deadline - Instant::now()).min(Duration::from_millis(50)));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".kvs_history"))
+}
+
+/// Splits the line into arguments, resolving quotes and escapes.
+fn split(line: &str) -> Result<Vec<String>> {
+    let mut args = vec![];
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+        // backslash commands, like `\watch`, are not escapes.
+        if args.is_empty() && chars.peek() == Some(&'\\') {
+            args.push(std::iter::from_fn(|| chars.next_if(|c| !c.is_whitespace())).collect());
+            continue;
+        }
+
+        // bytes, so that `\xHH` escapes can form multibyte characters.
+        let mut arg = vec![];
+        let mut quote = None;
+        loop {
+            let c = match (chars.next(), quote) {
+                (None, None) => break,
+                (None, Some(_)) => bail!("unterminated quote"),
+                (Some(c), None) if c.is_whitespace() => break,
+                (Some(c @ ('"' | '\'')), None) => {
+                    quote = Some(c);
+                    continue;
+                }
+                (Some(c), Some(q)) if c == q => {
+                    quote = None;
+                    continue;
+                }
+                (Some('\\'), q) if q != Some('\'') => match chars.next() {
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('0') => '\0',
+                    Some('x') => {
+                        let hex: String = chars.by_ref().take(2).collect();
+                        match u8::from_str_radix(&hex, 16) {
+                            Ok(byte) if hex.len() == 2 => arg.push(byte),
+                            _ => bail!("invalid escape \\x{}", hex),
+                        }
+                        continue;
+                    }
+                    Some(c @ ('\\' | '"' | '\'' | ' ')) => c,
+                    Some(c) => bail!("invalid escape \\{}", c),
+                    None => bail!("line ends with escape"),
+                },
+                (Some(c), _) => c,
+            };
+            let mut buf = [0; 4];
+            arg.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+        }
+        args.push(String::from_utf8(arg).map_err(|_| KvsError::Parse)?);
+    }
+    Ok(args)
+}
+
+/// Quotes the string, escaping quotes and control characters.
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() && (c as u32) < 0x80 => {
+                quoted.push_str(&format!("\\x{:02x}", c as u32))
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
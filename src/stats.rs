@@ -0,0 +1,169 @@
+use crate::cmd::{Response, ResponseError};
+use crate::replication::ReplicationStatus;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Counters shared by every listener of the server.
+#[derive(Debug)]
+pub struct Stats {
+    started: Instant,
+    total_connections: AtomicU64,
+    active_connections: AtomicU64,
+    total_requests: AtomicU64,
+    failed_requests: AtomicU64,
+    rejected_connections: AtomicU64,
+    queued_jobs: AtomicU64,
+    rejected_jobs: AtomicU64,
+    idle_timeouts: AtomicU64,
+    io_timeouts: AtomicU64,
+    request_timeouts: AtomicU64,
+    /// Set on followers only.
+    replication: Mutex<Option<ReplicationStatus>>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            total_connections: AtomicU64::default(),
+            active_connections: AtomicU64::default(),
+            total_requests: AtomicU64::default(),
+            failed_requests: AtomicU64::default(),
+            rejected_connections: AtomicU64::default(),
+            queued_jobs: AtomicU64::default(),
+            rejected_jobs: AtomicU64::default(),
+            idle_timeouts: AtomicU64::default(),
+            io_timeouts: AtomicU64::default(),
+            request_timeouts: AtomicU64::default(),
+            replication: Mutex::default(),
+        }
+    }
+}
+
+impl Stats {
+    /// Counts new connection, it stays active until returned guard is dropped.
+    pub fn connection(self: &Arc<Self>) -> ConnectionGuard {
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self.clone())
+    }
+
+    /// Counts processed request.
+    pub fn request(&self, failed: bool) {
+        self.total_requests.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.failed_requests.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts connection rejected over the limit.
+    pub fn rejected_connection(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts connection waiting in the thread pool queue, until
+    /// `dequeued_job`.
+    pub fn queued_job(&self) {
+        self.queued_jobs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts connection taken off the thread pool queue, `rejected` when
+    /// the pool dropped it without serving.
+    pub fn dequeued_job(&self, rejected: bool) {
+        self.queued_jobs.fetch_sub(1, Ordering::Relaxed);
+        if rejected {
+            self.rejected_jobs.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts connection closed after being idle for too long.
+    pub fn idle_timeout(&self) {
+        self.idle_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts connection failed on socket read or write timeout.
+    pub fn io_timeout(&self) {
+        self.io_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts request answered with `ResponseError::Timeout`.
+    pub fn request_timeout(&self) {
+        self.request_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts processed request by its response.
+    pub fn response(&self, response: &Response) {
+        self.request(matches!(response, Response::Err(_)));
+        if let Response::Err(ResponseError::Timeout) = response {
+            self.request_timeout();
+        }
+    }
+
+    /// Updates replication state of the follower.
+    pub fn set_replication(&self, status: ReplicationStatus) {
+        *self.replication.lock().unwrap() = Some(status);
+    }
+
+    pub fn snapshot(&self, keys: usize) -> StatsSnapshot {
+        StatsSnapshot {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            keys,
+            total_connections: self.total_connections.load(Ordering::Relaxed),
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            total_requests: self.total_requests.load(Ordering::Relaxed),
+            failed_requests: self.failed_requests.load(Ordering::Relaxed),
+            rejected_connections: self.rejected_connections.load(Ordering::Relaxed),
+            queued_jobs: self.queued_jobs.load(Ordering::Relaxed),
+            rejected_jobs: self.rejected_jobs.load(Ordering::Relaxed),
+            idle_timeouts: self.idle_timeouts.load(Ordering::Relaxed),
+            io_timeouts: self.io_timeouts.load(Ordering::Relaxed),
+            request_timeouts: self.request_timeouts.load(Ordering::Relaxed),
+            replication: self.replication.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Marks connection as active for its lifetime.
+pub struct ConnectionGuard(Arc<Stats>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Point in time view of server statistics.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatsSnapshot {
+    pub version: String,
+    pub uptime_secs: u64,
+    pub keys: usize,
+    pub total_connections: u64,
+    pub active_connections: u64,
+    pub total_requests: u64,
+    pub failed_requests: u64,
+    /// Connections rejected over `Limits::max_connections`.
+    #[serde(default)]
+    pub rejected_connections: u64,
+    /// Connections waiting for a thread of the pool.
+    #[serde(default)]
+    pub queued_jobs: u64,
+    /// Connections answered busy because thread pool queue was full.
+    #[serde(default)]
+    pub rejected_jobs: u64,
+    /// Connections closed after `Limits::idle_timeout`.
+    #[serde(default)]
+    pub idle_timeouts: u64,
+    /// Connections failed on socket read or write timeout.
+    #[serde(default)]
+    pub io_timeouts: u64,
+    /// Requests not answered before `Limits::request_timeout`.
+    #[serde(default)]
+    pub request_timeouts: u64,
+    /// Replication state, `None` unless server is a follower.
+    #[serde(default)]
+    pub replication: Option<ReplicationStatus>,
+}
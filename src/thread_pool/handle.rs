@@ -0,0 +1,73 @@
+use crossbeam::channel::{Receiver, RecvTimeoutError, TryRecvError};
+use std::any::Any;
+use std::fmt::{self, Debug};
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("job panicked: {message}")]
+/// Job spawned with `ThreadPool::spawn_with_handle` panicked, or was
+/// dropped by the pool without running.
+pub struct JobPanicked {
+    /// Message the job panicked with.
+    pub message: String,
+}
+
+impl JobPanicked {
+    /// Keeps message of `panic!` payload, which is either `&str` or `String`.
+    pub(crate) fn from_payload(payload: Box<dyn Any + Send>) -> Self {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => match payload.downcast::<&'static str>() {
+                Ok(message) => (*message).to_owned(),
+                Err(_) => "Box<dyn Any>".to_owned(),
+            },
+        };
+        Self { message }
+    }
+
+    fn dropped() -> Self {
+        Self {
+            message: "job was dropped without running".to_owned(),
+        }
+    }
+}
+
+/// Result of a job spawned with `ThreadPool::spawn_with_handle`.
+pub struct JobHandle<T> {
+    pub(crate) receiver: Receiver<Result<T, JobPanicked>>,
+}
+
+impl<T> JobHandle<T> {
+    /// Waits for the job to finish and returns its result.
+    pub fn join(self) -> Result<T, JobPanicked> {
+        self.receiver
+            .recv()
+            .unwrap_or_else(|_| Err(JobPanicked::dropped()))
+    }
+
+    /// Returns result of the job if it has finished, or the handle back.
+    pub fn try_join(self) -> Result<Result<T, JobPanicked>, Self> {
+        match self.receiver.try_recv() {
+            Ok(result) => Ok(result),
+            Err(TryRecvError::Empty) => Err(self),
+            Err(TryRecvError::Disconnected) => Ok(Err(JobPanicked::dropped())),
+        }
+    }
+
+    /// Waits at most `timeout` for the job to finish, returns the handle
+    /// back if it doesn't.
+    pub fn join_timeout(self, timeout: Duration) -> Result<Result<T, JobPanicked>, Self> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(result) => Ok(result),
+            Err(RecvTimeoutError::Timeout) => Err(self),
+            Err(RecvTimeoutError::Disconnected) => Ok(Err(JobPanicked::dropped())),
+        }
+    }
+}
+
+impl<T> Debug for JobHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JobHandle").finish_non_exhaustive()
+    }
+}
@@ -1,12 +1,24 @@
+mod handle;
 mod naive;
 mod rayon;
 mod shared_queue;
+mod work_stealing;
 
 use crate::Result;
+use crossbeam::channel;
+use std::panic::{self, AssertUnwindSafe};
+use thiserror::Error;
 
 pub use self::rayon::RayonThreadPool;
+pub use handle::{JobHandle, JobPanicked};
 pub use naive::NaiveThreadPool;
-pub use shared_queue::SharedQueueThreadPool;
+pub use shared_queue::{QueuePolicy, QueueStats, SharedQueueThreadPool};
+pub use work_stealing::WorkStealingThreadPool;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("job queue is full")]
+/// Job was rejected by pool with full bounded queue, see `QueuePolicy`.
+pub struct QueueFull;
 
 pub trait ThreadPool {
     fn new(threads: usize) -> Result<Self>
@@ -16,4 +28,42 @@ pub trait ThreadPool {
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+
+    /// Spawns the job unless the pool rejects it, in which case the job is
+    /// dropped. Pools without bounded queue never reject.
+    fn try_spawn<F>(&self, job: F) -> std::result::Result<(), QueueFull>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.spawn(job);
+        Ok(())
+    }
+
+    /// Spawns the job only if the pool takes it without waiting, whatever
+    /// its `QueuePolicy`, the job is dropped otherwise. Used for helper jobs
+    /// whose work the caller does itself when the pool is busy.
+    fn try_spawn_now<F>(&self, job: F) -> std::result::Result<(), QueueFull>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.try_spawn(job)
+    }
+
+    /// Spawns the job and returns handle for its result. Panic of the job is
+    /// caught and returned from the handle with the panic message, so it
+    /// doesn't take down the worker thread.
+    fn spawn_with_handle<F, T>(&self, job: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = channel::bounded(1);
+        self.spawn(move || {
+            let result =
+                panic::catch_unwind(AssertUnwindSafe(job)).map_err(JobPanicked::from_payload);
+            // nobody waits for the result if the handle was dropped.
+            let _ = sender.send(result);
+        });
+        JobHandle { receiver }
+    }
 }
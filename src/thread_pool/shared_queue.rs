@@ -1,7 +1,12 @@
-use crossbeam::channel::{self, Receiver, Sender};
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::thread::{self, spawn};
 
-use super::ThreadPool;
+use super::{QueueFull, ThreadPool};
+use crate::error::KvsError;
 
 /// Message that will be sent during job executions in SharedQueueThreadPool.
 enum ThreadPoolMessage {
@@ -9,15 +14,70 @@ enum ThreadPoolMessage {
     RunJob(Box<dyn FnOnce() + Send + 'static>),
 
     /// Sends shutdown signal.
+    #[allow(dead_code)]
     Shutdown,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+/// What happens to jobs spawned while bounded queue is full.
+pub enum QueuePolicy {
+    /// Caller waits until a worker takes a job off the queue.
+    #[default]
+    Block,
+    /// Job is dropped, `try_spawn` returns `QueueFull`.
+    Reject,
+    /// Oldest queued job is dropped to make room for the new one.
+    ShedOldest,
+}
+
+impl Display for QueuePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            QueuePolicy::Block => "block",
+            QueuePolicy::Reject => "reject",
+            QueuePolicy::ShedOldest => "shed-oldest",
+        })
+    }
+}
+
+impl FromStr for QueuePolicy {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> std::result::Result<QueuePolicy, KvsError> {
+        match s.to_lowercase().as_str() {
+            "block" => Ok(Self::Block),
+            "reject" => Ok(Self::Reject),
+            "shed-oldest" => Ok(Self::ShedOldest),
+            _ => Err(KvsError::Parse),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+/// Point in time view of the job queue.
+pub struct QueueStats {
+    /// Jobs waiting for a worker.
+    pub depth: usize,
+    /// `None` for unbounded queue.
+    pub capacity: Option<usize>,
+    /// Jobs dropped by `QueuePolicy::Reject`.
+    pub rejected: u64,
+    /// Jobs dropped by `QueuePolicy::ShedOldest`.
+    pub shed: u64,
+}
+
 /// Thread runner for concurrent jobs. Uses jobs queue for job distribution to spawned threads.
 /// It uses constant number of threads that will wait for specific job to run.
 /// It handles shutdown of those threads and panicking jobs.
 pub struct SharedQueueThreadPool {
     // will send proper message on some action.
     sender: Sender<ThreadPoolMessage>,
+    // kept for shedding oldest jobs of full queue.
+    receiver: Receiver<ThreadPoolMessage>,
+    policy: QueuePolicy,
+    rejected: AtomicU64,
+    shed: AtomicU64,
     //TODO: implement graceful shutdown.
     // handy for waiting for all threads to perform shutdown.
     // handles: Vec<JoinHandle<()>>,
@@ -26,30 +86,111 @@ pub struct SharedQueueThreadPool {
     // threads: usize,
 }
 
-impl ThreadPool for SharedQueueThreadPool {
-    /// Spawns `threads` number of new threads that will wait for job to execute.
-    fn new(threads: usize) -> crate::Result<Self>
-    where
-        Self: Sized,
-    {
-        let (sender, receiver) = channel::unbounded();
+impl SharedQueueThreadPool {
+    /// Spawns `threads` workers sharing queue of at most `capacity` jobs,
+    /// `policy` decides what happens to jobs spawned while it is full.
+    pub fn bounded(threads: usize, capacity: usize, policy: QueuePolicy) -> crate::Result<Self> {
+        if capacity == 0 {
+            return Err(KvsError::Server("job queue capacity must be positive".to_owned()).into());
+        }
+        Ok(Self::start(threads, channel::bounded(capacity), policy))
+    }
 
+    fn start(
+        threads: usize,
+        (sender, receiver): (Sender<ThreadPoolMessage>, Receiver<ThreadPoolMessage>),
+        policy: QueuePolicy,
+    ) -> Self {
         for _ in 0..threads {
             let receiver = receiver.clone();
             let _handle = spawn(move || handle(TaskReceiver(receiver)));
         }
 
-        Ok(Self { sender })
+        Self {
+            sender,
+            receiver,
+            policy,
+            rejected: AtomicU64::default(),
+            shed: AtomicU64::default(),
+        }
+    }
+
+    /// Current depth of the queue and jobs dropped so far.
+    pub fn queue_stats(&self) -> QueueStats {
+        QueueStats {
+            depth: self.sender.len(),
+            capacity: self.sender.capacity(),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            shed: self.shed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Queues the job according to the policy, returns it back if rejected.
+    fn send(&self, mut msg: ThreadPoolMessage) -> Result<(), ThreadPoolMessage> {
+        if self.policy == QueuePolicy::Block {
+            return self.sender.send(msg).map_err(|e| e.into_inner());
+        }
+        loop {
+            match self.sender.try_send(msg) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(back)) if self.policy == QueuePolicy::ShedOldest => {
+                    // workers may have emptied the queue meanwhile.
+                    if self.receiver.try_recv().is_ok() {
+                        debug!("Job queue is full, dropped the oldest job");
+                        self.shed.fetch_add(1, Ordering::Relaxed);
+                    }
+                    msg = back;
+                }
+                Err(TrySendError::Full(back)) => {
+                    self.rejected.fetch_add(1, Ordering::Relaxed);
+                    return Err(back);
+                }
+                Err(TrySendError::Disconnected(back)) => return Err(back),
+            }
+        }
+    }
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    /// Spawns `threads` number of new threads that will wait for job to execute.
+    /// Their queue is unbounded.
+    fn new(threads: usize) -> crate::Result<Self>
+    where
+        Self: Sized,
+    {
+        Ok(Self::start(
+            threads,
+            channel::unbounded(),
+            QueuePolicy::Block,
+        ))
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        if let Err(e) = self.sender.send(ThreadPoolMessage::RunJob(Box::new(job))) {
+        if let Err(e) = self.try_spawn(job) {
             error!("could not send a job: {}", e);
         }
     }
+
+    fn try_spawn<F>(&self, job: F) -> Result<(), QueueFull>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.send(ThreadPoolMessage::RunJob(Box::new(job)))
+            .map_err(|_| QueueFull)
+    }
+
+    fn try_spawn_now<F>(&self, job: F) -> Result<(), QueueFull>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // neither waits for room nor sheds queued jobs.
+        self.sender
+            .try_send(ThreadPoolMessage::RunJob(Box::new(job)))
+            .map_err(|_| QueueFull)
+    }
 }
 
 #[derive(Clone)]
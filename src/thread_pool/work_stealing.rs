@@ -0,0 +1,162 @@
+use crossbeam::deque::{Injector, Steal, Stealer, Worker};
+use std::cell::RefCell;
+use std::iter;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use super::ThreadPool;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+thread_local! {
+    /// Local queue of the worker running on this thread.
+    static LOCAL: RefCell<Option<Rc<LocalQueue>>> = const { RefCell::new(None) };
+}
+
+/// Local queue of a worker, together with the pool it belongs to.
+struct LocalQueue {
+    shared: Arc<Shared>,
+    worker: Worker<Job>,
+}
+
+/// State shared by the pool and its workers.
+struct Shared {
+    /// Jobs spawned from outside of the workers.
+    injector: Injector<Job>,
+    /// Stealing ends of local queues of all workers.
+    stealers: Vec<Stealer<Job>>,
+    /// Workers waiting for jobs.
+    sleeping: AtomicUsize,
+    lock: Mutex<()>,
+    wake: Condvar,
+    /// Set once the pool is dropped, workers quit when they run out of jobs.
+    shutdown: AtomicBool,
+}
+
+impl Shared {
+    fn has_jobs(&self) -> bool {
+        !self.injector.is_empty() || self.stealers.iter().any(|stealer| !stealer.is_empty())
+    }
+
+    /// Wakes a sleeping worker to pick up job pushed to any queue.
+    fn notify(&self) {
+        // pairs with the fence in `sleep`, either the worker sees the job or
+        // its sleep is seen here.
+        atomic::fence(Ordering::SeqCst);
+        if self.sleeping.load(Ordering::SeqCst) > 0 {
+            let _guard = self.lock.lock().unwrap();
+            self.wake.notify_one();
+        }
+    }
+
+    /// Waits until there may be a job or the pool is shut down.
+    fn sleep(&self) {
+        let guard = self.lock.lock().unwrap();
+        self.sleeping.fetch_add(1, Ordering::SeqCst);
+        atomic::fence(Ordering::SeqCst);
+        if !self.has_jobs() && !self.shutdown.load(Ordering::SeqCst) {
+            let _guard = self.wake.wait(guard).unwrap();
+        }
+        self.sleeping.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Thread runner with a local queue per worker. Jobs spawned from outside go
+/// to a global queue, jobs spawned by running jobs go to the local queue of
+/// their worker. Idle workers take jobs from the global queue first and then
+/// steal from local queues of others.
+pub struct WorkStealingThreadPool {
+    shared: Arc<Shared>,
+}
+
+impl ThreadPool for WorkStealingThreadPool {
+    /// Spawns `threads` workers, each with its own local queue.
+    fn new(threads: usize) -> crate::Result<Self>
+    where
+        Self: Sized,
+    {
+        let workers: Vec<_> = (0..threads).map(|_| Worker::new_fifo()).collect();
+        let shared = Arc::new(Shared {
+            injector: Injector::new(),
+            stealers: workers.iter().map(Worker::stealer).collect(),
+            sleeping: AtomicUsize::new(0),
+            lock: Mutex::new(()),
+            wake: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+        });
+
+        for worker in workers {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("kvs-worker".to_owned())
+                .spawn(move || run(LocalQueue { shared, worker }))?;
+        }
+
+        Ok(Self { shared })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let job: Job = Box::new(job);
+        let job = LOCAL.with(|local| match &*local.borrow() {
+            Some(local) if Arc::ptr_eq(&local.shared, &self.shared) => {
+                local.worker.push(job);
+                None
+            }
+            _ => Some(job),
+        });
+        if let Some(job) = job {
+            self.shared.injector.push(job);
+        }
+        self.shared.notify();
+    }
+}
+
+impl Drop for WorkStealingThreadPool {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        let _guard = self.shared.lock.lock().unwrap();
+        self.shared.wake.notify_all();
+    }
+}
+
+fn run(local: LocalQueue) {
+    let local = Rc::new(local);
+    LOCAL.with(|cell| *cell.borrow_mut() = Some(local.clone()));
+
+    loop {
+        match find_job(&local) {
+            Some(job) => {
+                // panic is reported by the hook, the worker keeps its queue.
+                if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                    error!("job panicked");
+                }
+            }
+            None if local.shared.shutdown.load(Ordering::SeqCst) => break,
+            None => local.shared.sleep(),
+        }
+    }
+
+    LOCAL.with(|cell| cell.borrow_mut().take());
+}
+
+/// Takes job from the local queue, then from the global one and finally
+/// steals it from other workers.
+fn find_job(local: &LocalQueue) -> Option<Job> {
+    local.worker.pop().or_else(|| {
+        iter::repeat_with(|| {
+            local
+                .shared
+                .injector
+                .steal_batch_and_pop(&local.worker)
+                .or_else(|| local.shared.stealers.iter().map(Stealer::steal).collect())
+        })
+        .find(|steal| !steal.is_retry())
+        .and_then(Steal::success)
+    })
+}
@@ -0,0 +1,279 @@
+//! Bulk import and export of keys with their values, in JSON Lines or CSV.
+//! Used by `kvs-client` against servers and by `kvs-admin` directly on
+//! data directories.
+
+use crate::client::{unexpected, KvsClient};
+use crate::cmd::{Response, CMD};
+use crate::error::KvsError;
+use crate::shard::{expect_ok, ShardedClient};
+use crate::{KvsEngine, Result};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Imported records are applied in batches of this many, unless configured
+/// otherwise.
+pub(crate) const DEFAULT_BATCH_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// Format of imported and exported files.
+pub enum Format {
+    /// One `{"key": .., "value": ..}` object per line.
+    Jsonl,
+    /// `key,value` header followed by one record per row.
+    Csv,
+}
+
+impl Format {
+    /// Format given explicitly, or the one matching file extension,
+    /// JSON Lines by default.
+    pub(crate) fn resolve(format: Option<Format>, path: Option<&Path>) -> Format {
+        match (format, path.and_then(|path| path.extension())) {
+            (Some(format), _) => format,
+            (None, Some(ext)) if ext == "csv" => Format::Csv,
+            _ => Format::Jsonl,
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!("{:?}", self).to_lowercase())
+    }
+}
+
+impl FromStr for Format {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> std::result::Result<Format, KvsError> {
+        match s.to_lowercase().as_str() {
+            "jsonl" | "json" => Ok(Self::Jsonl),
+            "csv" => Ok(Self::Csv),
+            _ => Err(KvsError::Parse),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+/// Single record of imported and exported files.
+struct Record {
+    key: String,
+    value: String,
+}
+
+/// Keys imported to or exported from, either over the network or
+/// directly from an engine.
+pub(crate) trait BulkStore {
+    /// Sets all values, fails with the first error.
+    fn set_many(&mut self, entries: Vec<(String, String)>) -> Result<()>;
+
+    /// Values of keys in the same order, `None` for keys that don't exist.
+    fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>>;
+
+    /// Keys starting with the prefix, in ascending order.
+    fn scan(&mut self, prefix: String) -> Result<Vec<String>>;
+}
+
+impl BulkStore for KvsClient {
+    fn set_many(&mut self, entries: Vec<(String, String)>) -> Result<()> {
+        let cmds = entries
+            .into_iter()
+            .map(|(key, value)| CMD::Set { key, value })
+            .collect();
+        expect_ok(self.batch(cmds)?)
+    }
+
+    fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let cmds = keys.into_iter().map(|key| CMD::Get { key }).collect();
+        self.batch(cmds)?
+            .into_iter()
+            .map(|response| match response {
+                Response::Value(value) => Ok(value),
+                Response::Err(e) => Err(KvsError::from(e).into()),
+                other => Err(unexpected(other)),
+            })
+            .collect()
+    }
+
+    fn scan(&mut self, prefix: String) -> Result<Vec<String>> {
+        KvsClient::scan(self, prefix)
+    }
+}
+
+impl BulkStore for ShardedClient {
+    fn set_many(&mut self, entries: Vec<(String, String)>) -> Result<()> {
+        ShardedClient::set_many(self, entries)
+    }
+
+    fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        ShardedClient::get_many(self, keys)
+    }
+
+    fn scan(&mut self, prefix: String) -> Result<Vec<String>> {
+        ShardedClient::scan(self, prefix)
+    }
+}
+
+/// Engine opened directly on its data directory.
+pub(crate) struct EngineStore<E>(pub E);
+
+impl<E: KvsEngine> BulkStore for EngineStore<E> {
+    fn set_many(&mut self, entries: Vec<(String, String)>) -> Result<()> {
+        entries
+            .into_iter()
+            .try_for_each(|(key, value)| self.0.set(key, value))
+    }
+
+    fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.0.get(key)).collect()
+    }
+
+    fn scan(&mut self, prefix: String) -> Result<Vec<String>> {
+        self.0.scan(prefix)
+    }
+}
+
+/// Import of a file, `-` being standard input.
+pub(crate) struct Import<'a> {
+    pub path: &'a Path,
+    pub format: Option<Format>,
+    pub batch_size: usize,
+}
+
+impl Import<'_> {
+    /// Reads all records and sets them batch by batch, returns how many
+    /// were read. Without store records are only validated. Batches applied
+    /// before a failure stay applied.
+    pub fn run(&self, mut store: Option<&mut dyn BulkStore>) -> Result<u64> {
+        let input: Box<dyn Read> = if self.path == Path::new("-") {
+            Box::new(io::stdin().lock())
+        } else {
+            Box::new(
+                File::open(self.path)
+                    .with_context(|| format!("could not open {}", self.path.display()))?,
+            )
+        };
+        let format = Format::resolve(self.format, Some(self.path));
+        let batch_size = self.batch_size.max(1);
+
+        let mut imported = 0;
+        let mut batch = Vec::with_capacity(batch_size);
+        for record in read_records(BufReader::new(input), format) {
+            let record = record.with_context(|| format!("invalid record {}", imported + 1))?;
+            batch.push((record.key, record.value));
+            imported += 1;
+            if batch.len() == batch_size {
+                apply(&mut store, std::mem::take(&mut batch), imported)?;
+            }
+        }
+        if !batch.is_empty() {
+            apply(&mut store, batch, imported)?;
+        }
+        Ok(imported)
+    }
+}
+
+/// Sets the batch ending at `imported` record and reports progress.
+fn apply(
+    store: &mut Option<&mut dyn BulkStore>,
+    batch: Vec<(String, String)>,
+    imported: u64,
+) -> Result<()> {
+    match store {
+        Some(store) => {
+            store
+                .set_many(batch)
+                .with_context(|| format!("import failed in batch ending at record {}", imported))?;
+            info!("Imported {} records", imported);
+        }
+        None => info!("Validated {} records", imported),
+    }
+    Ok(())
+}
+
+/// Writes keys starting with the prefix, with their values, to `out`.
+/// Returns how many were written, keys removed meanwhile are skipped.
+pub(crate) fn export(
+    store: &mut dyn BulkStore,
+    prefix: String,
+    format: Format,
+    out: impl Write,
+) -> Result<u64> {
+    let mut writer = RecordWriter::new(BufWriter::new(out), format);
+    let keys = store.scan(prefix)?;
+
+    let mut exported = 0;
+    for chunk in keys.chunks(DEFAULT_BATCH_SIZE) {
+        let values = store.get_many(chunk.to_vec())?;
+        for (key, value) in chunk.iter().zip(values) {
+            if let Some(value) = value {
+                writer.write(Record {
+                    key: key.clone(),
+                    value,
+                })?;
+                exported += 1;
+            }
+        }
+        info!("Exported {} of {} keys", exported, keys.len());
+    }
+    writer.finish()?;
+    Ok(exported)
+}
+
+/// Decodes records of the input one by one.
+fn read_records<'a>(
+    input: impl Read + 'a,
+    format: Format,
+) -> Box<dyn Iterator<Item = Result<Record>> + 'a> {
+    match format {
+        Format::Jsonl => Box::new(
+            serde_json::Deserializer::from_reader(input)
+                .into_iter()
+                .map(|record| Ok(record?)),
+        ),
+        Format::Csv => Box::new(
+            csv::Reader::from_reader(input)
+                .into_deserialize()
+                .map(|record| Ok(record?)),
+        ),
+    }
+}
+
+/// Encodes records to the output.
+enum RecordWriter<W: Write> {
+    Jsonl(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> RecordWriter<W> {
+    fn new(out: W, format: Format) -> Self {
+        match format {
+            Format::Jsonl => RecordWriter::Jsonl(out),
+            Format::Csv => RecordWriter::Csv(Box::new(csv::Writer::from_writer(out))),
+        }
+    }
+
+    fn write(&mut self, record: Record) -> Result<()> {
+        match self {
+            RecordWriter::Jsonl(out) => {
+                serde_json::to_writer(&mut *out, &record)?;
+                out.write_all(b"\n")?;
+            }
+            RecordWriter::Csv(out) => out.serialize(record)?,
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            RecordWriter::Jsonl(mut out) => out.flush()?,
+            RecordWriter::Csv(mut out) => out.flush()?,
+        }
+        Ok(())
+    }
+}
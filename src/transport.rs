@@ -0,0 +1,365 @@
+use crate::error::KvsError;
+use crate::tls;
+use crate::Result;
+use rustls::{
+    ClientConfig, ClientConnection, ServerConfig, ServerConnection, ServerName, StreamOwned,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How long rejected connection is kept open for the reply to arrive.
+pub(crate) const REJECT_LINGER: Duration = Duration::from_millis(100);
+
+/// Prefix of Unix domain socket addresses.
+const UNIX_PREFIX: &str = "unix:";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+/// Address of a server: `host:port` with ip or hostname, resolved when
+/// used, or `unix:/path` of a Unix domain socket.
+pub enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl Address {
+    /// Host part of tcp address, without brackets of IPv6 addresses.
+    pub fn host(&self) -> Option<&str> {
+        match self {
+            Address::Tcp(addr) => addr
+                .rsplit_once(':')
+                .map(|(host, _)| host.trim_start_matches('[').trim_end_matches(']')),
+            Address::Unix(_) => None,
+        }
+    }
+}
+
+impl FromStr for Address {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> std::result::Result<Address, KvsError> {
+        if let Some(path) = s.strip_prefix(UNIX_PREFIX) {
+            if path.is_empty() {
+                return Err(KvsError::Parse);
+            }
+            return Ok(Address::Unix(path.into()));
+        }
+        match s.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                Ok(Address::Tcp(s.to_owned()))
+            }
+            _ => Err(KvsError::Parse),
+        }
+    }
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => f.write_str(addr),
+            Address::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+impl TryFrom<String> for Address {
+    type Error = KvsError;
+
+    fn try_from(s: String) -> std::result::Result<Address, KvsError> {
+        s.parse()
+    }
+}
+
+impl From<Address> for String {
+    fn from(addr: Address) -> String {
+        addr.to_string()
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Self {
+        Address::Tcp(addr.to_string())
+    }
+}
+
+/// Bound listener of any address kind.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Binds to the address. Socket file left by previous run is removed
+    /// before binding Unix socket.
+    pub fn bind(addr: &Address) -> Result<Self> {
+        match addr {
+            Address::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr.as_str())?)),
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                    fs::remove_file(path)?;
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?))
+            }
+            #[cfg(not(unix))]
+            Address::Unix(_) => Err(unix_unsupported()),
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<Address> {
+        match self {
+            Listener::Tcp(listener) => Ok(listener.local_addr()?.into()),
+            #[cfg(unix)]
+            Listener::Unix(listener) => Ok(Address::Unix(
+                listener
+                    .local_addr()?
+                    .as_pathname()
+                    .map(Into::into)
+                    .unwrap_or_default(),
+            )),
+        }
+    }
+
+    /// Waits for the next connection, not wrapped in TLS yet.
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => Ok(Stream::Tcp(listener.accept()?.0)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => Ok(Stream::Unix(listener.accept()?.0)),
+        }
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Listener::Unix(listener)
+    }
+}
+
+/// Connection between client and server, either plain tcp or wrapped in
+/// TLS. Unix sockets are never wrapped in TLS, only processes of the same
+/// host that may access the socket file can connect to them.
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    TlsServer(Box<StreamOwned<ServerConnection, TcpStream>>),
+    TlsClient(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl From<TcpStream> for Stream {
+    fn from(stream: TcpStream) -> Self {
+        Stream::Tcp(stream)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixStream> for Stream {
+    fn from(stream: UnixStream) -> Self {
+        Stream::Unix(stream)
+    }
+}
+
+impl Stream {
+    /// Wraps accepted tcp connection in TLS when configured, performing the
+    /// handshake. Unix socket connections are never wrapped.
+    pub fn accept(stream: impl Into<Stream>, tls: Option<&Arc<ServerConfig>>) -> Result<Self> {
+        match (stream.into(), tls) {
+            (Stream::Tcp(tcp), Some(config)) => {
+                let mut stream = StreamOwned::new(ServerConnection::new(config.clone())?, tcp);
+                while stream.conn.is_handshaking() {
+                    stream.conn.complete_io(&mut stream.sock)?;
+                }
+                Ok(Stream::TlsServer(Box::new(stream)))
+            }
+            (stream, _) => Ok(stream),
+        }
+    }
+
+    /// Opens plain tcp connection.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        Ok(Stream::Tcp(TcpStream::connect(addr)?))
+    }
+
+    /// Opens plain connection to tcp or Unix socket address.
+    pub fn connect_to(addr: &Address) -> Result<Self> {
+        match addr {
+            Address::Tcp(addr) => Self::connect(addr.as_str()),
+            #[cfg(unix)]
+            Address::Unix(path) => Ok(Stream::Unix(UnixStream::connect(path)?)),
+            #[cfg(not(unix))]
+            Address::Unix(_) => Err(unix_unsupported()),
+        }
+    }
+
+    /// Opens TLS connection to tcp address, see `connect_tls`.
+    pub fn connect_tls_to(
+        addr: &Address,
+        server_name: &str,
+        config: Arc<ClientConfig>,
+    ) -> Result<Self> {
+        match addr {
+            Address::Tcp(addr) => Self::connect_tls(addr.as_str(), server_name, config),
+            Address::Unix(_) => {
+                Err(KvsError::Tls("TLS is not supported over Unix sockets".to_owned()).into())
+            }
+        }
+    }
+
+    /// Opens TLS connection, `server_name` is verified against server certificate.
+    pub fn connect_tls(
+        addr: impl ToSocketAddrs,
+        server_name: &str,
+        config: Arc<ClientConfig>,
+    ) -> Result<Self> {
+        let tcp = TcpStream::connect(addr)?;
+        let server_name = ServerName::try_from(server_name)?;
+        let mut stream = StreamOwned::new(ClientConnection::new(config, server_name)?, tcp);
+        while stream.conn.is_handshaking() {
+            stream.conn.complete_io(&mut stream.sock)?;
+        }
+        Ok(Stream::TlsClient(Box::new(stream)))
+    }
+
+    /// Address of the peer for logging, Unix socket clients are unnamed.
+    pub fn peer_addr(&self) -> io::Result<String> {
+        let tcp = match self {
+            Stream::Tcp(tcp) => tcp,
+            #[cfg(unix)]
+            Stream::Unix(_) => return Ok("unix socket client".to_owned()),
+            Stream::TlsServer(stream) => &stream.sock,
+            Stream::TlsClient(stream) => &stream.sock,
+        };
+        Ok(tcp.peer_addr()?.to_string())
+    }
+
+    /// Sets timeout of blocking reads, `None` waits forever.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(tcp) => tcp.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(unix) => unix.set_read_timeout(timeout),
+            Stream::TlsServer(stream) => stream.sock.set_read_timeout(timeout),
+            Stream::TlsClient(stream) => stream.sock.set_read_timeout(timeout),
+        }
+    }
+
+    /// Sets timeout of blocking writes, `None` waits forever.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(tcp) => tcp.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(unix) => unix.set_write_timeout(timeout),
+            Stream::TlsServer(stream) => stream.sock.set_write_timeout(timeout),
+            Stream::TlsClient(stream) => stream.sock.set_write_timeout(timeout),
+        }
+    }
+
+    /// Sends reply to accepted plain connection and closes it. Whatever
+    /// client sends is read until it closes its side, for at most
+    /// `REJECT_LINGER`, so that closing doesn't reset the connection before
+    /// reply arrives.
+    pub(crate) fn reject(mut self, reply: &[u8]) {
+        // TLS is not established yet, reply can't be sent.
+        if matches!(self, Stream::TlsServer(_) | Stream::TlsClient(_)) {
+            return;
+        }
+        if self.set_write_timeout(Some(REJECT_LINGER)).is_err()
+            || self.write_all(reply).is_err()
+            || self.shutdown_write().is_err()
+        {
+            return;
+        }
+        let deadline = Instant::now() + REJECT_LINGER;
+        let mut buf = [0u8; 1024];
+        while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+            if self.set_read_timeout(Some(timeout)).is_err()
+                || !matches!(self.read(&mut buf), Ok(n) if n > 0)
+            {
+                return;
+            }
+        }
+    }
+
+    /// Closes writing half of plain connection.
+    fn shutdown_write(&self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(tcp) => tcp.shutdown(Shutdown::Write),
+            #[cfg(unix)]
+            Stream::Unix(unix) => unix.shutdown(Shutdown::Write),
+            Stream::TlsServer(stream) => stream.sock.shutdown(Shutdown::Write),
+            Stream::TlsClient(stream) => stream.sock.shutdown(Shutdown::Write),
+        }
+    }
+
+    /// Identity from the verified client certificate, available on the server
+    /// side of mutual TLS connections.
+    pub fn peer_identity(&self) -> Option<String> {
+        match self {
+            Stream::TlsServer(stream) => stream
+                .conn
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(tls::certificate_identity),
+            _ => None,
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(tcp) => tcp.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(unix) => unix.read(buf),
+            Stream::TlsServer(stream) => stream.read(buf),
+            Stream::TlsClient(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(tcp) => tcp.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(unix) => unix.write(buf),
+            Stream::TlsServer(stream) => stream.write(buf),
+            Stream::TlsClient(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(tcp) => tcp.flush(),
+            #[cfg(unix)]
+            Stream::Unix(unix) => unix.flush(),
+            Stream::TlsServer(stream) => stream.flush(),
+            Stream::TlsClient(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn unix_unsupported() -> anyhow::Error {
+    KvsError::Server("Unix sockets are not supported on this platform".to_owned()).into()
+}
@@ -0,0 +1,142 @@
+use assert_cmd::prelude::*;
+use kvs::{Codec, Config, EngineType, KvStore, KvsClient, KvsEngine, Result, SledKvsEngine};
+use predicates::str::contains;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn fill_kvs(temp_dir: &TempDir, keys: usize) -> Result<()> {
+    let store = KvStore::open(temp_dir.path().join("kv"))?;
+    for i in 0..keys {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    // overwritten and removed keys are not migrated.
+    store.set("key0".to_owned(), "changed".to_owned())?;
+    store.remove(format!("key{}", keys - 1))?;
+    Ok(())
+}
+
+#[test]
+fn migrate_kvs_to_sled_in_place() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    fill_kvs(&temp_dir, 100)?;
+    let location = format!("{}", temp_dir.path().display());
+
+    Command::cargo_bin("kvs-admin")?
+        .args(["migrate", "--from", &format!("kvs:{}", location)])
+        .args(["--to", &format!("sled:{}", location)])
+        .assert()
+        .success()
+        .stdout(contains("Migrated 99 keys"));
+
+    let sled = SledKvsEngine::new(temp_dir.path().join("sled"))?;
+    assert_eq!(sled.scan(String::new())?.len(), 99);
+    assert_eq!(sled.get("key0".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(sled.get("key42".to_owned())?, Some("value42".to_owned()));
+    assert_eq!(sled.get("key99".to_owned())?, None);
+    drop(sled);
+
+    // data directory now belongs to sled.
+    let mut config = Config {
+        data_dir: temp_dir.path().to_owned(),
+        engine: EngineType::Sled,
+        ..Config::default()
+    };
+    config.check_data_dir()?;
+    config.engine = EngineType::Kvs;
+    assert!(config.check_data_dir().is_err());
+    Ok(())
+}
+
+#[test]
+fn migrate_refuses_target_with_keys() -> Result<()> {
+    let source = TempDir::new()?;
+    let target = TempDir::new()?;
+    fill_kvs(&source, 10)?;
+    SledKvsEngine::new(target.path().join("sled"))?.set("key".to_owned(), "value".to_owned())?;
+
+    Command::cargo_bin("kvs-admin")?
+        .args([
+            "migrate",
+            "--from",
+            &format!("kvs:{}", source.path().display()),
+        ])
+        .args(["--to", &format!("sled:{}", target.path().display())])
+        .assert()
+        .failure();
+
+    // source engine must match its marker.
+    Config {
+        data_dir: source.path().to_owned(),
+        ..Config::default()
+    }
+    .check_data_dir()?;
+    Command::cargo_bin("kvs-admin")?
+        .args([
+            "migrate",
+            "--from",
+            &format!("sled:{}", source.path().display()),
+        ])
+        .args(["--to", &format!("kvs:{}", target.path().display())])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn hash_password_reads_stdin() -> Result<()> {
+    let output = Command::cargo_bin("kvs-admin")?
+        .arg("hash-password")
+        .with_stdin()
+        .buffer("wonderland\n")
+        .output()?;
+    assert!(output.status.success());
+    let hash = String::from_utf8(output.stdout)?;
+    assert!(hash.starts_with("$argon2id$"));
+
+    Command::cargo_bin("kvs-admin")?
+        .arg("hash-password")
+        .with_stdin()
+        .buffer("")
+        .assert()
+        .failure()
+        .stderr(contains("no password"));
+    Ok(())
+}
+
+#[test]
+fn migrate_online_serves_target_while_copying() -> Result<()> {
+    let source = TempDir::new()?;
+    let target = TempDir::new()?;
+    fill_kvs(&source, 100)?;
+
+    let mut child = Command::cargo_bin("kvs-admin")?
+        .args([
+            "migrate",
+            "--from",
+            &format!("kvs:{}", source.path().display()),
+        ])
+        .args(["--to", &format!("sled:{}", target.path().display())])
+        .args(["--serve", "127.0.0.1:4020"])
+        .spawn()?;
+    thread::sleep(Duration::from_secs(1));
+
+    let result = (|| -> Result<()> {
+        let mut client = KvsClient::connect("127.0.0.1:4020", Codec::Bincode)?;
+        for _ in 0..50 {
+            if client.scan(String::new())?.len() == 99 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(client.scan(String::new())?.len(), 99);
+        assert_eq!(client.get("key0".to_owned())?, Some("changed".to_owned()));
+        client.set("key1".to_owned(), "written".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, Some("written".to_owned()));
+        Ok(())
+    })();
+    child.kill()?;
+    child.wait()?;
+    result
+}
@@ -0,0 +1,148 @@
+#![cfg(feature = "async")]
+
+mod common;
+
+use kvs::async_client::{Pool, PoolOptions};
+use kvs::protocol::{self, Codec, Credentials, MAGIC, PROTOCOL_VERSION};
+use kvs::{AsyncKvsClient, KvsError, Response, Result, Runtime, CMD};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Server that completes the handshake and never answers requests.
+fn start_silent_server() -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || -> Result<()> {
+        let mut connections = vec![];
+        for stream in listener.incoming() {
+            let mut stream = stream?;
+            let hello = protocol::client_hello(Codec::Json, &Credentials::Anonymous)?;
+            stream.read_exact(&mut vec![0u8; hello.len()])?;
+            let mut reply = MAGIC.to_vec();
+            reply.extend([PROTOCOL_VERSION, 0]);
+            protocol::write_frame(&mut reply, Codec::Json, &Response::Ok)?;
+            stream.write_all(&reply)?;
+            connections.push(stream);
+        }
+        Ok(())
+    });
+    Ok(addr)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_client_operations() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (_server, addr) = common::start_server(&temp_dir, 1, Runtime::Async)?;
+
+    for codec in [Codec::Json, Codec::Bincode] {
+        let mut client = AsyncKvsClient::connect(addr, codec).await?;
+        client.set("key1".to_owned(), "value1".to_owned()).await?;
+        assert_eq!(
+            client.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(
+            client.get_stale("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(client.scan("key".to_owned()).await?, vec!["key1"]);
+        client.remove("key1".to_owned()).await?;
+        assert_eq!(client.get("key1".to_owned()).await?, None);
+        let err = client.remove("key1".to_owned()).await.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(KvsError::KeyNotFound)));
+    }
+
+    // more commands than fit in flight at once.
+    let mut client = AsyncKvsClient::connect(addr, Codec::Bincode).await?;
+    let mut cmds: Vec<CMD> = (0..300)
+        .map(|i| CMD::Set {
+            key: format!("key{}", i % 7),
+            value: format!("value{}", i),
+        })
+        .collect();
+    cmds.push(CMD::Get {
+        key: "key6".to_owned(),
+    });
+    let responses = client.batch(cmds).await?;
+    assert_eq!(responses.len(), 301);
+    assert!(responses[..300].iter().all(|r| *r == Response::Ok));
+    assert_eq!(responses[300], Response::Value(Some("value293".to_owned())));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn request_timeout_breaks_connection() -> Result<()> {
+    let addr = start_silent_server()?;
+    let mut client = AsyncKvsClient::connect(addr, Codec::Json)
+        .await?
+        .with_timeout(Duration::from_millis(100));
+
+    let err = client.get("key".to_owned()).await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(KvsError::Timeout)));
+    assert!(client.is_broken());
+    assert!(client.get("key".to_owned()).await.is_err());
+
+    // broken connections are not returned to the pool.
+    let options = PoolOptions {
+        request_timeout: Some(Duration::from_millis(100)),
+        ..PoolOptions::default()
+    };
+    let pool = Pool::new(addr.to_string(), Codec::Json, options);
+    assert!(pool.get("key".to_owned()).await.is_err());
+    assert_eq!(pool.idle(), 0);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pool_limits_reuses_and_evicts_connections() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (server, addr) = common::start_server(&temp_dir, 1, Runtime::Async)?;
+    let options = PoolOptions {
+        max_size: 2,
+        idle_timeout: Duration::from_millis(300),
+        health_check_after: Duration::from_millis(100),
+        ..PoolOptions::default()
+    };
+    let pool = Pool::new(addr.to_string(), Codec::Bincode, options);
+
+    let mut first = pool.acquire().await?;
+    let second = pool.acquire().await?;
+    first.set("key".to_owned(), "value".to_owned()).await?;
+    // third caller waits for a connection to be returned.
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), pool.acquire())
+            .await
+            .is_err()
+    );
+    drop(second);
+    let third = tokio::time::timeout(Duration::from_secs(1), pool.acquire()).await??;
+    drop((first, third));
+    assert_eq!(pool.idle(), 2);
+
+    let tasks: Vec<_> = (0..20)
+        .map(|i| {
+            let pool = pool.clone();
+            tokio::spawn(async move { pool.set(format!("key{}", i), i.to_string()).await })
+        })
+        .collect();
+    for task in tasks {
+        task.await??;
+    }
+    assert_eq!(pool.scan("key".to_owned()).await?.len(), 21);
+    assert_eq!(server.stats()?.total_connections, 2);
+
+    // idle for a while, connection is pinged and reused.
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert_eq!(pool.get("key".to_owned()).await?, Some("value".to_owned()));
+    assert_eq!(server.stats()?.total_connections, 2);
+
+    // idle for too long, connections are closed and a new one opened.
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    assert_eq!(pool.get("key3".to_owned()).await?, Some("3".to_owned()));
+    assert_eq!(pool.idle(), 1);
+    assert_eq!(server.stats()?.total_connections, 3);
+    Ok(())
+}
@@ -0,0 +1,89 @@
+#![cfg(feature = "async")]
+
+mod common;
+
+use kvs::{Codec, KvsClient, Response, Result, Runtime, CMD};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+#[test]
+fn async_runtime_serves_framed_and_legacy_clients() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    // single pool thread, which the async runtime must not depend on.
+    let (_server, addr) = common::start_server(&temp_dir, 1, Runtime::Async)?;
+
+    for codec in [Codec::Json, Codec::Bincode] {
+        let mut client = KvsClient::connect(addr, codec)?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+        client.remove("key1".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, None);
+        assert!(client.remove("key1".to_owned()).is_err());
+    }
+
+    let mut client = KvsClient::connect(addr, Codec::Bincode)?;
+    let mut cmds = vec![];
+    for i in 0..200 {
+        cmds.push(CMD::Set {
+            key: format!("key{}", i % 10),
+            value: format!("value{}", i),
+        });
+    }
+    cmds.push(CMD::Get {
+        key: "key9".to_owned(),
+    });
+    let responses = client.batch(cmds)?;
+    assert!(responses[..200].iter().all(|r| *r == Response::Ok));
+    assert_eq!(responses[200], Response::Value(Some("value199".to_owned())));
+    assert_eq!(client.scan("key".to_owned())?.len(), 10);
+
+    // legacy command split across writes.
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#" {"Set":{"key":"legacy","val"#)?;
+    thread::sleep(Duration::from_millis(50));
+    stream.write_all(br#"ue":"value"}} {"Get":{"key":"legacy"}}"#)?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut responses = String::new();
+    stream.read_to_string(&mut responses)?;
+    assert_eq!(responses, r#"{"Ok":null}{"Ok":"value"}"#);
+    Ok(())
+}
+
+#[test]
+fn async_runtime_handles_thousands_of_idle_connections() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (server, addr) = common::start_server(&temp_dir, 1, Runtime::Async)?;
+
+    // half never send anything, half finish the handshake and go idle.
+    let mut idle = vec![];
+    let mut clients = vec![];
+    for _ in 0..1000 {
+        idle.push(TcpStream::connect(addr)?);
+        clients.push(KvsClient::connect(addr, Codec::Bincode)?);
+    }
+
+    let mut client = KvsClient::connect(addr, Codec::Json)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(clients[0].get("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(
+        clients[999].get("key".to_owned())?,
+        Some("value".to_owned())
+    );
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while server.stats()?.active_connections < 2001 {
+        assert!(Instant::now() < deadline, "{:?}", server.stats()?);
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    drop(idle);
+    drop(clients);
+    while server.stats()?.active_connections > 1 {
+        assert!(Instant::now() < deadline, "{:?}", server.stats()?);
+        thread::sleep(Duration::from_millis(20));
+    }
+    Ok(())
+}
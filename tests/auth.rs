@@ -0,0 +1,231 @@
+mod common;
+
+use kvs::auth::{self, Auth};
+use kvs::protocol::Credentials;
+use kvs::{Codec, KvsClient, KvsError, Response, ResponseError, Result, Stream, CMD};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use tempfile::TempDir;
+
+/// Starts framed, HTTP and RESP listeners with authentication enabled.
+fn start_server(temp_dir: &TempDir) -> Result<(SocketAddr, SocketAddr, SocketAddr)> {
+    let config = temp_dir.path().join("auth.json");
+    fs::write(
+        &config,
+        serde_json::json!({
+            "users": {
+                "ci": {
+                    "token": "s3cret",
+                    "grants": [
+                        { "prefix": "", "access": "read" },
+                        { "prefix": "app:", "access": "write" }
+                    ]
+                },
+                "alice": {
+                    "password_hash": auth::hash_password("wonderland")?,
+                    "grants": [{ "prefix": "", "access": "admin" }]
+                }
+            },
+            "anonymous": [{ "prefix": "public:", "access": "read" }]
+        })
+        .to_string(),
+    )?;
+
+    let server = common::store_server(temp_dir, 4)?.with_auth(Auth::load(&config)?);
+
+    let mut addrs = vec![];
+    for i in 0..3 {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        addrs.push(listener.local_addr()?);
+        let server = server.clone();
+        thread::spawn(move || match i {
+            0 => server.run_on(listener),
+            1 => server.run_http_on(listener),
+            _ => server.run_resp_on(listener),
+        });
+    }
+    Ok((addrs[0], addrs[1], addrs[2]))
+}
+
+fn connect(addr: SocketAddr, credentials: Credentials) -> Result<KvsClient> {
+    KvsClient::handshake(Stream::connect(addr)?, Codec::Bincode, &credentials)
+}
+
+fn is_permission_denied(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref(), Some(KvsError::PermissionDenied(_)))
+}
+
+#[test]
+fn token_grants_per_prefix() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (addr, _, _) = start_server(&temp_dir)?;
+
+    let mut ci = connect(addr, Credentials::Token("s3cret".to_owned()))?;
+    ci.set("app:1".to_owned(), "value".to_owned())?;
+    assert_eq!(ci.get("app:1".to_owned())?, Some("value".to_owned()));
+    assert_eq!(ci.get("other".to_owned())?, None);
+
+    let e = ci.set("other".to_owned(), "value".to_owned()).unwrap_err();
+    assert!(is_permission_denied(&e));
+    let e = ci.remove("secret".to_owned()).unwrap_err();
+    assert!(is_permission_denied(&e));
+
+    // denied commands don't fail the rest of the batch.
+    let responses = ci.batch(vec![
+        CMD::Set {
+            key: "app:2".to_owned(),
+            value: "value".to_owned(),
+        },
+        CMD::Set {
+            key: "other".to_owned(),
+            value: "value".to_owned(),
+        },
+        CMD::Get {
+            key: "app:2".to_owned(),
+        },
+    ])?;
+    assert_eq!(responses[0], Response::Ok);
+    assert!(matches!(
+        responses[1],
+        Response::Err(ResponseError::PermissionDenied(_))
+    ));
+    assert_eq!(responses[2], Response::Value(Some("value".to_owned())));
+    Ok(())
+}
+
+#[test]
+fn invalid_credentials_rejected_in_handshake() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (addr, _, _) = start_server(&temp_dir)?;
+
+    let e = connect(addr, Credentials::Token("guess".to_owned()))
+        .err()
+        .unwrap();
+    assert!(is_permission_denied(&e));
+    let e = connect(
+        addr,
+        Credentials::Password {
+            user: "alice".to_owned(),
+            password: "guess".to_owned(),
+        },
+    )
+    .err()
+    .unwrap();
+    assert!(is_permission_denied(&e));
+
+    let mut alice = connect(
+        addr,
+        Credentials::Password {
+            user: "alice".to_owned(),
+            password: "wonderland".to_owned(),
+        },
+    )?;
+    alice.set("public:1".to_owned(), "value".to_owned())?;
+
+    // anonymous clients only get grants listed in the configuration.
+    let mut anonymous = KvsClient::connect(addr, Codec::Json)?;
+    assert_eq!(
+        anonymous.get("public:1".to_owned())?,
+        Some("value".to_owned())
+    );
+    let e = anonymous.get("app:1".to_owned()).unwrap_err();
+    assert!(is_permission_denied(&e));
+    Ok(())
+}
+
+/// Sends HTTP request on a new connection, returns status and body.
+fn http(addr: SocketAddr, method: &str, path: &str, authorization: &str) -> Result<(u16, String)> {
+    let mut stream = TcpStream::connect(addr)?;
+    let mut request = format!("{} {} HTTP/1.1\r\n", method, path);
+    if !authorization.is_empty() {
+        request.push_str(&format!("Authorization: {}\r\n", authorization));
+    }
+    request.push_str("Content-Length: 5\r\nConnection: close\r\n\r\nvalue");
+    stream.write_all(request.as_bytes())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status = response.split(' ').nth(1).unwrap().parse()?;
+    let body = response.split("\r\n\r\n").nth(1).unwrap_or_default();
+    Ok((status, body.to_owned()))
+}
+
+#[test]
+fn http_authorization() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (_, addr, _) = start_server(&temp_dir)?;
+
+    assert_eq!(http(addr, "PUT", "/keys/app:1", "Bearer s3cret")?.0, 200);
+    assert_eq!(http(addr, "PUT", "/keys/other", "Bearer s3cret")?.0, 403);
+    assert_eq!(http(addr, "GET", "/keys/app:1", "Bearer wrong")?.0, 401);
+    assert_eq!(http(addr, "GET", "/stats", "Bearer s3cret")?.0, 403);
+
+    // alice:wonderland
+    let basic = "Basic YWxpY2U6d29uZGVybGFuZA==";
+    assert_eq!(http(addr, "PUT", "/keys/other", basic)?.0, 200);
+    assert_eq!(http(addr, "GET", "/stats", basic)?.0, 200);
+
+    assert_eq!(http(addr, "PUT", "/keys/public:1", basic)?.0, 200);
+    assert_eq!(http(addr, "GET", "/keys/app:1", "Basic Og==")?.0, 401);
+
+    // listing leaves out keys that can't be read.
+    let (status, body) = http(addr, "GET", "/keys", "")?;
+    assert_eq!(status, 200);
+    assert_eq!(body, r#"{"keys":["public:1"]}"#);
+    Ok(())
+}
+
+#[test]
+fn resp_auth_command() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (_, _, addr) = start_server(&temp_dir)?;
+    let mut reader = BufReader::new(TcpStream::connect(addr)?);
+
+    let mut command = |line: &str| -> Result<String> {
+        reader
+            .get_mut()
+            .write_all(format!("{}\r\n", line).as_bytes())?;
+        let mut reply = String::new();
+        reader.read_line(&mut reply)?;
+        Ok(reply.trim_end().to_owned())
+    };
+
+    assert!(command("SET app:1 value")?.starts_with("-NOPERM"));
+    assert!(command("AUTH wrong")?.starts_with("-WRONGPASS"));
+    assert_eq!(command("AUTH s3cret")?, "+OK");
+    assert_eq!(command("SET app:1 value")?, "+OK");
+    assert!(command("DEL other")?.starts_with("-NOPERM"));
+    assert!(command("INFO")?.starts_with("-NOPERM"));
+    assert_eq!(command("AUTH alice wonderland")?, "+OK");
+    assert!(command("INFO")?.starts_with('$'));
+    Ok(())
+}
+
+#[test]
+fn configuration_with_shared_token_or_bad_hash_rejected() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let config = temp_dir.path().join("auth.json");
+    let load = |users: serde_json::Value| -> Result<Auth> {
+        fs::write(&config, serde_json::json!({ "users": users }).to_string())?;
+        Auth::load(&config)
+    };
+
+    assert!(load(serde_json::json!({
+        "ci": { "token": "s3cret" },
+        "deploy": { "token": "s3cret" }
+    }))
+    .is_err());
+    assert!(load(serde_json::json!({ "alice": { "password_hash": "wonderland" } })).is_err());
+    // unsalted hashes of older configurations are no longer accepted.
+    assert!(load(serde_json::json!({ "alice": { "password_sha256": "00" } })).is_err());
+
+    let hash = auth::hash_password("wonderland")?;
+    assert!(hash.starts_with("$argon2id$"));
+    assert_ne!(hash, auth::hash_password("wonderland")?);
+    load(serde_json::json!({
+        "ci": { "token": "s3cret" },
+        "alice": { "password_hash": hash }
+    }))?;
+    Ok(())
+}
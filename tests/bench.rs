@@ -0,0 +1,80 @@
+use assert_cmd::prelude::*;
+use kvs::{KvsClient, Result};
+use predicates::str::contains;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn bench_reports_json() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addr = "127.0.0.1:4034";
+    let mut server = Command::cargo_bin("kvs-server")?
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()?;
+    thread::sleep(Duration::from_secs(1));
+
+    let result = (|| -> Result<()> {
+        let output = Command::cargo_bin("kvs-bench")?
+            .args(["--addr", addr, "--clients", "4", "--requests", "2000"])
+            .args(["--keys", "100", "--key-size", "8-24", "--value-size", "50"])
+            .args([
+                "--read-ratio",
+                "0.5",
+                "--zipf",
+                "1.2",
+                "--preload",
+                "--json",
+            ])
+            .args(["--prefix", "b:"])
+            .output()?;
+        assert!(output.status.success());
+
+        let report: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+        assert_eq!(report["requests"], 2000);
+        assert_eq!(report["errors"], 0);
+        assert_eq!(report["clients"], 4);
+        assert_eq!(report["key_size"]["dist"], "uniform");
+        let gets = report["get"]["requests"].as_u64().unwrap();
+        let sets = report["set"]["requests"].as_u64().unwrap();
+        assert_eq!(gets + sets, 2000);
+        assert!(gets > 500 && sets > 500);
+        let p = &report["get"]["percentiles"];
+        assert!(p["p50"].as_u64() <= p["p99"].as_u64());
+        assert!(p["p99"].as_u64() <= p["max"].as_u64());
+        let histogram: u64 = report["get"]["histogram"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|bucket| bucket["count"].as_u64().unwrap())
+            .sum();
+        assert_eq!(histogram, gets);
+
+        // keys are preloaded, sizes stay within the distribution.
+        let mut client = KvsClient::connect(addr, kvs::Codec::Bincode)?;
+        let keys = client.scan("b:".to_owned())?;
+        assert_eq!(keys.len(), 100);
+        assert!(keys.iter().all(|key| (8..=24).contains(&key.len())));
+        assert_eq!(client.get(keys[0].clone())?.map(|v| v.len()), Some(50));
+        Ok(())
+    })();
+    server.kill()?;
+    server.wait()?;
+    result
+}
+
+#[test]
+fn bench_rejects_invalid_options() -> Result<()> {
+    Command::cargo_bin("kvs-bench")?
+        .args(["--read-ratio", "1.5"])
+        .assert()
+        .failure()
+        .stderr(contains("--read-ratio"));
+    Command::cargo_bin("kvs-bench")?
+        .args(["--value-size", "10-5"])
+        .assert()
+        .failure();
+    Ok(())
+}
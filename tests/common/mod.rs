@@ -0,0 +1,57 @@
+//! Fixtures shared by integration tests, every test crate uses only part of
+//! them.
+#![allow(dead_code)]
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvServer, KvStore, KvsEngine, Result, Runtime};
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+use tempfile::TempDir;
+
+pub type Server = KvServer<KvStore, SharedQueueThreadPool>;
+
+/// Runtimes servers are tested on, the async one only with `async` feature.
+pub fn runtimes() -> Vec<Runtime> {
+    vec![
+        Runtime::Threads,
+        #[cfg(feature = "async")]
+        Runtime::Async,
+    ]
+}
+
+/// Server with KvStore engine in the directory and pool of given size.
+pub fn store_server(temp_dir: &TempDir, threads: usize) -> Result<Server> {
+    Ok(KvServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(threads)?,
+    ))
+}
+
+/// Serves framed clients on ephemeral port from a background thread.
+pub fn serve<E: KvsEngine>(
+    server: &KvServer<E, SharedQueueThreadPool>,
+    runtime: Runtime,
+) -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = server.clone();
+    thread::spawn(move || match runtime {
+        Runtime::Threads => server.run_on(listener),
+        #[cfg(feature = "async")]
+        Runtime::Async => server.run_async_on(listener),
+        #[cfg(not(feature = "async"))]
+        Runtime::Async => unreachable!(),
+    });
+    Ok(addr)
+}
+
+/// Starts KvStore server with pool of given size on ephemeral port.
+pub fn start_server(
+    temp_dir: &TempDir,
+    threads: usize,
+    runtime: Runtime,
+) -> Result<(Server, SocketAddr)> {
+    let server = store_server(temp_dir, threads)?;
+    let addr = serve(&server, runtime)?;
+    Ok((server, addr))
+}
@@ -0,0 +1,147 @@
+use assert_cmd::prelude::*;
+use kvs::thread_pool::QueuePolicy;
+use kvs::{Config, Durability, EngineType, Result, ThreadPoolKind};
+use predicates::str::contains;
+use std::fs;
+use std::process::Command;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn config_round_trips_through_toml() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut config = Config {
+        engine: EngineType::Sled,
+        resp_addr: vec!["unix:/tmp/kvs.sock".parse()?],
+        ..Config::default()
+    };
+    config.thread_pool.kind = ThreadPoolKind::SharedQueue;
+    config.thread_pool.queue_size = Some(64);
+    config.thread_pool.queue_policy = QueuePolicy::ShedOldest;
+    config.storage.durability = Durability::Sync;
+    config.limits.max_connections = Some(8);
+    config.limits.idle_timeout = Some(Duration::from_millis(1500));
+
+    let path = temp_dir.path().join("kvs.toml");
+    fs::write(&path, config.to_toml()?)?;
+    assert_eq!(Config::load(&path)?, config);
+    Ok(())
+}
+
+#[test]
+fn config_file_is_validated() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("kvs.toml");
+
+    fs::write(&path, "engine = \"sled\"\n[limits]\nmax_key_size = 64\n")?;
+    let config = Config::load(&path)?;
+    assert_eq!(config.engine, EngineType::Sled);
+    assert_eq!(config.limits.max_key_size, Some(64));
+    assert_eq!(config.addr, Config::default().addr);
+
+    fs::write(&path, "engine = \"sled\"\nmax_keys = 64\n")?;
+    assert!(Config::load(&path).is_err());
+    fs::write(&path, "engine = \"rocks\"\n")?;
+    assert!(Config::load(&path).is_err());
+    Ok(())
+}
+
+#[test]
+fn flags_override_env_override_file() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("kvs.toml"),
+        "engine = \"sled\"\nruntime = \"async\"\n[limits]\nmax_connections = 10\nmax_key_size = 100\n",
+    )
+    .unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "kvs.toml", "--print-config"])
+        .args(["--addr", "127.0.0.1:4100", "--max-key-size", "200"])
+        .args(["--queue-policy", "reject"])
+        .env("KVS_MAX_CONNECTIONS", "20")
+        .env("KVS_MAX_KEY_SIZE", "300")
+        .env("KVS_THREAD_POOL", "rayon")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("addr = [\"127.0.0.1:4100\"]"))
+        .stdout(contains("engine = \"sled\""))
+        .stdout(contains("runtime = \"async\""))
+        .stdout(contains("kind = \"rayon\""))
+        .stdout(contains("queue_policy = \"reject\""))
+        .stdout(contains("max_connections = 20"))
+        .stdout(contains("max_key_size = 200"));
+
+    // printing settings doesn't touch data directory.
+    assert!(!temp_dir.path().join("meta.json").exists());
+}
+
+#[test]
+fn data_dir_records_engine() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut config = Config {
+        data_dir: temp_dir.path().join("data"),
+        ..Config::default()
+    };
+
+    config.check_data_dir()?;
+    let meta = fs::read_to_string(config.data_dir.join("meta.json"))?;
+    assert!(meta.contains("\"engine\": \"kvs\""));
+    config.check_data_dir()?;
+
+    config.engine = EngineType::Sled;
+    assert!(config.check_data_dir().is_err());
+    Ok(())
+}
+
+#[test]
+fn legacy_engine_marker_is_migrated() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut config = Config {
+        data_dir: temp_dir.path().to_owned(),
+        ..Config::default()
+    };
+    fs::write(temp_dir.path().join("conf"), "\"Sled\"")?;
+
+    assert!(config.check_data_dir().is_err());
+    config.engine = EngineType::Sled;
+    config.check_data_dir()?;
+    assert!(!temp_dir.path().join("conf").exists());
+    assert!(temp_dir.path().join("meta.json").exists());
+
+    config.engine = EngineType::Kvs;
+    assert!(config.check_data_dir().is_err());
+    Ok(())
+}
+
+#[test]
+fn newer_data_dir_is_refused() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let config = Config {
+        data_dir: temp_dir.path().to_owned(),
+        ..Config::default()
+    };
+    fs::write(
+        temp_dir.path().join("meta.json"),
+        r#"{"version": 1000, "engine": "kvs"}"#,
+    )?;
+    assert!(config.check_data_dir().is_err());
+    Ok(())
+}
+
+#[cfg(not(feature = "async"))]
+#[test]
+fn async_runtime_requires_feature() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--runtime", "async", "--addr", "127.0.0.1:4101"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("built without async runtime"));
+
+    assert!(!temp_dir.path().join("meta.json").exists());
+}
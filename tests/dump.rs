@@ -0,0 +1,74 @@
+use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsEngine, Result};
+use predicates::str::contains;
+use std::process::Command;
+use tempfile::TempDir;
+
+fn fill(temp_dir: &TempDir) -> Result<()> {
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    store.remove("key2".to_owned())?;
+    Ok(())
+}
+
+#[test]
+fn dump_generations() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    fill(&temp_dir)?;
+
+    Command::cargo_bin("kvs-dump")?
+        .args([temp_dir.path().to_str().unwrap(), "generations"])
+        .assert()
+        .success()
+        .stdout(contains("gen\tbytes\trecords\tsets\tremoves\tlive"))
+        .stdout(contains("\t4\t3\t1\t1\n"));
+    Ok(())
+}
+
+#[test]
+fn dump_records_of_key_as_json() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    fill(&temp_dir)?;
+
+    let output = Command::cargo_bin("kvs-dump")?
+        .args([temp_dir.path().to_str().unwrap(), "records"])
+        .args(["--key", "key1", "--values", "--json"])
+        .output()?;
+    assert!(output.status.success());
+
+    let lines: Vec<serde_json::Value> = String::from_utf8(output.stdout)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<std::result::Result<_, _>>()?;
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["status"], "stale");
+    assert_eq!(lines[0]["value"], "value1");
+    assert_eq!(lines[1]["status"], "live");
+    assert_eq!(lines[1]["value"], "changed");
+    assert_eq!(lines[1]["gen"], 0);
+    assert_eq!(
+        lines[1]["offset"].as_u64().unwrap(),
+        lines[0]["len"].as_u64().unwrap() * 2 // value2 has same length as value1
+    );
+    Ok(())
+}
+
+#[test]
+fn dump_reports_corrupted_log() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    fill(&temp_dir)?;
+    std::fs::OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("0.log"))
+        .and_then(|mut f| std::io::Write::write_all(&mut f, b"{\"Set\":{\"key"))?;
+
+    Command::cargo_bin("kvs-dump")?
+        .args([temp_dir.path().to_str().unwrap(), "records"])
+        .assert()
+        .failure()
+        .stdout(contains("unknown\tset\t\"key1\""))
+        .stderr(contains("invalid record in generation 0"));
+    Ok(())
+}
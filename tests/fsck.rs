@@ -0,0 +1,136 @@
+use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsEngine, Result};
+use predicates::str::contains;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::process::Command;
+use tempfile::TempDir;
+
+fn fill(temp_dir: &TempDir) -> Result<()> {
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    Ok(())
+}
+
+fn append(temp_dir: &TempDir, gen: u64, content: &str) -> Result<()> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(temp_dir.path().join(format!("{}.log", gen)))?
+        .write_all(content.as_bytes())?;
+    Ok(())
+}
+
+fn fsck(temp_dir: &TempDir) -> Result<Command> {
+    let mut cmd = Command::cargo_bin("kvs-fsck")?;
+    cmd.arg(temp_dir.path());
+    Ok(cmd)
+}
+
+#[test]
+fn fsck_healthy_store() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    fill(&temp_dir)?;
+
+    fsck(&temp_dir)?.assert().success().stdout(contains(
+        "1 generations, 3 records, 1 live keys, 0 problems",
+    ));
+    Ok(())
+}
+
+#[test]
+fn fsck_repairs_corrupt_tail() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    fill(&temp_dir)?;
+    append(&temp_dir, 0, r#"{"Set":{"key":"key3","val"#)?;
+
+    fsck(&temp_dir)?
+        .assert()
+        .failure()
+        .stdout(contains("generation 0: 25 bytes from offset"))
+        .stderr(contains("run with --repair"));
+    fsck(&temp_dir)?.arg("--repair").assert().success();
+    fsck(&temp_dir)?.assert().success();
+
+    let quarantined = fs::read_dir(temp_dir.path().join("quarantine"))?.count();
+    assert_eq!(quarantined, 1);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn fsck_repairs_checksum_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    fill(&temp_dir)?;
+    KvStore::open(temp_dir.path())?.set("key3".to_owned(), "value3".to_owned())?;
+    // bit flip that still decodes.
+    let log = temp_dir.path().join("0.log");
+    let content = fs::read_to_string(&log)?;
+    fs::write(&log, content.replacen("value1", "valuf1", 1))?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    fsck(&temp_dir)?.assert().failure().stdout(contains(
+        "generation 0 offset 0: record doesn't match its checksum",
+    ));
+    fsck(&temp_dir)?.arg("--repair").assert().success();
+    fsck(&temp_dir)?.assert().success();
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn fsck_repairs_dangling_remove() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    fill(&temp_dir)?;
+    append(&temp_dir, 0, r#"{"Rm":{"key":"missing"}}"#)?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    fsck(&temp_dir)?
+        .assert()
+        .failure()
+        .stdout(contains("remove of key \"missing\" that is not set"));
+    fsck(&temp_dir)?.arg("--repair").assert().success();
+    fsck(&temp_dir)?.assert().success().stdout(contains(
+        "1 generations, 1 records, 1 live keys, 0 problems",
+    ));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn fsck_repairs_bad_file_name_and_orphans() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    fill(&temp_dir)?;
+    // compaction into generation 1 interrupted before removing generation 0.
+    append(&temp_dir, 1, r#"{"Set":{"key":"key1","value":"value1"}}"#)?;
+    append(&temp_dir, 2, r#"{"Set":{"key":"key1","value":"changed"}}"#)?;
+    fs::write(temp_dir.path().join("backup.log"), "")?;
+
+    fsck(&temp_dir)?
+        .assert()
+        .failure()
+        .stdout(contains("backup.log: not a generation log"))
+        .stdout(contains(
+            "generation 0: left behind by interrupted compaction",
+        ))
+        .stdout(contains("2 problems"));
+    fsck(&temp_dir)?.arg("--repair").assert().success();
+    fsck(&temp_dir)?.assert().success().stdout(contains(
+        "1 generations, 1 records, 1 live keys, 0 problems",
+    ));
+
+    assert!(temp_dir.path().join("3.log").exists());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("changed".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,215 @@
+mod common;
+
+use kvs::{Codec, KvsClient, Result, Runtime, StatsSnapshot};
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use tempfile::TempDir;
+
+/// Starts HTTP and framed listeners sharing the same engine.
+fn start_server(temp_dir: &TempDir) -> Result<(SocketAddr, SocketAddr)> {
+    let server = common::store_server(temp_dir, 4)?;
+
+    let http = TcpListener::bind("127.0.0.1:0")?;
+    let http_addr = http.local_addr()?;
+    let http_server = server.clone();
+    thread::spawn(move || http_server.run_http_on(http));
+
+    let tcp_addr = common::serve(&server, Runtime::Threads)?;
+
+    Ok((http_addr, tcp_addr))
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Response {
+    fn json(&self) -> Value {
+        serde_json::from_str(&self.body).unwrap()
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Sends single request over keep-alive connection and reads the response.
+fn request(
+    reader: &mut BufReader<TcpStream>,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> Result<Response> {
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n", method, path);
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+    reader.get_mut().write_all(request.as_bytes())?;
+    read_response(reader)
+}
+
+fn read_response(reader: &mut BufReader<TcpStream>) -> Result<Response> {
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    let status = status_line.split(' ').nth(1).unwrap().parse()?;
+
+    let mut headers = vec![];
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').unwrap();
+        headers.push((name.to_owned(), value.trim().to_owned()));
+    }
+
+    let len: usize = headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case("content-length"))
+        .map(|(_, v)| v.parse().unwrap())
+        .unwrap_or(0);
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body)?;
+
+    Ok(Response {
+        status,
+        headers,
+        body: String::from_utf8(body)?,
+    })
+}
+
+#[test]
+fn http_crud() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (http_addr, _) = start_server(&temp_dir)?;
+    let mut conn = BufReader::new(TcpStream::connect(http_addr)?);
+
+    let resp = request(&mut conn, "GET", "/keys/key1", &[], "")?;
+    assert_eq!(resp.status, 404);
+
+    let resp = request(&mut conn, "PUT", "/keys/key1", &[], "value1")?;
+    assert_eq!(resp.status, 200);
+
+    let resp = request(&mut conn, "GET", "/keys/key1", &[], "")?;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.json()["value"], "value1");
+
+    let resp = request(
+        &mut conn,
+        "PUT",
+        "/keys/with%20space",
+        &[("Content-Type", "application/json")],
+        r#"{"value": "json value"}"#,
+    )?;
+    assert_eq!(resp.status, 200);
+    let resp = request(&mut conn, "GET", "/keys/with%20space", &[], "")?;
+    assert_eq!(resp.json()["value"], "json value");
+
+    // `+` of the path is part of the key, only queries use it for space.
+    let resp = request(&mut conn, "PUT", "/keys/a+b", &[], "plus")?;
+    assert_eq!(resp.status, 200);
+    let resp = request(&mut conn, "GET", "/keys/a%2Bb", &[], "")?;
+    assert_eq!(resp.json()["value"], "plus");
+    let resp = request(&mut conn, "GET", "/keys?prefix=a%2B", &[], "")?;
+    assert_eq!(resp.json()["keys"], serde_json::json!(["a+b"]));
+    let resp = request(&mut conn, "GET", "/keys?prefix=with+", &[], "")?;
+    assert_eq!(resp.json()["keys"], serde_json::json!(["with space"]));
+
+    let resp = request(&mut conn, "DELETE", "/keys/key1", &[], "")?;
+    assert_eq!(resp.status, 204);
+    let resp = request(&mut conn, "DELETE", "/keys/key1", &[], "")?;
+    assert_eq!(resp.status, 404);
+    Ok(())
+}
+
+#[test]
+fn http_compare_and_set() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (http_addr, _) = start_server(&temp_dir)?;
+    let mut conn = BufReader::new(TcpStream::connect(http_addr)?);
+
+    let resp = request(
+        &mut conn,
+        "PUT",
+        "/keys/key1",
+        &[("If-None-Match", "*")],
+        "v1",
+    )?;
+    assert_eq!(resp.status, 201);
+    let etag = resp.header("etag").unwrap().to_owned();
+
+    let resp = request(
+        &mut conn,
+        "PUT",
+        "/keys/key1",
+        &[("If-None-Match", "*")],
+        "v2",
+    )?;
+    assert_eq!(resp.status, 409);
+
+    let resp = request(&mut conn, "PUT", "/keys/key1", &[("If-Match", &etag)], "v2")?;
+    assert_eq!(resp.status, 200);
+
+    // etag is stale now.
+    let resp = request(&mut conn, "PUT", "/keys/key1", &[("If-Match", &etag)], "v3")?;
+    assert_eq!(resp.status, 409);
+
+    let resp = request(&mut conn, "GET", "/keys/key1", &[], "")?;
+    assert_eq!(resp.json()["value"], "v2");
+    Ok(())
+}
+
+#[test]
+fn http_prefix_listing_and_stats() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (http_addr, tcp_addr) = start_server(&temp_dir)?;
+
+    // values written through the framed protocol are visible over HTTP.
+    let mut client = KvsClient::connect(tcp_addr, Codec::Bincode)?;
+    for key in ["user:1", "user:2", "order:1"] {
+        client.set(key.to_owned(), "value".to_owned())?;
+    }
+
+    let mut conn = BufReader::new(TcpStream::connect(http_addr)?);
+    let resp = request(&mut conn, "GET", "/keys?prefix=user%3A", &[], "")?;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.json()["keys"], serde_json::json!(["user:1", "user:2"]));
+
+    let resp = request(&mut conn, "GET", "/stats", &[], "")?;
+    assert_eq!(resp.status, 200);
+    let stats: StatsSnapshot = serde_json::from_str(&resp.body)?;
+    assert_eq!(stats.keys, 3);
+    assert!(stats.total_requests >= 4);
+    assert!(stats.active_connections >= 1);
+    Ok(())
+}
+
+#[test]
+fn http_oversized_value() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (http_addr, _) = start_server(&temp_dir)?;
+    let mut conn = BufReader::new(TcpStream::connect(http_addr)?);
+
+    // server answers as soon as it sees the length, without reading the body.
+    conn.get_mut()
+        .write_all(b"PUT /keys/big HTTP/1.1\r\nContent-Length: 2097152\r\n\r\n")?;
+    let resp = read_response(&mut conn)?;
+    assert_eq!(resp.status, 413);
+    assert_eq!(resp.header("connection"), Some("close"));
+
+    let mut conn = BufReader::new(TcpStream::connect(http_addr)?);
+    let resp = request(&mut conn, "GET", "/keys/big", &[], "")?;
+    assert_eq!(resp.status, 404);
+    Ok(())
+}
@@ -1,4 +1,5 @@
 use kvs::{KvStore, KvsEngine, Result};
+use std::io::{Read, Write};
 use std::sync::{Arc, Barrier};
 use std::thread;
 use std::time::Duration;
@@ -232,3 +233,172 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn concurrent_set_and_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..500 {
+                let key = format!("key{}", i % 10);
+                if thread_id % 2 == 0 {
+                    store.set(key, format!("value{}", i)).unwrap();
+                } else {
+                    // another thread may have removed the key already.
+                    let _ = store.remove(key);
+                }
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // Open from disk again and check it matches what was in memory
+    let values = (0..10)
+        .map(|i| store.get(format!("key{}", i)))
+        .collect::<Result<Vec<_>>>()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for (i, value) in values.into_iter().enumerate() {
+        assert_eq!(store.get(format!("key{}", i))?, value, "for {} key", i);
+    }
+
+    Ok(())
+}
+
+// Should return only keys with given prefix, in order
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key in ["user:2", "order:1", "user:1", "users", "user:3"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    store.remove("user:3".to_owned())?;
+
+    assert_eq!(store.scan("user:".to_owned())?, vec!["user:1", "user:2"]);
+    assert_eq!(store.scan(String::new())?.len(), 4);
+    assert!(store.scan("missing".to_owned())?.is_empty());
+
+    Ok(())
+}
+
+// Should set value only when current value matches expected one
+#[test]
+fn compare_and_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert!(store.compare_and_set("key1".to_owned(), None, "value1".to_owned())?);
+    assert!(!store.compare_and_set("key1".to_owned(), None, "value2".to_owned())?);
+    assert!(!store.compare_and_set(
+        "key1".to_owned(),
+        Some("other".to_owned()),
+        "value2".to_owned()
+    )?);
+    assert!(store.compare_and_set(
+        "key1".to_owned(),
+        Some("value1".to_owned()),
+        "value2".to_owned()
+    )?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should remove key only when its value matches expected one, also after reopen
+#[test]
+fn compare_and_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(!store.compare_and_remove("key1".to_owned(), "other".to_owned())?);
+    assert!(!store.compare_and_remove("missing".to_owned(), "value1".to_owned())?);
+    assert!(store.compare_and_remove("key1".to_owned(), "value1".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should list keys of the prefix in pages following the given key
+#[test]
+fn scan_page() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in ["a", "user:1", "user:2", "user:3", "v"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+
+    let page =
+        |after: Option<&str>| store.scan_page("user:".to_owned(), after.map(str::to_owned), 2);
+    assert_eq!(page(None)?, vec!["user:1", "user:2"]);
+    assert_eq!(page(Some("user:2"))?, vec!["user:3"]);
+    assert!(page(Some("user:3"))?.is_empty());
+    assert_eq!(page(Some("a"))?, vec!["user:1", "user:2"]);
+
+    Ok(())
+}
+
+// Should write and read values in parts, escaping them like regular values
+#[test]
+fn stream_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value: String = "quote \" backslash \\ tab \t bell \u{7} żółw 🐢\n"
+        .chars()
+        .cycle()
+        .take(100_000)
+        .collect();
+
+    let mut writer = store.set_writer("key1".to_owned())?;
+    // pieces split multi-byte characters.
+    for piece in value.as_bytes().chunks(1000) {
+        writer.write_all(piece)?;
+    }
+    writer.commit()?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+
+    let mut read = String::new();
+    store
+        .get_reader("key1".to_owned())?
+        .expect("value is stored")
+        .read_to_string(&mut read)?;
+    assert_eq!(read, value);
+    assert!(store.get_reader("key2".to_owned())?.is_none());
+
+    // uncommitted and invalid values are discarded.
+    let mut writer = store.set_writer("key2".to_owned())?;
+    writer.write_all(b"value2")?;
+    drop(writer);
+    let mut writer = store.set_writer("key2".to_owned())?;
+    writer.write_all(&[b'v', 0xc5])?;
+    assert!(writer.commit().is_err());
+    assert_eq!(store.get("key2".to_owned())?, None);
+    let uploads = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .is_ok_and(|entry| entry.path().extension().is_some_and(|ext| ext == "upload"))
+        })
+        .count();
+    assert_eq!(uploads, 0);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value));
+
+    Ok(())
+}
@@ -0,0 +1,334 @@
+mod common;
+
+use kvs::thread_pool::{QueuePolicy, SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    Codec, KvServer, KvStore, KvsClient, KvsEngine, KvsError, Limits, Overflow, Response,
+    ResponseError, Result, Runtime, StatsSnapshot, CMD,
+};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Store that takes a while to read key "slow".
+#[derive(Clone)]
+struct SlowStore(KvStore);
+
+impl KvsEngine for SlowStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        if key == "slow" {
+            thread::sleep(Duration::from_millis(500));
+        }
+        self.0.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+
+    fn compare_and_set(
+        &self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<bool> {
+        self.0.compare_and_set(key, expected, value)
+    }
+
+    fn compare_and_remove(&self, key: String, expected: String) -> Result<bool> {
+        self.0.compare_and_remove(key, expected)
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<String>> {
+        self.0.scan(prefix)
+    }
+}
+
+type Server = KvServer<SlowStore, SharedQueueThreadPool>;
+
+fn start_server(
+    temp_dir: &TempDir,
+    limits: Limits,
+    runtime: Runtime,
+) -> Result<(Server, SocketAddr)> {
+    let server = KvServer::new(
+        SlowStore(KvStore::open(temp_dir.path())?),
+        SharedQueueThreadPool::new(4)?,
+    )
+    .with_limits(limits);
+    let addr = common::serve(&server, runtime)?;
+    Ok((server, addr))
+}
+
+/// Polls server stats until the condition holds.
+fn wait_for_stats(server: &Server, condition: impl Fn(&StatsSnapshot) -> bool) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let stats = server.stats()?;
+        if condition(&stats) {
+            return Ok(());
+        }
+        assert!(Instant::now() < deadline, "{:?}", stats);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn connections_over_limit_are_rejected() -> Result<()> {
+    for runtime in common::runtimes() {
+        let temp_dir = TempDir::new()?;
+        let limits = Limits {
+            max_connections: Some(1),
+            ..Limits::default()
+        };
+        let (server, addr) = start_server(&temp_dir, limits, runtime)?;
+
+        let mut client = KvsClient::connect(addr, Codec::Json)?;
+        client.set("key".to_owned(), "value".to_owned())?;
+        let err = KvsClient::connect(addr, Codec::Json).err().unwrap();
+        assert!(
+            matches!(err.downcast_ref(), Some(KvsError::Busy)),
+            "{}: {}",
+            runtime,
+            err
+        );
+        wait_for_stats(&server, |stats| stats.rejected_connections == 1)?;
+
+        // slot is freed once the client disconnects.
+        drop(client);
+        wait_for_stats(&server, |stats| stats.active_connections == 0)?;
+        let mut client = KvsClient::connect(addr, Codec::Bincode)?;
+        assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    }
+    Ok(())
+}
+
+#[test]
+fn connections_over_limit_are_queued() -> Result<()> {
+    for runtime in common::runtimes() {
+        let temp_dir = TempDir::new()?;
+        let limits = Limits {
+            max_connections: Some(1),
+            overflow: Overflow::Queue,
+            ..Limits::default()
+        };
+        let (server, addr) = start_server(&temp_dir, limits, runtime)?;
+
+        let client = KvsClient::connect(addr, Codec::Json)?;
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let result = KvsClient::connect(addr, Codec::Json)
+                .and_then(|mut client| client.get("key".to_owned()));
+            sender.send(result.is_ok()).unwrap();
+        });
+        assert!(receiver.recv_timeout(Duration::from_millis(300)).is_err());
+
+        drop(client);
+        assert!(
+            receiver.recv_timeout(Duration::from_secs(5))?,
+            "{}",
+            runtime
+        );
+        assert_eq!(server.stats()?.rejected_connections, 0);
+    }
+    Ok(())
+}
+
+#[test]
+fn connections_over_full_queue_are_rejected() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let server = KvServer::new(
+        SlowStore(KvStore::open(temp_dir.path())?),
+        SharedQueueThreadPool::bounded(1, 1, QueuePolicy::Reject)?,
+    );
+    let addr = common::serve(&server, Runtime::Threads)?;
+
+    // occupies the only thread, next connection waits in the queue.
+    let mut client = KvsClient::connect(addr, Codec::Json)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    let queued = TcpStream::connect(addr)?;
+    wait_for_stats(&server, |stats| stats.queued_jobs == 1)?;
+
+    let err = KvsClient::connect(addr, Codec::Json).err().unwrap();
+    assert!(
+        matches!(err.downcast_ref(), Some(KvsError::Busy)),
+        "{}",
+        err
+    );
+    wait_for_stats(&server, |stats| {
+        stats.rejected_jobs == 1 && stats.queued_jobs == 1 && stats.rejected_connections == 0
+    })?;
+
+    // queued connection is served once the thread is free.
+    drop(client);
+    drop(queued);
+    wait_for_stats(&server, |stats| stats.queued_jobs == 0)?;
+    let mut client = KvsClient::connect(addr, Codec::Bincode)?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn idle_connections_are_closed() -> Result<()> {
+    for runtime in common::runtimes() {
+        let temp_dir = TempDir::new()?;
+        let limits = Limits {
+            idle_timeout: Some(Duration::from_millis(200)),
+            ..Limits::default()
+        };
+        let (server, addr) = start_server(&temp_dir, limits, runtime)?;
+
+        // never sends anything.
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        assert_eq!(stream.read(&mut [0u8; 16])?, 0);
+
+        // goes idle after the handshake and a request.
+        let mut client = KvsClient::connect(addr, Codec::Bincode)?;
+        client.set("key".to_owned(), "value".to_owned())?;
+        thread::sleep(Duration::from_millis(400));
+        assert!(client.get("key".to_owned()).is_err(), "{}", runtime);
+
+        wait_for_stats(&server, |stats| {
+            stats.idle_timeouts == 2 && stats.active_connections == 0
+        })?;
+    }
+    Ok(())
+}
+
+#[test]
+fn stalled_requests_hit_read_timeout() -> Result<()> {
+    for runtime in common::runtimes() {
+        let temp_dir = TempDir::new()?;
+        let limits = Limits {
+            read_timeout: Some(Duration::from_millis(200)),
+            idle_timeout: Some(Duration::from_secs(30)),
+            ..Limits::default()
+        };
+        let (server, addr) = start_server(&temp_dir, limits, runtime)?;
+
+        // half of the hello and nothing more.
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        stream.write_all(b"KVS")?;
+        assert_eq!(stream.read(&mut [0u8; 16])?, 0, "{}", runtime);
+
+        wait_for_stats(&server, |stats| {
+            stats.io_timeouts == 1 && stats.idle_timeouts == 0
+        })?;
+    }
+    Ok(())
+}
+
+#[test]
+fn requests_past_deadline_time_out() -> Result<()> {
+    for runtime in common::runtimes() {
+        let temp_dir = TempDir::new()?;
+        let limits = Limits {
+            request_timeout: Some(Duration::from_millis(100)),
+            ..Limits::default()
+        };
+        let (server, addr) = start_server(&temp_dir, limits, runtime)?;
+
+        let mut client = KvsClient::connect(addr, Codec::Bincode)?;
+        // scan keeps the window in order, set starts after the deadline.
+        let responses = client.batch(vec![
+            CMD::Scan {
+                prefix: String::new(),
+            },
+            CMD::Get {
+                key: "slow".to_owned(),
+            },
+            CMD::Set {
+                key: "late".to_owned(),
+                value: "value".to_owned(),
+            },
+        ])?;
+        let timeout = Response::Err(ResponseError::Timeout);
+        assert_eq!(responses[2], timeout, "{}", runtime);
+        let timeouts = responses.iter().filter(|r| **r == timeout).count();
+        assert_eq!(server.stats()?.request_timeouts, timeouts as u64);
+
+        // late write was never run.
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(client.get("late".to_owned())?, None);
+    }
+    Ok(())
+}
+
+#[test]
+fn pipelined_windows_over_full_blocking_queue_complete() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let server = KvServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::bounded(2, 1, QueuePolicy::Block)?,
+    );
+    let addr = common::serve(&server, Runtime::Threads)?;
+
+    // every connection keeps a worker busy and the rest fill the queue,
+    // helpers of multi-key windows must not wait for room in it.
+    let (done, finished) = mpsc::channel();
+    for client_id in 0..4 {
+        let done = done.clone();
+        thread::spawn(move || {
+            let result = (|| -> Result<()> {
+                let mut client = KvsClient::connect(addr, Codec::Bincode)?;
+                for round in 0..20 {
+                    let cmds = (0..16)
+                        .map(|i| CMD::Set {
+                            key: format!("key{}-{}-{}", client_id, round, i),
+                            value: "value".to_owned(),
+                        })
+                        .collect();
+                    assert!(client.batch(cmds)?.iter().all(|r| *r == Response::Ok));
+                }
+                Ok(())
+            })();
+            done.send(result).unwrap();
+        });
+    }
+    for _ in 0..4 {
+        finished
+            .recv_timeout(Duration::from_secs(20))
+            .expect("pipelined clients deadlocked")?;
+    }
+    Ok(())
+}
+
+#[test]
+fn oversized_legacy_requests_close_connection() -> Result<()> {
+    for runtime in common::runtimes() {
+        let temp_dir = TempDir::new()?;
+        let limits = Limits {
+            max_frame_size: Some(1024),
+            ..Limits::default()
+        };
+        let (_server, addr) = start_server(&temp_dir, limits, runtime)?;
+
+        // value string never ends, server stops reading at the limit.
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let _ = stream.write_all(br#"{"Set":{"key":"key","value":""#);
+        let _ = stream.write_all(&[b'v'; 64 * 1024]);
+        let closed = match stream.read(&mut [0u8; 16]) {
+            Ok(len) => len == 0,
+            Err(e) => !matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut),
+        };
+        assert!(closed, "{}", runtime);
+
+        // requests within the limit are still served.
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        stream.write_all(br#"{"Set":{"key":"key","value":"value"}}"#)?;
+        let mut reply = [0u8; 16];
+        let len = stream.read(&mut reply)?;
+        assert_eq!(&reply[..len], br#"{"Ok":null}"#, "{}", runtime);
+    }
+    Ok(())
+}
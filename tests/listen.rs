@@ -0,0 +1,106 @@
+mod common;
+
+use kvs::{Address, Codec, KvsClient, Listener, Result};
+use std::net::TcpListener;
+use std::thread;
+use tempfile::TempDir;
+
+fn check_set_get(addr: &Address, key: &str) -> Result<()> {
+    let mut client = KvsClient::connect_to(addr, Codec::Bincode)?;
+    client.set(key.to_owned(), "value".to_owned())?;
+    assert_eq!(client.get(key.to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn parse_addresses() -> Result<()> {
+    for (addr, host) in [
+        ("127.0.0.1:4000", "127.0.0.1"),
+        ("[::1]:4000", "::1"),
+        ("localhost:4000", "localhost"),
+    ] {
+        let parsed: Address = addr.parse()?;
+        assert_eq!(parsed, Address::Tcp(addr.to_owned()));
+        assert_eq!(parsed.host(), Some(host));
+        assert_eq!(parsed.to_string(), addr);
+    }
+
+    let unix: Address = "unix:/tmp/kvs.sock".parse()?;
+    assert_eq!(unix, Address::Unix("/tmp/kvs.sock".into()));
+    assert_eq!(unix.host(), None);
+    assert_eq!(unix.to_string(), "unix:/tmp/kvs.sock");
+
+    for invalid in ["", "localhost", "localhost:", ":4000", "host:port", "unix:"] {
+        assert!(invalid.parse::<Address>().is_err(), "{}", invalid);
+    }
+    Ok(())
+}
+
+#[test]
+fn listen_on_ipv6_and_hostname() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let server = common::store_server(&temp_dir, 2)?;
+    let ipv6 = Listener::bind(&"[::1]:0".parse()?)?;
+    let hostname = Listener::bind(&"localhost:0".parse()?)?;
+    let ipv6_addr = ipv6.local_addr()?;
+    let hostname_port = match hostname.local_addr()? {
+        Address::Tcp(addr) => addr.rsplit_once(':').unwrap().1.to_owned(),
+        Address::Unix(_) => unreachable!(),
+    };
+    thread::spawn(move || server.run_on_many(vec![ipv6, hostname]));
+
+    check_set_get(&ipv6_addr, "key1")?;
+    check_set_get(&format!("localhost:{}", hostname_port).parse()?, "key2")?;
+    let mut client = KvsClient::connect_to(&ipv6_addr, Codec::Json)?;
+    assert_eq!(client.scan("key".to_owned())?.len(), 2);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn listen_on_unix_socket_and_tcp() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let server = common::store_server(&temp_dir, 2)?;
+    let socket: Address = format!("unix:{}", temp_dir.path().join("kvs.sock").display()).parse()?;
+    let unix = Listener::bind(&socket)?;
+    assert_eq!(unix.local_addr()?, socket);
+    let tcp = TcpListener::bind("127.0.0.1:0")?;
+    let tcp_addr = Address::from(tcp.local_addr()?);
+    thread::spawn(move || server.run_on_many(vec![unix, tcp.into()]));
+
+    check_set_get(&socket, "key1")?;
+    check_set_get(&tcp_addr, "key2")?;
+
+    // socket file left behind is replaced on next bind.
+    drop(Listener::bind(&socket));
+    let mut client = KvsClient::connect_to(&tcp_addr, Codec::Json)?;
+    assert_eq!(client.scan("key".to_owned())?.len(), 2);
+    Ok(())
+}
+
+#[cfg(all(unix, feature = "async"))]
+#[tokio::test(flavor = "multi_thread")]
+async fn async_runtime_on_unix_socket() -> Result<()> {
+    use kvs::async_client::{Pool, PoolOptions};
+    use kvs::AsyncKvsClient;
+
+    let temp_dir = TempDir::new()?;
+    let server = common::store_server(&temp_dir, 2)?;
+    let socket: Address = format!("unix:{}", temp_dir.path().join("kvs.sock").display()).parse()?;
+    let unix = Listener::bind(&socket)?;
+    thread::spawn(move || server.run_async_on(unix));
+
+    let mut client = AsyncKvsClient::connect_to(&socket, Codec::Bincode).await?;
+    client.set("key".to_owned(), "value".to_owned()).await?;
+    // sync client over the same socket sees the write.
+    let sync_socket = socket.clone();
+    let value = tokio::task::spawn_blocking(move || {
+        KvsClient::connect_to(&sync_socket, Codec::Json)?.get("key".to_owned())
+    })
+    .await??;
+    assert_eq!(value, Some("value".to_owned()));
+
+    let pool = Pool::new(socket.to_string(), Codec::Json, PoolOptions::default());
+    assert_eq!(pool.get("key".to_owned()).await?, Some("value".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,125 @@
+mod common;
+
+use kvs::protocol::{MAGIC, PROTOCOL_VERSION};
+use kvs::{Codec, KvsClient, KvsError, Response, ResponseError, Result, Runtime, CMD};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use tempfile::TempDir;
+
+fn set_get_remove(codec: Codec) -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (_server, addr) = common::start_server(&temp_dir, 2, Runtime::Threads)?;
+
+    let mut client = KvsClient::connect(addr, codec)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, None);
+
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    let err = client.remove("key1".to_owned()).unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(KvsError::KeyNotFound)));
+    Ok(())
+}
+
+#[test]
+fn framed_json_codec() -> Result<()> {
+    set_get_remove(Codec::Json)
+}
+
+#[test]
+fn framed_bincode_codec() -> Result<()> {
+    set_get_remove(Codec::Bincode)
+}
+
+#[test]
+fn legacy_json_stream() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (_server, addr) = common::start_server(&temp_dir, 2, Runtime::Threads)?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#"{"Set":{"key":"key1","value":"value1"}}{"Get":{"key":"key1"}}"#)?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+
+    let mut responses = String::new();
+    stream.read_to_string(&mut responses)?;
+    assert_eq!(responses, r#"{"Ok":null}{"Ok":"value1"}"#);
+    Ok(())
+}
+
+#[test]
+fn handshake_rejects_unknown_version() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (_server, addr) = common::start_server(&temp_dir, 2, Runtime::Threads)?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&MAGIC)?;
+    stream.write_all(&[PROTOCOL_VERSION + 1, 0])?;
+
+    let mut reply = [0u8; 6];
+    stream.read_exact(&mut reply)?;
+    assert_eq!(reply[..4], MAGIC);
+    assert_ne!(reply[5], 0);
+    Ok(())
+}
+
+#[test]
+fn pipelined_batch() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (_server, addr) = common::start_server(&temp_dir, 2, Runtime::Threads)?;
+    let mut client = KvsClient::connect(addr, Codec::Bincode)?;
+
+    let mut cmds = vec![];
+    for i in 0..500 {
+        let key = format!("key{}", i % 10);
+        cmds.push(CMD::Set {
+            key: key.clone(),
+            value: format!("value{}", i),
+        });
+        cmds.push(CMD::Get { key });
+    }
+    cmds.push(CMD::Rm {
+        key: "missing".to_owned(),
+    });
+
+    let responses = client.batch(cmds)?;
+    assert_eq!(responses.len(), 1001);
+    for i in 0..500 {
+        assert_eq!(responses[2 * i], Response::Ok);
+        // commands for the same key keep their order.
+        assert_eq!(
+            responses[2 * i + 1],
+            Response::Value(Some(format!("value{}", i)))
+        );
+    }
+    assert_eq!(responses[1000], Response::Err(ResponseError::KeyNotFound));
+    Ok(())
+}
+
+#[test]
+fn pipelined_send_receive() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (_server, addr) = common::start_server(&temp_dir, 2, Runtime::Threads)?;
+    let mut client = KvsClient::connect(addr, Codec::Json)?;
+
+    let mut ids = vec![];
+    for i in 0..20 {
+        ids.push(client.send(CMD::Set {
+            key: format!("key{}", i),
+            value: format!("value{}", i),
+        })?);
+    }
+
+    let mut received = vec![];
+    for _ in 0..ids.len() {
+        let (id, response) = client.receive()?;
+        assert_eq!(response, Response::Ok);
+        received.push(id);
+    }
+    received.sort_unstable();
+    assert_eq!(received, ids);
+
+    assert_eq!(client.get("key7".to_owned())?, Some("value7".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,343 @@
+use kvs::raft::{
+    Member, Membership, MembershipChange, NodeId, RaftEngine, RaftOptions, SimulatedNetwork,
+    TcpTransport,
+};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{Codec, KvServer, KvStore, KvsClient, KvsEngine, KvsError, Result};
+use std::collections::BTreeMap;
+use std::net::TcpListener;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Polls until the condition holds, replication is asynchronous.
+fn eventually(mut condition: impl FnMut() -> Result<bool>) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition()? {
+        assert!(Instant::now() < deadline, "condition not met in time");
+        thread::sleep(Duration::from_millis(20));
+    }
+    Ok(())
+}
+
+fn member(id: NodeId) -> Member {
+    Member {
+        raft_addr: format!("raft{}", id),
+        client_addr: format!("client{}", id),
+    }
+}
+
+/// Nodes of one cluster connected by simulated network.
+struct Cluster {
+    network: SimulatedNetwork,
+    nodes: BTreeMap<NodeId, RaftEngine<KvStore>>,
+    snapshot_threshold: u64,
+    dirs: Vec<TempDir>,
+}
+
+impl Cluster {
+    fn new(size: u64, snapshot_threshold: u64) -> Result<Self> {
+        let mut cluster = Self {
+            network: SimulatedNetwork::new(),
+            nodes: BTreeMap::new(),
+            snapshot_threshold,
+            dirs: vec![],
+        };
+        let members: Membership = (1..=size).map(|id| (id, member(id))).collect();
+        for id in 1..=size {
+            cluster.start(id, members.clone())?;
+        }
+        Ok(cluster)
+    }
+
+    fn start(&mut self, id: NodeId, members: Membership) -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut options = RaftOptions::new(id, members)
+            .with_dir(dir.path().join("raft"))
+            .with_snapshot_threshold(self.snapshot_threshold);
+        options.request_timeout = Duration::from_secs(1);
+        let engine = RaftEngine::start(options, KvStore::open(dir.path())?, self.network.clone())?;
+        self.network.register(id, engine.mailbox());
+        self.nodes.insert(id, engine);
+        self.dirs.push(dir);
+        Ok(())
+    }
+
+    /// Waits until one of the nodes is leader that the others follow.
+    fn leader(&self, among: &[NodeId]) -> Result<NodeId> {
+        let mut leader = None;
+        eventually(|| {
+            leader = None;
+            for id in among {
+                let status = self.nodes[id].status()?;
+                if status.leader == Some(*id) {
+                    leader = Some(*id);
+                }
+            }
+            let agreed = match leader {
+                Some(leader) => among
+                    .iter()
+                    .map(|id| self.nodes[id].status())
+                    .collect::<Result<Vec<_>>>()?
+                    .iter()
+                    .all(|status| status.leader == Some(leader)),
+                None => false,
+            };
+            Ok(agreed)
+        })?;
+        Ok(leader.unwrap())
+    }
+}
+
+fn is_not_leader(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref(), Some(KvsError::NotLeader(_)))
+}
+
+#[test]
+fn writes_commit_on_every_node() -> Result<()> {
+    let cluster = Cluster::new(3, 1000)?;
+    let leader = cluster.leader(&[1, 2, 3])?;
+    let engine = &cluster.nodes[&leader];
+
+    for i in 0..20 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    engine.remove("key0".to_owned())?;
+    assert!(engine.compare_and_set(
+        "key1".to_owned(),
+        Some("value1".to_owned()),
+        "swapped".to_owned()
+    )?);
+    assert!(!engine.compare_and_set("key2".to_owned(), None, "swapped".to_owned())?);
+    assert_eq!(engine.get("key1".to_owned())?, Some("swapped".to_owned()));
+    assert_eq!(engine.scan("key1".to_owned())?.len(), 11);
+
+    // followers redirect to the leader and serve stale reads.
+    for (id, node) in &cluster.nodes {
+        if *id == leader {
+            continue;
+        }
+        let e = node.set("key".to_owned(), "value".to_owned()).unwrap_err();
+        match e.downcast_ref() {
+            Some(KvsError::NotLeader(Some(addr))) => assert_eq!(addr, &format!("client{}", leader)),
+            other => panic!("expected redirect, got {:?}", other),
+        }
+        assert!(is_not_leader(&node.get("key1".to_owned()).unwrap_err()));
+        eventually(|| Ok(node.get_stale("key19".to_owned())?.is_some()))?;
+        assert_eq!(
+            node.get_stale("key1".to_owned())?,
+            Some("swapped".to_owned())
+        );
+        assert_eq!(node.get_stale("key0".to_owned())?, None);
+    }
+    Ok(())
+}
+
+#[test]
+fn new_leader_elected_after_partition() -> Result<()> {
+    let cluster = Cluster::new(3, 1000)?;
+    let old = cluster.leader(&[1, 2, 3])?;
+    cluster.nodes[&old].set("key".to_owned(), "old".to_owned())?;
+
+    cluster.network.isolate(old);
+    let rest: Vec<NodeId> = (1..=3).filter(|id| *id != old).collect();
+    let new = cluster.leader(&rest)?;
+    assert_ne!(new, old);
+    assert_eq!(
+        cluster.nodes[&new].get("key".to_owned())?,
+        Some("old".to_owned())
+    );
+    cluster.nodes[&new].set("key".to_owned(), "new".to_owned())?;
+
+    // isolated leader can't commit writes nor confirm reads.
+    assert!(cluster.nodes[&old]
+        .set("key".to_owned(), "lost".to_owned())
+        .is_err());
+    assert!(cluster.nodes[&old].get("key".to_owned()).is_err());
+
+    cluster.network.heal(old);
+    let leader = cluster.leader(&[1, 2, 3])?;
+    assert_eq!(
+        cluster.nodes[&leader].get("key".to_owned())?,
+        Some("new".to_owned())
+    );
+    eventually(|| Ok(cluster.nodes[&old].get_stale("key".to_owned())? == Some("new".to_owned())))?;
+    Ok(())
+}
+
+#[test]
+fn lagging_follower_installs_snapshot() -> Result<()> {
+    let cluster = Cluster::new(3, 10)?;
+    let leader = cluster.leader(&[1, 2, 3])?;
+    let lagging = (1..=3).find(|id| *id != leader).unwrap();
+
+    cluster.nodes[&leader].set("gone".to_owned(), "value".to_owned())?;
+    eventually(|| {
+        Ok(cluster.nodes[&lagging]
+            .get_stale("gone".to_owned())?
+            .is_some())
+    })?;
+
+    cluster.network.isolate(lagging);
+    cluster.nodes[&leader].remove("gone".to_owned())?;
+    for i in 0..50 {
+        cluster.nodes[&leader].set(format!("key{}", i % 20), format!("value{}", i))?;
+    }
+    let status = cluster.nodes[&leader].status()?;
+    assert!(status.commit_index > 50);
+
+    cluster.network.heal(lagging);
+    let node = &cluster.nodes[&lagging];
+    eventually(|| Ok(node.get_stale("key9".to_owned())? == Some("value49".to_owned())))?;
+    assert_eq!(node.get_stale("gone".to_owned())?, None);
+    assert_eq!(
+        node.get_stale("key19".to_owned())?,
+        Some("value39".to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn large_snapshot_is_sent_in_chunks() -> Result<()> {
+    let cluster = Cluster::new(3, 10)?;
+    let leader = cluster.leader(&[1, 2, 3])?;
+    let lagging = (1..=3).find(|id| *id != leader).unwrap();
+
+    // several times the size of a single chunk.
+    cluster.network.isolate(lagging);
+    let value = "v".repeat(256 * 1024);
+    for i in 0..30 {
+        cluster.nodes[&leader].set(format!("key{}", i % 15), format!("{}{}", value, i))?;
+    }
+
+    cluster.network.heal(lagging);
+    let node = &cluster.nodes[&lagging];
+    eventually(|| Ok(node.get_stale("key14".to_owned())? == Some(format!("{}29", value))))?;
+    for i in 0..15 {
+        assert_eq!(
+            node.get_stale(format!("key{}", i))?,
+            Some(format!("{}{}", value, i + 15))
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn membership_changes_one_node_at_a_time() -> Result<()> {
+    let mut cluster = Cluster::new(3, 1000)?;
+    let leader = cluster.leader(&[1, 2, 3])?;
+    cluster.nodes[&leader].set("key".to_owned(), "value".to_owned())?;
+
+    // joining node waits to be contacted by the leader.
+    cluster.start(4, Membership::new())?;
+    cluster.nodes[&leader].change_membership(MembershipChange::Add {
+        id: 4,
+        member: member(4),
+    })?;
+    eventually(|| Ok(cluster.nodes[&4].get_stale("key".to_owned())?.is_some()))?;
+    assert_eq!(cluster.nodes[&4].status()?.membership.len(), 4);
+
+    // removed leader steps down and the rest elects a new one.
+    cluster.nodes[&leader].change_membership(MembershipChange::Remove { id: leader })?;
+    let rest: Vec<NodeId> = (1..=4).filter(|id| *id != leader).collect();
+    let new = cluster.leader(&rest)?;
+    assert_ne!(new, leader);
+    let status = cluster.nodes[&new].status()?;
+    assert_eq!(status.membership.keys().copied().collect::<Vec<_>>(), rest);
+
+    cluster.nodes[&new].set("key".to_owned(), "changed".to_owned())?;
+    for id in &rest {
+        eventually(|| {
+            Ok(cluster.nodes[id].get_stale("key".to_owned())? == Some("changed".to_owned()))
+        })?;
+    }
+    Ok(())
+}
+
+#[test]
+fn clients_follow_leader_over_loopback() -> Result<()> {
+    let raft_listeners = (0..3)
+        .map(|_| TcpListener::bind("127.0.0.1:0"))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let client_listeners = (0..3)
+        .map(|_| TcpListener::bind("127.0.0.1:0"))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let mut members = Membership::new();
+    for i in 0..3 {
+        members.insert(
+            i as NodeId + 1,
+            Member {
+                raft_addr: raft_listeners[i].local_addr()?.to_string(),
+                client_addr: client_listeners[i].local_addr()?.to_string(),
+            },
+        );
+    }
+
+    let mut dirs = vec![];
+    let mut client_addrs = vec![];
+    for (i, (raft, client)) in raft_listeners.into_iter().zip(client_listeners).enumerate() {
+        let dir = TempDir::new()?;
+        let options =
+            RaftOptions::new(i as NodeId + 1, members.clone()).with_dir(dir.path().join("raft"));
+        let transport = TcpTransport::new("secret");
+        let engine = RaftEngine::start(options, KvStore::open(dir.path())?, transport.clone())?;
+        transport.listen(raft, engine.mailbox());
+        client_addrs.push(client.local_addr()?);
+        let server = KvServer::new(engine, SharedQueueThreadPool::new(2)?);
+        thread::spawn(move || server.run_on(client));
+        dirs.push(dir);
+    }
+
+    // every node accepts writes, followers redirect the client.
+    for (i, addr) in client_addrs.iter().enumerate() {
+        let mut client = KvsClient::connect(addr, Codec::Bincode)?;
+        client.set(format!("key{}", i), format!("value{}", i))?;
+        assert_eq!(
+            client.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    client_addrs.iter().try_for_each(|addr| {
+        let mut client = KvsClient::connect(addr, Codec::Json)?;
+        eventually(|| Ok(client.get_stale("key2".to_owned())?.is_some()))
+    })?;
+
+    let mut client = KvsClient::connect(client_addrs[0], Codec::Bincode)?;
+    client.remove("key0".to_owned())?;
+    assert_eq!(client.get("key0".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn nodes_with_another_token_are_refused() -> Result<()> {
+    let listeners = (0..2)
+        .map(|_| TcpListener::bind("127.0.0.1:0"))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let mut members = Membership::new();
+    for (i, listener) in listeners.iter().enumerate() {
+        members.insert(
+            i as NodeId + 1,
+            Member {
+                raft_addr: listener.local_addr()?.to_string(),
+                client_addr: format!("client{}", i + 1),
+            },
+        );
+    }
+
+    let mut nodes = vec![];
+    for (i, (listener, token)) in listeners.into_iter().zip(["secret", "guess"]).enumerate() {
+        let dir = TempDir::new()?;
+        let options = RaftOptions::new(i as NodeId + 1, members.clone());
+        let transport = TcpTransport::new(token);
+        let engine = RaftEngine::start(options, KvStore::open(dir.path())?, transport.clone())?;
+        transport.listen(listener, engine.mailbox());
+        nodes.push((engine, dir));
+    }
+
+    // neither node gets the vote of the other one.
+    thread::sleep(Duration::from_secs(2));
+    for (engine, _) in &nodes {
+        assert_eq!(engine.status()?.leader, None);
+    }
+    Ok(())
+}
@@ -0,0 +1,208 @@
+mod common;
+
+use kvs::protocol::Credentials;
+use kvs::replication::LogBatch;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{Codec, KvServer, KvStore, KvsClient, KvsEngine, KvsError, Result, Runtime};
+use std::fs;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn start_leader(temp_dir: &TempDir) -> Result<(SocketAddr, KvStore)> {
+    let store = KvStore::open(temp_dir.path())?;
+    let server = KvServer::new(store.clone(), SharedQueueThreadPool::new(4)?);
+    let addr = common::serve(&server, Runtime::Threads)?;
+    Ok((addr, store))
+}
+
+fn start_follower(
+    temp_dir: &TempDir,
+    leader: SocketAddr,
+) -> Result<(SocketAddr, KvServer<KvStore, SharedQueueThreadPool>)> {
+    let server = KvServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+    )
+    .with_replica_of(leader, Credentials::Anonymous);
+    let addr = common::serve(&server, Runtime::Threads)?;
+    Ok((addr, server))
+}
+
+/// Polls until the condition holds, replication is asynchronous.
+fn eventually(mut condition: impl FnMut() -> Result<bool>) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition()? {
+        assert!(Instant::now() < deadline, "condition not met in time");
+        thread::sleep(Duration::from_millis(20));
+    }
+    Ok(())
+}
+
+#[test]
+fn follower_streams_leader_writes() -> Result<()> {
+    let leader_dir = TempDir::new()?;
+    let follower_dir = TempDir::new()?;
+    let (leader_addr, _) = start_leader(&leader_dir)?;
+    let mut leader = KvsClient::connect(leader_addr, Codec::Bincode)?;
+    leader.set("before".to_owned(), "value".to_owned())?;
+
+    let (follower_addr, follower_server) = start_follower(&follower_dir, leader_addr)?;
+    let mut follower = KvsClient::connect(follower_addr, Codec::Bincode)?;
+    eventually(|| Ok(follower.get("before".to_owned())?.is_some()))?;
+
+    for i in 0..50 {
+        leader.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    leader.remove("before".to_owned())?;
+    eventually(|| Ok(follower.get("key49".to_owned())?.is_some()))?;
+    eventually(|| Ok(follower.get("before".to_owned())?.is_none()))?;
+    assert_eq!(follower.get("key7".to_owned())?, Some("value7".to_owned()));
+
+    // followers serve reads only.
+    let e = follower
+        .set("key1".to_owned(), "changed".to_owned())
+        .unwrap_err();
+    assert!(matches!(e.downcast_ref(), Some(KvsError::ReadOnly)));
+
+    let stats = follower_server.stats()?;
+    assert_eq!(stats.keys, 50);
+    let replication = stats.replication.expect("follower reports replication");
+    assert_eq!(replication.leader, leader_addr.to_string());
+    assert!(replication.connected);
+    assert!(replication.position.is_some());
+    Ok(())
+}
+
+#[test]
+fn follower_bootstraps_from_snapshot_after_compaction() -> Result<()> {
+    let leader_dir = TempDir::new()?;
+    let follower_dir = TempDir::new()?;
+    let (leader_addr, leader_store) = start_leader(&leader_dir)?;
+
+    // follower data that leader doesn't have is discarded.
+    KvStore::open(follower_dir.path())?.set("stale".to_owned(), "value".to_owned())?;
+
+    // enough writes for compaction to remove the first generations.
+    for i in 0..2500 {
+        leader_store.set(format!("key{}", i % 300), format!("value{}", i))?;
+    }
+    leader_store.remove("key0".to_owned())?;
+
+    let (follower_addr, _follower_server) = start_follower(&follower_dir, leader_addr)?;
+    let mut follower = KvsClient::connect(follower_addr, Codec::Json)?;
+    eventually(|| Ok(follower.get("key299".to_owned())?.is_some()))?;
+    assert_eq!(
+        follower.get("key1".to_owned())?,
+        Some("value2401".to_owned())
+    );
+    assert_eq!(follower.get("key0".to_owned())?, None);
+    assert_eq!(follower.get("stale".to_owned())?, None);
+
+    // and keeps streaming after the snapshot, across further compactions.
+    for i in 0..1500 {
+        leader_store.set(format!("key{}", i % 300), format!("next{}", i))?;
+    }
+    eventually(|| Ok(follower.get("key299".to_owned())? == Some("next1499".to_owned())))?;
+    assert_eq!(
+        follower.get("key0".to_owned())?,
+        Some("next1200".to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn snapshot_is_sent_in_parts() -> Result<()> {
+    let leader_dir = TempDir::new()?;
+    let follower_dir = TempDir::new()?;
+    let (leader_addr, leader_store) = start_leader(&leader_dir)?;
+    let value = "v".repeat(10 * 1024);
+    for i in 0..300 {
+        leader_store.set(format!("key{:03}", i), format!("{}{}", value, i))?;
+    }
+
+    let mut client = KvsClient::connect(leader_addr, Codec::Bincode)?;
+    let (mut entries, at) = match client.replicate(None)? {
+        LogBatch::Snapshot {
+            entries,
+            next,
+            done,
+        } => {
+            assert!(!done);
+            (entries, next)
+        }
+        other => panic!("unexpected batch: {:?}", other),
+    };
+    let mut parts = 1;
+    loop {
+        let after = entries.last().unwrap().0.clone();
+        match client.replicate_snapshot(at, after)? {
+            LogBatch::Snapshot {
+                entries: part,
+                next,
+                done,
+            } => {
+                assert_eq!(next, at);
+                entries.extend(part);
+                parts += 1;
+                if done {
+                    break;
+                }
+            }
+            other => panic!("unexpected batch: {:?}", other),
+        }
+    }
+    assert!(parts > 2);
+    assert_eq!(entries.len(), 300);
+    assert_eq!(entries[299], ("key299".to_owned(), format!("{}299", value)));
+
+    // follower bootstraps from all the parts.
+    let (follower_addr, _follower_server) = start_follower(&follower_dir, leader_addr)?;
+    let mut follower = KvsClient::connect(follower_addr, Codec::Bincode)?;
+    eventually(|| Ok(follower.get("key299".to_owned())?.is_some()))?;
+    assert_eq!(
+        follower.get("key000".to_owned())?,
+        Some(format!("{}0", value))
+    );
+    Ok(())
+}
+
+#[test]
+fn follower_resumes_from_saved_position() -> Result<()> {
+    let leader_dir = TempDir::new()?;
+    let follower_dir = TempDir::new()?;
+    let (leader_addr, leader_store) = start_leader(&leader_dir)?;
+    leader_store.set("before".to_owned(), "value".to_owned())?;
+    let at = match KvsClient::connect(leader_addr, Codec::Bincode)?.replicate(None)? {
+        LogBatch::Snapshot { next, .. } => next,
+        other => panic!("unexpected batch: {:?}", other),
+    };
+
+    // follower stopped at `at`, data it has is kept instead of snapshot.
+    KvStore::open(follower_dir.path())?.set("local".to_owned(), "value".to_owned())?;
+    let state = follower_dir.path().join("replication.json");
+    let saved = format!(
+        r#"{{"leader":"{}","position":{{"gen":{},"pos":{}}}}}"#,
+        leader_addr, at.gen, at.pos
+    );
+    fs::write(&state, &saved)?;
+
+    let server = KvServer::new(
+        KvStore::open(follower_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+    )
+    .with_replica_of(leader_addr, Credentials::Anonymous)
+    .with_replica_state(&state);
+    let follower_addr = common::serve(&server, Runtime::Threads)?;
+
+    leader_store.set("after".to_owned(), "value".to_owned())?;
+    let mut follower = KvsClient::connect(follower_addr, Codec::Bincode)?;
+    eventually(|| Ok(follower.get("after".to_owned())?.is_some()))?;
+    assert_eq!(follower.get("local".to_owned())?, Some("value".to_owned()));
+    assert_eq!(follower.get("before".to_owned())?, None);
+
+    // saved position moves on together with the follower.
+    eventually(|| Ok(fs::read_to_string(&state)? != saved))?;
+    Ok(())
+}
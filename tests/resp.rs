@@ -0,0 +1,232 @@
+mod common;
+
+use kvs::Result;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+/// Minimal hand-written RESP2 client.
+struct RespClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl RespClient {
+    fn connect(temp_dir: &TempDir) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server = common::store_server(temp_dir, 2)?;
+        thread::spawn(move || server.run_resp_on(listener));
+
+        let stream = TcpStream::connect(addr)?;
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        })
+    }
+
+    fn command(&mut self, args: &[&str]) -> Result<Reply> {
+        let mut request = format!("*{}\r\n", args.len());
+        for arg in args {
+            request.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.writer.write_all(request.as_bytes())?;
+        self.read_reply()
+    }
+
+    fn read_reply(&mut self) -> Result<Reply> {
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        let (kind, rest) = line.split_at(1);
+
+        Ok(match kind {
+            "+" => Reply::Simple(rest.to_owned()),
+            "-" => Reply::Error(rest.to_owned()),
+            ":" => Reply::Integer(rest.parse()?),
+            "$" => match rest.parse::<i64>()? {
+                -1 => Reply::Bulk(None),
+                len => {
+                    let mut buf = vec![0u8; len as usize + 2];
+                    self.reader.read_exact(&mut buf)?;
+                    buf.truncate(len as usize);
+                    Reply::Bulk(Some(String::from_utf8(buf)?))
+                }
+            },
+            "*" => {
+                let len: usize = rest.parse()?;
+                let mut items = vec![];
+                for _ in 0..len {
+                    items.push(self.read_reply()?);
+                }
+                Reply::Array(items)
+            }
+            _ => panic!("unexpected reply: {}", line),
+        })
+    }
+}
+
+fn bulk(s: &str) -> Reply {
+    Reply::Bulk(Some(s.to_owned()))
+}
+
+#[test]
+fn resp_basic_commands() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut client = RespClient::connect(&temp_dir)?;
+
+    assert_eq!(client.command(&["PING"])?, Reply::Simple("PONG".to_owned()));
+    assert_eq!(
+        client.command(&["set", "key1", "value1"])?,
+        Reply::Simple("OK".to_owned())
+    );
+    assert_eq!(client.command(&["GET", "key1"])?, bulk("value1"));
+    assert_eq!(client.command(&["GET", "key2"])?, Reply::Bulk(None));
+
+    assert_eq!(
+        client.command(&["MSET", "key2", "value2", "key3", "value3"])?,
+        Reply::Simple("OK".to_owned())
+    );
+    assert_eq!(
+        client.command(&["MGET", "key1", "missing", "key3"])?,
+        Reply::Array(vec![bulk("value1"), Reply::Bulk(None), bulk("value3")])
+    );
+    assert_eq!(
+        client.command(&["EXISTS", "key1", "key2", "missing"])?,
+        Reply::Integer(2)
+    );
+    assert_eq!(
+        client.command(&["DEL", "key1", "missing"])?,
+        Reply::Integer(1)
+    );
+    assert_eq!(client.command(&["EXISTS", "key1"])?, Reply::Integer(0));
+
+    match client.command(&["INFO"])? {
+        Reply::Bulk(Some(info)) => assert!(info.contains("keys:2")),
+        other => panic!("unexpected INFO reply: {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn resp_scan() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut client = RespClient::connect(&temp_dir)?;
+
+    for i in 0..15 {
+        client.command(&["SET", &format!("user:{:02}", i), "v"])?;
+    }
+    client.command(&["SET", "order:1", "v"])?;
+
+    let mut cursor = "0".to_owned();
+    let mut keys = vec![];
+    loop {
+        match client.command(&["SCAN", &cursor, "MATCH", "user:*", "COUNT", "4"])? {
+            Reply::Array(mut reply) => {
+                if let Reply::Array(batch) = reply.pop().unwrap() {
+                    keys.extend(batch);
+                }
+                cursor = match reply.pop().unwrap() {
+                    Reply::Bulk(Some(next)) => next,
+                    other => panic!("unexpected cursor: {:?}", other),
+                };
+            }
+            other => panic!("unexpected SCAN reply: {:?}", other),
+        }
+        if cursor == "0" {
+            break;
+        }
+    }
+    assert_eq!(keys.len(), 15);
+    assert_eq!(keys[0], bulk("user:00"));
+    Ok(())
+}
+
+#[test]
+fn resp_expire_and_ttl() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut client = RespClient::connect(&temp_dir)?;
+
+    client.command(&["SET", "key1", "value1"])?;
+    assert_eq!(client.command(&["TTL", "key1"])?, Reply::Integer(-1));
+    assert_eq!(client.command(&["TTL", "missing"])?, Reply::Integer(-2));
+    assert_eq!(
+        client.command(&["EXPIRE", "missing", "10"])?,
+        Reply::Integer(0)
+    );
+
+    assert_eq!(
+        client.command(&["EXPIRE", "key1", "100"])?,
+        Reply::Integer(1)
+    );
+    assert_eq!(client.command(&["TTL", "key1"])?, Reply::Integer(100));
+
+    client.command(&["SET", "key2", "value2", "PX", "50"])?;
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(client.command(&["GET", "key2"])?, Reply::Bulk(None));
+    assert_eq!(client.command(&["TTL", "key2"])?, Reply::Integer(-2));
+    Ok(())
+}
+
+#[test]
+fn resp_errors() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut client = RespClient::connect(&temp_dir)?;
+
+    match client.command(&["HSET", "hash", "field", "value"])? {
+        Reply::Error(e) => assert!(e.starts_with("ERR unknown command 'hset'")),
+        other => panic!("unexpected reply: {:?}", other),
+    }
+    match client.command(&["GET"])? {
+        Reply::Error(e) => assert!(e.contains("wrong number of arguments")),
+        other => panic!("unexpected reply: {:?}", other),
+    }
+    match client.command(&["EXPIRE", "key", "soon"])? {
+        Reply::Error(e) => assert!(e.contains("not an integer")),
+        other => panic!("unexpected reply: {:?}", other),
+    }
+    // quoted arguments can't inject replies.
+    match client.command(&["FOO", "a\r\n+OK\r\nb"])? {
+        Reply::Error(e) => assert!(e.ends_with("'a  +OK  b'"), "{}", e),
+        other => panic!("unexpected reply: {:?}", other),
+    }
+
+    // connection is still usable after errors.
+    assert_eq!(client.command(&["PING", "hi"])?, bulk("hi"));
+    Ok(())
+}
+
+#[test]
+fn resp_oversized_headers() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut client = RespClient::connect(&temp_dir)?;
+    let addr = client.writer.peer_addr()?;
+
+    for request in [
+        "*99999999999\r\n".to_owned(),
+        format!("*1\r\n${}\r\n", usize::MAX),
+        "*1\r\n$99999999999\r\n".to_owned(),
+        "a".repeat(100 * 1024),
+    ] {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.write_all(request.as_bytes())?;
+        let mut reply = String::new();
+        BufReader::new(stream).read_line(&mut reply)?;
+        assert!(reply.starts_with("-ERR Protocol error"), "{:?}", reply);
+    }
+
+    // server is still up.
+    assert_eq!(client.command(&["PING"])?, Reply::Simple("PONG".to_owned()));
+    Ok(())
+}
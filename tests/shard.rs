@@ -0,0 +1,194 @@
+mod common;
+
+use kvs::shard::{HashRing, ShardedClient, DEFAULT_VNODES};
+use kvs::{Codec, KvsClient, Result, Runtime};
+use std::collections::HashMap;
+use tempfile::TempDir;
+
+fn start_servers(count: usize) -> Result<(Vec<TempDir>, Vec<String>)> {
+    let dirs = (0..count)
+        .map(|_| TempDir::new())
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let addrs = dirs
+        .iter()
+        .map(|dir| {
+            Ok(common::start_server(dir, 8, Runtime::Threads)?
+                .1
+                .to_string())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok((dirs, addrs))
+}
+
+fn keys(count: usize) -> Vec<String> {
+    (0..count).map(|i| format!("key{:04}", i)).collect()
+}
+
+/// Number of keys stored directly on each server.
+fn counts(addrs: &[String]) -> Result<HashMap<String, usize>> {
+    addrs
+        .iter()
+        .map(|addr| {
+            let mut client = KvsClient::connect(addr.as_str(), Codec::Bincode)?;
+            Ok((addr.clone(), client.scan(String::new())?.len()))
+        })
+        .collect()
+}
+
+#[test]
+fn ring_spreads_keys_and_moves_few_on_growth() {
+    let nodes: Vec<String> = (0..4).map(|i| format!("10.0.0.{}:4000", i)).collect();
+    let ring = HashRing::new(nodes.clone(), DEFAULT_VNODES);
+    let keys = keys(10000);
+
+    let mut per_node: HashMap<&str, usize> = HashMap::new();
+    for key in &keys {
+        *per_node.entry(ring.node(key).unwrap()).or_default() += 1;
+    }
+    assert_eq!(per_node.len(), 4);
+    for count in per_node.values() {
+        assert!(
+            *count > 1500 && *count < 3500,
+            "uneven spread {:?}",
+            per_node
+        );
+    }
+
+    // new node takes about a fifth of keys, only from the others.
+    let mut grown = nodes.clone();
+    grown.push("10.0.0.4:4000".to_owned());
+    let grown = HashRing::new(grown, DEFAULT_VNODES);
+    let moved: Vec<&String> = keys
+        .iter()
+        .filter(|key| ring.node(key) != grown.node(key))
+        .collect();
+    assert!(
+        moved.len() > 1000 && moved.len() < 3000,
+        "moved {}",
+        moved.len()
+    );
+    assert!(moved
+        .iter()
+        .all(|key| grown.node(key) == Some("10.0.0.4:4000")));
+
+    assert_eq!(HashRing::new(vec![], DEFAULT_VNODES).node("key"), None);
+}
+
+#[test]
+fn sharded_client_routes_and_fans_out() -> Result<()> {
+    let (_dirs, addrs) = start_servers(3)?;
+    let mut client = ShardedClient::connect(&addrs, Codec::Bincode)?;
+
+    let keys = keys(300);
+    client.set_many(
+        keys.iter()
+            .map(|k| (k.clone(), format!("v-{}", k)))
+            .collect(),
+    )?;
+    client.set("single".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("single".to_owned())?, Some("value".to_owned()));
+    client.remove("single".to_owned())?;
+    assert_eq!(client.get("single".to_owned())?, None);
+
+    // every key lives only on its owner.
+    for key in keys.iter().take(30) {
+        let owner = client.ring().node(key).unwrap().to_owned();
+        for addr in &addrs {
+            let mut direct = KvsClient::connect(addr.as_str(), Codec::Json)?;
+            assert_eq!(direct.get(key.clone())?.is_some(), *addr == owner);
+        }
+    }
+    let counts = counts(&addrs)?;
+    assert!(counts.values().all(|count| *count > 50), "{:?}", counts);
+
+    let mut wanted = vec!["missing".to_owned()];
+    wanted.extend(keys.iter().rev().cloned());
+    let values = client.get_many(wanted)?;
+    assert_eq!(values[0], None);
+    assert_eq!(values[1], Some("v-key0299".to_owned()));
+    assert_eq!(values[300], Some("v-key0000".to_owned()));
+
+    assert_eq!(client.scan(String::new())?, keys);
+    assert_eq!(client.scan("key01".to_owned())?.len(), 100);
+
+    client.remove_many(keys[..100].to_vec())?;
+    assert_eq!(client.scan(String::new())?, keys[100..].to_vec());
+    assert!(client.remove_many(vec!["missing".to_owned()]).is_err());
+    Ok(())
+}
+
+#[test]
+fn rebalance_after_adding_and_draining_servers() -> Result<()> {
+    let (_dirs, addrs) = start_servers(4)?;
+    let keys = keys(400);
+
+    let mut client = ShardedClient::connect(&addrs[..3], Codec::Bincode)?;
+    client.set_many(
+        keys.iter()
+            .map(|k| (k.clone(), format!("v-{}", k)))
+            .collect(),
+    )?;
+
+    // new server owns part of the keys, they are still on the old ones.
+    let mut grown = ShardedClient::connect(&addrs, Codec::Bincode)?;
+    let misplaced = keys
+        .iter()
+        .filter(|key| client.ring().node(key) != grown.ring().node(key))
+        .count();
+    assert!(misplaced > 0);
+    let moved = grown.rebalance(vec![])?;
+    assert_eq!(moved, misplaced);
+    assert_eq!(counts(&addrs[3..])?[&addrs[3]], misplaced);
+    assert_eq!(grown.scan(String::new())?, keys);
+    assert_eq!(
+        grown.get("key0123".to_owned())?,
+        Some("v-key0123".to_owned())
+    );
+    assert_eq!(grown.rebalance(vec![])?, 0);
+
+    // draining the first server moves all its keys to the rest.
+    let mut shrunk = ShardedClient::connect(&addrs[1..], Codec::Bincode)?;
+    let drained = vec![(
+        addrs[0].clone(),
+        KvsClient::connect(addrs[0].as_str(), Codec::Bincode)?,
+    )];
+    shrunk.rebalance(drained)?;
+    assert_eq!(counts(&addrs[..1])?[&addrs[0]], 0);
+    assert_eq!(shrunk.scan(String::new())?, keys);
+    let values = shrunk.get_many(keys.clone())?;
+    assert!(values
+        .iter()
+        .zip(&keys)
+        .all(|(value, key)| value.as_deref() == Some(format!("v-{}", key).as_str())));
+    Ok(())
+}
+
+#[test]
+fn rebalance_keeps_newer_value_of_owner() -> Result<()> {
+    let (_dirs, addrs) = start_servers(2)?;
+    // more keys than a page of rebalance listing.
+    let keys = keys(2500);
+    let mut client = ShardedClient::connect(&addrs[..1], Codec::Bincode)?;
+    client.set_many(keys.iter().map(|k| (k.clone(), "old".to_owned())).collect())?;
+
+    let mut grown = ShardedClient::connect(&addrs, Codec::Bincode)?;
+    let misplaced: Vec<&String> = keys
+        .iter()
+        .filter(|key| grown.ring().node(key) == Some(addrs[1].as_str()))
+        .collect();
+    assert!(misplaced.len() > 1);
+
+    // written to the new owner before the key was moved, it must not be
+    // overwritten by the old value.
+    let newer = misplaced[0].clone();
+    grown.set(newer.clone(), "new".to_owned())?;
+
+    assert_eq!(grown.rebalance(vec![])?, misplaced.len() - 1);
+    assert_eq!(grown.get(newer.clone())?, Some("new".to_owned()));
+    let mut old = KvsClient::connect(addrs[0].as_str(), Codec::Bincode)?;
+    assert_eq!(old.get(newer)?, None);
+    let counts = counts(&addrs)?;
+    assert_eq!(counts[&addrs[1]], misplaced.len());
+    assert_eq!(counts[&addrs[0]] + counts[&addrs[1]], keys.len());
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use assert_cmd::prelude::*;
+use kvs::{KvsClient, Result};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn shell(temp_dir: &TempDir, addr: &str) -> Result<Command> {
+    let mut cmd = Command::cargo_bin("kvs-client")?;
+    cmd.args(["shell", "--addr", addr])
+        // keeps history out of the real home directory.
+        .env("HOME", temp_dir.path())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped());
+    Ok(cmd)
+}
+
+fn with_server(addr: &str, test: impl FnOnce(&TempDir) -> Result<()>) -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut server = Command::cargo_bin("kvs-server")?
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()?;
+    thread::sleep(Duration::from_secs(1));
+    let result = test(&temp_dir);
+    server.kill()?;
+    server.wait()?;
+    result
+}
+
+#[test]
+fn shell_runs_commands() -> Result<()> {
+    let addr = "127.0.0.1:4031";
+    with_server(addr, |temp_dir| {
+        let mut child = shell(temp_dir, addr)?.spawn()?;
+        child.stdin.take().unwrap().write_all(
+            concat!(
+                "set \"a key\" \"line\\nnext \\xc5\\x82\"\n",
+                "get 'a key'\n",
+                "get missing\n",
+                "set raw 'no\\escape'\n",
+                "scan\n",
+                "rm raw\n",
+                "rm raw\n",
+                "set x\n",
+                "bogus\n",
+                "info\n",
+                "quit\n",
+                "get 'a key'\n",
+            )
+            .as_bytes(),
+        )?;
+        let output = child.wait_with_output()?;
+        assert!(output.status.success());
+
+        let stdout = String::from_utf8(output.stdout)?;
+        let lines: Vec<&str> = stdout
+            .lines()
+            .filter(|line| !line.ends_with(" ms)"))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "OK",
+                "\"line\\nnext ł\"",
+                "(nil)",
+                "OK",
+                "1) \"a key\"",
+                "2) \"raw\"",
+                "OK",
+                "(error) Key not found",
+                "(error) wrong number of arguments for 'set'",
+                "(error) unknown command 'bogus', try 'help'",
+                "server: 127.0.0.1:4031",
+                "keys: 1",
+                "commands: 10",
+            ]
+        );
+        assert!(temp_dir.path().join(".kvs_history").exists());
+
+        let mut client = KvsClient::connect(addr, kvs::Codec::Bincode)?;
+        assert_eq!(
+            client.get("a key".to_owned())?,
+            Some("line\nnext ł".to_owned())
+        );
+        Ok(())
+    })
+}
+
+#[cfg(unix)]
+#[test]
+fn shell_watch_polls_until_interrupted() -> Result<()> {
+    let addr = "127.0.0.1:4032";
+    with_server(addr, |temp_dir| {
+        let mut client = KvsClient::connect(addr, kvs::Codec::Bincode)?;
+        client.set("key".to_owned(), "value".to_owned())?;
+
+        let mut child = shell(temp_dir, addr)?.spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(b"\\watch key 0.1\n")?;
+        thread::sleep(Duration::from_millis(550));
+        Command::new("kill")
+            .args(["-INT", &child.id().to_string()])
+            .status()?;
+        stdin.write_all(b"get key\n")?;
+        drop(stdin);
+        let output = child.wait_with_output()?;
+        assert!(output.status.success());
+
+        let stdout = String::from_utf8(output.stdout)?;
+        let polls = stdout.lines().filter(|line| line.starts_with('[')).count();
+        assert!(polls >= 3, "{}", stdout);
+        assert!(stdout.contains("] \"value\" ("));
+        // shell goes on after the watch.
+        assert!(stdout.contains("\n\"value\"\n"));
+        Ok(())
+    })
+}
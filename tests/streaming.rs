@@ -0,0 +1,135 @@
+mod common;
+
+use kvs::{Codec, KvsClient, KvsError, Limits, Result};
+use std::io::Cursor;
+use tempfile::TempDir;
+
+#[test]
+fn large_values_are_streamed() -> Result<()> {
+    let value: String = "large \"value\" żółw 🐢\n"
+        .chars()
+        .cycle()
+        .take(3 * 1024 * 1024)
+        .collect();
+
+    for runtime in common::runtimes() {
+        for codec in [Codec::Json, Codec::Bincode] {
+            let temp_dir = TempDir::new()?;
+            let addr = common::serve(&common::store_server(&temp_dir, 4)?, runtime)?;
+            let mut client = KvsClient::connect(addr, codec)?;
+
+            client.set_from("large".to_owned(), value.as_bytes())?;
+            let mut read = vec![];
+            assert!(client.get_to("large".to_owned(), &mut read)?);
+            assert_eq!(read, value.as_bytes(), "{} {}", runtime, codec);
+            assert_eq!(client.get("large".to_owned())?, Some(value.clone()));
+
+            // small and empty values take single chunk.
+            client.set("small".to_owned(), "value".to_owned())?;
+            let mut read = vec![];
+            assert!(client.get_to("small".to_owned(), &mut read)?);
+            assert_eq!(read, b"value");
+            client.set_from("empty".to_owned(), Cursor::new(""))?;
+            assert_eq!(client.get("empty".to_owned())?, Some(String::new()));
+            assert!(!client.get_to("missing".to_owned(), &mut vec![])?);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn keys_and_values_over_limit_are_refused() -> Result<()> {
+    for runtime in common::runtimes() {
+        let temp_dir = TempDir::new()?;
+        let limits = Limits {
+            max_key_size: Some(16),
+            max_value_size: Some(1000),
+            ..Limits::default()
+        };
+        let addr = common::serve(
+            &common::store_server(&temp_dir, 4)?.with_limits(limits),
+            runtime,
+        )?;
+        let mut client = KvsClient::connect(addr, Codec::Bincode)?;
+
+        let err = client.get("k".repeat(17)).err().unwrap();
+        assert!(
+            matches!(
+                err.downcast_ref(),
+                Some(KvsError::KeyTooLarge { size: 17, max: 16 })
+            ),
+            "{}: {}",
+            runtime,
+            err
+        );
+        let err = client
+            .set("key".to_owned(), "v".repeat(1001))
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(KvsError::ValueTooLarge {
+                size: 1001,
+                max: 1000
+            })
+        ));
+
+        // uploaded value fails once it grows over the limit.
+        let err = client
+            .set_from("key".to_owned(), "v".repeat(200_000).as_bytes())
+            .err()
+            .unwrap();
+        assert!(
+            matches!(
+                err.downcast_ref(),
+                Some(KvsError::ValueTooLarge { max: 1000, .. })
+            ),
+            "{}: {}",
+            runtime,
+            err
+        );
+        assert_eq!(client.get("key".to_owned())?, None);
+
+        client.set_from("key".to_owned(), "v".repeat(1000).as_bytes())?;
+        assert_eq!(client.get("key".to_owned())?, Some("v".repeat(1000)));
+    }
+    Ok(())
+}
+
+#[test]
+fn frames_over_limit_are_skipped() -> Result<()> {
+    for runtime in common::runtimes() {
+        for codec in [Codec::Json, Codec::Bincode] {
+            let temp_dir = TempDir::new()?;
+            let limits = Limits {
+                max_frame_size: Some(4096),
+                ..Limits::default()
+            };
+            let addr = common::serve(
+                &common::store_server(&temp_dir, 4)?.with_limits(limits),
+                runtime,
+            )?;
+            let mut client = KvsClient::connect(addr, codec)?;
+
+            let err = client
+                .set("key".to_owned(), "v".repeat(5000))
+                .err()
+                .unwrap();
+            assert!(
+                matches!(
+                    err.downcast_ref(),
+                    Some(KvsError::FrameTooLarge { max: 4096, .. })
+                ),
+                "{} {}: {}",
+                runtime,
+                codec,
+                err
+            );
+
+            // connection stays usable.
+            client.set("key".to_owned(), "v".repeat(1000))?;
+            assert_eq!(client.get("key".to_owned())?, Some("v".repeat(1000)));
+        }
+    }
+    Ok(())
+}
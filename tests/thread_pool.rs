@@ -1,5 +1,6 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 use kvs::thread_pool::*;
 use kvs::Result;
@@ -68,3 +69,210 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+fn spawn_with_handle_result<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(4)?;
+    let handles: Vec<_> = (0..20)
+        .map(|i| pool.spawn_with_handle(move || i * 2))
+        .collect();
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.join(), Ok(i * 2));
+    }
+    Ok(())
+}
+
+fn spawn_with_handle_panic<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(4)?;
+    let handle = pool.spawn_with_handle(|| -> u32 {
+        panic_control::disable_hook_in_current_thread();
+        panic!("job {} failed", 7);
+    });
+    let err = handle.join().unwrap_err();
+    assert_eq!(err.message, "job 7 failed");
+    assert_eq!(err.to_string(), "job panicked: job 7 failed");
+
+    let handle = pool.spawn_with_handle(|| -> u32 {
+        panic_control::disable_hook_in_current_thread();
+        panic!("static message");
+    });
+    assert_eq!(handle.join().unwrap_err().message, "static message");
+
+    // the pool keeps working after panics.
+    assert_eq!(pool.spawn_with_handle(|| 42).join(), Ok(42));
+    Ok(())
+}
+
+fn spawn_with_handle_try_join<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(4)?;
+    let (sender, receiver) = crossbeam::channel::bounded::<()>(0);
+    let handle = pool.spawn_with_handle(move || receiver.recv().map(|_| "done"));
+
+    let handle = handle.try_join().expect_err("job is blocked");
+    let handle = handle
+        .join_timeout(Duration::from_millis(50))
+        .expect_err("job is blocked");
+
+    sender.send(())?;
+    let result = handle
+        .join_timeout(Duration::from_secs(5))
+        .expect("job is unblocked");
+    assert_eq!(result, Ok(Ok("done")));
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle_result::<NaiveThreadPool>()?;
+    spawn_with_handle_panic::<NaiveThreadPool>()?;
+    spawn_with_handle_try_join::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle_result::<SharedQueueThreadPool>()?;
+    spawn_with_handle_panic::<SharedQueueThreadPool>()?;
+    spawn_with_handle_try_join::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle_result::<RayonThreadPool>()?;
+    spawn_with_handle_panic::<RayonThreadPool>()?;
+    spawn_with_handle_try_join::<RayonThreadPool>()
+}
+
+/// Occupies the only worker of the pool until returned sender is dropped.
+fn block_worker(pool: &SharedQueueThreadPool) -> crossbeam::channel::Sender<()> {
+    let (started_sender, started) = crossbeam::channel::bounded(0);
+    let (sender, receiver) = crossbeam::channel::bounded::<()>(0);
+    pool.spawn(move || {
+        started_sender.send(()).unwrap();
+        let _ = receiver.recv();
+    });
+    started.recv().unwrap();
+    sender
+}
+
+#[test]
+fn shared_queue_thread_pool_rejects_over_capacity() -> Result<()> {
+    let pool = SharedQueueThreadPool::bounded(1, 2, QueuePolicy::Reject)?;
+    let unblock = block_worker(&pool);
+    let handles = [
+        pool.spawn_with_handle(|| 1),
+        pool.spawn_with_handle(|| 2),
+        pool.spawn_with_handle(|| 3),
+    ];
+    assert_eq!(pool.try_spawn(|| {}), Err(QueueFull));
+    let stats = pool.queue_stats();
+    assert_eq!(stats.depth, 2);
+    assert_eq!(stats.capacity, Some(2));
+    assert_eq!(stats.rejected, 2);
+
+    drop(unblock);
+    let [first, second, third] = handles;
+    assert_eq!(first.join(), Ok(1));
+    assert_eq!(second.join(), Ok(2));
+    // rejected job is dropped without running.
+    assert!(third.join().is_err());
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_sheds_oldest() -> Result<()> {
+    let pool = SharedQueueThreadPool::bounded(1, 2, QueuePolicy::ShedOldest)?;
+    let unblock = block_worker(&pool);
+    let handles: Vec<_> = (0..4).map(|i| pool.spawn_with_handle(move || i)).collect();
+    assert_eq!(pool.try_spawn(|| {}), Ok(()));
+    let stats = pool.queue_stats();
+    assert_eq!(stats.depth, 2);
+    assert_eq!(stats.shed, 3);
+    assert_eq!(stats.rejected, 0);
+
+    drop(unblock);
+    let results: Vec<_> = handles
+        .into_iter()
+        .map(|handle| handle.join().ok())
+        .collect();
+    assert_eq!(results, [None, None, None, Some(3)]);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_blocks_until_room() -> Result<()> {
+    let pool = Arc::new(SharedQueueThreadPool::bounded(1, 1, QueuePolicy::Block)?);
+    let unblock = block_worker(&pool);
+    pool.spawn(|| {});
+
+    let spawned = Arc::new(AtomicUsize::new(0));
+    let spawner = {
+        let pool = pool.clone();
+        let spawned = spawned.clone();
+        std::thread::spawn(move || {
+            let handle = pool.spawn_with_handle(|| "done");
+            spawned.store(1, Ordering::SeqCst);
+            handle.join()
+        })
+    };
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(spawned.load(Ordering::SeqCst), 0);
+    assert_eq!(pool.queue_stats().depth, 1);
+
+    drop(unblock);
+    assert_eq!(spawner.join().unwrap(), Ok("done"));
+    assert_eq!(pool.queue_stats().rejected, 0);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_needs_capacity() {
+    assert!(SharedQueueThreadPool::bounded(1, 0, QueuePolicy::Reject).is_err());
+}
+
+#[test]
+fn work_stealing_thread_pool_spawn_counter() -> Result<()> {
+    let pool = WorkStealingThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn work_stealing_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle_result::<WorkStealingThreadPool>()?;
+    spawn_with_handle_panic::<WorkStealingThreadPool>()?;
+    spawn_with_handle_try_join::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_spawn_from_worker() -> Result<()> {
+    const FAN_OUT: usize = 10;
+
+    let pool = Arc::new(WorkStealingThreadPool::new(4)?);
+    let wg = WaitGroup::new();
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..FAN_OUT {
+        let inner_pool = pool.clone();
+        let counter = counter.clone();
+        let wg = wg.clone();
+        // jobs spawned here go to the local queue of the worker and are
+        // stolen by the others.
+        pool.spawn(move || {
+            for _ in 0..FAN_OUT {
+                let counter = counter.clone();
+                let wg = wg.clone();
+                inner_pool.spawn(move || {
+                    std::thread::sleep(Duration::from_millis(1));
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    drop(wg);
+                });
+            }
+        });
+    }
+
+    wg.wait();
+    assert_eq!(counter.load(Ordering::SeqCst), FAN_OUT * FAN_OUT);
+    Ok(())
+}
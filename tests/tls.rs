@@ -0,0 +1,197 @@
+mod common;
+
+use kvs::raft::{Member, Membership, NodeId, RaftEngine, RaftOptions, TcpTransport};
+use kvs::{tls, Codec, KvStore, KvsClient, KvsEngine, Result, Runtime, Stream};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// PEM files of a throwaway CA with server and client certificates signed by it.
+struct Pki {
+    ca: PathBuf,
+    server_cert: PathBuf,
+    server_key: PathBuf,
+    client_cert: PathBuf,
+    client_key: PathBuf,
+}
+
+impl Pki {
+    fn generate(dir: &Path) -> Result<Self> {
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "kvs test ca");
+        let ca = Certificate::from_params(params)?;
+
+        let server = Certificate::from_params(CertificateParams::new(vec![
+            "127.0.0.1".to_owned(),
+            "localhost".to_owned(),
+        ]))?;
+
+        let mut params = CertificateParams::new(vec![]);
+        params.distinguished_name.push(DnType::CommonName, "alice");
+        let client = Certificate::from_params(params)?;
+
+        let pki = Self {
+            ca: dir.join("ca.pem"),
+            server_cert: dir.join("server.pem"),
+            server_key: dir.join("server.key"),
+            client_cert: dir.join("client.pem"),
+            client_key: dir.join("client.key"),
+        };
+        fs::write(&pki.ca, ca.serialize_pem()?)?;
+        fs::write(&pki.server_cert, server.serialize_pem_with_signer(&ca)?)?;
+        fs::write(&pki.server_key, server.serialize_private_key_pem())?;
+        fs::write(&pki.client_cert, client.serialize_pem_with_signer(&ca)?)?;
+        fs::write(&pki.client_key, client.serialize_private_key_pem())?;
+        Ok(pki)
+    }
+}
+
+fn start_server(temp_dir: &TempDir, pki: &Pki, mutual: bool) -> Result<SocketAddr> {
+    let client_ca = if mutual { Some(pki.ca.as_path()) } else { None };
+    let server = common::store_server(temp_dir, 2)?.with_tls(tls::server_config(
+        &pki.server_cert,
+        &pki.server_key,
+        client_ca,
+    )?);
+    common::serve(&server, Runtime::Threads)
+}
+
+#[test]
+fn tls_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let pki = Pki::generate(temp_dir.path())?;
+    let addr = start_server(&temp_dir, &pki, false)?;
+
+    let config = tls::client_config(&pki.ca, None)?;
+    let mut client = KvsClient::connect_tls(addr, "localhost", Codec::Bincode, config)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    // plain tcp clients can't talk to TLS listener.
+    assert!(KvsClient::connect(addr, Codec::Bincode)
+        .and_then(|mut client| client.get("key1".to_owned()))
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn tls_rejects_untrusted_server() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let pki = Pki::generate(temp_dir.path())?;
+    let addr = start_server(&temp_dir, &pki, false)?;
+
+    // certificates of another CA are not trusted.
+    let other_dir = TempDir::new()?;
+    let other = Pki::generate(other_dir.path())?;
+    let config = tls::client_config(&other.ca, None)?;
+    assert!(KvsClient::connect_tls(addr, "localhost", Codec::Json, config).is_err());
+
+    // name not listed in server certificate.
+    let config = tls::client_config(&pki.ca, None)?;
+    assert!(KvsClient::connect_tls(addr, "example.com", Codec::Json, config).is_err());
+    Ok(())
+}
+
+#[test]
+fn mutual_tls_requires_client_certificate() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let pki = Pki::generate(temp_dir.path())?;
+    let addr = start_server(&temp_dir, &pki, true)?;
+
+    let config = tls::client_config(&pki.ca, None)?;
+    assert!(
+        KvsClient::connect_tls(addr, "127.0.0.1", Codec::Bincode, config)
+            .and_then(|mut client| client.get("key1".to_owned()))
+            .is_err()
+    );
+
+    let config = tls::client_config(&pki.ca, Some((&pki.client_cert, &pki.client_key)))?;
+    let mut client = KvsClient::connect_tls(addr, "127.0.0.1", Codec::Bincode, config)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn mutual_tls_peer_identity() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let pki = Pki::generate(temp_dir.path())?;
+    let server_config = tls::server_config(&pki.server_cert, &pki.server_key, Some(&pki.ca))?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+
+    let server = thread::spawn(move || -> Result<Option<String>> {
+        let (tcp, _) = listener.accept()?;
+        let mut stream = Stream::accept(tcp, Some(&server_config))?;
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf)?;
+        stream.write_all(&buf)?;
+        Ok(stream.peer_identity())
+    });
+
+    let config = tls::client_config(&pki.ca, Some((&pki.client_cert, &pki.client_key)))?;
+    let mut stream = Stream::connect_tls(addr, "localhost", config)?;
+    stream.write_all(b"ping")?;
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf)?;
+    assert_eq!(&buf, b"ping");
+
+    assert_eq!(server.join().unwrap()?, Some("alice".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn raft_nodes_talk_over_tls() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let pki = Pki::generate(temp_dir.path())?;
+    let listeners = (0..2)
+        .map(|_| TcpListener::bind("127.0.0.1:0"))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let mut members = Membership::new();
+    for (i, listener) in listeners.iter().enumerate() {
+        members.insert(
+            i as NodeId + 1,
+            Member {
+                raft_addr: listener.local_addr()?.to_string(),
+                client_addr: format!("client{}", i + 1),
+            },
+        );
+    }
+
+    let mut nodes = vec![];
+    for (i, listener) in listeners.into_iter().enumerate() {
+        let dir = TempDir::new()?;
+        let transport = TcpTransport::new("secret").with_tls(
+            tls::server_config(&pki.server_cert, &pki.server_key, Some(&pki.ca))?,
+            tls::client_config(&pki.ca, Some((&pki.server_cert, &pki.server_key)))?,
+        );
+        let options = RaftOptions::new(i as NodeId + 1, members.clone());
+        let engine = RaftEngine::start(options, KvStore::open(dir.path())?, transport.clone())?;
+        transport.listen(listener, engine.mailbox());
+        nodes.push((engine, dir));
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let leader = nodes[0].0.status()?.leader;
+        if let Some(leader) = leader {
+            let engine = &nodes[leader as usize - 1].0;
+            engine.set("key1".to_owned(), "value1".to_owned())?;
+            assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+            break;
+        }
+        assert!(Instant::now() < deadline, "no leader elected");
+        thread::sleep(Duration::from_millis(20));
+    }
+    Ok(())
+}
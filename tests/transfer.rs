@@ -0,0 +1,156 @@
+use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsClient, KvsEngine, Result, SledKvsEngine};
+use predicates::str::contains;
+use std::fs;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn admin_import_and_export_csv() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let input = temp_dir.path().join("input.csv");
+    fs::write(
+        &input,
+        "key,value\nkey1,value1\n\"key,2\",\"multi\nline \"\"value\"\"\"\nother,value3\n",
+    )?;
+    let location = format!("kvs:{}", temp_dir.path().join("data").display());
+
+    Command::cargo_bin("kvs-admin")?
+        .args(["import", input.to_str().unwrap(), "--to", &location])
+        .args(["--batch-size", "2"])
+        .assert()
+        .success()
+        .stdout(contains("Imported 3 records"));
+
+    let output = temp_dir.path().join("output.csv");
+    Command::cargo_bin("kvs-admin")?
+        .args(["export", "--from", &location, "--prefix", "key"])
+        .args(["--output", output.to_str().unwrap()])
+        .assert()
+        .success();
+    assert_eq!(
+        fs::read_to_string(output)?,
+        "key,value\n\"key,2\",\"multi\nline \"\"value\"\"\"\nkey1,value1\n"
+    );
+
+    let store = KvStore::open(temp_dir.path().join("data").join("kv"))?;
+    assert_eq!(store.get("other".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn admin_export_jsonl_to_stdout() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let sled = SledKvsEngine::new(temp_dir.path().join("sled"))?;
+    sled.set("a".to_owned(), "1".to_owned())?;
+    sled.set("b".to_owned(), "two \"2\"".to_owned())?;
+    drop(sled);
+
+    Command::cargo_bin("kvs-admin")?
+        .args([
+            "export",
+            "--from",
+            &format!("sled:{}", temp_dir.path().display()),
+        ])
+        .assert()
+        .success()
+        .stdout("{\"key\":\"a\",\"value\":\"1\"}\n{\"key\":\"b\",\"value\":\"two \\\"2\\\"\"}\n");
+
+    // data directory holds sled, not kvs.
+    Command::cargo_bin("kvs-admin")?
+        .args([
+            "export",
+            "--from",
+            &format!("kvs:{}", temp_dir.path().display()),
+        ])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn admin_import_dry_run_validates_only() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let input = temp_dir.path().join("input.jsonl");
+    let data = temp_dir.path().join("data");
+    let location = format!("kvs:{}", data.display());
+
+    fs::write(&input, "{\"key\":\"a\",\"value\":\"1\"}\n{\"key\":\"b\"}\n")?;
+    Command::cargo_bin("kvs-admin")?
+        .args([
+            "import",
+            input.to_str().unwrap(),
+            "--to",
+            &location,
+            "--dry-run",
+        ])
+        .assert()
+        .failure()
+        .stderr(contains("invalid record 2"));
+
+    fs::write(
+        &input,
+        "{\"key\":\"a\",\"value\":\"1\"}\n{\"key\":\"b\",\"value\":\"2\"}\n",
+    )?;
+    Command::cargo_bin("kvs-admin")?
+        .args([
+            "import",
+            input.to_str().unwrap(),
+            "--to",
+            &location,
+            "--dry-run",
+        ])
+        .assert()
+        .success()
+        .stdout(contains("Dry run: 2 valid records"));
+    assert!(!data.exists());
+    Ok(())
+}
+
+#[test]
+fn client_import_and_export() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addr = "127.0.0.1:4030";
+    let mut server = Command::cargo_bin("kvs-server")?
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()?;
+    thread::sleep(Duration::from_secs(1));
+
+    let result = (|| -> Result<()> {
+        let input = temp_dir.path().join("input.jsonl");
+        let records: String = (0..2500)
+            .map(|i| format!("{{\"key\":\"key{}\",\"value\":\"value{}\"}}\n", i, i))
+            .collect();
+        fs::write(&input, records)?;
+
+        Command::cargo_bin("kvs-client")?
+            .args(["import", input.to_str().unwrap(), "--addr", addr])
+            .assert()
+            .success()
+            .stdout(contains("Imported 2500 records"));
+        let mut client = KvsClient::connect(addr, kvs::Codec::Bincode)?;
+        assert_eq!(
+            client.get("key1234".to_owned())?,
+            Some("value1234".to_owned())
+        );
+
+        Command::cargo_bin("kvs-client")?
+            .args([
+                "export", "--prefix", "key123", "--format", "csv", "--addr", addr,
+            ])
+            .assert()
+            .success()
+            .stdout(
+                "key,value\nkey123,value123\nkey1230,value1230\nkey1231,value1231\n\
+                 key1232,value1232\nkey1233,value1233\nkey1234,value1234\nkey1235,value1235\n\
+                 key1236,value1236\nkey1237,value1237\nkey1238,value1238\nkey1239,value1239\n",
+            );
+        Ok(())
+    })();
+    server.kill()?;
+    server.wait()?;
+    result
+}